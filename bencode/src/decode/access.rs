@@ -4,13 +4,30 @@ use crate::{Error, Result};
 use super::{decoder::Decoder, DecodedType};
 
 pub struct Access<'a, R: 'a + Read> {
-    d:      &'a mut Decoder<R>,
-    length: Option<usize>,
+    d:        &'a mut Decoder<R>,
+    length:   Option<usize>,
+    // Previous key of a dictionary, to check ordering in strict mode.
+    last_key: Option<Vec<u8>>,
 }
 
 impl<'a, R: 'a + Read> Access<'a, R> {
     pub fn new(deserializer: &'a mut Decoder<R>, length: Option<usize>) -> Self {
-        Self { d: deserializer, length }
+        Self { d: deserializer, length, last_key: None }
+    }
+
+    fn check_key_order(&mut self, key: &DecodedType) -> Result<()> {
+        let DecodedType::ByteString(key) = key else {
+            return Err(Error::InvalidToken { expected: "byte string key".to_string(), found: format!("{:?}", key) });
+        };
+        if let Some(last) = &self.last_key {
+            if key <= last {
+                return Err(Error::Custom(format!(
+                    "dictionary key {} is not after {}", String::from_utf8_lossy(key), String::from_utf8_lossy(last),
+                )));
+            }
+        }
+        self.last_key = Some(key.clone());
+        Ok(())
     }
 }
 
@@ -21,7 +38,7 @@ impl<'de, 'a, R: 'a + Read> de::SeqAccess<'de> for Access<'a, R> {
         where T: de::DeserializeSeed<'de> 
     {
         let out: Result<Option<T::Value>> = match self.d.read_next()? {
-            DecodedType::EOF => Ok(None),
+            DecodedType::End => Ok(None),
             x => {
                 self.d.next_token = Some(x);
                 Ok(Some(seed.deserialize(&mut *self.d)?))
@@ -31,7 +48,7 @@ impl<'de, 'a, R: 'a + Read> de::SeqAccess<'de> for Access<'a, R> {
         if let Some(l) = self.length {
             let l = l - 1;
             self.length = Some(l);
-            if l == 0 && self.d.read_next()? != DecodedType::EOF {
+            if l == 0 && self.d.read_next()? != DecodedType::End {
                 return Err(Error::InvalidType("expected e".to_string()))
             }
         }
@@ -46,8 +63,11 @@ impl<'de, 'a, R: Read> de::MapAccess<'de> for Access<'a, R> {
         where K: de::DeserializeSeed<'de> 
     {
         match self.d.read_next()? {
-            DecodedType::EOF => Ok(None),
+            DecodedType::End => Ok(None),
             x => {
+                if self.d.strict {
+                    self.check_key_order(&x)?;
+                }
                 self.d.next_token = Some(x);
                 Ok(Some(seed.deserialize(&mut *self.d)?))
             },
@@ -71,7 +91,7 @@ impl<'de, 'a, R: Read> de::VariantAccess<'de> for Access<'a, R> {
     {
         let out = seed.deserialize(&mut *self.d)?;
         match self.d.read_next()? {
-            DecodedType::EOF => Ok(out),
+            DecodedType::End => Ok(out),
             e => Err(Error::InvalidToken{ expected: "e for end".to_string(), found: format!("{:?}", e) }),
         }
     }
@@ -80,11 +100,11 @@ impl<'de, 'a, R: Read> de::VariantAccess<'de> for Access<'a, R> {
         where V: de::Visitor<'de> 
     {
         let out = match self.d.read_next()? {
-            DecodedType::List => visitor.visit_seq(Access::new(&mut *self.d, Some(len)))?,
+            DecodedType::List => self.d.nested(|d| visitor.visit_seq(Access::new(d, Some(len))))?,
             e => return Err(Error::InvalidToken{ expected: "l for list".to_string(), found: format!("{:?}", e) }),
         };
         match self.d.read_next()? {
-            DecodedType::EOF => Ok(out),
+            DecodedType::End => Ok(out),
             e => Err(Error::InvalidToken{ expected: "e for end".to_string(), found: format!("{:?}", e) }),
         }
     }
@@ -98,7 +118,7 @@ impl<'de, 'a, R: Read> de::VariantAccess<'de> for Access<'a, R> {
     {
         let out = serde::Deserializer::deserialize_any(&mut *self.d, visitor)?;
         match self.d.read_next()? {
-            DecodedType::EOF => Ok(out),
+            DecodedType::End => Ok(out),
             e => Err(Error::InvalidToken{ expected: "e for end".to_string(), found: format!("{:?}", e) }),
         }
     }
@@ -1,16 +1,27 @@
 use std::io::Read;
 use serde::de;
 use crate::{Error, Result};
-use super::{DecodedType, access::Access};
+use super::{DecodedType, access::Access, MAX_BYTES_LEN, MAX_DEPTH};
 
 pub struct Decoder<R: Read> {
-    pub scanner:    R,
-    pub next_token: Option<DecodedType>,
+    pub scanner:       R,
+    pub next_token:    Option<DecodedType>,
+    // Require dictionary keys in ascending order, as the spec demands.
+    pub strict:        bool,
+    // Longest byte string accepted, lengths come straight from the input.
+    pub max_bytes_len: usize,
+    // Lists and dicts currently open, decoding recurses for each.
+    pub depth:         usize,
+    pub max_depth:     usize,
 }
 
-impl<'de, R: Read> Decoder<R> {
+impl<R: Read> Decoder<R> {
 
-    pub fn new(scanner: R) -> Self { Self { scanner, next_token: None } }
+    pub fn new(scanner: R) -> Self {
+        Self { scanner, next_token: None, strict: false, max_bytes_len: MAX_BYTES_LEN, depth: 0, max_depth: MAX_DEPTH }
+    }
+
+    pub fn new_strict(scanner: R) -> Self { Self { strict: true, ..Self::new(scanner) } }
 
     pub fn read_next(&mut self) -> Result<DecodedType> { 
         if let Some(next) = self.next_token.take() {
@@ -27,11 +38,31 @@ impl<'de, R: Read> Decoder<R> {
             n @ b'0'..=b'9' => Ok(DecodedType::ByteString(self.read_bytes(n)?)),
             b'l' => Ok(DecodedType::List),
             b'd' => Ok(DecodedType::Dictionary),
-            b'e' => Ok(DecodedType::EOF),
+            b'e' => Ok(DecodedType::End),
             e => Err(Error::InvalidToken { expected: "a valid token type".to_string(), found: (e as char).to_string() }),
         }
     }
 
+    // Whether all input has been consumed.
+    pub fn is_finished(&mut self) -> Result<bool> {
+        if self.next_token.is_some() {
+            return Ok(false);
+        }
+        let mut buf = [0; 1];
+        Ok(self.scanner.read(&mut buf).map_err(Error::IoError)? == 0)
+    }
+
+    // Decodes the contents of a list or dict, limiting nesting so input can't overflow the stack.
+    pub fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= self.max_depth {
+            return Err(Error::TooDeep);
+        }
+        self.depth += 1;
+        let out = f(self);
+        self.depth -= 1;
+        out
+    }
+
     fn read_i64(&mut self) -> Result<i64>{
 
         let mut buf = [0; 1];
@@ -90,30 +121,32 @@ impl<'de, R: Read> Decoder<R> {
     fn read_bytes(&mut self, n: u8) -> Result<Vec<u8>> {
         
         let length = self.read_usize(n)?;
-        let mut buf = vec![0u8; length];
-        let n = self.scanner.read(&mut buf).map_err(Error::IoError)?;
-        
-        if n != length {
-            Err(Error::EOF)
-        } else {
-            Ok(buf)
+        if length > self.max_bytes_len {
+            return Err(Error::BytesTooLong(length));
+        }
+        // Grown as data is read rather than allocated up front, so a length past the end of the input costs nothing.
+        let mut buf = Vec::new();
+        (&mut self.scanner).take(length as u64).read_to_end(&mut buf).map_err(Error::IoError)?;
+        if buf.len() != length {
+            return Err(Error::EOF);
         }
+        Ok(buf)
     }
 }
 
-impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Decoder<R> {
+impl<'de, R: Read> de::Deserializer<'de> for &mut Decoder<R> {
 
     type Error = Error;
 
-    fn deserialize_any<V>(mut self, visitor: V) -> Result<V::Value>
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
         where V: serde::de::Visitor<'de> 
     {
         match self.read_next()? {
             DecodedType::Integer(i) => visitor.visit_i64(i),
             DecodedType::ByteString(s) => visitor.visit_bytes(&s),
-            DecodedType::List => visitor.visit_seq(Access::new(&mut self, None)),
-            DecodedType::Dictionary => visitor.visit_map(Access::new(&mut self, None)),
-            DecodedType::EOF => Err(Error::EOF),
+            DecodedType::List => self.nested(|d| visitor.visit_seq(Access::new(d, None))),
+            DecodedType::Dictionary => self.nested(|d| visitor.visit_map(Access::new(d, None))),
+            DecodedType::End => Err(Error::EOF),
         }
     }
 
@@ -121,7 +154,6 @@ impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Decoder<R> {
         bool char
         i8 i16 i32 i64
         u8 u16 u32 u64
-        f32 f64
         unit bytes byte_buf 
         seq map unit_struct tuple_struct
         ignored_any struct
@@ -154,6 +186,28 @@ impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Decoder<R> {
         visitor.visit_enum(Access::new(self, None))   
     }
 
+    // Floats aren't part of bencode, so are accepted as integers or their string representation.
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de> 
+    {
+        match self.read_next()? {
+            DecodedType::Integer(i) => visitor.visit_f64(i as f64),
+            DecodedType::ByteString(b) => {
+                let f = std::str::from_utf8(&b).ok().and_then(|s| s.parse().ok()).ok_or_else(|| Error::Custom(
+                    format!("cannot parse {} into float", String::from_utf8_lossy(&b))
+                ))?;
+                visitor.visit_f64(f)
+            },
+            x => Err(Error::InvalidToken { expected: "i for integer or a byte string".to_string(), found: format!("{:?}", x) }),
+        }
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor<'de> 
+    {
+        self.deserialize_f64(visitor)
+    }
+
     fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
         where V: de::Visitor<'de> 
     {
@@ -185,7 +239,7 @@ impl<'de, 'a, R: Read> de::Deserializer<'de> for &'a mut Decoder<R> {
                 _ => Err(Error::InvalidToken { expected: "l for list".to_string(), found: format!("{:?}", x) }),
             }
         )?;
-        visitor.visit_seq(Access::new(self, Some(len)))
+        self.nested(|d| visitor.visit_seq(Access::new(d, Some(len))))
     }
 
     fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
@@ -1,5 +1,6 @@
+use std::io::Read;
 use serde::de;
-use crate::Result;
+use crate::{Error, Result};
 
 mod decoder;
 mod access;
@@ -9,21 +10,62 @@ mod test;
 
 use decoder::Decoder;
 
+// Default limit on the length of a byte string, well above any metainfo or tracker response.
+pub const MAX_BYTES_LEN: usize = 100 * 1024 * 1024;
+
+// Limit on how deeply lists and dicts can be nested, metainfo and tracker responses only go a few levels.
+pub const MAX_DEPTH: usize = 64;
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum DecodedType {
     Integer(i64),
     ByteString(Vec<u8>),
     List,
     Dictionary,
-    EOF,
+    // The e ending a list or dictionary.
+    End,
 }
 
+// The input must be exactly one value, anything after it is an error.
 pub fn decode_bytes<'de, T>(b: &'de [u8]) -> Result<T>
     where T: de::Deserialize<'de>
 {
-    de::Deserialize::deserialize(&mut Decoder::new(b))
+    decode_complete(Decoder::new(b))
+}
+
+// As decode_bytes, but dictionary keys must be unique and sorted, so re-encoding gives back the same bytes.
+pub fn decode_bytes_strict<'de, T>(b: &'de [u8]) -> Result<T>
+    where T: de::Deserialize<'de>
+{
+    decode_complete(Decoder::new_strict(b))
+}
+
+fn decode_complete<'de, T>(mut decoder: Decoder<&'de [u8]>) -> Result<T>
+    where T: de::Deserialize<'de>
+{
+    let value = de::Deserialize::deserialize(&mut decoder)?;
+    if !decoder.is_finished()? {
+        return Err(Error::TrailingData);
+    }
+    Ok(value)
+}
+
+
+// Decodes straight from a reader, e.g. a file or socket, without buffering the whole input.
+// Wrap unbuffered readers in a BufReader, as the decoder reads a byte at a time.
+// Reading stops after the value, so the reader can carry on with whatever follows.
+pub fn decode_reader<R, T>(r: R) -> Result<T>
+    where R: Read, T: de::DeserializeOwned
+{
+    de::Deserialize::deserialize(&mut Decoder::new(r))
 }
 
+// As decode_reader, with a limit on the length of byte strings other than MAX_BYTES_LEN.
+pub fn decode_reader_with_limit<R, T>(r: R, max_bytes_len: usize) -> Result<T>
+    where R: Read, T: de::DeserializeOwned
+{
+    de::Deserialize::deserialize(&mut Decoder { max_bytes_len, ..Decoder::new(r) })
+}
 
 pub fn decode_str<'de, T>(s: &'de str) -> Result<T>
     where T: de::Deserialize<'de> 
@@ -1,7 +1,7 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use serde_derive::Deserialize;
-use crate::token::Token;
-use super::decode_str;
+use crate::Value;
+use super::{decode_bytes_strict, decode_reader, decode_reader_with_limit, decode_str};
 
 #[test]
 fn decode_to_num() {
@@ -42,15 +42,149 @@ fn decode_to_struct() {
 #[test]
 fn decode_to_map() {
     let b = "d1:xi1111e1:y3:doge";
-    let r: Token = decode_str(b).unwrap();
-    let mut d = HashMap::new();
-    d.insert("x".into(), Token::Integer(1111_i64));
-    d.insert("y".into(), Token::ByteString("dog".as_bytes().to_vec()));
-    assert_eq!(r, Token::Dictionary(d));
+    let r: Value = decode_str(b).unwrap();
+    let mut d = BTreeMap::new();
+    d.insert("x".into(), Value::Int(1111_i64));
+    d.insert("y".into(), Value::Bytes("dog".as_bytes().to_vec()));
+    assert_eq!(r, Value::Dict(d));
 }
 
 #[test]
 fn deserialize_to_vec() {
     let r: Vec<i64> = decode_str("li666ee").unwrap();
     assert_eq!(r, [666]);
-}
\ No newline at end of file
+}
+
+#[test]
+fn decode_to_float() {
+    let r: f64 = decode_str("4:1.25").unwrap();
+    assert_eq!(r, 1.25);
+    let r: f32 = decode_str("i-3e").unwrap();
+    assert_eq!(r, -3.0);
+    assert!(decode_str::<f64>("3:abc").is_err());
+}
+
+#[test]
+fn decode_identifiers() {
+    // Struct fields and enum variants are both read through deserialize_identifier.
+    #[derive(PartialEq, Debug, Deserialize)]
+    enum Kind {
+        Single,
+        Multi,
+    }
+    #[derive(PartialEq, Debug, Deserialize)]
+    struct Fake {
+        #[serde(rename = "piece length")]
+        piece_length: i64,
+        kind: Kind,
+    }
+    let r: Fake = decode_str("d4:kind5:Multi12:piece lengthi16384ee").unwrap();
+    assert_eq!(r, Fake { piece_length: 16384, kind: Kind::Multi });
+    let r: Kind = decode_str("6:Single").unwrap();
+    assert_eq!(r, Kind::Single);
+}
+
+#[test]
+fn decode_from_reader() {
+    let b = b"d4:listli1ei2ee4:name4:spame";
+    #[derive(PartialEq, Debug, Deserialize)]
+    struct Fake {
+        list: Vec<i64>,
+        name: String,
+    }
+    let expected = Fake { list: vec![1, 2], name: "spam".to_string() };
+
+    let r: Fake = decode_reader(std::io::Cursor::new(b)).unwrap();
+    assert_eq!(r, expected);
+
+    let path = std::env::temp_dir().join(format!("bencode-reader-{}", std::process::id()));
+    std::fs::write(&path, b).unwrap();
+    let r: Fake = decode_reader(std::io::BufReader::new(std::fs::File::open(&path).unwrap())).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(r, expected);
+
+    // Input ending early.
+    assert!(decode_reader::<_, Fake>(std::io::Cursor::new(&b[..20])).is_err());
+}
+
+#[test]
+fn decode_trailing_data() {
+    let r: Value = decode_str("d3:fooi1ee").unwrap();
+    assert_eq!(r, Value::Dict(BTreeMap::from([("foo".into(), Value::Int(1))])));
+    assert!(matches!(decode_str::<Value>("d3:fooi1eextra"), Err(crate::Error::TrailingData)));
+    assert!(matches!(decode_str::<i64>("i1ei2e"), Err(crate::Error::TrailingData)));
+}
+
+#[test]
+fn value_from_u64() {
+    use serde::{de::{value::{Error, U64Deserializer}, IntoDeserializer}, Deserialize};
+    let de: U64Deserializer<Error> = (i64::MAX as u64).into_deserializer();
+    assert_eq!(Value::deserialize(de).unwrap(), Value::Int(i64::MAX));
+    // Too large for a bencode integer, rather than wrapping.
+    let de: U64Deserializer<Error> = u64::MAX.into_deserializer();
+    assert!(Value::deserialize(de).is_err());
+}
+
+#[test]
+fn decode_strict_key_order() {
+    let sorted = b"d1:ad1:xi1e1:yi2ee1:bi3ee";
+    assert!(decode_bytes_strict::<Value>(sorted).is_ok());
+
+    // Out of order, including in a nested dict.
+    assert!(decode_str::<Value>("d1:bi3e1:ai1ee").is_ok());
+    assert!(decode_bytes_strict::<Value>(b"d1:bi3e1:ai1ee").is_err());
+    assert!(decode_bytes_strict::<Value>(b"d1:ad1:yi2e1:xi1ee1:bi3ee").is_err());
+    // Duplicate keys.
+    assert!(decode_bytes_strict::<Value>(b"d1:ai1e1:ai2ee").is_err());
+}
+
+#[test]
+fn decode_any_value() {
+    let raw = b"d4:infod6:lengthi5e6:pieces2:\xff\x00e4:listli1e3:abcee";
+    let value: Value = crate::decode_bytes(raw).unwrap();
+    // Encodes back to the same bytes.
+    assert_eq!(crate::encode_to_raw(&value).unwrap(), raw);
+
+    assert_eq!(format!("{:?}", value), r#"{"info": {"length": 5, "pieces": 0xff00}, "list": [1, "abc"]}"#);
+    assert_eq!(
+        value.to_string(),
+        "{\n  info: {\n    length: 5,\n    pieces: <2 bytes>,\n  },\n  list: [\n    1,\n    \"abc\",\n  ],\n}",
+    );
+}
+
+#[test]
+fn decode_huge_byte_string() {
+    // Over the default limit, rejected before reading any of it.
+    let huge = format!("{}:abc", usize::MAX);
+    assert!(matches!(decode_str::<Value>(&huge), Err(crate::Error::BytesTooLong(usize::MAX))));
+
+    // Under the limit but past the end of the input.
+    let truncated = format!("{}:abc", crate::MAX_BYTES_LEN);
+    assert!(matches!(decode_str::<Value>(&truncated), Err(crate::Error::EOF)));
+    assert!(matches!(decode_reader::<_, Value>(truncated.as_bytes()), Err(crate::Error::EOF)));
+
+    let r: Value = decode_reader_with_limit("5:hello".as_bytes(), 5).unwrap();
+    assert_eq!(r, Value::Bytes(b"hello".to_vec()));
+    assert!(matches!(decode_reader_with_limit::<_, Value>("5:hello".as_bytes(), 4), Err(crate::Error::BytesTooLong(5))));
+}
+
+#[test]
+fn decode_deeply_nested() {
+    let nested = |depth: usize| format!("{}{}", "l".repeat(depth), "e".repeat(depth));
+    assert!(decode_str::<Value>(&nested(crate::MAX_DEPTH)).is_ok());
+    assert!(matches!(decode_str::<Value>(&nested(crate::MAX_DEPTH + 1)), Err(crate::Error::TooDeep)));
+
+    // Far too deep to decode recursively, rejected without overflowing the stack.
+    let dicts = format!("{}i1e{}", "d1:a".repeat(100_000), "e".repeat(100_000));
+    assert!(matches!(decode_str::<Value>(&dicts), Err(crate::Error::TooDeep)));
+    assert!(matches!(decode_reader::<_, Value>("l".repeat(100_000).as_bytes()), Err(crate::Error::TooDeep)));
+
+    // Also when skipping fields a struct doesn't have.
+    #[derive(Deserialize)]
+    struct Fake {
+        #[allow(dead_code)]
+        x: i64,
+    }
+    let unknown = format!("d1:xi1e1:y{}e", nested(100_000));
+    assert!(matches!(decode_str::<Fake>(&unknown), Err(crate::Error::TooDeep)));
+}
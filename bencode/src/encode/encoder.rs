@@ -121,12 +121,12 @@ impl<'a> ser::Serializer for &'a mut Encoder {
         self.serialize_str(variant)
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
+    fn serialize_newtype_struct<T>(
             self,
             _name: &'static str,
             value: &T,
         ) -> Result<()>
-        where T: serde::Serialize 
+        where T: ?Sized + serde::Serialize 
     {
         value.serialize(self)
     }
@@ -135,8 +135,8 @@ impl<'a> ser::Serializer for &'a mut Encoder {
 
     fn serialize_unit_struct(self, _name: &'static str) -> Result<()> { Ok(()) }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<()>
-        where T: serde::Serialize 
+    fn serialize_some<T>(self, value: &T) -> Result<()>
+        where T: ?Sized + serde::Serialize 
     {
         value.serialize(self)    
     }
@@ -196,14 +196,14 @@ impl<'a> ser::Serializer for &'a mut Encoder {
         Ok(SerializeMap::new(self, len))
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
             self,
             _name: &'static str,
             _variant_index: u32,
             variant: &'static str,
             value: &T,
         ) -> Result<()>
-        where T: serde::Serialize 
+        where T: ?Sized + serde::Serialize 
     {
         self.push("d");
         self.serialize_bytes(variant.as_bytes())?;
@@ -232,8 +232,8 @@ impl ser::SerializeSeq for &mut Encoder {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()>
-        where T: serde::Serialize 
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+        where T: ?Sized + serde::Serialize 
     {
         value.serialize(&mut **self)
     }
@@ -249,8 +249,8 @@ impl ser::SerializeTuple for &mut Encoder {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()>
-        where T: serde::Serialize 
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+        where T: ?Sized + serde::Serialize 
     {
         value.serialize(&mut **self)
     }
@@ -264,8 +264,8 @@ impl ser::SerializeTupleStruct for &mut Encoder {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()>
-        where T: serde::Serialize 
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+        where T: ?Sized + serde::Serialize 
     {
         value.serialize(&mut **self)
     }
@@ -279,8 +279,8 @@ impl ser::SerializeTupleVariant for &mut Encoder {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()>
-        where T: serde::Serialize 
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+        where T: ?Sized + serde::Serialize 
     {
         value.serialize(&mut **self)
     }
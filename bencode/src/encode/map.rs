@@ -30,7 +30,7 @@ impl<'a> SerializeMap<'a> {
         }
         // Take items and sort lexicographically.
         let mut items = std::mem::take(&mut self.items);
-        items.sort_by(| &(ref k, _), &(ref v, _) | { k.cmp(v) });
+        items.sort_by(|(k, _), (v, _)| k.cmp(v));
 
         self.serializer.push("d");
         for (k, v) in items {
@@ -47,8 +47,8 @@ impl<'a> ser::SerializeMap for SerializeMap<'a> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
-        where T: serde::Serialize 
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
+        where T: ?Sized + serde::Serialize 
     {
         match self.current_key {
             // We are supposed to be serializing value here.
@@ -62,8 +62,8 @@ impl<'a> ser::SerializeMap for SerializeMap<'a> {
         }
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
-        where T: serde::Serialize 
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where T: ?Sized + serde::Serialize 
     {
         let key = self.current_key.take().ok_or_else(
             || { 
@@ -84,12 +84,12 @@ impl<'a> ser::SerializeMap for SerializeMap<'a> {
         Ok(())
     }
     
-    fn serialize_entry<K: ?Sized, V: ?Sized>(
+    fn serialize_entry<K, V>(
         &mut self,
         key: &K,
         value: &V,
     ) -> Result<(), Self::Error>
-        where K: serde::Serialize, V: serde::Serialize, 
+        where K: ?Sized + serde::Serialize, V: ?Sized + serde::Serialize, 
     {
         if self.current_key.is_some() {
             return Err(Error::MapSerializationOrder(
@@ -116,12 +116,12 @@ impl<'a> ser::SerializeStruct for SerializeMap<'a> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
+    fn serialize_field<T>(
             &mut self,
             key: &'static str,
             value: &T,
         ) -> Result<(), Self::Error>
-        where T: serde::Serialize 
+        where T: ?Sized + serde::Serialize 
     {
         ser::SerializeMap::serialize_entry(self, key, value)
     }
@@ -135,12 +135,12 @@ impl<'a> ser::SerializeStructVariant for SerializeMap<'a> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
+    fn serialize_field<T>(
             &mut self,
             key: &'static str,
             value: &T,
         ) -> Result<(), Self::Error>
-        where T: serde::Serialize 
+        where T: ?Sized + serde::Serialize 
     {
         ser::SerializeMap::serialize_entry(self, key, value)
     }
@@ -19,7 +19,7 @@ fn unexpected<T>(unexp: de::Unexpected) -> Result<T> {
 /// length prefix).
 pub struct StringSerializer;
 
-impl<'a> ser::Serializer for &'a mut StringSerializer {
+impl ser::Serializer for &mut StringSerializer {
     type Ok = Vec<u8>;
     type Error = Error;
     type SerializeSeq = ser::Impossible<Vec<u8>, Error>;
@@ -82,4 +82,22 @@ fn serialize_lexical_sorted_keys() {
         c: 4,
     };
     assert_eq!(encode_to_str(&f).unwrap(), "d3:aaai1e2:bbi2e1:ci4e1:zi3ee");
-}
\ No newline at end of file
+}
+
+#[test]
+fn float_round_trip() {
+    #[derive(Debug, PartialEq, Serialize, serde_derive::Deserialize)]
+    struct Fake {
+        #[serde(with = "crate::float")]
+        ratio: f64,
+        #[serde(with = "crate::float")]
+        small: f32,
+    }
+    let f = Fake { ratio: 0.5, small: 2.0 };
+    let s = encode_to_str(&f).unwrap();
+    assert_eq!(s, "d5:ratio3:0.55:small1:2e");
+    assert_eq!(crate::decode_str::<Fake>(&s).unwrap(), f);
+
+    // Still rejected without opting in.
+    assert!(encode_to_str(&0.5).is_err());
+}
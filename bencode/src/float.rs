@@ -0,0 +1,17 @@
+use serde::{de, ser};
+
+// Floats aren't part of bencode, so the encoder rejects them.
+// Fields can opt in to being encoded as their string representation with #[serde(with = "bencode::float")].
+// Decoding accepts either that or an integer.
+
+pub fn serialize<T, S>(v: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where T: std::fmt::Display, S: ser::Serializer
+{
+    serializer.serialize_str(&v.to_string())
+}
+
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where T: de::Deserialize<'de>, D: de::Deserializer<'de>
+{
+    T::deserialize(deserializer)
+}
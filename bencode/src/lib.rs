@@ -1,13 +1,21 @@
 mod encode;
 mod decode;
-mod token;
+mod value;
+mod raw;
+pub mod float;
 
 // For bencode -> T
-pub use decode::{decode_bytes, decode_str};
+pub use decode::{decode_bytes, decode_bytes_strict, decode_reader, decode_reader_with_limit, decode_str, MAX_BYTES_LEN, MAX_DEPTH};
 
 // For T -> bencode
 pub use encode::{encode_to_raw, encode_to_str};
 
+// Dynamic type for any value.
+pub use value::Value;
+
+// For inspecting encoded values in place.
+pub use raw::{raw_field, value_len};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 // Errors specific to bencoding on top of those present in serde.
@@ -37,6 +45,18 @@ pub enum Error {
     #[error("expected end of input stream")]
     EOF,
 
+    // Declared length of a byte string is over the decoder's limit.
+    #[error("byte string too long: {0} bytes")]
+    BytesTooLong(usize),
+
+    // Lists and dicts nested deeper than the decoder's limit.
+    #[error("lists or dictionaries nested too deeply")]
+    TooDeep,
+
+    // Input continues after the top level value.
+    #[error("trailing data after bencoded value")]
+    TrailingData,
+
 }
 
 impl serde::ser::Error for Error {
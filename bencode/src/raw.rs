@@ -0,0 +1,93 @@
+use crate::{Error, Result};
+
+// Working with encoded values without decoding them.
+
+// Length of the bencoded value at the start of the buffer, None if it is invalid or cut short.
+// Scans without recursing, so deeply nested input can't overflow the stack.
+pub fn value_len(buf: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    // Lists and dicts entered but not yet ended.
+    let mut open = 0usize;
+    loop {
+        match buf.get(pos)? {
+            b'i' => pos += buf[pos..].iter().position(|b| *b == b'e')? + 1,
+            b'l' | b'd' => {
+                open += 1;
+                pos += 1;
+            },
+            b'e' if open > 0 => {
+                open -= 1;
+                pos += 1;
+            },
+            b'0'..=b'9' => {
+                let colon = buf[pos..].iter().position(|b| *b == b':')?;
+                let len: usize = std::str::from_utf8(&buf[pos..pos + colon]).ok()?.parse().ok()?;
+                let end = pos.checked_add(colon)?.checked_add(1)?.checked_add(len)?;
+                if end > buf.len() {
+                    return None;
+                }
+                pos = end;
+            },
+            _ => return None,
+        }
+        if open == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+// The exact bytes of a value in the top level dictionary, e.g. the info dict of a torrent.
+// Hashing these, rather than a re-encoding, can't be thrown off by anything the decoder drops or the encoder changes.
+pub fn raw_field<'a>(buf: &'a [u8], key: &str) -> Result<Option<&'a [u8]>> {
+
+    if buf.first() != Some(&b'd') {
+        return Err(Error::InvalidToken { expected: "d for dict".to_string(), found: format!("{:?}", buf.first().map(|b| *b as char)) });
+    }
+
+    let mut pos = 1;
+    while *buf.get(pos).ok_or(Error::EOF)? != b'e' {
+        if !buf[pos].is_ascii_digit() {
+            return Err(Error::InvalidToken { expected: "byte string key".to_string(), found: (buf[pos] as char).to_string() });
+        }
+        let key_len = value_len(&buf[pos..]).ok_or(Error::EOF)?;
+        let raw_key = &buf[pos..pos + key_len];
+        pos += key_len;
+
+        let value_len = value_len(&buf[pos..]).ok_or(Error::EOF)?;
+        // Key contents follow the length prefix.
+        let colon = raw_key.iter().position(|b| *b == b':').ok_or(Error::EOF)?;
+        if &raw_key[colon + 1..] == key.as_bytes() {
+            return Ok(Some(&buf[pos..pos + value_len]));
+        }
+        pos += value_len;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_len() {
+        assert_eq!(value_len(b"d3:fooli1e3:bareee"), Some(17));
+        assert_eq!(value_len(b"d3:foo"), None);
+        assert_eq!(value_len(b"4:spam"), Some(6));
+        assert_eq!(value_len(b"i-12eabc"), Some(5));
+        assert_eq!(value_len(b"e"), None);
+
+        let deep = format!("{}{}", "l".repeat(1_000_000), "e".repeat(1_000_000));
+        assert_eq!(value_len(deep.as_bytes()), Some(deep.len()));
+        assert_eq!(value_len(&deep.as_bytes()[..deep.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_raw_field() {
+        let buf = b"d8:announce3:url4:infod6:lengthi5e6:sourcei1ee5:otheri2ee";
+        assert_eq!(raw_field(buf, "info").unwrap(), Some(&b"d6:lengthi5e6:sourcei1ee"[..]));
+        assert_eq!(raw_field(buf, "other").unwrap(), Some(&b"i2e"[..]));
+        assert_eq!(raw_field(buf, "missing").unwrap(), None);
+        assert!(raw_field(b"li1ee", "info").is_err());
+        assert!(raw_field(b"d4:infod6:length", "info").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::{collections::BTreeMap, fmt};
+use serde::{de, ser::{SerializeSeq, SerializeMap}};
+
+// Any bencode value, for data without a fixed structure.
+// Dictionaries are ordered, so values encode back the same as they were decoded.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl serde::Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer 
+    {
+        match self {
+            Value::Int(int) => serializer.serialize_i64(*int),
+
+            Value::Bytes(string) => serializer.serialize_bytes(string),
+
+            Value::List(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for elem in list {
+                    seq.serialize_element(elem)?;
+                }
+                seq.end()
+            },
+
+            Value::Dict(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (k, v) in dict {
+                    map.serialize_entry(serde_bytes::Bytes::new(k), v)?;
+                }
+                map.end()
+            },
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de> 
+    {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> de::Visitor<'de> for ValueVisitor {
+
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("any bencode token type")
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where E: de::Error 
+    {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where E: de::Error
+    {
+        i64::try_from(v).map(Value::Int).map_err(|_| E::custom(format!("integer {} out of range", v)))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where E: de::Error 
+    {
+        Ok(Value::Bytes(v.into()))    
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where E: de::Error 
+    {
+        Ok(Value::Bytes(v.into()))    
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+        where E: de::Error
+    {
+        Ok(Value::Bytes(v.into()))
+    }
+
+    fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
+        where E: de::Error 
+    {
+        Ok(Value::Bytes(v.into()))    
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where A: de::SeqAccess<'de>
+    {
+        let mut out = Vec::new();
+        while let Some(elem) = seq.next_element()? {
+            out.push(elem)
+        }
+        Ok(Value::List(out))
+    }
+
+    fn visit_map<A>(self, mut access: A) -> Result<Self::Value, A::Error>
+        where A: de::MapAccess<'de> 
+    {
+        let mut dict = BTreeMap::new();
+        while let Some((k, v)) = access.next_entry::<serde_bytes::ByteBuf, _>()? {
+            dict.insert(k.into_vec(), v);
+        }
+        Ok(Value::Dict(dict))
+    }
+}
+// Byte strings show as strings when they're UTF-8, otherwise as hex.
+struct DebugBytes<'a>(&'a [u8]);
+
+impl fmt::Debug for DebugBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(self.0) {
+            Ok(s) => write!(f, "{:?}", s),
+            Err(_) => {
+                f.write_str("0x")?;
+                self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(int) => write!(f, "{}", int),
+            Value::Bytes(bytes) => DebugBytes(bytes).fmt(f),
+            Value::List(list) => f.debug_list().entries(list).finish(),
+            Value::Dict(dict) => f.debug_map().entries(dict.iter().map(|(k, v)| (DebugBytes(k), v))).finish(),
+        }
+    }
+}
+
+// Indented for reading, binary strings (like piece hashes) are only shown by length.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl Value {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ";
+        match self {
+            Value::Int(int) => write!(f, "{}", int),
+            Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => write!(f, "{:?}", s),
+                Err(_) => write!(f, "<{} bytes>", bytes.len()),
+            },
+            Value::List(list) if list.is_empty() => f.write_str("[]"),
+            Value::List(list) => {
+                f.write_str("[\n")?;
+                for elem in list {
+                    write!(f, "{}", pad.repeat(indent + 1))?;
+                    elem.fmt_indented(f, indent + 1)?;
+                    f.write_str(",\n")?;
+                }
+                write!(f, "{}]", pad.repeat(indent))
+            },
+            Value::Dict(dict) if dict.is_empty() => f.write_str("{}"),
+            Value::Dict(dict) => {
+                f.write_str("{\n")?;
+                for (k, v) in dict {
+                    write!(f, "{}{}: ", pad.repeat(indent + 1), String::from_utf8_lossy(k))?;
+                    v.fmt_indented(f, indent + 1)?;
+                    f.write_str(",\n")?;
+                }
+                write!(f, "{}}}", pad.repeat(indent))
+            },
+        }
+    }
+}
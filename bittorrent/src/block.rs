@@ -1,5 +1,9 @@
 use crate::{info::TorrentInfo, BLOCK_SIZE};
 
+// Largest block a peer may request from us.
+// Clients conventionally request 16KiB, anything much larger is refused rather than read into memory.
+pub const MAX_REQUEST_LEN: usize = 2 * BLOCK_SIZE;
+
 // The data of a block can either be:
 // 1. Owned - when peer sends us the data.
 // 2. Cached - when we have the data cached and we need to share it with peers.
@@ -80,17 +84,20 @@ impl BlockRequest {
     }
 
     pub fn idx_in_piece(&self) -> usize {
-        self.offset / BLOCK_SIZE as usize
+        self.offset / BLOCK_SIZE
     }
 
     pub fn is_valid(&self, info: &TorrentInfo) -> bool {
         if self.piece_idx >= info.num_pieces as usize {
             return false;
         }
-        if self.offset + self.len > info.piece_len(self.piece_idx) {
+        if self.len == 0 || self.len > MAX_REQUEST_LEN {
             return false;
         }
-        true
+        match self.offset.checked_add(self.len) {
+            Some(end) => end <= info.piece_len(self.piece_idx),
+            None => false,
+        }
     }
 }
 
@@ -99,7 +106,7 @@ pub fn block_len(piece_len: usize, block_idx: usize) -> usize {
 }
 
 pub fn num_blocks(piece_len: usize) -> u32 {
-    ((piece_len + (BLOCK_SIZE - 1)) / BLOCK_SIZE) as u32
+    piece_len.div_ceil(BLOCK_SIZE) as u32
 }
 
 #[cfg(test)]
@@ -116,17 +123,44 @@ mod tests {
         assert_eq!(block_len(normal_piece_len, 1), normal_block_len);
         assert_eq!(block_len(last_piece_len, 0), normal_block_len);
         assert_eq!(block_len(last_piece_len, 1), last_block_len);
+        // Two full blocks and a short one.
+        assert_eq!(block_len(40_000, 1), normal_block_len);
+        assert_eq!(block_len(40_000, 2), 7232);
+        assert_eq!(num_blocks(40_000), 3);
     }
 
     #[test]
     fn test_num_blocks() {
         (0..12)
-            .into_iter()
             .for_each(|i| assert_eq!(num_blocks(BLOCK_SIZE * i), i as u32));
         assert_eq!(num_blocks(BLOCK_SIZE + 500), 2);
         assert_eq!(num_blocks(BLOCK_SIZE * 5 + 1000), 6);
         assert_eq!(num_blocks(0), 0);
     }
 
-    // TODO: add tests for BlockInfo::is_valid
+    #[test]
+    fn test_request_is_valid() {
+        let info = TorrentInfo {
+            total_len: 4 * 65_536 + 1000,
+            piece_len: 65_536,
+            last_piece_len: 1000,
+            num_pieces: 5,
+        };
+        let request = |piece_idx, offset, len| BlockRequest { piece_idx, offset, len };
+
+        assert!(request(0, 0, BLOCK_SIZE).is_valid(&info));
+        assert!(request(0, 65_536 - MAX_REQUEST_LEN, MAX_REQUEST_LEN).is_valid(&info));
+        assert!(request(4, 0, 1000).is_valid(&info));
+
+        // Oversized.
+        assert!(!request(0, 0, MAX_REQUEST_LEN + 1).is_valid(&info));
+        assert!(!request(0, 0, 65_536).is_valid(&info));
+        assert!(!request(0, 0, 0).is_valid(&info));
+        // Overrunning the piece.
+        assert!(!request(0, 65_536 - 100, BLOCK_SIZE).is_valid(&info));
+        assert!(!request(4, 0, 1001).is_valid(&info));
+        assert!(!request(0, usize::MAX, BLOCK_SIZE).is_valid(&info));
+        // No such piece.
+        assert!(!request(5, 0, BLOCK_SIZE).is_valid(&info));
+    }
 }
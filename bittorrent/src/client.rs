@@ -1,33 +1,136 @@
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use std::{collections::HashMap, net::Ipv4Addr, path::{Path, PathBuf}, sync::Arc};
+use tokio::{net::TcpListener, sync::{mpsc, oneshot, Semaphore}};
 use crate::{
-    config::Config, 
-    disk::{start_disk, DiskCommand, DiskTx},
-    metainfo::MetaInfo,
+    config::{Config, Encryption},
+    dht::{start_dht, DhtCommand, DhtTx},
+    disk::{start_disk, AllocationError, DiskCommand, DiskError, DiskTx},
+    metainfo::{MetaInfo, MetaInfoError},
+    picker::piece_picker::FilePriority,
+    p2p::{self, peer_id::{generate_peer_id, CLIENT_ID_PREFIX}, InboundPeer},
     info::TorrentInfo,
-    torrent::{self, TorrentHandle, TorrentParams},
+    limiter::{ConnectionLimit, RateLimiter},
+    magnet::{Magnet, MagnetHandle, MagnetParams},
+    resume,
+    stats::TorrentStats,
+    torrent::{self, DhtNodes, TorrentError, TorrentHandle, TorrentParams, TorrentState, TorrentTx},
     ID,
+    UserCommand,
     UserTx,
 };
 
 #[derive(thiserror::Error, Debug)]
 pub enum ClientError {
 
+        // Boxed, as the unsent command would make every result large.
         #[error("client has been unexpectedly dropped")]
-        ClientDropped(#[from] mpsc::error::SendError<ClientCommand>),
+        ClientDropped(#[source] Box<mpsc::error::SendError<ClientCommand>>),
 
         #[error("client panicked")]
         ClientPanic,
         
         #[error("disk task panicked")]
-        DiskFailure(#[from] mpsc::error::SendError<DiskCommand>),
+        DiskFailure(#[source] Box<mpsc::error::SendError<DiskCommand>>),
+
+        #[error("disk task stopped unexpectedly")]
+        DiskStopped,
+
+        #[error("torrent not found: {}", hex::encode(.0))]
+        TorrentNotFound(ID),
+
+        // The torrent is still in the client, but its task has stopped taking commands.
+        #[error("torrent has stopped: {}", hex::encode(.0))]
+        TorrentStopped(ID),
+
+        #[error("torrent already added: {}", hex::encode(.0))]
+        DuplicateTorrent(ID),
+
+        #[error("failed to allocate torrent")]
+        AllocationFailed(#[from] AllocationError),
+
+        #[error("invalid metainfo")]
+        InvalidMetaInfo(#[from] MetaInfoError),
+
+        #[error("torrent failed")]
+        TorrentFailed(#[from] TorrentError),
+
+        #[error("disk error")]
+        DiskError(#[from] DiskError),
+
+        #[error("can't write to download directory {dir:?}")]
+        DirNotWritable { dir: PathBuf, source: std::io::Error },
+
+        #[error("can't listen for peers on port {port}")]
+        Listen { port: u16, source: std::io::Error },
+}
+
+impl From<mpsc::error::SendError<ClientCommand>> for ClientError {
+    fn from(e: mpsc::error::SendError<ClientCommand>) -> Self {
+        ClientError::ClientDropped(Box::new(e))
+    }
+}
+
+impl From<mpsc::error::SendError<DiskCommand>> for ClientError {
+    fn from(e: mpsc::error::SendError<DiskCommand>) -> Self {
+        ClientError::DiskFailure(Box::new(e))
+    }
 }
 
 pub enum ClientCommand {
 
-    NewTorrent(MetaInfo),
+    // Downloads to the configured dir unless another is given.
+    NewTorrent {
+        metainfo: MetaInfo,
+        dir: Option<PathBuf>,
+    },
+
+    // Starts announcing for a magnet link until its metadata is fetched.
+    NewMagnet(String),
+
+    // Sent by a magnet task with the verified metadata, the magnet becomes a torrent.
+    MetadataFetched(MetaInfo),
+
+    // Responds once the torrent is fully shut down and removed from disk.
+    RemoveTorrent {
+        id: ID,
+        tx: oneshot::Sender<Result<()>>,
+    },
+
+    // Announce to a torrent's trackers now.
+    Reannounce(ID),
+
+    // Asks the torrent's trackers for swarm stats.
+    Scrape(ID),
+
+    GetStats {
+        id: ID,
+        tx: oneshot::Sender<Result<TorrentStats>>,
+    },
+
+    // Responds with the state of every torrent, magnets still fetching metadata aren't included.
+    ListTorrents(oneshot::Sender<Vec<(ID, TorrentState)>>),
+
+    // Stops the torrent if it is seeding.
+    StopSeeding(ID),
 
-    RemoveTorrent(ID),
+    // Disconnects a torrent from peers and trackers, keeping it in the client.
+    PauseTorrent(ID),
+
+    ResumeTorrent(ID),
+
+    // Only download the pieces covering a byte range of the torrent.
+    SetDownloadRange {
+        id: ID,
+        range: std::ops::Range<u64>,
+    },
+
+    // One priority for each file of the torrent.
+    SetFilePriorities {
+        id: ID,
+        priorities: Vec<FilePriority>,
+    },
+
+    // Sent by the client's accept loop, once the peer's handshake says which torrent it wants.
+    InboundPeer(Box<InboundPeer>),
 
     Shutdown,
 
@@ -36,20 +139,47 @@ pub enum ClientCommand {
 pub type Result<T> = std::result::Result<T, ClientError>;
 pub type ClientRx = mpsc::UnboundedReceiver<ClientCommand>;
 pub type ClientTx = mpsc::UnboundedSender<ClientCommand>;
+pub type WeakClientTx = mpsc::WeakUnboundedSender<ClientCommand>;
 
 pub struct Client {
 
     client_rx: ClientRx,
 
+    // Handed to magnet tasks to send back their metadata.
+    // Weak so the client still stops once the user's handle is dropped.
+    client_tx: WeakClientTx,
+
     torrents: HashMap<ID, TorrentHandle>,
 
+    // Magnets still waiting on metadata.
+    magnets: HashMap<ID, MagnetHandle>,
+
     user_tx: UserTx,
 
     config: Config,
 
-    // Last used listening port.
-    // Incremented by 1 for each new torrent.
-    current_port: u16,
+    // DHT nodes advertised by peers of any torrent.
+    dht_nodes: DhtNodes,
+
+    // Present when the DHT is enabled and running.
+    dht_tx: Option<DhtTx>,
+
+    // Limits shared by all torrents, if set.
+    down_limit: Option<Arc<RateLimiter>>,
+
+    up_limit: Option<Arc<RateLimiter>>,
+
+    // Used by every torrent, for both announces and handshakes.
+    client_id: ID,
+
+    // Peer connections of all torrents.
+    connections: Arc<ConnectionLimit>,
+
+    // Inbound peers whose handshake is still being read, no more than the global connection limit.
+    handshakes: Arc<Semaphore>,
+
+    // Port peers of every torrent connect to, announced to trackers.
+    listen_port: u16,
 
 }
 
@@ -58,15 +188,29 @@ impl Client {
     pub fn new(config: Config, user_tx: UserTx) -> (Self, ClientTx) {
         
         let (client_tx, client_rx) = mpsc::unbounded_channel();
-        let current_port = config.listen_port_start;
+        let listen_port = config.listen_port_start;
+        let down_limit = config.global_max_down_rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+        let up_limit = config.global_max_up_rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+        let connections = Arc::new(ConnectionLimit::new(config.max_connections_global));
+        let handshakes = Arc::new(Semaphore::new(config.max_connections_global.min(Semaphore::MAX_PERMITS)));
+        let client_id = config.client_id.unwrap_or_else(|| generate_peer_id(&CLIENT_ID_PREFIX));
         
         (
             Client {
                 torrents: HashMap::new(),
+                magnets: HashMap::new(),
                 client_rx,
+                client_tx: client_tx.downgrade(),
                 user_tx,
                 config,
-                current_port,
+                dht_nodes: DhtNodes::default(),
+                dht_tx: None,
+                down_limit,
+                up_limit,
+                client_id,
+                connections,
+                handshakes,
+                listen_port,
             },
             client_tx,
         )
@@ -74,34 +218,200 @@ impl Client {
 
     pub async fn run(&mut self) -> Result<()> {
         
+        // One listener for the peers of every torrent, they're told apart by the info hash of their handshake.
+        let listener = bind_listener(self.config.listen_port_start, self.config.listen_port_end).await?;
+        self.listen_port = listener
+            .local_addr()
+            .map_err(|source| ClientError::Listen { port: self.listen_port, source })?
+            .port();
+        tracing::info!("listening for peers on port {}", self.listen_port);
+
         // Start the disk task.
-        let (_, disk_tx) = start_disk();
+        let (mut disk_handle, disk_tx) = start_disk();
+
+        // Start the DHT node if enabled.
+        if self.config.enable_dht {
+            let (_, dht_tx) = start_dht(self.config.dht_port);
+            self.dht_tx = Some(dht_tx);
+        }
+
+        loop { tokio::select! {
 
-        while let Some(cmd) = self.client_rx.recv().await {
-            match cmd {
+            cmd = self.client_rx.recv() => match cmd {
                 
-                ClientCommand::NewTorrent(metainfo) => self.new_torrent(metainfo, &disk_tx).await?,
+                Some(ClientCommand::NewTorrent { metainfo, dir }) => {
+                    match self.new_torrent(metainfo, dir, &disk_tx).await {
+                        Err(e @ (ClientError::DuplicateTorrent(_) | ClientError::DirNotWritable { .. })) => tracing::warn!("{}", e),
+                        result => result?,
+                    }
+                },
 
-                ClientCommand::RemoveTorrent(id) => {
-                    if let Some(torrent) = self.torrents.remove(&id) {
-                        let _ = torrent.torrent_tx.send(torrent::TorrentCommand::Shutdown);
-                        disk_tx.send(DiskCommand::RemoveTorrent(id))?;
+                Some(ClientCommand::NewMagnet(uri)) => {
+                    match self.new_magnet(&uri, &disk_tx).await {
+                        Err(e @ (ClientError::DuplicateTorrent(_) | ClientError::InvalidMetaInfo(_) | ClientError::DirNotWritable { .. })) => tracing::warn!("{}", e),
+                        result => result?,
+                    }
+                },
+
+                Some(ClientCommand::MetadataFetched(metainfo)) => {
+                    // Magnet may have been removed while fetching.
+                    if self.magnets.remove(&metainfo.info_hash()).is_some() {
+                        let id = metainfo.info_hash();
+                        match self.new_torrent(metainfo.clone(), None, &disk_tx).await {
+                            Ok(()) => { let _ = self.user_tx.send(UserCommand::MetadataFetched { id, metainfo }); },
+                            Err(e @ (ClientError::DuplicateTorrent(_) | ClientError::DirNotWritable { .. })) => tracing::warn!("{}", e),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                },
+
+                Some(ClientCommand::RemoveTorrent { id, tx }) => {
+                    if let Some(magnet) = self.magnets.remove(&id) {
+                        let _ = magnet.torrent_tx.send(torrent::TorrentCommand::Shutdown);
+                        tokio::spawn(async move {
+                            let _ = magnet.handle.await;
+                            let _ = tx.send(Ok(()));
+                        });
+                    } else if let Some(torrent) = self.torrents.remove(&id) {
+                        // Removal waits on peers and trackers, so don't hold up other commands.
+                        tokio::spawn(remove_torrent(id, torrent, disk_tx.clone(), tx));
                     } else {
-                        tracing::warn!("attempted to remove non-existent torrent: {}", hex::encode(id));
+                        let _ = tx.send(Err(ClientError::TorrentNotFound(id)));
                     }
                 }
 
-                ClientCommand::Shutdown => return Ok(self.shutdown().await),
+                Some(ClientCommand::Reannounce(id)) => {
+                    if let Err(e) = self.send_to_torrent(id, torrent::TorrentCommand::Reannounce) {
+                        tracing::warn!("{}", e);
+                    }
+                },
+
+                Some(ClientCommand::Scrape(id)) => {
+                    if let Err(e) = self.send_to_torrent(id, torrent::TorrentCommand::Scrape) {
+                        tracing::warn!("{}", e);
+                    }
+                },
 
-            }
-        }
+                Some(ClientCommand::GetStats { id, tx }) => {
+                    let (stats_tx, stats_rx) = oneshot::channel();
+                    match self.send_to_torrent(id, torrent::TorrentCommand::GetStats(stats_tx)) {
+                        // Torrents answer between other work, so don't hold up other commands.
+                        Ok(()) => {
+                            tokio::spawn(async move {
+                                let _ = tx.send(stats_rx.await.map_err(|_| ClientError::TorrentStopped(id)));
+                            });
+                        },
+                        Err(e) => { let _ = tx.send(Err(e)); },
+                    }
+                },
 
-        Ok(())
+                Some(ClientCommand::ListTorrents(tx)) => {
+                    let torrents = self.torrents
+                        .iter()
+                        .map(|(id, torrent)| (*id, torrent.torrent_tx.clone()))
+                        .collect();
+                    // Torrents answer between other work, so don't hold up other commands.
+                    tokio::spawn(list_torrents(torrents, tx));
+                },
+
+                Some(ClientCommand::StopSeeding(id)) => {
+                    if let Err(e) = self.send_to_torrent(id, torrent::TorrentCommand::StopSeeding) {
+                        tracing::warn!("{}", e);
+                    }
+                },
+
+                Some(ClientCommand::PauseTorrent(id)) => {
+                    if let Err(e) = self.send_to_torrent(id, torrent::TorrentCommand::Pause) {
+                        tracing::warn!("{}", e);
+                    }
+                },
+
+                Some(ClientCommand::ResumeTorrent(id)) => {
+                    if let Err(e) = self.send_to_torrent(id, torrent::TorrentCommand::Resume) {
+                        tracing::warn!("{}", e);
+                    }
+                },
+
+                Some(ClientCommand::SetDownloadRange { id, range }) => {
+                    if let Err(e) = self.send_to_torrent(id, torrent::TorrentCommand::SetDownloadRange(range)) {
+                        tracing::warn!("{}", e);
+                    }
+                },
+
+                Some(ClientCommand::SetFilePriorities { id, priorities }) => {
+                    if let Err(e) = self.send_to_torrent(id, torrent::TorrentCommand::SetFilePriorities(priorities)) {
+                        tracing::warn!("{}", e);
+                    }
+                },
+
+                Some(ClientCommand::InboundPeer(peer)) => {
+                    if let Some(torrent) = self.torrents.get(&peer.handshake.info_hash) {
+                        let _ = torrent.torrent_tx.send(torrent::TorrentCommand::InboundPeer(peer));
+                    } else {
+                        // Dropping the socket closes the connection without a reply.
+                        tracing::debug!("rejected inbound peer {} for unknown torrent {}", peer.address, hex::encode(peer.handshake.info_hash));
+                    }
+                },
+
+                Some(ClientCommand::Shutdown) => {
+                    self.shutdown().await;
+                    return Ok(());
+                },
+
+                None => return Ok(()),
+            },
+
+            accepted = listener.accept() => match accepted {
+                Ok((_, address)) if self.config.encryption == Encryption::Forced => {
+                    tracing::debug!("dropping inbound peer {}, inbound connections can't be encrypted yet", address);
+                },
+                Ok((stream, address)) => {
+                    let Ok(permit) = self.handshakes.clone().try_acquire_owned() else {
+                        tracing::debug!("dropping inbound peer {}, too many handshakes pending", address);
+                        continue;
+                    };
+                    // Handshakes are read in their own task, so slow peers don't hold up commands.
+                    let client_tx = self.client_tx.clone();
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        match p2p::read_handshake(stream, address).await {
+                            Ok(peer) => if let Some(client_tx) = client_tx.upgrade() {
+                                let _ = client_tx.send(ClientCommand::InboundPeer(Box::new(peer)));
+                            },
+                            Err(e) => tracing::debug!("no handshake from inbound peer {}: {}", address, e),
+                        }
+                    });
+                },
+                Err(e) => tracing::warn!("inbound peer connection error: {}", e),
+            },
+
+            // Without the disk no torrent can make progress, so stop them rather than let them hang.
+            result = &mut disk_handle => {
+                match result {
+                    Ok(_) => tracing::error!("disk task stopped unexpectedly"),
+                    Err(e) => tracing::error!("disk task panicked: {}", e),
+                }
+                let _ = self.user_tx.send(UserCommand::DiskFailure);
+                self.shutdown().await;
+                return Err(ClientError::DiskStopped);
+            },
+
+        }}
     }
 
-    async fn new_torrent(&mut self, metainfo: MetaInfo, disk_tx: &DiskTx) -> Result<()> {
+    async fn new_torrent(&mut self, metainfo: MetaInfo, dir: Option<PathBuf>, disk_tx: &DiskTx) -> Result<()> {
         
         let info_hash = metainfo.info_hash();
+        if self.torrents.contains_key(&info_hash) || self.magnets.contains_key(&info_hash) {
+            return Err(ClientError::DuplicateTorrent(info_hash));
+        }
+        let dir = dir.unwrap_or_else(|| self.config.dir.clone());
+        check_writable(&dir)?;
+        if let Some(resume_dir) = &self.config.resume_dir {
+            if let Err(e) = resume::save_metainfo(resume_dir, &metainfo) {
+                tracing::warn!("failed to save resume metainfo: {}", e);
+            }
+        }
         let info: TorrentInfo = TorrentInfo::new(&metainfo);
         let piece_hashes = metainfo.piece_hashes();
         let (tx, rx) = tokio::sync::oneshot::channel();
@@ -109,22 +419,30 @@ impl Client {
         let torrent_handle = TorrentHandle::start_torrent(
             TorrentParams {
                 info: info.clone(),
+                files: metainfo.files(),
                 info_hash,
-                client_id: self.config.client_id,
+                client_id: self.client_id,
                 tracker_urls: metainfo.tracker_urls(),
                 config: self.config.clone(),
                 disk_tx: disk_tx.clone(),
                 user_tx: self.user_tx.clone(),
-                listen_port: self.current_port,
+                listen_port: self.listen_port,
+                dht_nodes: self.dht_nodes.clone(),
+                // Private torrents must only get peers from their trackers.
+                dht_tx: if metainfo.is_private() { None } else { self.dht_tx.clone() },
+                private: metainfo.is_private(),
+                global_down_limit: self.down_limit.clone(),
+                global_up_limit: self.up_limit.clone(),
+                global_connections: self.connections.clone(),
             },
             rx,
         );
 
         // If the torrent is multi file, create a directory for it.
         let dir = if metainfo.is_multi_file() {
-            self.config.dir.join(metainfo.info.name.clone())
+            dir.join(metainfo.info.name.clone())
         } else {
-            self.config.dir.clone()
+            dir
         };
         // If the torrent is single file, create a single element vector. 
         let files = if let Some(files) = metainfo.info.files {
@@ -144,22 +462,73 @@ impl Client {
             files,
             dir,
             torrent_tx: torrent_handle.torrent_tx.clone(),
+            write_through: self.config.write_through_cache,
+            cache_size: self.config.disk_cache_size,
+            preallocate: self.config.preallocate,
+            sync_on_write: self.config.sync_on_write,
+            check_threads: self.config.check_threads,
+            resume_dir: self.config.resume_dir.clone(),
             tx,
         })?;
-        // Increment the port for the next torrent.
-        self.current_port += 1;
 
         self.torrents.insert(info_hash, torrent_handle);
         Ok(())
     }
 
+    async fn new_magnet(&mut self, uri: &str, disk_tx: &DiskTx) -> Result<()> {
+
+        let magnet = Magnet::parse(uri)?;
+        let info_hash = magnet.info_hash;
+        if self.torrents.contains_key(&info_hash) || self.magnets.contains_key(&info_hash) {
+            return Err(ClientError::DuplicateTorrent(info_hash));
+        }
+
+        // Metadata saved from a previous session means there is nothing to fetch.
+        if let Some(metainfo) = self.config.resume_dir.as_ref().and_then(|dir| resume::load_metainfo(dir, &info_hash)) {
+            tracing::info!("loaded metainfo for magnet {} from resume data", magnet.name());
+            return self.new_torrent(metainfo, None, disk_tx).await;
+        }
+
+        let magnet_handle = MagnetHandle::start(MagnetParams {
+            magnet,
+            client_id: self.client_id,
+            listen_port: self.listen_port,
+            announce_ip: self.config.announce_ip,
+            connect_options: p2p::ConnectOptions::from_config(&self.config),
+            custom_trackers: self.config.custom_trackers.clone(),
+            dht_tx: self.dht_tx.clone(),
+            client_tx: self.client_tx.clone(),
+        });
+
+        self.magnets.insert(info_hash, magnet_handle);
+        Ok(())
+    }
+
+    // Tells apart a torrent that was never added from one that has stopped.
+    fn send_to_torrent(&self, id: ID, cmd: torrent::TorrentCommand) -> Result<()> {
+        let torrent = self.torrents.get(&id).ok_or(ClientError::TorrentNotFound(id))?;
+        torrent.torrent_tx.send(cmd).map_err(|_| ClientError::TorrentStopped(id))
+    }
+
     async fn shutdown(&mut self) {
 
+        if let Some(dht_tx) = self.dht_tx.take() {
+            dht_tx.send(DhtCommand::Shutdown).ok();
+        }
+
         for torrent in self.torrents.values_mut() {
             // Some torrents may have already been shut down so don't return err.
             torrent.torrent_tx.send(torrent::TorrentCommand::Shutdown).ok();
         }
 
+        for magnet in self.magnets.values() {
+            magnet.torrent_tx.send(torrent::TorrentCommand::Shutdown).ok();
+        }
+
+        for (_, magnet) in self.magnets.drain() {
+            let _ = magnet.handle.await;
+        }
+
         for (id, torrent) in self.torrents.drain() {
             if let Err(e) = torrent.handle.await {
                 tracing::error!("torrent {} pacicked: {}", hex::encode(id), e);
@@ -167,4 +536,372 @@ impl Client {
         }
     }
 
-}
\ No newline at end of file
+}
+
+// Shuts down the torrent's peers and trackers, then flushes and removes it from disk.
+async fn remove_torrent(id: ID, torrent: TorrentHandle, disk_tx: DiskTx, tx: oneshot::Sender<Result<()>>) {
+    
+    let _ = torrent.torrent_tx.send(torrent::TorrentCommand::Shutdown);
+    if let Err(e) = torrent.handle.await {
+        tracing::error!("torrent {} panicked: {}", hex::encode(id), e);
+    }
+
+    let (disk_done_tx, disk_done_rx) = oneshot::channel();
+    let result = match disk_tx.send(DiskCommand::RemoveTorrent { id, tx: disk_done_tx }) {
+        Ok(()) => disk_done_rx.await.map_err(|_| ClientError::DiskStopped),
+        Err(e) => Err(e.into()),
+    };
+    let _ = tx.send(result);
+}
+
+// Takes the first free port of the range, the error is for the last port tried.
+async fn bind_listener(start: u16, end: u16) -> Result<TcpListener> {
+    let mut port = start;
+    loop {
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(source) if port >= end => return Err(ClientError::Listen { port, source }),
+            Err(e) => tracing::debug!("port {} unavailable: {}", port, e),
+        }
+        port += 1;
+    }
+}
+
+// Every torrent is asked at once, those that have stopped are left out.
+async fn list_torrents(torrents: Vec<(ID, TorrentTx)>, tx: oneshot::Sender<Vec<(ID, TorrentState)>>) {
+    let pending: Vec<_> = torrents
+        .into_iter()
+        .map(|(id, torrent_tx)| {
+            let (stats_tx, stats_rx) = oneshot::channel();
+            let _ = torrent_tx.send(torrent::TorrentCommand::GetStats(stats_tx));
+            (id, stats_rx)
+        })
+        .collect();
+
+    let mut states = Vec::with_capacity(pending.len());
+    for (id, stats_rx) in pending {
+        if let Ok(stats) = stats_rx.await {
+            states.push((id, stats.state));
+        }
+    }
+    let _ = tx.send(states);
+}
+
+// Checked before a torrent is allocated, creating the directory if needed.
+pub(crate) fn check_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(".bitter-write-test");
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::File::create(&probe))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|source| ClientError::DirNotWritable { dir: dir.to_path_buf(), source })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use super::*;
+
+    #[test]
+    fn test_error_source_chain() {
+        let err = ClientError::from(MetaInfoError::InvalidPiecesLength);
+        assert!(matches!(err, ClientError::InvalidMetaInfo(_)));
+        assert_eq!(err.to_string(), "invalid metainfo");
+        assert_eq!(err.source().unwrap().to_string(), "invalid pieces length, must be divisible by 20");
+
+        let err = ClientError::from(TorrentError::AllocationError(AllocationError::DuplicateTorrent));
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "torrent already exists in disk task");
+
+        assert_eq!(ClientError::TorrentNotFound([0xab; 20]).to_string(), format!("torrent not found: {}", "ab".repeat(20)));
+        assert_eq!(ClientError::TorrentStopped([0xab; 20]).to_string(), format!("torrent has stopped: {}", "ab".repeat(20)));
+    }
+
+    #[test]
+    fn test_error_is_send_sync() {
+        // Needed to convert into boxed errors, as eyre and anyhow do.
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<ClientError>();
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a/b");
+        check_writable(&nested).unwrap();
+        assert!(nested.is_dir());
+        assert_eq!(std::fs::read_dir(&nested).unwrap().count(), 0);
+
+        // A file where the directory should be.
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(matches!(check_writable(&file.join("dir")), Err(ClientError::DirNotWritable { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_remove_one_of_two_torrents() {
+        
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            listen_port_start: 50000 + rand::random::<u16>() % 10000,
+            ..Default::default()
+        };
+        let (handle, mut user_rx) = crate::start_client(Some(config));
+
+        let single = MetaInfo::new("tests/test_torrents/test_single.torrent").unwrap();
+        let multi = MetaInfo::new("tests/test_torrents/test_multi.torrent").unwrap();
+        let (single_id, multi_id) = (single.info_hash(), multi.info_hash());
+        let single_pieces = single.num_pieces() as usize;
+        handle.new_torrent(single).unwrap();
+        handle.new_torrent(multi).unwrap();
+
+        // Waits for stats from a torrent, showing it is running.
+        async fn wait_for_stats(user_rx: &mut crate::UserRx, id: ID) {
+            let wait = async {
+                while let Some(cmd) = user_rx.recv().await {
+                    if matches!(cmd, UserCommand::TorrentStats { id: stats_id, .. } if stats_id == id) {
+                        return;
+                    }
+                }
+                panic!("client stopped");
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(10), wait).await.expect("no stats from torrent");
+        }
+        wait_for_stats(&mut user_rx, single_id).await;
+        wait_for_stats(&mut user_rx, multi_id).await;
+
+        let mut listed: Vec<ID> = handle.list_torrents().await.unwrap().into_iter().map(|(id, _)| id).collect();
+        listed.sort();
+        let mut ids = vec![single_id, multi_id];
+        ids.sort();
+        assert_eq!(listed, ids);
+        assert_eq!(handle.stats(single_id).await.unwrap().piece_stats.num_pieces, single_pieces);
+
+        handle.remove_torrent(single_id).await.unwrap();
+        assert!(matches!(handle.remove_torrent(single_id).await, Err(ClientError::TorrentNotFound(_))));
+        assert!(matches!(handle.stats(single_id).await, Err(ClientError::TorrentNotFound(_))));
+        assert_eq!(handle.list_torrents().await.unwrap().len(), 1);
+        
+        // The other torrent is unaffected.
+        wait_for_stats(&mut user_rx, multi_id).await;
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_magnet_resumed_into_unwritable_dir() {
+
+        let dir = tempfile::tempdir().unwrap();
+        let resume_dir = dir.path().join("resume");
+        let metainfo = MetaInfo::new("tests/test_torrents/test_single.torrent").unwrap();
+        resume::save_metainfo(&resume_dir, &metainfo).unwrap();
+        // A file where the download directory should be.
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let config = Config {
+            dir: file.join("dir"),
+            resume_dir: Some(resume_dir),
+            listen_port_start: 50000 + rand::random::<u16>() % 10000,
+            ..Default::default()
+        };
+        let (handle, _user_rx) = crate::start_client(Some(config));
+
+        handle.new_magnet(&format!("magnet:?xt=urn:btih:{}", metainfo.info_hash_hex())).unwrap();
+        // The client carries on without the torrent.
+        assert!(handle.list_torrents().await.unwrap().is_empty());
+        handle.shutdown().await.unwrap();
+    }
+
+    // Handshake a peer would send for the torrent.
+    fn handshake_bytes(info_hash: ID) -> Vec<u8> {
+        let mut bytes = vec![19];
+        bytes.extend_from_slice(b"BitTorrent protocol");
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&info_hash);
+        bytes.extend_from_slice(&rand::random::<ID>());
+        bytes
+    }
+
+    // Waits for stats from after the torrent has checked its files.
+    async fn wait_until_running(user_rx: &mut crate::UserRx) {
+        loop {
+            match user_rx.recv().await {
+                Some(UserCommand::TorrentStats { stats, .. }) if stats.state != crate::TorrentState::Checking => return,
+                Some(_) => {},
+                None => panic!("client stopped"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbound_routed_by_info_hash() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let port = 50000 + rand::random::<u16>() % 10000;
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            listen_port_start: port,
+            ..Default::default()
+        };
+        let (handle, mut user_rx) = crate::start_client(Some(config));
+        let metainfo = MetaInfo::new("tests/test_torrents/test_single.torrent").unwrap();
+        let info_hash = metainfo.info_hash();
+        handle.new_torrent(metainfo).unwrap();
+        wait_until_running(&mut user_rx).await;
+
+        let timeout = std::time::Duration::from_secs(5);
+        let mut buf = [0; 68];
+
+        // Closed without a reply.
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(&handshake_bytes([0xff; 20])).await.unwrap();
+        let read = tokio::time::timeout(timeout, stream.read(&mut buf)).await.expect("connection left open");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        // The torrent replies with its own handshake.
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(&handshake_bytes(info_hash)).await.unwrap();
+        tokio::time::timeout(timeout, stream.read_exact(&mut buf)).await.expect("no handshake").unwrap();
+        assert_eq!(&buf[28..48], &info_hash);
+
+        drop(stream);
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pending_handshakes_capped() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let port = 50000 + rand::random::<u16>() % 10000;
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            listen_port_start: port,
+            max_connections_global: 1,
+            ..Default::default()
+        };
+        let (handle, mut user_rx) = crate::start_client(Some(config));
+        handle.new_torrent(MetaInfo::new("tests/test_torrents/test_single.torrent").unwrap()).unwrap();
+        wait_until_running(&mut user_rx).await;
+
+        // The first peer is waited on for its handshake, the second is dropped straight away.
+        let mut buf = [0; 68];
+        let mut waiting = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let mut dropped = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), dropped.read(&mut buf)).await.expect("connection left open");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(100), waiting.read(&mut buf)).await.is_err());
+
+        drop(waiting);
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_listener_falls_back() {
+        let taken = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        // Assumes one of the next few ports is free.
+        let listener = bind_listener(port, port.saturating_add(10)).await.unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), port);
+        assert!(matches!(bind_listener(port, port).await, Err(ClientError::Listen { port: p, .. }) if p == port));
+    }
+
+    #[tokio::test]
+    async fn test_torrents_share_listen_port() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let port = 50000 + rand::random::<u16>() % 10000;
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            listen_port_start: port,
+            listen_port_end: port,
+            ..Default::default()
+        };
+        let (handle, mut user_rx) = crate::start_client(Some(config));
+        let mut ids = Vec::new();
+        for path in ["tests/test_torrents/test_single.torrent", "tests/test_torrents/test_multi.torrent"] {
+            let metainfo = MetaInfo::new(path).unwrap();
+            ids.push(metainfo.info_hash());
+            handle.new_torrent(metainfo).unwrap();
+        }
+
+        // Both torrents have checked their files.
+        let mut running = std::collections::HashSet::new();
+        while running.len() < ids.len() {
+            match user_rx.recv().await {
+                Some(UserCommand::TorrentStats { id, stats }) if stats.state != crate::TorrentState::Checking => {
+                    running.insert(id);
+                },
+                Some(_) => {},
+                None => panic!("client stopped"),
+            }
+        }
+
+        let mut streams = Vec::new();
+        for id in ids {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            stream.write_all(&handshake_bytes(id)).await.unwrap();
+            let mut buf = [0; 68];
+            tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut buf))
+                .await
+                .expect("no handshake")
+                .unwrap();
+            assert_eq!(&buf[28..48], &id);
+            streams.push(stream);
+        }
+
+        drop(streams);
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inbound_connections_capped() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let port = 50000 + rand::random::<u16>() % 10000;
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            listen_port_start: port,
+            max_peers: 2,
+            ..Default::default()
+        };
+        let (handle, mut user_rx) = crate::start_client(Some(config));
+        let metainfo = MetaInfo::new("tests/test_torrents/test_single.torrent").unwrap();
+        let info_hash = metainfo.info_hash();
+        handle.new_torrent(metainfo).unwrap();
+
+        async fn num_peers(user_rx: &mut crate::UserRx) -> usize {
+            loop {
+                match user_rx.recv().await {
+                    // Stats are also sent whilst checking files, before the torrent takes peers.
+                    Some(UserCommand::TorrentStats { stats, .. }) if stats.state != crate::TorrentState::Checking => {
+                        return stats.peer_stats.len()
+                    },
+                    Some(_) => {},
+                    None => panic!("client stopped"),
+                }
+            }
+        }
+        // Stats show the torrent is listening.
+        assert_eq!(num_peers(&mut user_rx).await, 0);
+
+        let mut streams = Vec::new();
+        for _ in 0..5 {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            // Peers reach the torrent once the client has read their handshake.
+            stream.write_all(&handshake_bytes(info_hash)).await.unwrap();
+            streams.push(stream);
+        }
+        for _ in 0..3 {
+            assert!(num_peers(&mut user_rx).await <= 2);
+        }
+        assert_eq!(num_peers(&mut user_rx).await, 2);
+
+        // Sessions stay open until the streams close.
+        drop(streams);
+        handle.shutdown().await.unwrap();
+    }
+}
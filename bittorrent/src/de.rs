@@ -1,4 +1,4 @@
-use serde::{de, Deserialize};
+use serde::{de, ser, Deserialize};
 use url::Url;
 use crate::metainfo::MetaInfoError;
 
@@ -12,6 +12,13 @@ where
     Url::parse(&s).map_err(de::Error::custom)
 }
 
+pub fn url_option_deserialize<'de, D>(deserializer: D) -> Result<Option<Url>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    url_deserialize(deserializer).map(Some)
+}
+
 pub fn announce_list_deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<Vec<Url>>>, D::Error>
 where
     D: de::Deserializer<'de>,
@@ -37,7 +44,35 @@ where
 {
     let raw = Vec::<String>::deserialize(deserializer)?;
     if raw.is_empty() {
-        return Err(MetaInfoError::FileEmptyPath).map_err(de::Error::custom);
+        return Err(de::Error::custom(MetaInfoError::FileEmptyPath));
     }
     Ok(raw.into_iter().collect())
 }
+
+
+// Serialiser functions for metainfo.
+
+pub fn url_option_serialize<S>(url: &Option<Url>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: ser::Serializer,
+{
+    match url {
+        Some(url) => serializer.serialize_some(url.as_str()),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn announce_list_serialize<S>(announce_list: &Option<Vec<Vec<Url>>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: ser::Serializer,
+{
+    use ser::Serialize;
+    announce_list
+        .as_ref()
+        .map(|tiers| tiers
+            .iter()
+            .map(|tier| tier.iter().map(Url::as_str).collect::<Vec<&str>>())
+            .collect::<Vec<Vec<&str>>>()
+        )
+        .serialize(serializer)
+}
\ No newline at end of file
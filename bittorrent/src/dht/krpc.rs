@@ -0,0 +1,232 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use bytes::{Buf, BufMut};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use crate::ID;
+
+// KRPC messages are bencoded dictionaries sent over UDP.
+// A message is either a query, a response or an error, indicated by the "y" key.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct KrpcMessage {
+
+    // Transaction id, echoed back in the response to a query.
+    #[serde(rename = "t")]
+    pub transaction_id: ByteBuf,
+
+    // "q" for query, "r" for response, "e" for error.
+    #[serde(rename = "y")]
+    pub kind: String,
+
+    // Method name of a query.
+    #[serde(rename = "q")]
+    #[serde(default)]
+    pub query: Option<String>,
+
+    // Named arguments of a query.
+    #[serde(rename = "a")]
+    #[serde(default)]
+    pub args: Option<QueryArgs>,
+
+    // Named return values of a response.
+    #[serde(rename = "r")]
+    #[serde(default)]
+    pub response: Option<Response>,
+
+    // Error code and message.
+    #[serde(rename = "e")]
+    #[serde(default)]
+    pub error: Option<(i64, String)>,
+
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct QueryArgs {
+
+    // Id of the querying node.
+    pub id: ByteBuf,
+
+    // find_node: id of the node being searched for.
+    #[serde(default)]
+    pub target: Option<ByteBuf>,
+
+    // get_peers/announce_peer: info hash of the torrent.
+    #[serde(default)]
+    pub info_hash: Option<ByteBuf>,
+
+    // announce_peer: port the querying peer is listening on.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    // announce_peer: token from a previous get_peers response.
+    #[serde(default)]
+    pub token: Option<ByteBuf>,
+
+    // announce_peer: if 1, use the source port of the packet instead of port.
+    #[serde(default)]
+    pub implied_port: Option<u8>,
+
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Response {
+
+    // Id of the responding node.
+    pub id: ByteBuf,
+
+    // Compact node info of the closest nodes to the target.
+    #[serde(default)]
+    pub nodes: Option<ByteBuf>,
+
+    // Compact peer info for the requested info hash.
+    #[serde(default)]
+    pub values: Option<Vec<ByteBuf>>,
+
+    // Token required to announce to the responding node.
+    #[serde(default)]
+    pub token: Option<ByteBuf>,
+
+}
+
+impl KrpcMessage {
+
+    pub fn query(transaction_id: &[u8], method: &str, args: QueryArgs) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "q".to_string(),
+            query: Some(method.to_string()),
+            args: Some(args),
+            response: None,
+            error: None,
+        }
+    }
+
+    pub fn response(transaction_id: &[u8], response: Response) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "r".to_string(),
+            query: None,
+            args: None,
+            response: Some(response),
+            error: None,
+        }
+    }
+
+    pub fn error(transaction_id: &[u8], code: i64, msg: &str) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "e".to_string(),
+            query: None,
+            args: None,
+            response: None,
+            error: Some((code, msg.to_string())),
+        }
+    }
+}
+
+// Converts a byte string in a message to an id, if it is the correct length.
+pub fn to_id(bytes: &[u8]) -> Option<ID> {
+    bytes.try_into().ok()
+}
+
+// Compact node info: 20 byte node id followed by 6 byte compact IPv4 address.
+pub fn decode_nodes(mut buf: &[u8]) -> Vec<(ID, SocketAddr)> {
+    let mut nodes = Vec::with_capacity(buf.len() / 26);
+    while buf.len() >= 26 {
+        let mut id = [0; 20];
+        buf.copy_to_slice(&mut id);
+        nodes.push((id, decode_addr(&mut buf)));
+    }
+    nodes
+}
+
+pub fn encode_nodes(nodes: &[(ID, SocketAddr)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nodes.len() * 26);
+    for (id, addr) in nodes {
+        // Compact format can only represent IPv4.
+        if let IpAddr::V4(ip) = addr.ip() {
+            buf.extend_from_slice(id);
+            buf.put_u32(ip.into());
+            buf.put_u16(addr.port());
+        }
+    }
+    buf
+}
+
+// Compact peer info: 4 byte IPv4 address followed by 2 byte port.
+pub fn decode_peer(mut buf: &[u8]) -> Option<SocketAddr> {
+    if buf.len() != 6 {
+        return None;
+    }
+    Some(decode_addr(&mut buf))
+}
+
+pub fn encode_peer(addr: &SocketAddr) -> Option<Vec<u8>> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut buf = Vec::with_capacity(6);
+            buf.put_u32(ip.into());
+            buf.put_u16(addr.port());
+            Some(buf)
+        },
+        IpAddr::V6(_) => None,
+    }
+}
+
+fn decode_addr(buf: &mut &[u8]) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::from(buf.get_u32())), buf.get_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_krpc_ping_encoding() {
+        // Example from BEP-5.
+        let raw = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let args = QueryArgs { id: ByteBuf::from(&b"abcdefghij0123456789"[..]), ..Default::default() };
+        let msg = KrpcMessage::query(b"aa", "ping", args);
+        assert_eq!(bencode::encode_to_raw(&msg).unwrap(), raw.to_vec());
+        let decoded: KrpcMessage = bencode::decode_bytes(raw).unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_krpc_get_peers_response() {
+        let raw = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+        let decoded: KrpcMessage = bencode::decode_bytes(raw).unwrap();
+        let response = decoded.response.unwrap();
+        assert_eq!(response.token.unwrap().into_vec(), b"aoeusnth".to_vec());
+        let peers: Vec<SocketAddr> = response.values
+            .unwrap()
+            .iter()
+            .filter_map(|v| decode_peer(v))
+            .collect();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0], "97.120.106.101:11893".parse().unwrap());
+    }
+
+    #[test]
+    fn test_krpc_error() {
+        let raw = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let decoded: KrpcMessage = bencode::decode_bytes(raw).unwrap();
+        assert_eq!(decoded.error, Some((201, "A Generic Error Ocurred".to_string())));
+        assert_eq!(bencode::encode_to_raw(&decoded).unwrap(), raw.to_vec());
+    }
+
+    #[test]
+    fn test_krpc_deeply_nested() {
+        // As deep as a datagram allows, rejected rather than decoded recursively.
+        let raw = format!("d1:t2:aa1:y1:q1:x{}e", "l".repeat(2000));
+        assert!(matches!(bencode::decode_bytes::<KrpcMessage>(raw.as_bytes()), Err(bencode::Error::TooDeep)));
+    }
+
+    #[test]
+    fn test_compact_nodes() {
+        let nodes = vec![
+            ([1; 20], "1.2.3.4:6881".parse().unwrap()),
+            ([2; 20], "5.6.7.8:51413".parse().unwrap()),
+        ];
+        assert_eq!(decode_nodes(&encode_nodes(&nodes)), nodes);
+    }
+}
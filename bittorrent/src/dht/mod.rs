@@ -0,0 +1,730 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+use serde_bytes::ByteBuf;
+use sha1::Digest;
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle, time};
+use tracing::Instrument;
+use crate::{torrent::{TorrentCommand, TorrentTx}, ID};
+
+mod krpc;
+mod routing;
+
+use krpc::{KrpcMessage, QueryArgs, Response};
+use routing::{distance, RoutingTable, K};
+
+// Reference: https://www.bittorrent.org/beps/bep_0005.html
+
+const BOOTSTRAP_NODES: [&str; 2] = ["router.bittorrent.com:6881", "dht.transmissionbt.com:6881"];
+
+// Number of queries in flight at once for a single lookup.
+const ALPHA: usize = 3;
+
+// Queries without a response after this are dropped.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Minimum time between lookups for the same info hash.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Secret used to generate announce tokens is rotated at this interval.
+const TOKEN_ROTATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Maximum number of peers we store for another node's announce per info hash.
+const MAX_STORED_PEERS: usize = 100;
+
+// Maximum number of info hashes we store announced peers for.
+const MAX_STORED_INFO_HASHES: usize = 1000;
+
+// Announced peers are dropped if not announced again within this time, as BEP 5 suggests.
+const STORED_PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+type Result<T> = std::result::Result<T, DhtError>;
+pub type DhtTx = mpsc::UnboundedSender<DhtCommand>;
+type DhtRx = mpsc::UnboundedReceiver<DhtCommand>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DhtError {
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("bencode error: {0}")]
+    BencodeError(#[from] bencode::Error),
+
+}
+
+pub enum DhtCommand {
+
+    // Search for peers of an info hash, and announce ourselves as a peer on the given port.
+    GetPeers {
+        info_hash: ID,
+        port: u16,
+        torrent_tx: TorrentTx,
+    },
+
+    // A node address learned elsewhere, eg. from a peer's port message.
+    AddNode(SocketAddr),
+
+    Shutdown,
+
+}
+
+pub fn start_dht(port: u16) -> (JoinHandle<()>, DhtTx) {
+    let (dht_tx, dht_rx) = mpsc::unbounded_channel();
+    let handle = tokio::spawn(async move {
+        match Dht::bind(port, dht_rx).await {
+            Ok(mut dht) => if let Err(e) = dht.run().await {
+                tracing::error!("dht error: {}", e);
+            },
+            Err(e) => tracing::error!("failed to start dht: {}", e),
+        }
+    }.instrument(tracing::info_span!("dht")));
+    (handle, dht_tx)
+}
+
+enum QueryKind {
+    Ping,
+    FindNode,
+    GetPeers(ID),
+    AnnouncePeer,
+}
+
+struct PendingQuery {
+    addr: SocketAddr,
+    kind: QueryKind,
+    sent: Instant,
+}
+
+// An iterative get_peers search converging on the nodes closest to an info hash.
+struct Lookup {
+
+    port: u16,
+
+    torrent_tx: TorrentTx,
+
+    // Nodes found so far keyed by distance to the info hash, and whether they have been queried.
+    candidates: BTreeMap<ID, (SocketAddr, bool)>,
+
+    // Tokens from nodes that responded, keyed by distance, used to announce once the search ends.
+    tokens: BTreeMap<ID, (SocketAddr, Vec<u8>)>,
+
+    // Transaction ids of the get_peers queries awaiting a response.
+    in_flight: HashSet<u16>,
+
+}
+
+struct Dht {
+
+    // Our node id.
+    id: ID,
+
+    socket: UdpSocket,
+
+    dht_rx: DhtRx,
+
+    table: RoutingTable,
+
+    // Queries awaiting a response, by transaction id.
+    pending: HashMap<u16, PendingQuery>,
+
+    next_transaction_id: u16,
+
+    // Active get_peers searches.
+    lookups: HashMap<ID, Lookup>,
+
+    // When a search for an info hash was last started.
+    last_lookup: HashMap<ID, Instant>,
+
+    // Peers other nodes have announced to us, and when they were last announced.
+    peer_store: HashMap<ID, HashMap<SocketAddr, Instant>>,
+
+    // Current and previous secret for announce tokens.
+    secrets: ([u8; 20], [u8; 20]),
+
+    last_rotate: Instant,
+
+}
+
+impl Dht {
+
+    async fn bind(port: u16, dht_rx: DhtRx) -> Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).await?;
+        tracing::info!("dht listening on {:?}", socket.local_addr()?);
+        let id = rand::random();
+        Ok(Self {
+            id,
+            socket,
+            dht_rx,
+            table: RoutingTable::new(id),
+            pending: HashMap::new(),
+            next_transaction_id: 0,
+            lookups: HashMap::new(),
+            last_lookup: HashMap::new(),
+            peer_store: HashMap::new(),
+            secrets: (rand::random(), rand::random()),
+            last_rotate: Instant::now(),
+        })
+    }
+
+    async fn run(&mut self) -> Result<()> {
+
+        let mut ticker = time::interval(Duration::from_secs(5));
+        let mut buf = [0u8; 2048];
+
+        loop { tokio::select! {
+
+            recv = self.socket.recv_from(&mut buf) => {
+                // Some platforms report an ICMP error from a node we sent to on the next receive.
+                let (n, addr) = match recv {
+                    Ok(recv) => recv,
+                    Err(e) => {
+                        tracing::debug!("dht receive error: {}", e);
+                        continue;
+                    },
+                };
+                match bencode::decode_bytes::<KrpcMessage>(&buf[..n]) {
+                    Ok(msg) => self.handle_msg(msg, addr).await?,
+                    Err(e) => tracing::trace!("invalid krpc message from {}: {}", addr, e),
+                }
+            },
+
+            Some(cmd) = self.dht_rx.recv() => {
+                match cmd {
+
+                    DhtCommand::GetPeers { info_hash, port, torrent_tx } => {
+                        self.start_lookup(info_hash, port, torrent_tx).await?;
+                    },
+
+                    DhtCommand::AddNode(addr) => self.ping(addr).await?,
+
+                    DhtCommand::Shutdown => break,
+                }
+            },
+
+            _ = ticker.tick() => self.tick().await?,
+
+        }}
+
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+
+        let now = Instant::now();
+
+        // Expire queries that never got a response.
+        let expired: Vec<u16> = self.pending
+            .iter()
+            .filter(|(_, q)| now.duration_since(q.sent) >= QUERY_TIMEOUT)
+            .map(|(tid, _)| *tid)
+            .collect();
+        for tid in expired {
+            let Some(query) = self.pending.remove(&tid) else { continue };
+            self.table.remove(&query.addr);
+            if let QueryKind::GetPeers(info_hash) = query.kind {
+                self.lookup_query_done(info_hash, tid).await?;
+            }
+        }
+
+        self.expire_peers(now);
+
+        if now.duration_since(self.last_rotate) >= TOKEN_ROTATE_INTERVAL {
+            self.secrets = (rand::random(), self.secrets.0);
+            self.last_rotate = now;
+        }
+
+        // (Re)bootstrap when we don't know any nodes.
+        if self.table.len() == 0 && self.pending.is_empty() {
+            self.bootstrap().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn bootstrap(&mut self) -> Result<()> {
+        tracing::debug!("bootstrapping dht");
+        for host in BOOTSTRAP_NODES {
+            // Resolving is blocking, so do it off the runtime.
+            let addrs = tokio::task::spawn_blocking(move || host.to_socket_addrs().map(|a| a.collect::<Vec<_>>()))
+                .await
+                .map_err(std::io::Error::other)?;
+            match addrs {
+                Ok(addrs) => for addr in addrs.into_iter().filter(SocketAddr::is_ipv4) {
+                    self.find_node(addr, self.id).await?;
+                },
+                Err(e) => tracing::warn!("failed to resolve bootstrap node {}: {}", host, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_msg(&mut self, msg: KrpcMessage, addr: SocketAddr) -> Result<()> {
+        match msg.kind.as_str() {
+            "q" => self.handle_query(msg, addr).await,
+            "r" => self.handle_response(msg, addr).await,
+            "e" => {
+                tracing::debug!("error from {}: {:?}", addr, msg.error);
+                if let Some((tid, query)) = self.take_pending(&msg, addr) {
+                    if let QueryKind::GetPeers(info_hash) = query.kind {
+                        self.lookup_query_done(info_hash, tid).await?;
+                    }
+                }
+                Ok(())
+            },
+            kind => {
+                tracing::trace!("unknown krpc message type {} from {}", kind, addr);
+                Ok(())
+            },
+        }
+    }
+
+    async fn handle_query(&mut self, msg: KrpcMessage, addr: SocketAddr) -> Result<()> {
+
+        let (Some(method), Some(args)) = (msg.query, msg.args) else {
+            return self.send(addr, KrpcMessage::error(&msg.transaction_id, 203, "malformed query")).await;
+        };
+        let Some(id) = krpc::to_id(&args.id) else {
+            return self.send(addr, KrpcMessage::error(&msg.transaction_id, 203, "invalid id")).await;
+        };
+        self.table.insert(id, addr);
+
+        let mut response = Response {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+
+        match method.as_str() {
+
+            "ping" => {},
+
+            "find_node" => {
+                let Some(target) = args.target.as_ref().and_then(|b| krpc::to_id(b)) else {
+                    return self.send(addr, KrpcMessage::error(&msg.transaction_id, 203, "invalid target")).await;
+                };
+                response.nodes = Some(ByteBuf::from(self.compact_closest(&target)));
+            },
+
+            "get_peers" => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(|b| krpc::to_id(b)) else {
+                    return self.send(addr, KrpcMessage::error(&msg.transaction_id, 203, "invalid info hash")).await;
+                };
+                response.token = Some(ByteBuf::from(self.token(&addr, &self.secrets.0)));
+                match self.peer_store.get(&info_hash) {
+                    Some(peers) if !peers.is_empty() => {
+                        response.values = Some(peers
+                            .keys()
+                            .filter_map(krpc::encode_peer)
+                            .map(ByteBuf::from)
+                            .collect());
+                    },
+                    _ => response.nodes = Some(ByteBuf::from(self.compact_closest(&info_hash))),
+                }
+            },
+
+            "announce_peer" => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(|b| krpc::to_id(b)) else {
+                    return self.send(addr, KrpcMessage::error(&msg.transaction_id, 203, "invalid info hash")).await;
+                };
+                let valid_token = args.token.as_ref().is_some_and(|token| {
+                    token.as_slice() == self.token(&addr, &self.secrets.0)
+                    || token.as_slice() == self.token(&addr, &self.secrets.1)
+                });
+                if !valid_token {
+                    return self.send(addr, KrpcMessage::error(&msg.transaction_id, 203, "bad token")).await;
+                }
+                let port = if args.implied_port == Some(1) {
+                    addr.port()
+                } else if let Some(port) = args.port {
+                    port
+                } else {
+                    return self.send(addr, KrpcMessage::error(&msg.transaction_id, 203, "missing port")).await;
+                };
+                self.store_peer(info_hash, SocketAddr::new(addr.ip(), port));
+            },
+
+            _ => return self.send(addr, KrpcMessage::error(&msg.transaction_id, 204, "method unknown")).await,
+        }
+
+        self.send(addr, KrpcMessage::response(&msg.transaction_id, response)).await
+    }
+
+    async fn handle_response(&mut self, msg: KrpcMessage, addr: SocketAddr) -> Result<()> {
+
+        let Some((tid, query)) = self.take_pending(&msg, addr) else {
+            return Ok(());
+        };
+        let Some(response) = msg.response else {
+            if let QueryKind::GetPeers(info_hash) = query.kind {
+                self.lookup_query_done(info_hash, tid).await?;
+            }
+            return Ok(());
+        };
+        if let Some(id) = krpc::to_id(&response.id) {
+            self.table.insert(id, addr);
+        }
+        let nodes = response.nodes
+            .as_ref()
+            .map(|b| krpc::decode_nodes(b))
+            .unwrap_or_default();
+
+        match query.kind {
+
+            QueryKind::Ping | QueryKind::AnnouncePeer => {},
+
+            // Only sent while bootstrapping, keep filling the table from the nodes returned.
+            QueryKind::FindNode => {
+                for (_, addr) in nodes {
+                    if self.table.len() + self.pending.len() < K * 8 {
+                        self.find_node(addr, self.id).await?;
+                    }
+                }
+            },
+
+            QueryKind::GetPeers(info_hash) => {
+                let Some(lookup) = self.lookups.get_mut(&info_hash) else {
+                    return Ok(());
+                };
+
+                if let Some(values) = response.values {
+                    let peers: Vec<SocketAddr> = values.iter().filter_map(|v| krpc::decode_peer(v)).collect();
+                    tracing::debug!("dht provided {} peers", peers.len());
+                    if !peers.is_empty() {
+                        let _ = lookup.torrent_tx.send(TorrentCommand::Peers { peers, tracker: None });
+                    }
+                }
+                if let (Some(token), Some(id)) = (response.token, krpc::to_id(&response.id)) {
+                    lookup.tokens.insert(distance(&id, &info_hash), (addr, token.into_vec()));
+                }
+                for (id, node_addr) in nodes {
+                    lookup.candidates.entry(distance(&id, &info_hash)).or_insert((node_addr, false));
+                }
+
+                self.lookup_query_done(info_hash, tid).await?;
+            },
+        }
+
+        Ok(())
+    }
+
+    // Peers already stored are refreshed, new ones are dropped once the store is full.
+    fn store_peer(&mut self, info_hash: ID, peer: SocketAddr) {
+        if self.peer_store.len() >= MAX_STORED_INFO_HASHES && !self.peer_store.contains_key(&info_hash) {
+            return;
+        }
+        let peers = self.peer_store.entry(info_hash).or_default();
+        if peers.len() < MAX_STORED_PEERS || peers.contains_key(&peer) {
+            peers.insert(peer, Instant::now());
+        }
+    }
+
+    fn expire_peers(&mut self, now: Instant) {
+        for peers in self.peer_store.values_mut() {
+            peers.retain(|_, announced| now.duration_since(*announced) < STORED_PEER_LIFETIME);
+        }
+        self.peer_store.retain(|_, peers| !peers.is_empty());
+    }
+
+    // Removes the query a response or error answers, if it came from the node the query was sent to.
+    fn take_pending(&mut self, msg: &KrpcMessage, addr: SocketAddr) -> Option<(u16, PendingQuery)> {
+        let Some((tid, query)) = transaction_id(msg).and_then(|tid| self.pending.remove_entry(&tid)) else {
+            tracing::trace!("unexpected response from {}", addr);
+            return None;
+        };
+        if query.addr != addr {
+            tracing::trace!("response from {} to query sent to {}", addr, query.addr);
+            // Left for the node it was sent to.
+            self.pending.insert(tid, query);
+            return None;
+        }
+        Some((tid, query))
+    }
+
+    // A get_peers query was answered, failed or timed out, so the lookup can query another node.
+    // Queries from a lookup that was started over don't count towards the new one.
+    async fn lookup_query_done(&mut self, info_hash: ID, tid: u16) -> Result<()> {
+        let Some(lookup) = self.lookups.get_mut(&info_hash) else {
+            return Ok(());
+        };
+        if !lookup.in_flight.remove(&tid) {
+            return Ok(());
+        }
+        self.step_lookup(info_hash).await
+    }
+
+    async fn start_lookup(&mut self, info_hash: ID, port: u16, torrent_tx: TorrentTx) -> Result<()> {
+
+        if let Some(last) = self.last_lookup.get(&info_hash) {
+            if last.elapsed() < LOOKUP_INTERVAL {
+                return Ok(());
+            }
+        }
+        // Lookups finish well within the interval, one still running has stalled so is started over.
+        if self.lookups.remove(&info_hash).is_some() {
+            tracing::debug!("restarting stalled dht lookup");
+        }
+
+        let candidates = self.table
+            .closest(&info_hash, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &info_hash), (node.addr, false)))
+            .collect();
+
+        self.last_lookup.insert(info_hash, Instant::now());
+        self.lookups.insert(info_hash, Lookup {
+            port,
+            torrent_tx,
+            candidates,
+            tokens: BTreeMap::new(),
+            in_flight: HashSet::new(),
+        });
+        self.step_lookup(info_hash).await
+    }
+
+    // Queries the closest unqueried candidates, or finishes the lookup if there are none left.
+    async fn step_lookup(&mut self, info_hash: ID) -> Result<()> {
+
+        let Some(lookup) = self.lookups.get_mut(&info_hash) else {
+            return Ok(());
+        };
+
+        let mut to_query = Vec::new();
+        for (addr, queried) in lookup.candidates.values_mut().take(K) {
+            if lookup.in_flight.len() + to_query.len() >= ALPHA {
+                break;
+            }
+            if !*queried {
+                *queried = true;
+                to_query.push(*addr);
+            }
+        }
+        if lookup.in_flight.is_empty() && to_query.is_empty() {
+            // Search has converged, announce to the closest nodes that gave us a token.
+            let lookup = self.lookups.remove(&info_hash).unwrap();
+            tracing::debug!("dht lookup finished, announcing to {} nodes", lookup.tokens.len().min(K));
+            for (addr, token) in lookup.tokens.into_values().take(K) {
+                self.announce_peer(addr, info_hash, lookup.port, token).await?;
+            }
+            return Ok(());
+        }
+
+        for addr in to_query {
+            let args = QueryArgs {
+                id: ByteBuf::from(self.id.to_vec()),
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                ..Default::default()
+            };
+            let tid = self.query(addr, "get_peers", args, QueryKind::GetPeers(info_hash)).await?;
+            if let Some(lookup) = self.lookups.get_mut(&info_hash) {
+                lookup.in_flight.insert(tid);
+            }
+        }
+        Ok(())
+    }
+
+    async fn ping(&mut self, addr: SocketAddr) -> Result<()> {
+        let args = QueryArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        self.query(addr, "ping", args, QueryKind::Ping).await?;
+        Ok(())
+    }
+
+    async fn find_node(&mut self, addr: SocketAddr, target: ID) -> Result<()> {
+        let args = QueryArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        self.query(addr, "find_node", args, QueryKind::FindNode).await?;
+        Ok(())
+    }
+
+    async fn announce_peer(&mut self, addr: SocketAddr, info_hash: ID, port: u16, token: Vec<u8>) -> Result<()> {
+        let args = QueryArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(port),
+            token: Some(ByteBuf::from(token)),
+            implied_port: Some(0),
+            ..Default::default()
+        };
+        self.query(addr, "announce_peer", args, QueryKind::AnnouncePeer).await?;
+        Ok(())
+    }
+
+    // Returns the transaction id the response will carry.
+    async fn query(&mut self, addr: SocketAddr, method: &str, args: QueryArgs, kind: QueryKind) -> Result<u16> {
+        let tid = self.next_transaction_id;
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+        self.pending.insert(tid, PendingQuery { addr, kind, sent: Instant::now() });
+        self.send(addr, KrpcMessage::query(&tid.to_be_bytes(), method, args)).await?;
+        Ok(tid)
+    }
+
+    async fn send(&self, addr: SocketAddr, msg: KrpcMessage) -> Result<()> {
+        let buf = bencode::encode_to_raw(&msg)?;
+        // A single unreachable node shouldn't stop the dht.
+        if let Err(e) = self.socket.send_to(&buf, addr).await {
+            tracing::trace!("failed to send to {}: {}", addr, e);
+        }
+        Ok(())
+    }
+
+    fn compact_closest(&self, target: &ID) -> Vec<u8> {
+        let nodes: Vec<(ID, SocketAddr)> = self.table
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        krpc::encode_nodes(&nodes)
+    }
+
+    // Token given to a node so it can later announce, tied to its IP address.
+    fn token(&self, addr: &SocketAddr, secret: &[u8; 20]) -> Vec<u8> {
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(secret);
+        hasher.update(addr.ip().to_string());
+        hasher.finalize()[..8].to_vec()
+    }
+}
+
+fn transaction_id(msg: &KrpcMessage) -> Option<u16> {
+    msg.transaction_id
+        .as_slice()
+        .try_into()
+        .ok()
+        .map(u16::from_be_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_dht() -> Dht {
+        Dht::bind(0, mpsc::unbounded_channel().1).await.unwrap()
+    }
+
+    // A node in the routing table, answered by hand.
+    async fn test_node(dht: &mut Dht) -> UdpSocket {
+        let node = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        dht.table.insert([7; 20], node.local_addr().unwrap());
+        node
+    }
+
+    async fn recv_query(node: &UdpSocket) -> KrpcMessage {
+        let mut buf = [0; 2048];
+        let (n, _) = node.recv_from(&mut buf).await.unwrap();
+        bencode::decode_bytes(&buf[..n]).unwrap()
+    }
+
+    fn get_peers_response(peer: SocketAddr) -> Response {
+        Response {
+            id: ByteBuf::from(vec![7; 20]),
+            values: Some(vec![ByteBuf::from(krpc::encode_peer(&peer).unwrap())]),
+            token: Some(ByteBuf::from(b"token".to_vec())),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let mut dht = test_dht().await;
+        let node = test_node(&mut dht).await;
+        let node_addr = node.local_addr().unwrap();
+        let (torrent_tx, mut torrent_rx) = mpsc::unbounded_channel();
+        let peer: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+
+        dht.start_lookup([1; 20], 6882, torrent_tx).await.unwrap();
+        let query = recv_query(&node).await;
+        assert_eq!(query.query.as_deref(), Some("get_peers"));
+
+        let response = KrpcMessage::response(&query.transaction_id, get_peers_response(peer));
+        dht.handle_msg(response, node_addr).await.unwrap();
+        assert!(matches!(torrent_rx.try_recv(), Ok(TorrentCommand::Peers { peers, .. }) if peers == vec![peer]));
+
+        // No nodes are left to ask, so we announce to the one that gave us a token.
+        assert!(dht.lookups.is_empty());
+        let announce = recv_query(&node).await;
+        assert_eq!(announce.query.as_deref(), Some("announce_peer"));
+        let args = announce.args.unwrap();
+        assert_eq!(args.token.unwrap().into_vec(), b"token");
+        assert_eq!(args.port, Some(6882));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_error() {
+        let mut dht = test_dht().await;
+        let node = test_node(&mut dht).await;
+        let node_addr = node.local_addr().unwrap();
+        let info_hash = [1; 20];
+
+        dht.start_lookup(info_hash, 6882, mpsc::unbounded_channel().0).await.unwrap();
+        let query = recv_query(&node).await;
+
+        // Answers from other addresses are ignored.
+        let spoofed = KrpcMessage::response(&query.transaction_id, get_peers_response("1.2.3.4:6881".parse().unwrap()));
+        dht.handle_msg(spoofed, "127.0.0.1:1".parse().unwrap()).await.unwrap();
+        assert_eq!(dht.lookups[&info_hash].in_flight.len(), 1);
+
+        // An error answers the query, so the lookup isn't left waiting on it.
+        dht.handle_msg(KrpcMessage::error(&query.transaction_id, 201, "A Generic Error"), node_addr).await.unwrap();
+        assert!(dht.lookups.is_empty());
+        assert!(dht.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peer_store_limits() {
+        let mut dht = test_dht().await;
+        let peer = |i: usize| SocketAddr::new([10, 0, 0, i as u8].into(), 6881);
+
+        for i in 0..=MAX_STORED_PEERS {
+            dht.store_peer([0; 20], peer(i));
+        }
+        assert_eq!(dht.peer_store[&[0; 20]].len(), MAX_STORED_PEERS);
+        for i in 1..=MAX_STORED_INFO_HASHES {
+            dht.store_peer((i as u32).to_be_bytes().repeat(5).try_into().unwrap(), peer(0));
+        }
+        assert_eq!(dht.peer_store.len(), MAX_STORED_INFO_HASHES);
+
+        // Peers announced again are kept, the rest expire along with their info hash.
+        let later = Instant::now() + STORED_PEER_LIFETIME / 2;
+        dht.peer_store.get_mut(&[0; 20]).unwrap().insert(peer(0), later);
+        dht.expire_peers(Instant::now() + STORED_PEER_LIFETIME);
+        assert_eq!(dht.peer_store.len(), 1);
+        assert_eq!(dht.peer_store[&[0; 20]].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_restart_lookup() {
+        let mut dht = test_dht().await;
+        let node = test_node(&mut dht).await;
+        let node_addr = node.local_addr().unwrap();
+        let info_hash = [1; 20];
+
+        dht.start_lookup(info_hash, 6882, mpsc::unbounded_channel().0).await.unwrap();
+        let stale = recv_query(&node).await;
+
+        // Too soon to search again.
+        dht.start_lookup(info_hash, 6882, mpsc::unbounded_channel().0).await.unwrap();
+        assert_eq!(dht.pending.len(), 1);
+
+        // Still running once the interval has passed, so it's started over.
+        dht.last_lookup.clear();
+        let (torrent_tx, mut torrent_rx) = mpsc::unbounded_channel();
+        dht.start_lookup(info_hash, 6882, torrent_tx).await.unwrap();
+        let query = recv_query(&node).await;
+        assert_eq!(dht.lookups[&info_hash].in_flight.len(), 1);
+
+        // A late answer to the first search only adds to the new one.
+        let peer: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        dht.handle_msg(KrpcMessage::response(&stale.transaction_id, get_peers_response(peer)), node_addr).await.unwrap();
+        assert_eq!(dht.lookups[&info_hash].in_flight.len(), 1);
+        assert!(matches!(torrent_rx.try_recv(), Ok(TorrentCommand::Peers { .. })));
+
+        dht.handle_msg(KrpcMessage::response(&query.transaction_id, get_peers_response(peer)), node_addr).await.unwrap();
+        assert!(dht.lookups.is_empty());
+    }
+}
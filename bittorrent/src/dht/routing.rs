@@ -0,0 +1,167 @@
+use std::{net::SocketAddr, time::{Duration, Instant}};
+use crate::ID;
+
+// Maximum number of nodes in a bucket.
+pub const K: usize = 8;
+
+// Nodes not heard from within this time may be replaced.
+const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Node {
+
+    pub id: ID,
+
+    pub addr: SocketAddr,
+
+    pub last_seen: Instant,
+
+}
+
+// Kademlia routing table, nodes are bucketed by the length of the prefix
+// their id shares with our own (ie. XOR distance).
+#[derive(Debug)]
+pub struct RoutingTable {
+
+    // Our own node id.
+    id: ID,
+
+    // Bucket i holds nodes sharing exactly i leading bits with our id.
+    buckets: Vec<Vec<Node>>,
+
+}
+
+impl RoutingTable {
+
+    pub fn new(id: ID) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    // Inserts or refreshes a node that we have heard from.
+    // Returns false if the node was not added because its bucket is full of good nodes.
+    pub fn insert(&mut self, id: ID, addr: SocketAddr) -> bool {
+        if id == self.id {
+            return false;
+        }
+
+        let now = Instant::now();
+        let bucket = &mut self.buckets[bucket_idx(&self.id, &id)];
+        
+        // Known node, move to the back as most recently seen.
+        if let Some(pos) = bucket.iter().position(|n| n.id == id) {
+            let mut node = bucket.remove(pos);
+            node.addr = addr;
+            node.last_seen = now;
+            bucket.push(node);
+            return true;
+        }
+
+        let node = Node { id, addr, last_seen: now };
+        if bucket.len() < K {
+            bucket.push(node);
+            true
+        // Bucket is full, replace the least recently seen node if it has gone quiet.
+        } else if now.duration_since(bucket[0].last_seen) >= NODE_TIMEOUT {
+            bucket.remove(0);
+            bucket.push(node);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Removes a node that stopped responding.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain(|n| n.addr != *addr);
+        }
+    }
+
+    // Returns up to n nodes closest to the target.
+    pub fn closest(&self, target: &ID, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().cloned().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+}
+
+pub fn distance(a: &ID, b: &ID) -> ID {
+    let mut out = [0; 20];
+    for i in 0..20 {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+fn bucket_idx(own: &ID, id: &ID) -> usize {
+    let dist = distance(own, id);
+    let mut prefix_len = 0;
+    for byte in dist {
+        if byte == 0 {
+            prefix_len += 8;
+        } else {
+            prefix_len += byte.leading_zeros() as usize;
+            break;
+        }
+    }
+    // Only our own id shares all 160 bits.
+    prefix_len.min(159)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new([127, 0, 0, 1].into(), port)
+    }
+
+    #[test]
+    fn test_bucket_idx() {
+        let own = [0; 20];
+        let mut id = [0; 20];
+        id[0] = 0x80;
+        assert_eq!(bucket_idx(&own, &id), 0);
+        id[0] = 0x01;
+        assert_eq!(bucket_idx(&own, &id), 7);
+        id[0] = 0;
+        id[19] = 1;
+        assert_eq!(bucket_idx(&own, &id), 159);
+    }
+
+    #[test]
+    fn test_bucket_full() {
+        let mut table = RoutingTable::new([0; 20]);
+        // All ids share no prefix with our id, so land in the same bucket.
+        for i in 0..K as u8 {
+            let mut id = [0xff; 20];
+            id[19] = i;
+            assert!(table.insert(id, addr(i as u16)));
+        }
+        assert!(!table.insert([0xfe; 20], addr(100)));
+        assert_eq!(table.len(), K);
+        assert!(!table.insert([0; 20], addr(101)), "inserted own id");
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new([0; 20]);
+        for i in 1..=20u8 {
+            let mut id = [0; 20];
+            id[0] = i;
+            table.insert(id, addr(i as u16));
+        }
+        let mut target = [0; 20];
+        target[0] = 4;
+        let closest = table.closest(&target, 3);
+        assert_eq!(closest.iter().map(|n| n.id[0]).collect::<Vec<_>>(), vec![4, 5, 6]);
+    }
+}
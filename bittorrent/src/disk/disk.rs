@@ -38,35 +38,78 @@ impl Disk {
                     files,
                     dir,
                     torrent_tx,
+                    write_through,
+                    cache_size,
+                    preallocate,
+                    sync_on_write,
+                    check_threads,
+                    resume_dir,
                     tx,
                 } => {
 
-                    let msg = if self.torrents.contains_key(&id) {
-                        Err(AllocationError::DuplicateTorrent)
-                    } else {
-                        match torrent::Torrent::new(files, dir, piece_hashes, info, torrent_tx) {
-                            
-                            Ok(torrent) => {
-                                // Allocate the new torrent.
-                                // Maybe run this in a separate task, particularly the checking?
-                                let bf = torrent.check_existing_files();
-                                self.torrents.insert(id, RwLock::new(torrent));
-                                Ok(bf)
-                            },
-                            
-                            Err(e) => Err(e),
-                        }
-                    };
-                
-                    let _ = tx.send(msg);
+                    if self.torrents.contains_key(&id) {
+                        let _ = tx.send(Err(AllocationError::DuplicateTorrent));
+                        continue;
+                    }
+                    match torrent::Torrent::new(files, dir, piece_hashes, info, torrent_tx, write_through, cache_size, preallocate, sync_on_write) {
+                        
+                        Ok(torrent) => {
+                            let saved = resume_dir.and_then(|dir| torrent.load_progress(&dir, &id));
+                            if let Some(bf) = saved {
+                                tracing::info!("loaded progress from resume data, skipping check");
+                                let _ = tx.send(Ok(bf));
+                            } else {
+                                // Checked in the background so other torrents aren't held up.
+                                // The torrent doesn't write until it has the result.
+                                let check = torrent.file_checker(check_threads);
+                                tokio::task::spawn_blocking(move || {
+                                    let _ = tx.send(Ok(check()));
+                                });
+                            }
+                            self.torrents.insert(id, RwLock::new(torrent));
+                        },
+                        
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                        },
+                    }
                 },
 
-                DiskCommand::RemoveTorrent(id) => {
+                DiskCommand::RemoveTorrent { id, tx } => {
                     if let Some(torrent) = self.torrents.remove(&id) {
-                        // Wait for write lock to wait for pending writes/reads, then drop.
-                        let _ = torrent.write().await;
+                        // Finish pending writes in the background so other torrents aren't held up.
+                        tokio::spawn(async move {
+                            torrent.into_inner().flush().await;
+                            let _ = tx.send(());
+                        });
                     } else {
                         tracing::warn!("attempted to remove non-existent torrent: {}", hex::encode(id));
+                        let _ = tx.send(());
+                    }
+                },
+
+                DiskCommand::SaveProgress { id, dir, bitfield, tx } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.write().await.save_progress(id, dir, bitfield, tx);
+                    } else {
+                        tracing::warn!("torrent {} not found on disk", hex::encode(id));
+                        let _ = tx.send(());
+                    }
+                },
+
+                DiskCommand::SkipFiles { id, skip } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.read().await.skip_files(&skip);
+                    } else {
+                        tracing::warn!("torrent {} not found on disk", hex::encode(id));
+                    }
+                },
+
+                DiskCommand::VerifyMd5 { id } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.write().await.verify_md5();
+                    } else {
+                        tracing::warn!("torrent {} not found on disk", hex::encode(id));
                     }
                 },
 
@@ -84,10 +127,12 @@ impl Disk {
 
                 DiskCommand::ReadBlock { id, block, tx } => {
                     if let Some(torrent) = self.torrents.get(&id) {
-                        torrent
-                            .read()
-                            .await
-                            .read_block(block, tx);
+                        let torrent = torrent.read().await;
+                        let piece_idx = block.piece_idx;
+                        if let Err(e) = torrent.read_block(block, tx) {
+                            tracing::error!("failed to read block: {}", e);
+                            torrent.report_error(piece_idx, e);
+                        }
                     } else {
                         tracing::warn!("torrent {} not found on disk", hex::encode(id));
                         continue;
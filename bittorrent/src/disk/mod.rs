@@ -1,12 +1,14 @@
 use tokio::{sync::{mpsc, oneshot}, task::{self, JoinHandle}};
 use tracing::Instrument;
 use crate::{
-    block::{Block, BlockRequest}, info::TorrentInfo, metainfo, p2p::PeerTx, torrent::TorrentTx, Bitfield, ID
+    block::{Block, BlockRequest}, config::Preallocation, info::TorrentInfo, metainfo, p2p::PeerTx, torrent::TorrentTx, Bitfield, ID
 };
 
 mod piece;
+#[allow(clippy::module_inception)]
 mod disk;
 mod torrent;
+pub use torrent::piece_file_intersections;
 #[cfg(test)]
 mod tests;
 
@@ -60,11 +62,45 @@ pub enum DiskCommand {
         files: Vec<metainfo::File>,
         dir: std::path::PathBuf,
         torrent_tx: TorrentTx,
+        // Whether written pieces go into the read cache.
+        write_through: bool,
+        // Pieces kept in the read cache.
+        cache_size: usize,
+        preallocate: Preallocation,
+        // Whether pieces are synced to disk before being reported as written.
+        sync_on_write: bool,
+        // Threads used to check existing files.
+        check_threads: usize,
+        // Where progress is saved, checking is skipped if it's still valid.
+        resume_dir: Option<std::path::PathBuf>,
         // Sends the bitfield to the torrent task.
         tx: oneshot::Sender<std::result::Result<Bitfield, AllocationError>>,
     },
 
-    RemoveTorrent(ID),
+    // Responds once pending writes have finished and the torrent's files are closed.
+    RemoveTorrent {
+        id: ID,
+        tx: oneshot::Sender<()>,
+    },
+
+    // Saves the torrent's verified pieces once pending writes are done, responds when saved.
+    SaveProgress {
+        id: ID,
+        dir: std::path::PathBuf,
+        bitfield: Bitfield,
+        tx: oneshot::Sender<()>,
+    },
+
+    // One flag for each file, set if it is only given the skip priority.
+    SkipFiles {
+        id: ID,
+        skip: Vec<bool>,
+    },
+
+    // Checks files with an md5sum once pending writes are done, mismatches are sent to the torrent.
+    VerifyMd5 {
+        id: ID,
+    },
 
     // From peers sending blocks, write block data to disk.
     WriteBlock {
@@ -1,6 +1,6 @@
 use std::{io::{Read, Seek, Write}, sync::Arc};
 use sha1::{Sha1, Digest};
-use crate::{block::Block, BLOCK_SIZE, ID};
+use crate::{block::{block_len, Block}, info::FileRange, BLOCK_SIZE, ID};
 use super::{torrent::TorrentFile, Result};
 
 #[derive(Debug)]
@@ -28,8 +28,20 @@ pub struct PieceBuf {
 
 impl PieceBuf {
 
-    pub fn add_block(&mut self, block: &Block) {
+    // Blocks must match the picker's layout, the last block of a piece may be short.
+    // Returns false if the block doesn't fit, so it's dropped.
+    pub fn add_block(&mut self, block: &Block) -> bool {
         let block_idx = block.offset / BLOCK_SIZE;
+        if !block.offset.is_multiple_of(BLOCK_SIZE)
+            || block_idx >= self.blocks_received.len()
+            || block.data.len() != block_len(self.len, block_idx)
+        {
+            tracing::warn!(
+                "block in piece {} at offset {} with length {} doesn't fit",
+                block.piece_idx, block.offset, block.data.len(),
+            );
+            return false;
+        }
         if self.blocks_received[block_idx] {
             tracing::warn!("duplicate block in piece {} at offset {}", block.piece_idx, block.offset);
         } else {
@@ -37,6 +49,7 @@ impl PieceBuf {
             self.num_blocks_received += 1;
             self.data[block.offset..block.offset + block.data.len()].copy_from_slice(block.data.as_ref());
         }
+        true
     }
 
     pub fn is_complete(&self) -> bool {
@@ -51,6 +64,14 @@ impl PieceBuf {
         hash.as_slice() == self.hash
     }
 
+    // Splits the piece data into blocks, in the form held by the read cache.
+    pub fn into_blocks(self) -> Vec<Arc<Vec<u8>>> {
+        self.data
+            .chunks(BLOCK_SIZE)
+            .map(|chunk| Arc::new(chunk.to_vec()))
+            .collect()
+    }
+
     pub fn write(&self, piece_offset: usize, files: &[TorrentFile]) -> Result<()> {
         
         let mut total_offset = piece_offset;
@@ -58,6 +79,7 @@ impl PieceBuf {
         
         for file in files {
             let mut f = file.file_lock.write()?;
+            file.fill(&mut f)?;
             
             let byte_range = file.byte_range();
             let file_offset = total_offset - byte_range.start;
@@ -68,10 +90,10 @@ impl PieceBuf {
             // seek to the correct position in the file
             // TODO: do we only have to seek on the first file?
             f.seek(std::io::SeekFrom::Start(file_offset as u64))?;
-            let n = f.write(&self.data[bytes_written..bytes_written + bytes_remaining])?;
+            f.write_all(&self.data[bytes_written..bytes_written + bytes_remaining])?;
             
-            total_offset += n;
-            bytes_written += n;
+            total_offset += bytes_remaining;
+            bytes_written += bytes_remaining;
         }
         
         if bytes_written != self.len {
@@ -91,13 +113,26 @@ pub fn read_piece(
     len: usize,
     files: &[TorrentFile],
 ) -> Result<Vec<Arc<Vec<u8>>>> {
+    let buf = read_piece_with(offset, len, files, |idx, read| read(&mut *files[idx].file_lock.write()?))?;
+    Ok(buf.chunks(BLOCK_SIZE)
+        .map(|chunk| Arc::new(chunk.to_vec()))
+        .collect())
+}
+
+// Reads n contiguous bytes from files, with_file runs a read on the handle for the file at an index of files.
+// Lets readers with their own handles read at the same time as others.
+pub fn read_piece_with(
+    offset: usize,
+    len: usize,
+    files: &[TorrentFile],
+    mut with_file: impl FnMut(usize, &mut dyn FnMut(&mut std::fs::File) -> Result<usize>) -> Result<usize>,
+) -> Result<Vec<u8>> {
     
     let mut bytes_read: usize = 0;
     let mut total_offset = offset;
     let mut buf = vec![0; len];
 
-    for file in files.iter() {
-        let mut f = file.file_lock.write()?;
+    for (idx, file) in files.iter().enumerate() {
         let byte_range = file.byte_range();
 
         let file_offset = total_offset.checked_sub(byte_range.start).ok_or(super::DiskError::IoSizeError {
@@ -109,12 +144,19 @@ pub fn read_piece(
         let file_remaining = byte_range.end - total_offset;
         let bytes_remaining = std::cmp::min(piece_remaining, file_remaining);
 
-        // TODO: can this be skipped after first file (idx = 0)?.
-        f.seek(std::io::SeekFrom::Start(file_offset as u64))?;
-        let n = f.read(&mut buf[bytes_read..bytes_read + bytes_remaining])?;
+        let dst = &mut buf[bytes_read..bytes_read + bytes_remaining];
+        let n = with_file(idx, &mut |f| {
+            // TODO: can this be skipped after first file (idx = 0)?.
+            f.seek(std::io::SeekFrom::Start(file_offset as u64))?;
+            Ok(read_up_to(f, dst)?)
+        })?;
 
         bytes_read += n;
         total_offset += n;
+        // Files that aren't allocated end before data that hasn't been written.
+        if n < bytes_remaining {
+            break;
+        }
     }
     
     if bytes_read != len {
@@ -124,7 +166,19 @@ pub fn read_piece(
         });
     }
     
-    Ok(buf.chunks(BLOCK_SIZE as usize)
-        .map(|chunk| Arc::new(chunk.to_vec()))
-        .collect())
+    Ok(buf)
+}
+
+// Reads can return fewer bytes than asked for, so keeps reading until the buffer is full or the file ends.
+fn read_up_to(f: &mut std::fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match f.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
 }
@@ -1,4 +1,7 @@
-use crate::MetaInfo;
+use sha1::Digest;
+use crate::{config::Preallocation, info::{FileInfo, TorrentInfo}, metainfo, torrent::TorrentCommand, MetaInfo, Bitfield, ID};
+use crate::block::{Block, BlockData};
+use super::{piece::PieceBuf, torrent::{piece_file_intersections, Torrent}};
 
 
 
@@ -6,8 +9,166 @@ use crate::MetaInfo;
 #[ignore]
 async fn test_disk_reads() -> Result<(), Box<dyn std::error::Error>> {
 
-    let metainfo = MetaInfo::new("tests/test_torrents/test_multi.torrent")?;
+    let _metainfo = MetaInfo::new("tests/test_torrents/test_multi.torrent")?;
     
 
     Ok(())
-}
\ No newline at end of file
+}
+fn new_torrent(dir: &std::path::Path, preallocate: Preallocation, piece_hashes: Vec<ID>) -> Torrent {
+    let files = vec![
+        metainfo::File { path: vec!["a".into()], length: 10, md5sum: None },
+        metainfo::File { path: vec!["b".into()], length: 30, md5sum: None },
+    ];
+    let info = TorrentInfo { total_len: 40, piece_len: 16, last_piece_len: 8, num_pieces: 3 };
+    let (torrent_tx, _) = tokio::sync::mpsc::unbounded_channel();
+    Torrent::new(files, dir.to_path_buf(), piece_hashes, info, torrent_tx, false, 1, preallocate, false).unwrap()
+}
+
+#[test]
+fn test_preallocation() {
+    let len = |dir: &std::path::Path, name| std::fs::metadata(dir.join(name)).unwrap().len();
+
+    for preallocate in [Preallocation::Off, Preallocation::Sparse, Preallocation::Full] {
+        let dir = tempfile::tempdir().unwrap();
+        // Part of the first file from an earlier download.
+        std::fs::write(dir.path().join("a"), [1; 5]).unwrap();
+        let torrent = new_torrent(dir.path(), preallocate, vec![[0; 20]; 3]);
+        let expected = if preallocate == Preallocation::Off { (5, 0) } else { (10, 30) };
+        assert_eq!((len(dir.path(), "a"), len(dir.path(), "b")), expected);
+        // Short reads of unwritten data fail the check rather than panicking.
+        assert!(torrent.file_checker(2)().not_any());
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap()[..5], [1; 5]);
+    }
+}
+
+#[test]
+fn test_piece_file_intersections_last_piece() {
+    // 40 bytes over pieces of 16, so the last piece is 8 bytes.
+    let info = TorrentInfo { total_len: 40, piece_len: 16, last_piece_len: 8, num_pieces: 3 };
+    assert_eq!(info.piece_len(1), 16);
+    assert_eq!(info.piece_len(2), 8);
+
+    let mut offset = 0;
+    let files: Vec<FileInfo> = [10, 22, 8, 0].into_iter().map(|length| {
+        let file = FileInfo { path: "f".into(), length, offset, md5sum: None };
+        offset += length;
+        file
+    }).collect();
+    assert_eq!(piece_file_intersections(&info, &files, 0), 0..2);
+    assert_eq!(piece_file_intersections(&info, &files, 1), 1..2);
+    // Ends on the last byte of the third file, before the empty one.
+    assert_eq!(piece_file_intersections(&info, &files, 2), 2..3);
+
+    // A real torrent whose length isn't a multiple of its piece length.
+    let metainfo = MetaInfo::new("tests/test_torrents/test_multi.torrent").unwrap();
+    let info = TorrentInfo::new(&metainfo);
+    let files = metainfo.files();
+    let last = info.num_pieces as usize - 1;
+    assert!(info.piece_len(last) < info.piece_len);
+    assert_eq!(last as u64 * info.piece_len as u64 + info.piece_len(last) as u64, info.total_len);
+    assert_eq!(piece_file_intersections(&info, &files, last).end, files.len());
+}
+
+#[test]
+fn test_parallel_check() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..40).collect();
+    std::fs::write(dir.path().join("a"), &data[..10]).unwrap();
+    std::fs::write(dir.path().join("b"), &data[10..]).unwrap();
+    let piece_hashes: Vec<ID> = data.chunks(16).map(|piece| sha1::Sha1::digest(piece).into()).collect();
+
+    let mut expected = Bitfield::repeat(true, 3);
+    for threads in [1, 2, 3, 8] {
+        let torrent = new_torrent(dir.path(), Preallocation::Off, piece_hashes.clone());
+        assert_eq!(torrent.file_checker(threads)(), expected);
+    }
+
+    // Corrupt the middle piece, which is only in the second file.
+    std::fs::write(dir.path().join("b"), [&data[10..20], &[0; 4], &data[24..]].concat()).unwrap();
+    expected.set(1, false);
+    let torrent = new_torrent(dir.path(), Preallocation::Off, piece_hashes);
+    assert_eq!(torrent.file_checker(2)(), expected);
+}
+
+#[test]
+fn test_add_block_partial_last_block() {
+    // Two full blocks and one of 7232 bytes.
+    let mut piece = PieceBuf {
+        hash: [0; 20],
+        len: 40_000,
+        data: vec![0; 40_000],
+        blocks_received: vec![false; 3],
+        num_blocks_received: 0,
+        file_range: 0..1,
+    };
+    let block = |offset, len| Block { piece_idx: 0, offset, data: BlockData::Owned(vec![1; len]) };
+
+    assert!(piece.add_block(&block(0, 16_384)));
+    assert!(piece.add_block(&block(16_384, 16_384)));
+    // A full length last block would overrun the piece.
+    assert!(!piece.add_block(&block(32_768, 16_384)));
+    assert!(!piece.add_block(&block(32_768, 100)));
+    assert!(!piece.add_block(&block(100, 16_384)));
+    assert!(!piece.is_complete());
+
+    assert!(piece.add_block(&block(32_768, 7232)));
+    assert!(piece.is_complete());
+    assert!(piece.data.iter().all(|b| *b == 1));
+}
+
+#[tokio::test]
+async fn test_verify_md5() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a"), [1; 10]).unwrap();
+    std::fs::write(dir.path().join("b"), [2; 30]).unwrap();
+    std::fs::write(dir.path().join("c"), [3; 8]).unwrap();
+    let md5 = |data: &[u8]| hex::encode(md5::Md5::digest(data));
+    let files = vec![
+        metainfo::File { path: vec!["a".into()], length: 10, md5sum: Some(md5(&[1; 10]).to_uppercase()) },
+        metainfo::File { path: vec!["b".into()], length: 30, md5sum: Some(md5(&[0; 30])) },
+        metainfo::File { path: vec!["c".into()], length: 8, md5sum: None },
+    ];
+    let info = TorrentInfo { total_len: 48, piece_len: 16, last_piece_len: 16, num_pieces: 3 };
+    let (torrent_tx, mut torrent_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut torrent = Torrent::new(files, dir.path().to_path_buf(), vec![[0; 20]; 3], info, torrent_tx, false, 1, Preallocation::Off, false).unwrap();
+
+    torrent.verify_md5();
+    // Only the file with the wrong md5sum is reported.
+    match torrent_rx.recv().await {
+        Some(TorrentCommand::Md5Mismatch { path }) => assert_eq!(path, dir.path().join("b")),
+        _ => panic!("expected md5 mismatch"),
+    }
+    drop(torrent);
+    assert!(torrent_rx.recv().await.is_none());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_full_preallocation_skipped_file() {
+    use std::os::unix::fs::MetadataExt;
+    let dir = tempfile::tempdir().unwrap();
+    let len = 1 << 20;
+    let files = vec![
+        metainfo::File { path: vec!["a".into()], length: len, md5sum: None },
+        metainfo::File { path: vec!["b".into()], length: len, md5sum: None },
+    ];
+    // Every piece is one block of ones.
+    let data = vec![1; crate::BLOCK_SIZE];
+    let num_pieces = (2 * len as usize / data.len()) as u32;
+    let info = TorrentInfo { total_len: 2 * len, piece_len: data.len(), last_piece_len: data.len(), num_pieces };
+    let piece_hashes = vec![sha1::Sha1::digest(&data).into(); num_pieces as usize];
+    let (torrent_tx, _torrent_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut torrent = Torrent::new(files, dir.path().to_path_buf(), piece_hashes, info, torrent_tx, false, 1, Preallocation::Full, false).unwrap();
+
+    // The first piece of each file, the second file is skipped.
+    torrent.skip_files(&[false, true]);
+    for piece_idx in [0, num_pieces as usize / 2] {
+        torrent.write_block(Block { piece_idx, offset: 0, data: BlockData::Owned(data.clone()) });
+    }
+    torrent.flush().await;
+
+    let allocated = |name| std::fs::metadata(dir.path().join(name)).unwrap().blocks() * 512;
+    assert!(allocated("a") >= len);
+    assert!(allocated("b") < len);
+    assert_eq!(std::fs::read(dir.path().join("b")).unwrap()[..data.len()], data[..]);
+}
@@ -1,22 +1,27 @@
 use std::{
     collections::HashMap, 
+    time::{Duration, Instant},
+    io::{Seek, SeekFrom, Write},
     ops::Range, 
-    path::PathBuf, 
-    sync::{Arc, Mutex, RwLock},
+    path::{Path, PathBuf}, 
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, RwLock},
 };
 use sha1::Digest;
-use tokio::task::JoinHandle;
+use tokio::{sync::oneshot, task::JoinHandle};
 use crate::{
-    block::{num_blocks, Block, BlockData},
+    block::{Block, BlockData},
+    config::Preallocation,
     metainfo,
     p2p::{PeerCommand, PeerTx},
-    info::TorrentInfo,
+    info::{FileRange, TorrentInfo},
+    resume::{self, FileStamp},
+    stats::CacheStats,
     torrent::{TorrentCommand, TorrentTx}, 
     Bitfield,
     ID,
 };
 use super::{
-    piece::{read_piece, PieceBuf}, 
+    piece::{read_piece, read_piece_with, PieceBuf}, 
     AllocationError, 
     BlockRequest, 
     Result,
@@ -35,9 +40,15 @@ pub struct Torrent {
 
     // Context shared for piece writing task.
     ctx: Arc<Ctx>,
+
+    // Piece writes that may still be in progress.
+    write_tasks: Vec<JoinHandle<()>>,
     
 }
 
+// Cache stats are sent to the torrent at most this often, as blocks are read.
+const CACHE_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
 // Ctx involves data needed for the read/write tasks.
 #[derive(Debug)]
 struct Ctx {
@@ -51,6 +62,36 @@ struct Ctx {
     // Lru cache ensures least recently used pieces are removed.
     pub read_cache: Mutex<lru::LruCache<usize, Vec<Arc<Vec<u8>>>>>,
 
+    // Put freshly written pieces into the read cache, so peers requesting them
+    // straight after completion don't cause a disk read.
+    pub write_through: bool,
+
+    // Sync written pieces before telling the torrent, so they aren't lost in a crash.
+    pub sync_on_write: bool,
+
+    // Counts of reads served from the cache and when they were last sent.
+    pub cache_stats: Mutex<(CacheStats, Instant)>,
+
+}
+
+impl Ctx {
+    fn record_read(&self, hit: bool, bytes: usize) {
+        let Ok(mut guard) = self.cache_stats.lock() else {
+            return;
+        };
+        let (stats, last_report) = &mut *guard;
+        if hit {
+            stats.hits += 1;
+            stats.bytes_from_cache += bytes as u64;
+        } else {
+            stats.misses += 1;
+            stats.bytes_from_disk += bytes as u64;
+        }
+        if last_report.elapsed() >= CACHE_STATS_INTERVAL {
+            *last_report = Instant::now();
+            let _ = self.torrent_tx.send(TorrentCommand::CacheStats(*stats));
+        }
+    }
 }
 
 
@@ -63,24 +104,68 @@ pub struct TorrentFile {
 
     pub file_lock: RwLock<std::fs::File>,
 
+    // Opened separately when checking, so pieces can be read in parallel.
+    pub path: PathBuf,
+
+    // Whether the file had data before it was allocated, pieces only in new files aren't checked.
+    pub existed: bool,
+
     pub md5sum: Option<String>,
 
+    // With full preallocation, where zero filling starts once the file is first written.
+    pub fill_from: Mutex<Option<u64>>,
+
+    // Files only given the skip priority aren't zero filled, so take no more space than is written.
+    pub skip: AtomicBool,
+
 }
 
-impl TorrentFile {
-    pub fn byte_range(&self) -> Range<usize> {
+impl FileRange for TorrentFile {
+    fn byte_range(&self) -> Range<usize> {
         self.offset..(self.offset + self.len)
     }
 }
 
+impl TorrentFile {
+    pub fn stamp(&self) -> Result<FileStamp> {
+        Ok(FileStamp::from_metadata(&self.file_lock.read()?.metadata()?)?)
+    }
+
+    // Called with the file locked before it is written, only the first write fills.
+    pub fn fill(&self, f: &mut std::fs::File) -> Result<()> {
+        if let Some(from) = self.fill_from.lock()?.take() {
+            if !self.skip.load(Ordering::Relaxed) {
+                zero_fill(f, from, self.len as u64)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn file_stamps(files: &[TorrentFile]) -> Result<Vec<FileStamp>> {
+    files.iter().map(TorrentFile::stamp).collect()
+}
+
+fn sync_files(files: &[TorrentFile]) -> Result<()> {
+    for file in files {
+        file.file_lock.read()?.sync_data()?;
+    }
+    Ok(())
+}
+
 impl Torrent {
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         files: Vec<metainfo::File>,
         dir: PathBuf,
         piece_hashes: Vec<ID>, 
         info: TorrentInfo,
         torrent_tx: TorrentTx,
+        write_through: bool,
+        cache_size: usize,
+        preallocate: Preallocation,
+        sync_on_write: bool,
     ) -> std::result::Result<Self, AllocationError> {
 
         // Create the output directory if it doesn't exist.
@@ -89,49 +174,59 @@ impl Torrent {
             tracing::info!("created missing output directory: {:?}", dir);
         }
 
-        debug_assert!(files.len() > 0);
+        debug_assert!(!files.is_empty());
         let mut offset = 0;
         let mut file_buf = Vec::with_capacity(files.len());
         for file in files.into_iter() {
             let path: PathBuf = file.path.join("/").into();
             let len = file.length as usize;
             // Create sub-directories if they don't exist.
-            // TODO: handle more than one layer of subdirectories.
-            if let Some(subdir) = path.parent() {
-                if !subdir.exists() && subdir != PathBuf::from("") {
+            if let Some(subdir) = path.parent().map(|parent| dir.join(parent)) {
+                if !subdir.exists() {
                     tracing::info!("creating sub-directory: {:?}", subdir);
                     std::fs::create_dir_all(&subdir)?;
                 }
             }
             
+            let mut f = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(dir.join(&path))?;
+            let current = f.metadata()?.len();
+            let existed = current > 0;
+            allocate_file(&mut f, len as u64, preallocate)?;
             file_buf.push(
                     TorrentFile {
                         len,
                         offset,
-                        file_lock: RwLock::new(
-                            std::fs::OpenOptions::new()
-                                .create(true)
-                                .read(true)
-                                .write(true)
-                                .open(dir.join(&path))?,
-                        ),
+                        file_lock: RwLock::new(f),
+                        existed,
+                        path: dir.join(&path),
                         md5sum: file.md5sum,
+                        fill_from: Mutex::new((preallocate == Preallocation::Full && current < len as u64).then_some(current)),
+                        skip: AtomicBool::new(false),
                     }
             );
             tracing::info!("created file: {:?}", &dir.join(&path));
             offset += len;
         }
 
-        // TODO: make lru size a configurable option?
-        let read_cache = Mutex::new(lru::LruCache::new(std::num::NonZeroUsize::new(500).unwrap()));
+        let cache_size = std::num::NonZeroUsize::new(cache_size.max(1)).unwrap();
+        let read_cache = Mutex::new(lru::LruCache::new(cache_size));
         Ok(Self {
             info,
             piece_hashes,
             write_buf: HashMap::new(),
+            write_tasks: Vec::new(),
             ctx: Arc::new(Ctx {
                 files: file_buf,
                 torrent_tx,
                 read_cache,
+                write_through,
+                sync_on_write,
+                cache_stats: Mutex::new((CacheStats::default(), Instant::now())),
             })
         })
     }
@@ -146,14 +241,14 @@ impl Torrent {
                 hash: self.piece_hashes[piece_idx],
                 len,
                 data: vec![0; len],
-                blocks_received: vec![false; num_blocks(len) as usize],
+                blocks_received: vec![false; self.info.num_blocks(piece_idx) as usize],
                 num_blocks_received: 0,
                 file_range: piece_file_intersections(&self.info, &self.ctx.files, piece_idx),
             }
         });
 
         piece.add_block(&block);
-        tracing::trace!("piece {}: {} blocks received out of {}", piece_idx, piece.num_blocks_received, num_blocks(piece.len));
+        tracing::trace!("piece {}: {} blocks received out of {}", piece_idx, piece.num_blocks_received, piece.blocks_received.len());
         
         if piece.is_complete() {
             tracing::trace!("all blocks received for piece {} ... writing", piece_idx);
@@ -163,26 +258,123 @@ impl Torrent {
 
     fn write_piece(&mut self, piece_idx: usize) {
 
-        let piece = self.write_buf.remove(&piece_idx).expect("piece not found in write buf");
-        let offset = piece_idx * self.info.piece_len;
+        let Some(piece) = self.write_buf.remove(&piece_idx) else {
+            tracing::error!("piece {} not found in write buf", piece_idx);
+            return;
+        };
+        let offset = self.info.piece_offset(piece_idx);
         let ctx = Arc::clone(&self.ctx);
 
         // Spawn a thread for expensive workload.
-        let _ = tokio::task::spawn_blocking(move || {
+        self.write_tasks.retain(|task| !task.is_finished());
+        let task = tokio::task::spawn_blocking(move || {
 
             if piece.verify_hash() {
-                if let Err(e) = piece.write(offset, &ctx.files[piece.file_range.clone()]) {
-                    tracing::error!("failed to write piece {} to disk: {:?}", piece_idx, e);
+                let files = &ctx.files[piece.file_range.clone()];
+                let written = piece.write(offset, files)
+                    .and_then(|_| if ctx.sync_on_write { sync_files(files) } else { Ok(()) });
+                if let Err(error) = written {
+                    tracing::error!("failed to write piece {} to disk: {:?}", piece_idx, error);
+                    let _ = ctx.torrent_tx.send(TorrentCommand::DiskError { piece_idx, error });
                     return;
-                };
+                }
                 let _ = ctx.torrent_tx.send(TorrentCommand::PieceWritten { idx: piece_idx, valid: true });
+                if ctx.write_through {
+                    match ctx.read_cache.lock() {
+                        Ok(mut cache) => { cache.put(piece_idx, piece.into_blocks()); },
+                        Err(e) => tracing::error!("read cache poisoned: {:?}", e),
+                    }
+                }
             } else {
                 tracing::warn!("piece {} failed hash verification", piece_idx);
                 let _ = ctx.torrent_tx.send(TorrentCommand::PieceWritten { idx: piece_idx, valid: false });
             }
 
         });
+        self.write_tasks.push(task);
+
+    }
 
+    // Waits for pieces being written to reach disk.
+    pub async fn flush(&mut self) {
+        for task in self.write_tasks.drain(..) {
+            if let Err(e) = task.await {
+                tracing::error!("piece write task failed: {}", e);
+            }
+        }
+    }
+
+    // Saved progress, if the files haven't changed since it was saved.
+    pub fn load_progress(&self, dir: &Path, id: &ID) -> Option<Bitfield> {
+        match file_stamps(&self.ctx.files) {
+            Ok(stamps) => resume::load_progress(dir, id, self.info.num_pieces as usize, &stamps),
+            Err(e) => {
+                tracing::warn!("failed to stat torrent files: {}", e);
+                None
+            },
+        }
+    }
+
+    // Stamps are taken after pending writes finish, so they match the files as they're left.
+    pub fn save_progress(&mut self, id: ID, dir: PathBuf, bitfield: Bitfield, tx: oneshot::Sender<()>) {
+        let write_tasks = std::mem::take(&mut self.write_tasks);
+        let ctx = Arc::clone(&self.ctx);
+        tokio::spawn(async move {
+            for task in write_tasks {
+                let _ = task.await;
+            }
+            let saved = tokio::task::spawn_blocking(move || {
+                // Progress must not claim pieces that could still be lost in a crash.
+                sync_files(&ctx.files).map_err(|e| e.to_string())?;
+                let stamps = file_stamps(&ctx.files).map_err(|e| e.to_string())?;
+                resume::save_progress(&dir, &id, &bitfield, stamps).map_err(|e| e.to_string())
+            }).await;
+            match saved {
+                Ok(Ok(())) => tracing::debug!("saved progress"),
+                Ok(Err(e)) => tracing::warn!("failed to save progress: {}", e),
+                Err(e) => tracing::error!("save progress task failed: {}", e),
+            }
+            let _ = tx.send(());
+        });
+    }
+
+    // Files are read on a blocking thread after pending writes finish.
+    pub fn verify_md5(&mut self) {
+        let write_tasks = std::mem::take(&mut self.write_tasks);
+        let ctx = Arc::clone(&self.ctx);
+        tokio::spawn(async move {
+            for task in write_tasks {
+                let _ = task.await;
+            }
+            let verified = tokio::task::spawn_blocking(move || {
+                for file in ctx.files.iter() {
+                    let Some(expected) = &file.md5sum else { continue };
+                    match file_md5(&file.path) {
+                        Ok(md5) if md5.eq_ignore_ascii_case(expected) => tracing::debug!("md5 verified: {:?}", file.path),
+                        Ok(md5) => {
+                            tracing::warn!("md5 mismatch for {:?}, expected {} got {}", file.path, expected, md5);
+                            let _ = ctx.torrent_tx.send(TorrentCommand::Md5Mismatch { path: file.path.clone() });
+                        },
+                        Err(e) => tracing::warn!("failed to read {:?} for md5: {}", file.path, e),
+                    }
+                }
+            }).await;
+            if let Err(e) = verified {
+                tracing::error!("md5 task failed: {}", e);
+            }
+        });
+    }
+
+    // Marks the files only given the skip priority, one flag for each file.
+    pub fn skip_files(&self, skip: &[bool]) {
+        for (file, skip) in self.ctx.files.iter().zip(skip) {
+            file.skip.store(*skip, Ordering::Relaxed);
+        }
+    }
+
+    // Tells the torrent about an error it can't recover from by itself.
+    pub fn report_error(&self, piece_idx: usize, error: super::DiskError) {
+        let _ = self.ctx.torrent_tx.send(TorrentCommand::DiskError { piece_idx, error });
     }
 
     // Reads a block from disk and sends it to the peer.
@@ -196,6 +388,7 @@ impl Torrent {
             if block_idx >= cached.len() {
                 return Ok(());
             }
+            self.ctx.record_read(true, block_info.len);
             
             let _ = peer_tx.send(PeerCommand::BlockRead(Block:: from_block_request(
                 &block_info,
@@ -205,20 +398,21 @@ impl Torrent {
         } else {
             // If not in cache, read from disk and put in cache.
             let file_range = piece_file_intersections(&self.info, &self.ctx.files, block_info.piece_idx);
-            let offset = block_info.piece_idx * self.info.piece_len;
+            let offset = self.info.piece_offset(block_info.piece_idx);
             let len = self.info.piece_len(block_info.piece_idx);
             let ctx = Arc::clone(&self.ctx);
 
-            let _ = tokio::task::spawn_blocking(move || {
-                // TODO: Do we want to handle error, continuing task?
+            tokio::task::spawn_blocking(move || {
                 let piece = match read_piece(offset, len, &ctx.files[file_range]) {
                     Ok(piece) => piece,
-                    Err(e) => {
-                        tracing::error!("failed to read piece {}: {:?}", block_info.piece_idx, e);
+                    Err(error) => {
+                        tracing::error!("failed to read piece {}: {:?}", block_info.piece_idx, error);
+                        let _ = ctx.torrent_tx.send(TorrentCommand::DiskError { piece_idx: block_info.piece_idx, error });
                         return;
                     },
                 };
                 let block = Arc::clone(&piece[block_idx]);
+                ctx.record_read(false, block.len());
 
                 let mut cache_lock = match ctx.read_cache.lock() {
                     Ok(cache) => cache,
@@ -238,45 +432,132 @@ impl Torrent {
         Ok(())
     }
 
-    // Checks if the files exist, if so returns a bitfield of correctly occuring pieces.
-    pub fn check_existing_files(&self) -> Bitfield {
+    // Checks existing files for pieces we already have, to be run on a blocking thread.
+    // Pieces are split between threads, progress is sent to the torrent as they are checked.
+    pub fn file_checker(&self, threads: usize) -> impl FnOnce() -> Bitfield + Send + 'static {
+        let info = self.info.clone();
+        let piece_hashes = self.piece_hashes.clone();
+        let ctx = Arc::clone(&self.ctx);
+        move || check_pieces(&info, &piece_hashes, &ctx, threads)
+    }
+}
 
-        let mut bitfield = Bitfield::repeat(false, self.info.num_pieces as usize);
-        
-        // Iterate over all pieces and check hash matches.
-        for piece_idx in 0..self.info.num_pieces as usize {
-            let file_range = piece_file_intersections(&self.info, &self.ctx.files, piece_idx);
-            match read_piece(
-                piece_idx * self.info.piece_len,
-                self.info.piece_len(piece_idx),
-                &self.ctx.files[file_range],
-            ) {
-                Ok(piece) => {
-                    let mut hasher = sha1::Sha1::new();
-                    for block in piece.iter() {
-                        hasher.update(&**block);
-                    }
-                    let hash = hasher.finalize();
-                    if hash.as_slice() == self.piece_hashes[piece_idx] {
-                        bitfield.set(piece_idx, true);
+// Returns a bitfield of the pieces in the files that match their hashes.
+fn check_pieces(info: &TorrentInfo, piece_hashes: &[ID], ctx: &Ctx, threads: usize) -> Bitfield {
+
+    let num_pieces = info.num_pieces as usize;
+    let checked = AtomicUsize::new(0);
+    // Roughly every percent.
+    let report_every = (num_pieces / 100).max(1);
+    // Contiguous ranges so each thread reads through the files in order.
+    let chunk_len = num_pieces.div_ceil(threads.max(1)).max(1);
+
+    let have: Vec<usize> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..num_pieces)
+            .step_by(chunk_len)
+            .map(|start| {
+                let pieces = start..(start + chunk_len).min(num_pieces);
+                let checked = &checked;
+                scope.spawn(move || {
+                    // Own handles, so threads don't wait on each other's seeks and reads.
+                    let mut handles: Vec<Option<std::fs::File>> = (0..ctx.files.len()).map(|_| None).collect();
+                    let mut have = Vec::new();
+                    for piece_idx in pieces {
+                        if check_piece(info, piece_hashes, ctx, &mut handles, piece_idx) {
+                            have.push(piece_idx);
+                        }
+                        let done = checked.fetch_add(1, Ordering::Relaxed) + 1;
+                        if done.is_multiple_of(report_every) {
+                            let _ = ctx.torrent_tx.send(TorrentCommand::CheckProgress { checked: done });
+                        }
                     }
-                },
-                Err(_) => continue,
+                    have
+                })
+            })
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap_or_default()).collect()
+    });
+
+    let mut bitfield = Bitfield::repeat(false, num_pieces);
+    for piece_idx in have {
+        bitfield.set(piece_idx, true);
+    }
+    bitfield
+}
+
+fn check_piece(
+    info: &TorrentInfo,
+    piece_hashes: &[ID],
+    ctx: &Ctx,
+    handles: &mut [Option<std::fs::File>],
+    piece_idx: usize,
+) -> bool {
+    let file_range = piece_file_intersections(info, &ctx.files, piece_idx);
+    // Preallocated files read back as zeros, so checking them is wasted work.
+    if !ctx.files[file_range.clone()].iter().any(|f| f.existed) {
+        return false;
+    }
+    let first = file_range.start;
+    let piece = read_piece_with(
+        info.piece_offset(piece_idx),
+        info.piece_len(piece_idx),
+        &ctx.files[file_range],
+        |idx, read| {
+            let handle = &mut handles[first + idx];
+            if handle.is_none() {
+                *handle = Some(std::fs::File::open(&ctx.files[first + idx].path)?);
             }
-        }
+            read(handle.as_mut().unwrap())
+        },
+    );
+    match piece {
+        Ok(piece) => sha1::Sha1::digest(&piece).as_slice() == piece_hashes[piece_idx],
+        Err(_) => false,
+    }
+}
+
+// Hex encoded, as md5sums are given in metainfo.
+fn file_md5(path: &Path) -> std::io::Result<String> {
+    let mut hasher = md5::Md5::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Extends a file to its full length, files already at least that long are left alone.
+// Fully preallocated files are zero filled when first written, once it's known whether they're skipped.
+fn allocate_file(file: &mut std::fs::File, len: u64, preallocate: Preallocation) -> std::io::Result<()> {
+    let current = file.metadata()?.len();
+    if current >= len {
+        return Ok(());
+    }
+    match preallocate {
+        Preallocation::Sparse | Preallocation::Full => file.set_len(len)?,
+        Preallocation::Off => {},
+    }
+    Ok(())
+}
 
-        bitfield
+// Writes zeros over a range of a file, so its space is taken.
+fn zero_fill(file: &mut std::fs::File, from: u64, to: u64) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(from))?;
+    let zeros = vec![0; 1 << 16];
+    let mut remaining = to.saturating_sub(from);
+    while remaining > 0 {
+        let n = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        remaining -= n as u64;
     }
+    Ok(())
 }
 
 // Returns the idxs of the first and last file that a piece intersects.
-pub fn piece_file_intersections(info: &TorrentInfo, files: &[TorrentFile], piece_idx: usize) -> Range<usize> {
+pub fn piece_file_intersections<F: FileRange>(info: &TorrentInfo, files: &[F], piece_idx: usize) -> Range<usize> {
     // If only one file, there are no intersections to compute.
     if files.len() == 1 {
         return 0..1;
     }
 
-    let offset = piece_idx * info.piece_len;
+    let offset = info.piece_offset(piece_idx);
     let end = offset + info.piece_len(piece_idx) - 1;
 
     let start_file = match files
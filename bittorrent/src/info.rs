@@ -1,6 +1,6 @@
 use std::{path::PathBuf, ops::Range};
 use serde_derive::{Deserialize, Serialize};
-use crate::metainfo::MetaInfo;
+use crate::{block, metainfo::MetaInfo, BLOCK_SIZE};
 
 // File information deserialised from metainfo.
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -24,9 +24,14 @@ pub struct FileInfo {
 
 }
 
-impl FileInfo {
+// Files laid out in the torrent's byte space, either on disk or from the metainfo.
+pub trait FileRange {
     // Byte index range for whole torrent.
-    pub fn byte_range(&self) -> Range<usize> {
+    fn byte_range(&self) -> Range<usize>;
+}
+
+impl FileRange for FileInfo {
+    fn byte_range(&self) -> Range<usize> {
         self.offset..(self.offset + self.length)
     }
 }
@@ -70,4 +75,51 @@ impl TorrentInfo {
             self.piece_len
         }
     }
+
+    // Byte offset of a piece from the start of the torrent.
+    pub fn piece_offset(&self, idx: usize) -> usize {
+        idx * self.piece_len
+    }
+
+    pub fn num_blocks(&self, idx: usize) -> u32 {
+        block::num_blocks(self.piece_len(idx))
+    }
+
+    // Length of the block at an offset within a piece, the last block of a piece may be short.
+    pub fn block_len(&self, idx: usize, offset: usize) -> usize {
+        BLOCK_SIZE.min(self.piece_len(idx) - offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_last_piece_and_block() {
+        // 3 full pieces of 2 blocks, then a piece of one full block and 100 bytes.
+        let piece_len = 2 * BLOCK_SIZE;
+        let last_piece_len = BLOCK_SIZE + 100;
+        let info = TorrentInfo {
+            total_len: (3 * piece_len + last_piece_len) as u64,
+            piece_len,
+            last_piece_len,
+            num_pieces: 4,
+        };
+
+        assert_eq!(info.piece_len(0), piece_len);
+        assert_eq!(info.piece_len(3), last_piece_len);
+        assert_eq!(info.piece_offset(3) + info.piece_len(3), info.total_len as usize);
+
+        assert_eq!(info.num_blocks(2), 2);
+        assert_eq!(info.num_blocks(3), 2);
+        assert_eq!(info.block_len(2, BLOCK_SIZE), BLOCK_SIZE);
+        assert_eq!(info.block_len(3, 0), BLOCK_SIZE);
+        assert_eq!(info.block_len(3, BLOCK_SIZE), 100);
+
+        // A single piece smaller than a block.
+        let info = TorrentInfo { total_len: 5, piece_len: BLOCK_SIZE, last_piece_len: 5, num_pieces: 1 };
+        assert_eq!(info.num_blocks(0), 1);
+        assert_eq!(info.block_len(0, 0), 5);
+    }
 }
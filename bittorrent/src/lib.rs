@@ -11,6 +11,11 @@ mod disk;
 mod block;
 mod picker;
 mod de;
+mod resume;
+mod dht;
+mod watch;
+mod magnet;
+mod limiter;
 pub mod stats;
 
 // Most commonly used block size - 16KB.
@@ -24,16 +29,69 @@ pub type ID = [u8; 20];
 // Messages the users of the client expect to recieve.
 pub enum UserCommand {
 
-    // Sent when a torrent has finished.
+    // Sent when a torrent has downloaded every piece, it may carry on seeding.
     TorrentFinished {
         id: ID,
     },
 
+    // Sent when a magnet's metadata has been fetched, as it starts downloading.
+    MetadataFetched {
+        id: ID,
+        metainfo: MetaInfo,
+    },
+
+    // Sent when a torrent has stopped, after its trackers are told.
+    // The error is what stopped it, if it didn't stop cleanly e.g. on removal or shutdown.
+    TorrentStopped {
+        id: ID,
+        result: std::result::Result<(), TorrentError>,
+    },
+
     // Sent every second with the current stats of a torrent.
     TorrentStats {
         id: ID,
         stats: stats::TorrentStats,
     },
+
+    // Sent when a peer session is established, after handshakes are exchanged.
+    PeerConnected {
+        id: ID,
+        addr: std::net::SocketAddr,
+        peer_id: ID,
+    },
+
+    // Sent when a peer that had connected disconnects.
+    PeerDisconnected {
+        id: ID,
+        addr: std::net::SocketAddr,
+    },
+
+    // Sent when a peer advertises a DHT node not seen recently.
+    DhtNodeDiscovered {
+        addr: std::net::SocketAddr,
+    },
+
+    // Sent when the disk task has died, all torrents are shut down.
+    DiskFailure,
+
+    // Sent when a torrent hits a problem it carries on through, e.g. no trackers responding.
+    TorrentError {
+        id: ID,
+        msg: String,
+    },
+
+    // Sent for each tracker that answers a scrape.
+    ScrapeResult {
+        id: ID,
+        tracker: url::Url,
+        data: tracker::ScrapeData,
+    },
+
+    // Sent when no connected peer has some of the pieces we still need.
+    TorrentIncomplete {
+        id: ID,
+        missing_pieces: Vec<usize>,
+    },
 }
 
 type UserTx = mpsc::UnboundedSender<UserCommand>;
@@ -42,15 +100,25 @@ pub type UserRx = mpsc::UnboundedReceiver<UserCommand>;
 use client::{ClientCommand, ClientTx};
 
 // Re-exports
-pub use config::Config;
+pub use config::{Config, ConfigError, ChokedPeerAction, Encryption, Preallocation, ProxyConfig, Transport};
+pub use picker::piece_picker::{FilePriority, PickerStrategy};
 pub use client::{Result, ClientError};
-pub use p2p::state::{SessionState, ConnState};
-pub use metainfo::MetaInfo;
+pub use p2p::state::{SessionState, ConnState, CryptoMethod};
+pub use p2p::peer_id::{generate_peer_id, peer_id_to_client, CLIENT_ID_PREFIX};
+pub use metainfo::{MetaInfo, MetaInfoError, TorrentBuilder};
+pub use magnet::Magnet;
+pub use disk::{AllocationError, DiskError};
 pub use torrent::{TorrentError, TorrentState};
+pub use tracker::ScrapeData;
 
 pub fn start_client(config: Option<Config>) -> (Handle, UserRx) {
     let (user_tx, user_rx) = mpsc::unbounded_channel();
-    let (mut client, client_tx) = client::Client::new(config.unwrap_or_default(), user_tx);
+    let config = config.unwrap_or_default();
+    let watch = config.watch_dir.clone().map(|watch_dir| (watch_dir, config.dir.clone()));
+    let (mut client, client_tx) = client::Client::new(config, user_tx);
+    if let Some((watch_dir, download_dir)) = watch {
+        watch::start_watcher(watch_dir, download_dir, client_tx.clone());
+    }
     let client_handle = tokio::spawn(async move { 
         if let Err(e) = client.run().await {
             tracing::error!("client runtime error:  {:?}", e);
@@ -76,13 +144,93 @@ pub struct Handle {
 
 impl Handle {
     
+        // Downloads to the configured dir.
         pub fn new_torrent(&self, metainfo: MetaInfo) -> Result<()> {
-            self.client_tx.send(ClientCommand::NewTorrent(metainfo))?;
+            self.client_tx.send(ClientCommand::NewTorrent { metainfo, dir: None })?;
+            Ok(())
+        }
+
+        // Downloads to the given dir, which is checked to be writable first.
+        pub fn new_torrent_to(&self, metainfo: MetaInfo, dir: impl Into<std::path::PathBuf>) -> Result<()> {
+            let dir = dir.into();
+            client::check_writable(&dir)?;
+            self.client_tx.send(ClientCommand::NewTorrent { metainfo, dir: Some(dir) })?;
+            Ok(())
+        }
+
+        // Contents of a .torrent file, parsed here so invalid metainfo is reported straight away.
+        pub fn new_torrent_from_bytes(&self, raw: &[u8]) -> Result<()> {
+            self.new_torrent(MetaInfo::from_bytes(raw)?)
+        }
+
+        // The link is parsed here so an invalid one is reported straight away.
+        pub fn new_magnet(&self, uri: &str) -> Result<()> {
+            Magnet::parse(uri)?;
+            self.client_tx.send(ClientCommand::NewMagnet(uri.to_string()))?;
             Ok(())
         }
 
+        // Completes once the torrent's peers and trackers have stopped and its pending writes are on disk.
         pub async fn remove_torrent(&self, id: ID) -> Result<()> {
-            self.client_tx.send(ClientCommand::RemoveTorrent(id))?;
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            self.client_tx.send(ClientCommand::RemoveTorrent { id, tx })?;
+            rx.await.map_err(|_| ClientError::ClientPanic)?
+        }
+
+        // Announce to a torrent's trackers now, rather than waiting for the next interval.
+        pub fn reannounce(&self, id: ID) -> Result<()> {
+            self.client_tx.send(ClientCommand::Reannounce(id))?;
+            Ok(())
+        }
+
+        // Ask a torrent's trackers for seeder and leecher counts, answered with UserCommand::ScrapeResult.
+        pub fn scrape(&self, id: ID) -> Result<()> {
+            self.client_tx.send(ClientCommand::Scrape(id))?;
+            Ok(())
+        }
+
+        // Current stats of a torrent, without waiting for the next UserCommand::TorrentStats.
+        // Errors with TorrentStopped if the torrent is still in the client but no longer running.
+        pub async fn stats(&self, id: ID) -> Result<stats::TorrentStats> {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            self.client_tx.send(ClientCommand::GetStats { id, tx })?;
+            rx.await.map_err(|_| ClientError::ClientPanic)?
+        }
+
+        // Every torrent added and its state, in no particular order.
+        pub async fn list_torrents(&self) -> Result<Vec<(ID, TorrentState)>> {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            self.client_tx.send(ClientCommand::ListTorrents(tx))?;
+            rx.await.map_err(|_| ClientError::ClientPanic)
+        }
+
+        // Limit a torrent to the pieces covering bytes start..end, it is paused once they are downloaded.
+        pub fn set_download_range(&self, id: ID, start: u64, end: u64) -> Result<()> {
+            self.client_tx.send(ClientCommand::SetDownloadRange { id, range: start..end })?;
+            Ok(())
+        }
+
+        // Priorities are in the order of the metainfo's files, skipped files are only partly written
+        // where they share pieces with wanted files.
+        pub fn set_file_priorities(&self, id: ID, priorities: Vec<FilePriority>) -> Result<()> {
+            self.client_tx.send(ClientCommand::SetFilePriorities { id, priorities })?;
+            Ok(())
+        }
+
+        // Stops a torrent that is seeding after completing its download.
+        pub fn stop_seeding(&self, id: ID) -> Result<()> {
+            self.client_tx.send(ClientCommand::StopSeeding(id))?;
+            Ok(())
+        }
+
+        // Disconnects a torrent from the swarm, its progress is kept.
+        pub fn pause(&self, id: ID) -> Result<()> {
+            self.client_tx.send(ClientCommand::PauseTorrent(id))?;
+            Ok(())
+        }
+
+        pub fn resume(&self, id: ID) -> Result<()> {
+            self.client_tx.send(ClientCommand::ResumeTorrent(id))?;
             Ok(())
         }
 
@@ -0,0 +1,158 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+use crate::BLOCK_SIZE;
+
+// Token bucket limiting the bytes per second transferred by everything sharing it.
+// Transfers can take the bucket into debt, the next transfer then waits for it to be paid off.
+#[derive(Debug)]
+pub struct RateLimiter {
+
+    // Bytes per second.
+    rate: u64,
+
+    bucket: Mutex<Bucket>,
+
+}
+
+#[derive(Debug)]
+struct Bucket {
+
+    tokens: f64,
+
+    last_refill: Instant,
+
+}
+
+impl RateLimiter {
+
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: Self::capacity(rate),
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    // Allow a second's worth of burst, and always at least a block.
+    fn capacity(rate: u64) -> f64 {
+        rate.max(BLOCK_SIZE as u64) as f64
+    }
+
+    // Waits until the bytes can be transferred without exceeding the rate.
+    pub async fn acquire(&self, amount: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(Self::capacity(self.rate));
+            bucket.last_refill = now;
+            bucket.tokens -= amount as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// The limiters a transfer has to pass, e.g. the torrent's and the client wide one.
+// Empty when unlimited, so acquiring costs nothing.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+
+    limiters: Vec<Arc<RateLimiter>>,
+
+}
+
+impl RateLimits {
+
+    pub fn new(limiters: impl IntoIterator<Item = Option<Arc<RateLimiter>>>) -> Self {
+        Self {
+            limiters: limiters.into_iter().flatten().collect(),
+        }
+    }
+
+    pub async fn acquire(&self, amount: usize) {
+        for limiter in &self.limiters {
+            limiter.acquire(amount).await;
+        }
+    }
+}
+
+// Caps peer connections across every torrent sharing it.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+
+    max: usize,
+
+    count: AtomicUsize,
+
+}
+
+impl ConnectionLimit {
+
+    pub fn new(max: usize) -> Self {
+        Self { max, count: AtomicUsize::new(0) }
+    }
+
+    // Takes a connection slot, false if all are in use.
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < self.max).then_some(count + 1))
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        let _ = self.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+
+        // The initial burst goes straight through.
+        limiter.acquire(100_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Then transfers are held to the rate.
+        limiter.acquire(25_000).await;
+        limiter.acquire(25_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        // No limiters means no waiting.
+        let start = Instant::now();
+        RateLimits::new([None, None]).acquire(usize::MAX).await;
+        assert!(start.elapsed() < Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(2);
+        assert!(limit.try_acquire());
+        assert!(limit.try_acquire());
+        assert!(!limit.try_acquire());
+
+        limit.release();
+        assert!(limit.try_acquire());
+
+        // Releasing more than was taken doesn't add slots.
+        for _ in 0..3 {
+            limit.release();
+        }
+        assert!(limit.try_acquire());
+        assert!(limit.try_acquire());
+        assert!(!limit.try_acquire());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashSet, net::{IpAddr, SocketAddr}, time::Duration};
+use tokio::{sync::mpsc, task::{JoinHandle, JoinSet}};
+use tracing::Instrument;
+use url::Url;
+use crate::{
+    client::{ClientCommand, WeakClientTx},
+    dht::{DhtCommand, DhtTx},
+    metainfo::{MetaInfo, MetaInfoError},
+    p2p::{fetch_metadata, ConnectOptions},
+    torrent::{TorrentCommand, TorrentRx, TorrentTx},
+    tracker::{AnnounceParams, Event, TrackersHandle},
+    BLOCK_SIZE,
+    ID,
+};
+
+// Reference: https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+// A magnet only identifies a torrent, the info dictionary has to be fetched from peers.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+
+    pub info_hash: ID,
+
+    // Display name, shown until the metadata is fetched.
+    pub name: Option<String>,
+
+    pub trackers: Vec<Url>,
+
+    // Peers given directly in the link (x.pe).
+    pub peers: Vec<SocketAddr>,
+
+}
+
+impl Magnet {
+
+    pub fn parse(uri: &str) -> Result<Self, MetaInfoError> {
+
+        let url = Url::parse(uri).map_err(|_| MetaInfoError::InvalidMagnet("not a valid uri"))?;
+        if url.scheme() != "magnet" {
+            return Err(MetaInfoError::InvalidMagnet("scheme must be magnet"));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+
+                "xt" => if let Some(hash) = value.strip_prefix("urn:btih:") {
+                    info_hash = Some(parse_btih(hash)?);
+                },
+
+                "dn" => name = Some(value.into_owned()),
+
+                "tr" => match Url::parse(&value) {
+                    Ok(tracker) => trackers.push(tracker),
+                    Err(_) => tracing::warn!("invalid tracker in magnet: {}", value),
+                },
+
+                "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => tracing::warn!("invalid peer in magnet: {}", value),
+                },
+
+                _ => {},
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MetaInfoError::InvalidMagnet("missing btih exact topic"))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| hex::encode(self.info_hash))
+    }
+}
+
+// Peers to fetch metadata from at once.
+const MAX_METADATA_FETCHES: usize = 4;
+
+pub struct MagnetParams {
+
+    pub magnet: Magnet,
+
+    pub client_id: ID,
+
+    pub listen_port: u16,
+
+    pub announce_ip: Option<IpAddr>,
+
+    pub connect_options: ConnectOptions,
+
+    // Added to the magnet's own trackers.
+    pub custom_trackers: Vec<Url>,
+
+    pub dht_tx: Option<DhtTx>,
+
+    // Fetched metadata is handed back to the client to start the torrent.
+    pub client_tx: WeakClientTx,
+
+}
+
+pub struct MagnetHandle {
+
+    pub torrent_tx: TorrentTx,
+
+    pub handle: JoinHandle<()>,
+
+}
+
+impl MagnetHandle {
+
+    // Announces for the magnet and fetches its metadata from the peers found.
+    pub fn start(params: MagnetParams) -> Self {
+
+        let (torrent_tx, torrent_rx) = mpsc::unbounded_channel();
+        let tx = torrent_tx.clone();
+        let span = tracing::info_span!("magnet", name = %params.magnet.name());
+        let handle = tokio::spawn(run_magnet(params, tx, torrent_rx).instrument(span));
+
+        Self { torrent_tx, handle }
+    }
+}
+
+async fn run_magnet(params: MagnetParams, torrent_tx: TorrentTx, mut torrent_rx: TorrentRx) {
+
+    let MagnetParams { magnet, client_id, listen_port, announce_ip, connect_options, custom_trackers, dht_tx, client_tx } = params;
+    let info_hash = magnet.info_hash;
+    // Trackers in the link aren't tiered, so each gets its own tier and all are announced to.
+    let tiers = magnet.trackers.iter().chain(custom_trackers.iter()).map(|url| vec![url.clone()]).collect();
+    let mut trackers = TrackersHandle::new(tiers, connect_options.proxy.clone());
+    trackers.start(torrent_tx.clone()).await;
+
+    let announce_params = AnnounceParams {
+        info_hash,
+        client_id,
+        port: listen_port,
+        // Size is unknown until the metadata is fetched, but we aren't a seed.
+        left: BLOCK_SIZE as u64,
+        ip: announce_ip,
+        ..Default::default()
+    };
+    trackers.update(Some(AnnounceParams { event: Some(Event::Started), ..announce_params }));
+    if let Some(dht_tx) = &dht_tx {
+        let _ = dht_tx.send(DhtCommand::GetPeers {
+            info_hash,
+            port: listen_port,
+            torrent_tx: torrent_tx.clone(),
+        });
+    }
+
+    // Peers not yet tried for metadata.
+    let mut peers: HashSet<SocketAddr> = magnet.peers.iter().copied().collect();
+    let mut tried = HashSet::new();
+    let mut fetches = JoinSet::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+
+        // Keep a few fetches going while there are peers to try.
+        while fetches.len() < MAX_METADATA_FETCHES {
+            let Some(address) = peers.iter().next().copied() else { break };
+            peers.remove(&address);
+            tried.insert(address);
+            let options = connect_options.clone();
+            fetches.spawn(async move { (address, fetch_metadata(address, info_hash, client_id, options).await) });
+        }
+
+        tokio::select! {
+
+            _ = ticker.tick() => {
+                // Trackers decide themselves whether their interval has passed.
+                trackers.update(Some(announce_params));
+            },
+
+            Some(cmd) = torrent_rx.recv() => match cmd {
+                TorrentCommand::Peers { peers: new_peers, .. } => {
+                    peers.extend(new_peers.into_iter().filter(|peer| !tried.contains(peer)));
+                },
+                TorrentCommand::Shutdown => break,
+                _ => {},
+            },
+
+            Some(result) = fetches.join_next() => match result {
+                Ok((address, Ok(info))) => {
+                    tracing::info!("fetched metadata from {}", address);
+                    match MetaInfo::from_info_bytes(&info, info_hash, magnet.trackers.clone()) {
+                        Ok(metainfo) => {
+                            if let Some(client_tx) = client_tx.upgrade() {
+                                let _ = client_tx.send(ClientCommand::MetadataFetched(metainfo));
+                            }
+                            break;
+                        },
+                        Err(e) => tracing::warn!("invalid metadata from {}: {}", address, e),
+                    }
+                },
+                Ok((address, Err(e))) => tracing::debug!("metadata fetch from {} failed: {}", address, e),
+                Err(e) => tracing::error!("metadata fetch panicked: {}", e),
+            },
+
+        }
+    }
+
+    fetches.shutdown().await;
+    trackers.shutdown(announce_params).await;
+}
+
+// The info hash is either 40 hex characters or 32 base32 characters.
+fn parse_btih(hash: &str) -> Result<ID, MetaInfoError> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => base32_decode(hash),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(MetaInfoError::InvalidMagnet("invalid info hash"))
+}
+
+// RFC 4648 base32 without padding.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buf: u64 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let val = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buf = (buf << 5) | val as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buf >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn test_parse_magnet() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+File.iso&tr=udp%3A%2F%2Ftracker.example.com%3A6969&tr=http%3A%2F%2Fexample.org%2Fannounce&x.pe=10.0.0.1%3A6881",
+            HASH,
+        );
+        let magnet = Magnet::parse(&uri).unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), HASH);
+        assert_eq!(magnet.name.as_deref(), Some("Some File.iso"));
+        assert_eq!(magnet.trackers, vec![
+            Url::parse("udp://tracker.example.com:6969").unwrap(),
+            Url::parse("http://example.org/announce").unwrap(),
+        ]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_parse_magnet_base32() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), HASH);
+        assert_eq!(magnet.name(), HASH);
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_magnet_invalid() {
+        assert!(Magnet::parse("http://example.com").is_err());
+        assert!(Magnet::parse("magnet:?dn=name").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1").is_err());
+    }
+}
@@ -23,14 +23,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     while let Some(cmd) = rx.recv().await {
         match cmd {
-            UserCommand::TorrentFinished { id} => {
-                // tracing::error!("torrent result {}: {:?}", hex::encode(id), result);
-            },
-            UserCommand::TorrentStats { id, stats } => {
+            UserCommand::TorrentFinished { .. } => {},
+            UserCommand::TorrentStats { .. } => {
                 // stats.peer_stats.iter().for_each(|peer| {
                 //     tracing::info!("peer: {:#?}", peer);
                 // });
             },
+            UserCommand::TorrentStopped { id, result } => {
+                if let Err(e) = result {
+                    tracing::error!("torrent {} failed: {}", hex::encode(id), e);
+                }
+            },
+            UserCommand::MetadataFetched { .. } => {},
+            UserCommand::PeerConnected { .. } => {},
+            UserCommand::PeerDisconnected { .. } => {},
+            UserCommand::DhtNodeDiscovered { .. } => {},
+            UserCommand::DiskFailure => break,
+            UserCommand::TorrentIncomplete { .. } => {},
+            UserCommand::ScrapeResult { .. } => {},
+            UserCommand::TorrentError { .. } => {},
         }
     }
 
@@ -0,0 +1,256 @@
+use std::{collections::BTreeMap, io::Read, path::{Path, PathBuf}, time::UNIX_EPOCH};
+use sha1::Digest;
+use url::Url;
+use super::{File, Info, MetaInfo, MetaInfoError};
+
+// Default piece lengths are powers of two in this range.
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+
+// Number of pieces the default piece length aims for.
+const TARGET_NUM_PIECES: u64 = 1500;
+
+// Creates a torrent from a file, or a directory of files.
+pub struct TorrentBuilder {
+
+    path: PathBuf,
+
+    // Chosen from the total size if not given.
+    piece_length: Option<u32>,
+
+    trackers: Vec<Url>,
+
+    private: bool,
+
+    comment: Option<String>,
+
+}
+
+impl TorrentBuilder {
+
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            piece_length: None,
+            trackers: Vec::new(),
+            private: false,
+            comment: None,
+        }
+    }
+
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    // Each tracker gets its own tier, in the order they are added.
+    pub fn tracker(mut self, url: Url) -> Self {
+        self.trackers.push(url);
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    // Reads every file to hash its pieces, so takes as long as reading the whole torrent.
+    pub fn build(self) -> Result<MetaInfo, MetaInfoError> {
+
+        let root = self.path.canonicalize()?;
+        let name = root.file_name().ok_or(MetaInfoError::NoFiles)?.to_string_lossy().into_owned();
+
+        let multi_file = root.is_dir();
+        let mut files = Vec::new();
+        if multi_file {
+            walk_dir(&root, &mut Vec::new(), &mut files)?;
+        } else {
+            files.push(SourceFile { full_path: root.clone(), path: Vec::new(), length: root.metadata()?.len() });
+        }
+        // Empty files have no pieces to download, so are left out.
+        files.retain(|f| f.length > 0);
+        if files.is_empty() {
+            return Err(MetaInfoError::NoFiles);
+        }
+
+        let total_len = files.iter().map(|f| f.length).sum();
+        let piece_length = self.piece_length.unwrap_or_else(|| default_piece_length(total_len));
+        if piece_length == 0 {
+            return Err(MetaInfoError::InvalidPieceLength);
+        }
+        let pieces = hash_pieces(&files, piece_length as usize)?;
+
+        let info = Info {
+            name,
+            pieces,
+            piece_length,
+            md5sum: None,
+            length: if multi_file { None } else { Some(total_len) },
+            files: multi_file.then(|| files.into_iter().map(|f| File { path: f.path, length: f.length, md5sum: None }).collect()),
+            private: self.private.then_some(1),
+            root_hash: None,
+            meta_version: None,
+            extra: BTreeMap::new(),
+        };
+
+        let metainfo = MetaInfo {
+            announce: self.trackers.first().cloned(),
+            announce_list: if self.trackers.len() > 1 { Some(self.trackers.into_iter().map(|url| vec![url]).collect()) } else { None },
+            info,
+            // Filled in once the info dict is encoded.
+            info_hash: [0; 20],
+            encoding: None,
+            creation_date: UNIX_EPOCH.elapsed().ok().map(|d| d.as_secs() as i64),
+            comment: self.comment,
+            created_by: Some(concat!("bitter ", env!("CARGO_PKG_VERSION")).to_string()),
+        };
+
+        // Going through the encoded form validates it and hashes the info dict as it will be written.
+        MetaInfo::from_bytes(&metainfo.to_bytes()?)
+    }
+}
+
+struct SourceFile {
+
+    full_path: PathBuf,
+
+    // Relative to the torrent's directory.
+    path: Vec<String>,
+
+    length: u64,
+
+}
+
+// Files are sorted by name, so the same directory always gives the same torrent.
+fn walk_dir(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<SourceFile>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let full_path = entry.path();
+        let metadata = std::fs::metadata(&full_path)?;
+        prefix.push(entry.file_name().to_string_lossy().into_owned());
+        if metadata.is_dir() {
+            walk_dir(&full_path, prefix, files)?;
+        } else {
+            files.push(SourceFile { full_path, path: prefix.clone(), length: metadata.len() });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+// Files are hashed as one continuous stream, so pieces can span the end of one file and the start of the next.
+fn hash_pieces(files: &[SourceFile], piece_length: usize) -> std::io::Result<Vec<u8>> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+    for file in files {
+        let mut reader = std::fs::File::open(&file.full_path)?;
+        loop {
+            let read = (&mut reader).take((piece_length - piece.len()) as u64).read_to_end(&mut piece)?;
+            if read == 0 {
+                break;
+            }
+            if piece.len() == piece_length {
+                pieces.extend_from_slice(&sha1::Sha1::digest(&piece));
+                piece.clear();
+            }
+        }
+    }
+    // Last piece is shorter.
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&sha1::Sha1::digest(&piece));
+    }
+    Ok(pieces)
+}
+
+// Aims for around TARGET_NUM_PIECES, fewer for small torrents and more for large ones where the piece length is capped.
+fn default_piece_length(total_len: u64) -> u32 {
+    (total_len / TARGET_NUM_PIECES).next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn test_build_multi_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let a = data(20_000, 1);
+        let b = data(30_000, 2);
+        let c = data(5_000, 3);
+        std::fs::write(root.join("a.bin"), &a).unwrap();
+        std::fs::write(root.join("sub").join("c.bin"), &c).unwrap();
+        std::fs::write(root.join("b.bin"), &b).unwrap();
+        std::fs::write(root.join("empty"), b"").unwrap();
+
+        let tracker = Url::parse("http://example.com/announce").unwrap();
+        let metainfo = TorrentBuilder::new(&root)
+            .piece_length(16384)
+            .tracker(tracker.clone())
+            .private(true)
+            .build()
+            .unwrap();
+
+        let paths = metainfo.info.files.as_ref().unwrap().iter().map(|f| f.path.join("/")).collect::<Vec<_>>();
+        assert_eq!(paths, ["a.bin", "b.bin", "sub/c.bin"]);
+        assert_eq!(metainfo.name(), "content");
+        assert_eq!(metainfo.total_len(), 55_000);
+        assert!(metainfo.is_private());
+        assert_eq!(metainfo.tracker_urls(), vec![vec![tracker]]);
+
+        // Pieces cross file boundaries.
+        let all = [a, b, c].concat();
+        let expected = all.chunks(16384).map(|chunk| sha1::Sha1::digest(chunk).into()).collect::<Vec<crate::ID>>();
+        assert_eq!(metainfo.piece_hashes(), expected);
+
+        let path = dir.path().join("content.torrent");
+        metainfo.write_to(&path).unwrap();
+        let read = MetaInfo::new(&path).unwrap();
+        assert_eq!(read.info_hash(), metainfo.info_hash());
+        assert_eq!(read.piece_hashes(), expected);
+        assert_eq!(read.files().len(), 3);
+    }
+
+    #[test]
+    fn test_build_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(&path, data(40_000, 7)).unwrap();
+
+        let metainfo = TorrentBuilder::new(&path).build().unwrap();
+        assert!(!metainfo.is_multi_file());
+        assert!(!metainfo.is_private());
+        assert_eq!(metainfo.single_file_len(), Some(40_000));
+        assert_eq!(metainfo.piece_len(), MIN_PIECE_LENGTH as usize);
+        assert_eq!(metainfo.num_pieces(), 3);
+
+        let torrent = dir.path().join("file.torrent");
+        metainfo.write_to(&torrent).unwrap();
+        assert_eq!(MetaInfo::new(&torrent).unwrap().info_hash(), metainfo.info_hash());
+    }
+
+    #[test]
+    fn test_build_no_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("empty"), b"").unwrap();
+        assert!(matches!(TorrentBuilder::new(dir.path()).build(), Err(MetaInfoError::NoFiles)));
+    }
+
+    #[test]
+    fn test_default_piece_length() {
+        assert_eq!(default_piece_length(1), MIN_PIECE_LENGTH as u32);
+        assert_eq!(default_piece_length(700 * 1024 * 1024), 512 * 1024);
+        assert_eq!(default_piece_length(1 << 40), MAX_PIECE_LENGTH as u32);
+    }
+}
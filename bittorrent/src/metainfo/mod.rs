@@ -0,0 +1,572 @@
+use std::collections::BTreeMap;
+use rand::seq::SliceRandom;
+use serde_derive::{Deserialize, Serialize};
+use url::Url;
+use crate::{info::FileInfo, magnet::Magnet, ID};
+
+mod builder;
+pub use builder::TorrentBuilder;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetaInfoError {
+
+    #[error("bencode error whilst decoding metainfo: {0}")]
+    BencodeError(#[from] bencode::Error),
+
+    #[error("invalid file extension, expected .torrent")]
+    InvalidExtension,
+
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+
+    #[error("invalid pieces length, must be divisible by 20")]
+    InvalidPiecesLength,
+
+    #[error("piece length must be greater than 0")]
+    InvalidPieceLength,
+
+    #[error("torrent has {actual} pieces, its length needs {expected}")]
+    PieceCountMismatch { expected: u64, actual: u64 },
+
+    #[error("file(s) with size 0")]
+    FileNoSize,
+
+    #[error("file(s) with no path")]
+    FileEmptyPath,
+
+    #[error("file has absolute path")]
+    FileAbsolutePath,
+
+    #[error("file path leaves the download directory")]
+    FilePathTraversal,
+
+    #[error("invalid magnet link: {0}")]
+    InvalidMagnet(&'static str),
+
+    #[error("no files to create a torrent from")]
+    NoFiles,
+
+    #[error("torrent uses meta version {0}, only v1 and hybrid torrents are supported")]
+    UnsupportedMetaVersion(u64),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct File {
+
+    // #[serde(deserialize_with = "crate::de::path_deserialize")]
+    pub path: Vec<String>,
+
+    pub length: u64,
+
+    pub md5sum: Option<String>,
+
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Info {
+
+    // File namepub .
+    pub name: String,
+    
+    // String consisting of the concatenation of all 20-byte SHA1 hash values, one per piece.
+    // Missing from v2 only torrents.
+    #[serde(default)]
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+
+    // Number of bytes in each piece (integer).
+    #[serde(rename = "piece length")]
+    pub piece_length: u32,
+
+    // A 32-character hexadecimal string corresponding to the MD5 sum of the file.
+    #[serde(default)]
+    pub md5sum: Option<String>,
+    
+    // Length of the file in bytes (integer).
+    #[serde(default)]
+    pub length: Option<u64>,
+
+    // A list of dictionaries, one for each file.
+    #[serde(default)]
+    pub files: Option<Vec<File>>,
+    
+    // If it is set to "1", the client MUST publish its presence to get other peers ONLY 
+    // via the trackers explicitly described in the metainfo file. If this field is set to 
+    // "0" or is not present, the client may obtain peer from other means, e.g. PEX peer exchange, dht.
+    #[serde(default)]
+    pub private: Option<u8>,
+
+    #[serde(default)]
+    #[serde(rename = "root hash")]
+    pub root_hash: Option<String>,
+
+    // 2 for v2 torrents, which hash pieces per file with merkle trees rather than across files.
+    // Reference: https://www.bittorrent.org/beps/bep_0052.html
+    #[serde(default)]
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<u64>,
+
+    // Keys we don't use, e.g. source, kept so the info dict encodes back to the same bytes.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, bencode::Value>,
+
+}
+
+impl Info {
+
+    pub fn total_len(&self) -> u64 {
+        if let Some(files) = &self.files {
+            files.iter().map(|f| f.length).sum()
+        } else {
+            self.length.unwrap_or(0)
+        }
+    }
+
+    // Pieces must be whole hashes, and exactly enough of them to cover the files.
+    // Paths come from the torrent, so they mustn't point outside the download directory.
+    // Hybrid torrents also carry the v1 keys, so can be used as v1 torrents.
+    fn validate(&self) -> Result<(), MetaInfoError> {
+        match self.meta_version {
+            None | Some(1) => {},
+            Some(2) if !self.pieces.is_empty() => tracing::debug!("hybrid torrent, using v1 metadata"),
+            Some(version) => return Err(MetaInfoError::UnsupportedMetaVersion(version)),
+        }
+        check_path(std::slice::from_ref(&self.name))?;
+        for file in self.files.iter().flatten() {
+            check_path(&file.path)?;
+        }
+        if !self.pieces.len().is_multiple_of(20) || self.pieces.is_empty() {
+            return Err(MetaInfoError::InvalidPiecesLength);
+        }
+        if self.piece_length == 0 {
+            return Err(MetaInfoError::InvalidPieceLength);
+        }
+        let expected = self.total_len().div_ceil(self.piece_length as u64);
+        let actual = self.pieces.len() as u64 / 20;
+        if expected != actual {
+            return Err(MetaInfoError::PieceCountMismatch { expected, actual });
+        }
+        Ok(())
+    }
+}
+
+// Each component is checked on its own, as one could hold a separator, e.g. "../..".
+fn check_path(path: &[String]) -> Result<(), MetaInfoError> {
+    use std::path::{Component, Path};
+    if path.is_empty() {
+        return Err(MetaInfoError::FileEmptyPath);
+    }
+    for part in path {
+        if part.is_empty() {
+            return Err(MetaInfoError::FileEmptyPath);
+        }
+        for component in Path::new(part).components() {
+            match component {
+                Component::Normal(_) | Component::CurDir => {},
+                Component::ParentDir => return Err(MetaInfoError::FilePathTraversal),
+                Component::RootDir | Component::Prefix(_) => return Err(MetaInfoError::FileAbsolutePath),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Calculates the sha1 hash of the info dict as it appears in the file.
+// Re-encoding our Info could differ from the original, e.g. by dropping keys we don't model.
+fn info_hash(raw_info: &[u8]) -> ID {
+    use sha1::Digest;
+    sha1::Sha1::digest(raw_info).into()
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MetaInfo {
+    
+    // The announce URL of the tracker (string), missing for trackerless torrents.
+    #[serde(default)]
+    #[serde(deserialize_with = "crate::de::url_option_deserialize")]
+    #[serde(serialize_with = "crate::de::url_option_serialize")]
+    pub announce: Option<url::Url>,
+    
+    // A dictionary that describes the file(s) of the torrent.
+    pub info: Info,
+    
+    // sha1 hash of info dict
+    #[serde(skip)] 
+    pub info_hash: ID,
+    
+    // (optional) the string encoding format used to generate the pieces part of the info 
+    // dictionary in the .torrent metafile (string).
+    #[serde(default)]
+    pub encoding: Option<String>,
+    
+    // (optional) this is an extention to the official specification, offering backwards-compatibility.
+    #[serde(default)]
+    #[serde(rename = "announce-list")]
+    #[serde(deserialize_with = "crate::de::announce_list_deserialize")]
+    #[serde(serialize_with = "crate::de::announce_list_serialize")]
+    pub announce_list: Option<Vec<Vec<url::Url>>>,
+    
+    // (optional) the creation time of the torrent, in standard UNIX epoch format.
+    #[serde(default)]
+    #[serde(rename = "creation date")]
+    pub creation_date: Option<i64>,
+    
+    // (optional) free-form textual comments of the author (string).
+    #[serde(rename = "comment")]
+    pub comment: Option<String>,
+    
+    // (optional) name and version of the program used to create the .torrent (string).
+    #[serde(default)]
+    #[serde(rename = "created by")]
+    pub created_by: Option<String>,
+    
+}
+
+impl MetaInfo {
+
+    pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<MetaInfo, MetaInfoError> {
+        
+        if path.as_ref().extension().unwrap_or_default() != "torrent" {
+            return Err(MetaInfoError::InvalidExtension);
+        }
+
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    // Contents of a .torrent file, e.g. downloaded or pasted rather than read from disk.
+    pub fn from_bytes(raw: &[u8]) -> Result<MetaInfo, MetaInfoError> {
+
+        let mut metainfo: MetaInfo = bencode::decode_bytes(raw)?;
+        metainfo.info.validate()?;
+
+        // Decoding succeeded, so the info dict is there.
+        let raw_info = bencode::raw_field(raw, "info")?.ok_or(bencode::Error::EOF)?;
+        metainfo.info_hash = info_hash(raw_info);
+        tracing::debug!("metainfo created: {:#?}", metainfo);
+        Ok(metainfo)
+    }
+
+    // A magnet has no info dict, so gives the lightweight magnet until the metadata is fetched from peers.
+    pub fn from_magnet(uri: &str) -> Result<Magnet, MetaInfoError> {
+        Magnet::parse(uri)
+    }
+
+    // Builds metainfo around an info dict fetched from peers, already checked against its info hash.
+    pub fn from_info_bytes(info: &[u8], info_hash: ID, trackers: Vec<Url>) -> Result<MetaInfo, MetaInfoError> {
+        
+        let info: Info = bencode::decode_bytes(info)?;
+        info.validate()?;
+
+        Ok(MetaInfo {
+            announce: trackers.first().cloned(),
+            announce_list: if trackers.len() > 1 { Some(trackers.into_iter().map(|url| vec![url]).collect()) } else { None },
+            info,
+            info_hash,
+            encoding: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+        })
+    }
+
+    // Reconstructs the .torrent file contents.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MetaInfoError> {
+        Ok(bencode::encode_to_raw(self)?)
+    }
+
+    // Saves as a .torrent file.
+    pub fn write_to<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), MetaInfoError> {
+        std::fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+
+    pub fn piece_hashes(&self) -> Vec<ID> {
+        self.info.pieces
+            .chunks_exact(20)
+            // Safe as we have already checked length is a multiple of 20, in new.
+            .map(|c| c.try_into().unwrap())
+            .collect()
+    }
+
+    pub fn piece_len(&self) -> usize { self.info.piece_length as usize }
+
+    pub fn num_pieces(&self) -> u32 { self.info.pieces.len() as u32 / 20 }
+
+    pub fn is_multi_file(&self) -> bool { self.info.files.is_some() }
+
+    pub fn is_private(&self) -> bool { self.info.private == Some(1) }
+    
+    pub fn single_file_len(&self) -> Option<u64> { self.info.length }
+
+    pub fn total_len(&self) -> u64 { self.info.total_len() }
+
+    pub fn info_hash(&self) -> ID { self.info_hash }
+    
+    pub fn name(&self) -> &str { &self.info.name }
+
+    pub fn tracker_urls(&self) -> Vec<Vec<Url>> {
+        // If announce_list is present, we use that.
+        if let Some(announce_list) = self.announce_list.clone() {
+            let mut trackers = Vec::new();
+            for mut tier in announce_list {
+                let mut tier_trackers = Vec::new();
+                // Randomly shuffle the trackers in the tier.
+                tier.shuffle(&mut rand::thread_rng());
+                for url in tier {
+                    tier_trackers.push(url);
+                }
+                trackers.push(tier_trackers);
+            }
+            trackers
+        // Otherwise we just use the announce key.
+        } else if let Some(announce) = &self.announce {
+            vec![vec![announce.clone()]]
+        } else {
+            vec![]
+        }
+    }
+
+    // Multi-file torrents keep their files in a directory named after the torrent.
+    pub fn files(&self) -> Vec<FileInfo> {
+        if let Some(files) = &self.info.files {
+            let mut offset = 0;
+            files.iter().map(|f| {
+                let file_info = FileInfo {
+                    path: std::iter::once(&self.info.name).chain(&f.path).collect(),
+                    length: f.length as usize,
+                    offset,
+                    md5sum: f.md5sum.clone(),
+                };
+                offset += f.length as usize;
+                file_info
+            }).collect()
+        } else {
+            vec![FileInfo {
+                path: self.info.name.clone().into(),
+                length: self.info.length.unwrap() as usize,
+                offset: 0,
+                md5sum: None,
+            }]
+        }
+    }
+
+    // Formatting methods.
+
+    pub fn creation_date_fmt(&self) -> Option<String> {
+        self.creation_date.map(|v| {
+            let date = chrono::DateTime::from_timestamp(v, 0);
+            date.map(|v| v.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "Invalid date".to_string())
+        })
+    }
+
+    pub fn info_hash_hex(&self) -> String {
+        hex::encode(self.info_hash)
+    }
+
+    pub fn size_fmt(&self) -> String {
+        if self.is_multi_file() {
+            let size = self.info.files.as_ref().unwrap().iter()
+                .map(|f| f.length)
+                .sum::<u64>();
+            format_size(size)
+        } else {
+            format_size(self.info.length.unwrap())
+        }
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    if size > 1024.0 {
+        size /= 1024.0;
+        unit = "KiB";
+    }
+    if size > 1024.0 {
+        size /= 1024.0;
+        unit = "MiB";
+    }
+    if size > 1024.0 {
+        size /= 1024.0;
+        unit = "GiB";
+    }
+    if size > 1024.0 {
+        size /= 1024.0;
+        unit = "TiB";
+    }
+    format!("{:.2} {}", size, unit)
+}
+
+impl std::fmt::Debug for MetaInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetaInfo")
+            .field("announce", &self.announce.as_ref().map(Url::as_str))
+            .field("info", &self.info)
+            .field("info_hash", &self.info_hash_hex())
+            .field("encoding", &self.encoding)
+            // Change urls to strings for printing.
+            .field("announce_list", &self.announce_list.as_ref().map(|v| 
+                v.iter().map(|v| 
+                    v.iter().map(|v| 
+                        v.as_str()
+                    ).collect()
+                ).collect::<Vec<Vec<&str>>>()
+            ))
+            .field("creation_date", &self.creation_date_fmt())
+            .field("comment", &self.comment)
+            .field("created_by", &self.created_by)
+            .finish()
+    }
+}
+
+// Dont want to print out the pieces field, so we implement Debug manually.
+impl std::fmt::Debug for Info {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Info")
+            .field("name", &self.name)
+            .field("num pieces", &(&self.pieces.len() / 20))
+            .field("piece_length", &self.piece_length)
+            .field("md5sum", &self.md5sum)
+            .field("length", &self.length)
+            .field("files", &self.files)
+            .field("private", &self.private)
+            .field("root_hash", &self.root_hash)
+            .field("meta_version", &self.meta_version)
+            .field("extra", &self.extra.keys())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_info_hash_from_raw_bytes() {
+        // Info dict has a key Info doesn't model, it must survive re-encoding.
+        let info = b"d6:lengthi5e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:abce";
+        let mut raw = b"d8:announce22:http://example.com/ann4:info".to_vec();
+        raw.extend_from_slice(info);
+        raw.push(b'e');
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("source.torrent");
+        std::fs::write(&path, &raw).unwrap();
+        let metainfo = MetaInfo::new(&path).unwrap();
+        assert_eq!(metainfo.info_hash(), info_hash(info));
+        assert_eq!(bencode::encode_to_raw(&metainfo.info).unwrap(), info);
+        assert_eq!(metainfo.info.extra.get("source"), Some(&bencode::Value::Bytes(b"abc".to_vec())));
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let raw = std::fs::read("tests/test_torrents/test_multi.torrent").unwrap();
+        let from_file = MetaInfo::new("tests/test_torrents/test_multi.torrent").unwrap();
+        let from_bytes = MetaInfo::from_bytes(&raw).unwrap();
+        assert_eq!(from_bytes.info_hash(), from_file.info_hash());
+        assert_eq!(from_bytes.total_len(), from_file.total_len());
+
+        assert!(matches!(MetaInfo::from_bytes(&raw[..raw.len() / 2]), Err(MetaInfoError::BencodeError(_))));
+        let no_pieces = b"d4:infod6:lengthi5e4:name4:test12:piece lengthi16384e6:pieces0:ee";
+        assert!(matches!(MetaInfo::from_bytes(no_pieces), Err(MetaInfoError::InvalidPiecesLength)));
+    }
+
+    #[test]
+    fn test_piece_count_mismatch() {
+        // 5 bytes fit in one piece, but there are two hashes.
+        let info = b"d6:lengthi5e4:name4:test12:piece lengthi16384e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaae";
+        let mut raw = b"d4:info".to_vec();
+        raw.extend_from_slice(info);
+        raw.push(b'e');
+        assert!(matches!(
+            MetaInfo::from_bytes(&raw),
+            Err(MetaInfoError::PieceCountMismatch { expected: 1, actual: 2 }),
+        ));
+        assert!(matches!(
+            MetaInfo::from_info_bytes(info, [0; 20], vec![]),
+            Err(MetaInfoError::PieceCountMismatch { expected: 1, actual: 2 }),
+        ));
+
+        // 20000 bytes need two pieces, but there's one hash.
+        let short = b"d4:infod6:lengthi20000e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            MetaInfo::from_bytes(short),
+            Err(MetaInfoError::PieceCountMismatch { expected: 2, actual: 1 }),
+        ));
+
+        let no_piece_len = b"d4:infod6:lengthi5e4:name4:test12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(MetaInfo::from_bytes(no_piece_len), Err(MetaInfoError::InvalidPieceLength)));
+    }
+
+    #[test]
+    fn test_meta_version() {
+        let v2 = b"d4:infod9:file treed4:testd0:d6:lengthi5e11:pieces root32:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaeee12:meta versioni2e4:name4:test12:piece lengthi16384eee";
+        assert!(matches!(MetaInfo::from_bytes(v2), Err(MetaInfoError::UnsupportedMetaVersion(2))));
+
+        let v3 = b"d4:infod6:lengthi5e12:meta versioni3e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(MetaInfo::from_bytes(v3), Err(MetaInfoError::UnsupportedMetaVersion(3))));
+
+        // Hybrids keep their meta version when re-encoded, so the info hash is unchanged.
+        let info = b"d9:file treed4:testd0:d6:lengthi5e11:pieces root32:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaeee6:lengthi5e12:meta versioni2e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut hybrid = b"d4:info".to_vec();
+        hybrid.extend_from_slice(info);
+        hybrid.push(b'e');
+        let metainfo = MetaInfo::from_bytes(&hybrid).unwrap();
+        assert_eq!(metainfo.info.meta_version, Some(2));
+        assert_eq!(bencode::encode_to_raw(&metainfo.info).unwrap(), info);
+    }
+
+    // Multi-file torrent with the given path list for its only file.
+    fn with_path(path: &str) -> Vec<u8> {
+        format!(
+            "d4:infod5:filesld6:lengthi5e4:path{}ee4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+            path,
+        ).into_bytes()
+    }
+
+    #[test]
+    fn test_multi_file_paths() {
+        let metainfo = MetaInfo::from_bytes(&with_path("l3:dir8:file.txte")).unwrap();
+        let files = metainfo.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, std::path::PathBuf::from("test/dir/file.txt"));
+
+        let single = b"d4:infod6:lengthi5e4:name8:file.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let metainfo = MetaInfo::from_bytes(single).unwrap();
+        assert_eq!(metainfo.files()[0].path, std::path::PathBuf::from("file.txt"));
+    }
+
+    #[test]
+    fn test_unsafe_paths() {
+        assert!(MetaInfo::from_bytes(&with_path("l3:dir8:file.txte")).is_ok());
+        assert!(matches!(
+            MetaInfo::from_bytes(&with_path("l2:..2:..3:etc6:passwde")),
+            Err(MetaInfoError::FilePathTraversal),
+        ));
+        assert!(matches!(
+            MetaInfo::from_bytes(&with_path("l16:../../etc/passwde")),
+            Err(MetaInfoError::FilePathTraversal),
+        ));
+        assert!(matches!(
+            MetaInfo::from_bytes(&with_path("l11:/etc/passwde")),
+            Err(MetaInfoError::FileAbsolutePath),
+        ));
+        assert!(matches!(MetaInfo::from_bytes(&with_path("le")), Err(MetaInfoError::FileEmptyPath)));
+        assert!(matches!(MetaInfo::from_bytes(&with_path("l3:dir0:e")), Err(MetaInfoError::FileEmptyPath)));
+
+        // Single file torrents are named by their file.
+        let name = b"d4:infod6:lengthi5e4:name5:../ab12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(MetaInfo::from_bytes(name), Err(MetaInfoError::FilePathTraversal)));
+    }
+
+    #[test]
+    #[ignore]
+    fn debug_meta_info() {
+        let metainfo = MetaInfo::new("tests/test_torrents/test_single.torrent").unwrap();
+        // Pretty debug print.
+        println!("{:#?}", metainfo);
+        println!("{}", metainfo.total_len());
+    }
+}
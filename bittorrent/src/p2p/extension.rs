@@ -0,0 +1,143 @@
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
+use bytes::{Buf, BufMut};
+use serde_derive::{Deserialize, Serialize};
+
+// Extension protocol messages.
+// Reference: https://www.bittorrent.org/beps/bep_0010.html
+
+// Extended message id of the extended handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+// Ids we ask peers to use for extension messages sent to us.
+pub const UT_METADATA_ID: u8 = 1;
+pub const UT_PEX_ID: u8 = 2;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExtendedHandshake {
+
+    // Maps extension names to the message ids the sender wants to receive them with.
+    #[serde(default)]
+    pub m: HashMap<String, u8>,
+
+    #[serde(default)]
+    pub metadata_size: Option<usize>,
+
+}
+
+impl ExtendedHandshake {
+
+    // Id to send an extension's messages to the peer with, if it supports it.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+}
+
+// Peer exchange, sent at most once a minute with the changes since the last.
+// Reference: https://www.bittorrent.org/beps/bep_0011.html
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PexMessage {
+
+    #[serde(default, with = "serde_bytes")]
+    added: Vec<u8>,
+
+    // One flag byte per added peer, we don't set any.
+    #[serde(default, rename = "added.f", with = "serde_bytes")]
+    added_flags: Vec<u8>,
+
+    #[serde(default, with = "serde_bytes")]
+    dropped: Vec<u8>,
+
+    #[serde(default, with = "serde_bytes")]
+    added6: Vec<u8>,
+
+    #[serde(default, with = "serde_bytes")]
+    dropped6: Vec<u8>,
+
+}
+
+// Most peers to add or drop in one message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+impl PexMessage {
+
+    pub fn new(added: &[SocketAddr], dropped: &[SocketAddr]) -> Self {
+        let (added, added6) = encode_compact(added);
+        let (dropped, dropped6) = encode_compact(dropped);
+        Self {
+            added_flags: vec![0; added.len() / 6],
+            added,
+            dropped,
+            added6,
+            dropped6,
+        }
+    }
+
+    pub fn added(&self) -> Vec<SocketAddr> {
+        let mut peers = decode_compact(&self.added, false);
+        peers.extend(decode_compact(&self.added6, true));
+        peers
+    }
+
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        let mut peers = decode_compact(&self.dropped, false);
+        peers.extend(decode_compact(&self.dropped6, true));
+        peers
+    }
+}
+
+// Splits peers into compact ipv4 (6 bytes each) and ipv6 (18 bytes each) lists.
+fn encode_compact(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                v4.put_slice(&ip.octets());
+                v4.put_u16(peer.port());
+            },
+            IpAddr::V6(ip) => {
+                v6.put_slice(&ip.octets());
+                v6.put_u16(peer.port());
+            },
+        }
+    }
+    (v4, v6)
+}
+
+fn decode_compact(buf: &[u8], v6: bool) -> Vec<SocketAddr> {
+    let len = if v6 { 18 } else { 6 };
+    buf.chunks_exact(len).map(|mut chunk| {
+        let ip: IpAddr = if v6 {
+            Ipv6Addr::from(chunk.get_u128()).into()
+        } else {
+            Ipv4Addr::from(chunk.get_u32()).into()
+        };
+        SocketAddr::new(ip, chunk.get_u16())
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pex_round_trip() {
+        let added: Vec<SocketAddr> = vec!["1.2.3.4:6881".parse().unwrap(), "[::1]:51413".parse().unwrap()];
+        let dropped: Vec<SocketAddr> = vec!["5.6.7.8:80".parse().unwrap()];
+
+        let raw = bencode::encode_to_raw(&PexMessage::new(&added, &dropped)).unwrap();
+        let msg: PexMessage = bencode::decode_bytes(&raw).unwrap();
+        assert_eq!(msg.added(), added);
+        assert_eq!(msg.dropped(), dropped);
+        assert_eq!(msg.added_flags, vec![0]);
+    }
+
+    #[test]
+    fn test_extended_handshake_ids() {
+        let raw = b"d1:md11:ut_metadatai3e6:ut_pexi0ee13:metadata_sizei31235e1:v4:teste";
+        let handshake: ExtendedHandshake = bencode::decode_bytes(raw).unwrap();
+        assert_eq!(handshake.id("ut_metadata"), Some(3));
+        // Zero means the extension is disabled.
+        assert_eq!(handshake.id("ut_pex"), None);
+        assert_eq!(handshake.metadata_size, Some(31235));
+    }
+}
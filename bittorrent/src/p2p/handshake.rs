@@ -5,6 +5,13 @@ use super::PeerError;
 
 pub const PROTOCOL: [u8; 19] = *b"BitTorrent protocol";
 
+// Set in the 6th reserved byte to advertise the extension protocol.
+const EXTENSION_BIT: u8 = 0x10;
+
+// Set in the last reserved byte to advertise a DHT node (BEP-5).
+const DHT_BIT: u8 = 0x01;
+
+#[derive(Clone, Copy)]
 pub struct Handshake {
     pub protocol:   [u8; 19],
     pub reserved:   [u8; 8],
@@ -16,13 +23,28 @@ impl Handshake {
     pub fn new(info_hash: ID, peer_id: ID) -> Self {
         Self {
             protocol:   PROTOCOL,
-            reserved:   [0; 8], // TODO: Check there aren't any different situations for this.
+            reserved:   [0, 0, 0, 0, 0, EXTENSION_BIT, 0, 0],
             info_hash,
             peer_id,
         }
     }
+
+    pub fn set_dht(&mut self) {
+        self.reserved[7] |= DHT_BIT;
+    }
+
+    // Whether the peer runs a DHT node, and will accept our port message.
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & DHT_BIT != 0
+    }
+
+    // Whether the peer supports the extension protocol (BEP-10).
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & EXTENSION_BIT != 0
+    }
 }
 
+#[derive(Debug)]
 pub struct HandshakeCodec;
 
 impl Encoder<Handshake> for HandshakeCodec {
@@ -92,7 +114,7 @@ impl std::fmt::Debug for Handshake {
         f.debug_struct("Handshake")
             .field("protocol", &String::from_utf8_lossy(&self.protocol))
             .field("reserved", &self.reserved)
-            .field("info_hash", &hex::encode(&self.info_hash))
+            .field("info_hash", &hex::encode(self.info_hash))
             .field("peer_id", &String::from_utf8_lossy(&self.peer_id))
             .finish()
     }
@@ -119,6 +141,22 @@ mod tests {
         assert_eq!(handshake.peer_id, [0; 20]);
     }
 
+    #[test]
+    fn test_handshake_extension_bit() {
+        let mut src = BytesMut::new();
+        HandshakeCodec.encode(Handshake::new([0; 20], [0; 20]), &mut src).unwrap();
+        assert_eq!(src[25], 0x10);
+        let handshake = HandshakeCodec.decode(&mut src).unwrap().unwrap();
+        assert!(handshake.supports_extensions());
+        assert!(!handshake.supports_dht());
+
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        handshake.set_dht();
+        HandshakeCodec.encode(handshake, &mut src).unwrap();
+        assert_eq!(src[27], 0x01);
+        assert!(HandshakeCodec.decode(&mut src).unwrap().unwrap().supports_dht());
+    }
+
     #[test]
     fn test_handshake_decoding_with_incomplete_data() {
         let mut src = BytesMut::new();
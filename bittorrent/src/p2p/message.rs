@@ -40,10 +40,25 @@ pub enum Message {
     Cancel(block::BlockRequest),
 
     // The port message is sent to inform the peer of the port number that the client is listening on.
-    Port { port: u32 },
+    Port { port: u16 },
+
+    // Extension protocol message (BEP-10), id 0 is the extended handshake.
+    Extended { id: u8, payload: Vec<u8> },
+}
+
+// Messages with unknown IDs are skipped, as the spec asks for forward compatibility.
+#[derive(Debug, Default)]
+pub struct MessageCodec {
+    // Error on unknown message IDs instead.
+    strict: bool,
 }
 
-pub struct MessageCodec;
+impl MessageCodec {
+    #[cfg(test)]
+    pub fn strict() -> Self {
+        Self { strict: true }
+    }
+}
 
 impl Encoder<Message> for MessageCodec {
 
@@ -90,7 +105,7 @@ impl Encoder<Message> for MessageCodec {
             Message::Bitfield(bitfield) => {
                 dst.put_u32(1 + (bitfield.len() / 8) as u32);
                 dst.put_u8(5);
-                dst.extend_from_slice(&bitfield.as_raw_slice());
+                dst.extend_from_slice(bitfield.as_raw_slice());
             },
 
             // request: <len=0013><id=6><index><begin><length>
@@ -124,7 +139,15 @@ impl Encoder<Message> for MessageCodec {
             Message::Port { port } => {
                 dst.put_u32(3);
                 dst.put_u8(9);
-                dst.put_u32(port);
+                dst.put_u16(port);
+            },
+
+            // extended: <len=0002+X><id=20><extended id><payload>
+            Message::Extended { id, payload } => {
+                dst.put_u32(2 + payload.len() as u32);
+                dst.put_u8(20);
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
             },
         }
 
@@ -139,59 +162,76 @@ impl Decoder for MessageCodec {
     
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         
-        // Can't read message length.
-        if src.remaining() < 4 { return Ok(None); }
-
-        let mut peeker = std::io::Cursor::new(&src);
-        let msg_len: usize = peeker.get_u32() as usize;
-        peeker.set_position(0);
-
-        if src.remaining() >= 4 + msg_len {
-            src.advance(4);
-            if msg_len == 0 { return Ok(Some(Message::KeepAlive)); }
-        } else {
-            // Haven't recieved all of message.
-            return Ok(None);
-        }
-
-        let msg = match src.get_u8() {
-            0 => Message::Choke,
-            1 => Message::Unchoke,
-            2 => Message::Interested,
-            3 => Message::NotInterested,
-            4 => Message::Have { idx: src.get_u32() },
-            5 => {
-                let mut bitfield = vec![0; msg_len - 1];
-                src.copy_to_slice(&mut bitfield);
-                Message::Bitfield(Bitfield::from_vec(bitfield))
-            },
-            6 => {
-                let piece_idx = src.get_u32() as usize;
-                let offset = src.get_u32() as usize;
-                let len = src.get_u32() as usize;
-                Message::Request(block::BlockRequest { piece_idx, offset, len })
-            },
-            7 => {
-                let piece_idx = src.get_u32() as usize;
-                let offset = src.get_u32() as usize;
-                let mut data = vec![0; msg_len - 9];
-                src.copy_to_slice(&mut data);
-                Message::Block(block::Block { piece_idx, offset, data: block::BlockData::Owned(data) })
-            },
-            8 => {
-                let piece_idx = src.get_u32() as usize;
-                let offset = src.get_u32() as usize;
-                let len = src.get_u32() as usize;
-                Message::Cancel(block::BlockRequest { piece_idx, offset, len })
-            },
-            9 => Message::Port { port: src.get_u32() },
-            id => {
-                tracing::warn!("invalid message id: {}", id);
-                return Err(PeerError::InvalidMessageId(id));
+        // Messages with unknown ids are skipped until one we know, or an incomplete one.
+        loop {
+            // Can't read message length.
+            if src.remaining() < 4 { return Ok(None); }
+
+            let mut peeker = std::io::Cursor::new(&src);
+            let msg_len: usize = peeker.get_u32() as usize;
+            peeker.set_position(0);
+
+            if src.remaining() >= 4 + msg_len {
+                src.advance(4);
+                if msg_len == 0 { return Ok(Some(Message::KeepAlive)); }
+            } else {
+                // Haven't recieved all of message.
+                return Ok(None);
             }
-        };
+
+            let msg = match src.get_u8() {
+                0 => Message::Choke,
+                1 => Message::Unchoke,
+                2 => Message::Interested,
+                3 => Message::NotInterested,
+                4 => Message::Have { idx: src.get_u32() },
+                5 => {
+                    let mut bitfield = vec![0; msg_len - 1];
+                    src.copy_to_slice(&mut bitfield);
+                    Message::Bitfield(Bitfield::from_vec(bitfield))
+                },
+                6 => {
+                    let piece_idx = src.get_u32() as usize;
+                    let offset = src.get_u32() as usize;
+                    let len = src.get_u32() as usize;
+                    Message::Request(block::BlockRequest { piece_idx, offset, len })
+                },
+                7 => {
+                    let piece_idx = src.get_u32() as usize;
+                    let offset = src.get_u32() as usize;
+                    let mut data = vec![0; msg_len - 9];
+                    src.copy_to_slice(&mut data);
+                    Message::Block(block::Block { piece_idx, offset, data: block::BlockData::Owned(data) })
+                },
+                8 => {
+                    let piece_idx = src.get_u32() as usize;
+                    let offset = src.get_u32() as usize;
+                    let len = src.get_u32() as usize;
+                    Message::Cancel(block::BlockRequest { piece_idx, offset, len })
+                },
+                9 => Message::Port { port: src.get_u16() },
+                20 => {
+                    if msg_len < 2 {
+                        return Err(PeerError::InvalidMessage);
+                    }
+                    let id = src.get_u8();
+                    let mut payload = vec![0; msg_len - 2];
+                    src.copy_to_slice(&mut payload);
+                    Message::Extended { id, payload }
+                },
+                id if self.strict => {
+                    tracing::warn!("invalid message id: {}", id);
+                    return Err(PeerError::InvalidMessageId(id));
+                },
+                id => {
+                    tracing::debug!("skipping message with unknown id: {}", id);
+                    src.advance(msg_len - 1);
+                    continue;
+                },
+            };
         
-        Ok(Some(msg))
+            return Ok(Some(msg));
+        }
     }
 }
 
@@ -221,6 +261,7 @@ impl std::fmt::Display for Message {
                 block.len
             ),
             Message::Port { port } => write!(f, "port {}", port),
+            Message::Extended { id, payload } => write!(f, "extended id: {}, length: {}", id, payload.len()),
         }
     }
 }
@@ -254,6 +295,10 @@ mod tests {
         buf.extend_from_slice(&[0, 0, 0, 0xd, 0x6, 0, 0, 0, 0xb, 0, 0x13, 0x40, 0, 0, 0, 0x40, 0]);
         // Piece
         buf.extend_from_slice(&[0, 0, 0, 12, 0x7, 0, 0, 0, 0xb, 0, 0x13, 0x40, 0, 0x1, 0x2, 0x3]);
+        // Port
+        buf.extend_from_slice(&[0, 0, 0, 3, 0x9, 0x1a, 0xe1]);
+        // Extended
+        buf.extend_from_slice(&[0, 0, 0, 4, 0x14, 0x1, b'd', b'e']);
 
         let expected = [
             Message::KeepAlive,
@@ -265,12 +310,14 @@ mod tests {
             Message::Bitfield(BitVec::<u8, Msb0>::from_slice(&[0x1, 0x2, 0x3])),
             Message::Request(block::BlockRequest { piece_idx: 0xb, offset: 0x134000, len: 0x4000 }),
             Message::Block(block::Block { piece_idx: 0xb, offset: 0x134000, data: block::BlockData::Owned(vec![0x1, 0x2, 0x3]) }),
+            Message::Port { port: 6881 },
+            Message::Extended { id: 1, payload: b"de".to_vec() },
         ];
         let expected_buf = buf.clone();        
         
         for msg in expected.into_iter() {
-            MessageCodec.encode(msg.clone(), &mut out_buf).unwrap();
-            let decoded = MessageCodec.decode(&mut buf).unwrap().unwrap();
+            MessageCodec::default().encode(msg.clone(), &mut out_buf).unwrap();
+            let decoded = MessageCodec::default().decode(&mut buf).unwrap().unwrap();
             assert_eq!(decoded, msg, "decoded message does not match expected");
         }
         
@@ -284,27 +331,27 @@ mod tests {
 
         // Add 1/2 of interested message
         buf.extend_from_slice(&[0, 0, 0]);
-        let decoded = MessageCodec.decode(&mut buf).unwrap();
+        let decoded = MessageCodec::default().decode(&mut buf).unwrap();
         assert_eq!(decoded, None);
         // Add other 1/2
         buf.extend_from_slice(&[1, 2]);
-        let decoded = MessageCodec.decode(&mut buf).unwrap().unwrap();
+        let decoded = MessageCodec::default().decode(&mut buf).unwrap().unwrap();
         assert_eq!(decoded, Message::Interested);
 
         // Add 1/2 of piece message
         buf.extend_from_slice(&[0, 0, 0, 12, 0x7, 0, 0, 0, 0xb, 0, 0x13, 0x40, 0, 0x1]);
-        let decoded = MessageCodec.decode(&mut buf).unwrap();
+        let decoded = MessageCodec::default().decode(&mut buf).unwrap();
         assert_eq!(decoded, None);
         // Add other 1/2
         buf.extend_from_slice(&[0x2, 0x3]);
-        let decoded = MessageCodec.decode(&mut buf).unwrap().unwrap();
+        let decoded = MessageCodec::default().decode(&mut buf).unwrap().unwrap();
         assert_eq!(decoded, Message::Block(block::Block { piece_idx: 0xb, offset: 0x134000, data: block::BlockData::Owned(vec![0x1, 0x2, 0x3]) }));
     }
 
     #[test]
     fn test_msg_decode_empty() {
         let mut src = BytesMut::new();
-        let mut codec = MessageCodec;
+        let mut codec = MessageCodec::default();
         let message = codec.decode(&mut src).unwrap();
         assert_eq!(message, None);
     }
@@ -312,7 +359,7 @@ mod tests {
     #[test]
     fn test_msg_decode_incomplete_message() {
         let mut src = BytesMut::from(&[0u8, 1, 2][..]); // Not a complete message
-        let mut codec = MessageCodec;
+        let mut codec = MessageCodec::default();
         let message = codec.decode(&mut src).unwrap();
         assert_eq!(message, None);
     }
@@ -320,7 +367,7 @@ mod tests {
     #[test]
     fn test_msg_decode_invalid_id() {
         let mut src = BytesMut::from(&[0u8, 0, 0, 1, 255][..]); // Message ID 255 is invalid
-        let mut codec = MessageCodec;
+        let mut codec = MessageCodec::strict();
         let result = codec.decode(&mut src);
         match result {
             Ok(_) => panic!("Expected an error, but got Ok(_)"),
@@ -330,4 +377,28 @@ mod tests {
             },
         }
     }
+
+    #[test]
+    fn test_msg_decode_skip_unknown_id() {
+        let mut src = BytesMut::new();
+        // Unknown message with a payload, followed by unchoke.
+        src.extend_from_slice(&[0, 0, 0, 4, 0x63, 0x1, 0x2, 0x3]);
+        src.extend_from_slice(&[0, 0, 0, 1, 1]);
+        let mut codec = MessageCodec::default();
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Unchoke));
+        assert!(src.is_empty());
+
+        // Only the unknown message is buffered.
+        src.extend_from_slice(&[0, 0, 0, 2, 0x63, 0x1]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+
+        // Enough unknown messages to overflow the stack if each were skipped by recursing.
+        for _ in 0..1_000_000 {
+            src.extend_from_slice(&[0, 0, 0, 1, 0x63]);
+        }
+        src.extend_from_slice(&[0, 0, 0, 1, 1]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Unchoke));
+        assert!(src.is_empty());
+    }
 }
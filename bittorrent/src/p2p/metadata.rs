@@ -0,0 +1,252 @@
+use std::{collections::HashMap, net::SocketAddr};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time;
+use tokio_util::codec::Framed;
+use futures::{SinkExt, StreamExt};
+use crate::ID;
+use super::{*, message::*, handshake::*, extension::*};
+
+// Fetches the info dict from a peer with the ut_metadata extension.
+// Reference: https://www.bittorrent.org/beps/bep_0009.html
+
+// Metadata is sent in 16KiB pieces, only the last may be smaller.
+const METADATA_PIECE_LEN: usize = 0x4000;
+
+// Far larger than any real info dict, stops a peer making us allocate arbitrary amounts.
+const MAX_METADATA_SIZE: usize = 0x1000000;
+
+// Time given to a peer to send the whole info dict.
+const FETCH_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataHeader {
+
+    // 0 request, 1 data, 2 reject.
+    msg_type: u8,
+
+    piece: usize,
+
+    // Only present in data messages.
+    #[serde(default)]
+    total_size: Option<usize>,
+
+}
+
+const METADATA_REQUEST: u8 = 0;
+const METADATA_DATA: u8 = 1;
+const METADATA_REJECT: u8 = 2;
+
+// Reassembles the info dict from the pieces sent by a peer.
+#[derive(Debug)]
+pub struct MetadataBuf {
+
+    size: usize,
+
+    pieces: Vec<Option<Vec<u8>>>,
+
+}
+
+impl MetadataBuf {
+
+    pub fn new(size: usize) -> Result<Self> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(PeerError::InvalidMetadataSize(size));
+        }
+        Ok(Self {
+            size,
+            pieces: vec![None; size.div_ceil(METADATA_PIECE_LEN)],
+        })
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn add_piece(&mut self, piece: usize, total_size: Option<usize>, data: Vec<u8>) -> Result<()> {
+
+        // Data must match the size the peer advertised in its handshake.
+        if let Some(total_size) = total_size {
+            if total_size != self.size {
+                return Err(PeerError::MetadataSizeMismatch { expected: self.size, found: total_size });
+            }
+        }
+        if piece >= self.num_pieces() {
+            return Err(PeerError::InvalidMessage);
+        }
+        let expected_len = if piece == self.num_pieces() - 1 {
+            self.size - piece * METADATA_PIECE_LEN
+        } else {
+            METADATA_PIECE_LEN
+        };
+        if data.len() != expected_len {
+            return Err(PeerError::InvalidMessage);
+        }
+
+        self.pieces[piece] = Some(data);
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    // Joins the pieces, which must hash to the info hash.
+    pub fn finish(self, info_hash: &ID) -> Result<Vec<u8>> {
+        use sha1::Digest;
+        let data: Vec<u8> = self.pieces.into_iter().flatten().flatten().collect();
+        let hash: ID = sha1::Sha1::digest(&data).into();
+        if data.len() != self.size || hash != *info_hash {
+            return Err(PeerError::InvalidMetadata);
+        }
+        Ok(data)
+    }
+}
+
+// Connects to a peer and downloads the info dict for the info hash, verified against it.
+pub async fn fetch_metadata(address: SocketAddr, info_hash: ID, client_id: ID, options: ConnectOptions) -> Result<Vec<u8>> {
+    let result = async {
+        let stream = connect_peer(address, info_hash, &options).await?;
+        fetch(stream, info_hash, client_id).await
+    };
+    time::timeout(FETCH_TIMEOUT, result)
+        .await
+        .map_err(|_| PeerError::Timeout)?
+}
+
+async fn fetch(stream: PeerStream, info_hash: ID, client_id: ID) -> Result<Vec<u8>> {
+
+    let mut socket = Framed::new(stream, HandshakeCodec);
+    socket.send(Handshake::new(info_hash, client_id)).await?;
+
+    let handshake = match socket.next().await {
+        Some(handshake) => handshake?,
+        None => return Err(PeerError::NoHandshake),
+    };
+    if handshake.protocol != PROTOCOL {
+        return Err(PeerError::IncorrectProtocol);
+    }
+    if handshake.info_hash != info_hash {
+        return Err(PeerError::IncorrectInfoHash);
+    }
+    if !handshake.supports_extensions() {
+        return Err(PeerError::MetadataUnsupported);
+    }
+
+    let mut socket = socket.map_codec(|_| MessageCodec::default());
+    let ext_handshake = ExtendedHandshake {
+        m: HashMap::from([("ut_metadata".to_string(), UT_METADATA_ID)]),
+        metadata_size: None,
+    };
+    socket.send(Message::Extended {
+        id: EXTENDED_HANDSHAKE_ID,
+        payload: bencode::encode_to_raw(&ext_handshake)?,
+    }).await?;
+
+    let mut metadata: Option<MetadataBuf> = None;
+    while let Some(msg) = socket.next().await {
+        let (id, payload) = match msg? {
+            Message::Extended { id, payload } => (id, payload),
+            // Other messages don't matter until we have the metadata.
+            _ => continue,
+        };
+
+        if id == EXTENDED_HANDSHAKE_ID {
+            let ext_handshake: ExtendedHandshake = bencode::decode_bytes(&payload)?;
+            let peer_id = ext_handshake.id("ut_metadata").ok_or(PeerError::MetadataUnsupported)?;
+            let size = ext_handshake.metadata_size.ok_or(PeerError::MetadataUnsupported)?;
+            let buf = MetadataBuf::new(size)?;
+            tracing::debug!("requesting {} metadata pieces, {} bytes", buf.num_pieces(), size);
+
+            for piece in 0..buf.num_pieces() {
+                let header = MetadataHeader { msg_type: METADATA_REQUEST, piece, total_size: None };
+                socket.send(Message::Extended {
+                    id: peer_id,
+                    payload: bencode::encode_to_raw(&header)?,
+                }).await?;
+            }
+            metadata = Some(buf);
+
+        } else if id == UT_METADATA_ID {
+            let buf = metadata.as_mut().ok_or(PeerError::InvalidMessage)?;
+            // Piece data follows the bencoded header in data messages.
+            let header_len = bencode::value_len(&payload).ok_or(PeerError::InvalidMessage)?;
+            let header: MetadataHeader = bencode::decode_bytes(&payload[..header_len])?;
+            match header.msg_type {
+                METADATA_DATA => {
+                    buf.add_piece(header.piece, header.total_size, payload[header_len..].to_vec())?;
+                },
+                METADATA_REJECT => return Err(PeerError::MetadataRejected),
+                _ => {},
+            }
+            if buf.is_complete() {
+                return metadata.take().unwrap().finish(&info_hash);
+            }
+        }
+    }
+
+    Err(PeerError::Io(std::io::ErrorKind::UnexpectedEof.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::Digest;
+
+    fn metadata(size: usize) -> (Vec<u8>, ID) {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let hash = sha1::Sha1::digest(&data).into();
+        (data, hash)
+    }
+
+    #[test]
+    fn test_metadata_reassembly() {
+        let (data, hash) = metadata(METADATA_PIECE_LEN * 2 + 100);
+        let mut buf = MetadataBuf::new(data.len()).unwrap();
+        assert_eq!(buf.num_pieces(), 3);
+
+        // Pieces can arrive in any order.
+        for piece in [2, 0, 1] {
+            assert!(!buf.is_complete());
+            let start = piece * METADATA_PIECE_LEN;
+            let end = (start + METADATA_PIECE_LEN).min(data.len());
+            buf.add_piece(piece, Some(data.len()), data[start..end].to_vec()).unwrap();
+        }
+        assert!(buf.is_complete());
+        assert_eq!(buf.finish(&hash).unwrap(), data);
+    }
+
+    #[test]
+    fn test_metadata_size_mismatch() {
+        let (data, hash) = metadata(100);
+
+        // Data message disagrees with the size from the handshake.
+        let mut buf = MetadataBuf::new(200).unwrap();
+        assert!(matches!(
+            buf.add_piece(0, Some(100), data.clone()),
+            Err(PeerError::MetadataSizeMismatch { expected: 200, found: 100 }),
+        ));
+
+        // Handshake size is wrong and the data doesn't say otherwise.
+        assert!(matches!(buf.add_piece(0, None, data.clone()), Err(PeerError::InvalidMessage)));
+
+        // Right size but the wrong data.
+        let mut buf = MetadataBuf::new(100).unwrap();
+        buf.add_piece(0, None, vec![0; 100]).unwrap();
+        assert!(matches!(buf.finish(&hash), Err(PeerError::InvalidMetadata)));
+
+        assert!(MetadataBuf::new(0).is_err());
+        assert!(MetadataBuf::new(MAX_METADATA_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn test_metadata_message_header() {
+        let mut payload = bencode::encode_to_raw(&MetadataHeader { msg_type: METADATA_DATA, piece: 1, total_size: Some(3) }).unwrap();
+        let header_len = payload.len();
+        payload.extend_from_slice(b"abc");
+
+        assert_eq!(bencode::value_len(&payload), Some(header_len));
+        let header: MetadataHeader = bencode::decode_bytes(&payload[..header_len]).unwrap();
+        assert_eq!((header.msg_type, header.piece, header.total_size), (METADATA_DATA, 1, Some(3)));
+        assert_eq!(&payload[header_len..], b"abc");
+    }
+}
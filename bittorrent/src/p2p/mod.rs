@@ -1,16 +1,33 @@
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{sync::mpsc, task::JoinHandle};
+use futures::StreamExt;
+use tokio::{net::TcpStream, sync::mpsc, task::JoinHandle, time};
+use tokio_util::codec::Framed;
 use tracing::Instrument;
-use crate::{block::Block, torrent::TorrentContext};
+use crate::{block::{Block, BlockRequest}, torrent::TorrentContext};
+use handshake::HandshakeCodec;
 
 mod session;
 mod message;
 mod handshake;
+mod metadata;
+mod extension;
+mod socks;
+mod mse;
+mod transport;
+mod utp;
 pub mod state;
+pub mod peer_id;
 
 pub use session::PeerSession;
+pub use metadata::fetch_metadata;
+pub use handshake::Handshake;
+pub use mse::PeerStream;
+pub use transport::{connect_peer, ConnectOptions, PeerTransport};
 use state::SessionState;
 
+// How long an inbound peer has to send its handshake.
+const INBOUND_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
 type Result<T> = std::result::Result<T, PeerError>;
 type PeerRx = mpsc::UnboundedReceiver<PeerCommand>;
 pub type PeerTx = mpsc::UnboundedSender<PeerCommand>;
@@ -41,6 +58,36 @@ pub enum PeerError {
 
     #[error("connection timeout")]
     Timeout,
+
+    #[error("connected to ourselves")]
+    ConnectedToSelf,
+
+    #[error("peer unchoked us but sent no blocks")]
+    Snubbed,
+
+    #[error("peer sent too many blocks we didn't request")]
+    UnexpectedBlocks,
+
+    #[error("peer sent block of a different length than requested")]
+    InvalidBlockLength,
+
+    #[error("error decoding extension message: {0}")]
+    BencodeError(#[from] bencode::Error),
+
+    #[error("peer does not support metadata exchange")]
+    MetadataUnsupported,
+
+    #[error("peer rejected metadata request")]
+    MetadataRejected,
+
+    #[error("invalid metadata size: {0}")]
+    InvalidMetadataSize(usize),
+
+    #[error("metadata size mismatch, expected {expected} found {found}")]
+    MetadataSizeMismatch { expected: usize, found: usize },
+
+    #[error("metadata does not match info hash")]
+    InvalidMetadata,
 }
 
 // Commands that can be sent to a peer.
@@ -52,6 +99,15 @@ pub enum PeerCommand {
     // Block read from disk.
     BlockRead(Block),
 
+    // Give up on a peer that keeps choking us, without disconnecting.
+    NotInterested,
+
+    // Block received from another peer in end game, cancel our request for it.
+    CancelRequest(BlockRequest),
+
+    // Connected peers of the torrent, shared with the peer through peer exchange.
+    Pex(Vec<SocketAddr>),
+
     Shutdown,
 
 }
@@ -70,16 +126,40 @@ pub struct PeerHandle {
     
 }
 
+// A peer that connected to us, its handshake has been read to find the torrent it wants.
+#[derive(Debug)]
+pub struct InboundPeer {
+
+    pub address: SocketAddr,
+
+    // Anything the peer sent after its handshake is still buffered here.
+    pub socket: Framed<PeerStream, HandshakeCodec>,
+
+    pub handshake: Handshake,
+
+}
+
+// Reads the handshake of a peer that connected to us, we reply once its torrent takes it.
+pub async fn read_handshake(stream: TcpStream, address: SocketAddr) -> Result<InboundPeer> {
+    let mut socket = Framed::new(PeerStream::new(Box::new(stream)), HandshakeCodec);
+    match time::timeout(INBOUND_HANDSHAKE_TIMEOUT, socket.next()).await {
+        Ok(Some(Ok(handshake))) => Ok(InboundPeer { address, socket, handshake }),
+        Ok(Some(Err(e))) => Err(e),
+        Ok(None) => Err(PeerError::NoHandshake),
+        Err(_) => Err(PeerError::Timeout),
+    }
+}
+
 impl PeerHandle {
     pub fn start_session(
         address: SocketAddr,
         ctx: Arc<TorrentContext>,
-        socket: Option<tokio::net::TcpStream>
+        inbound: Option<InboundPeer>,
     ) -> Self {
 
         let (mut session, peer_tx) = PeerSession::new(address, ctx);
         let session_handle = tokio::spawn(async move {
-            if let Err(e) = session.start_session(socket).await {
+            if let Err(e) = session.start_session(inbound).await {
                 tracing::error!("session error: {}", e);
             }
             session.disconnect().await;
@@ -0,0 +1,381 @@
+use std::{io, pin::Pin, task::{ready, Context, Poll}};
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use crate::{config::Encryption, ID};
+use super::{state::CryptoMethod, transport::PeerTransport};
+
+// Message stream encryption, a Diffie-Hellman exchange then RC4 to hide the BitTorrent handshake,
+// and the rest of the connection if both sides choose to. Only the connecting side is done so far.
+// Reference: https://wiki.vuze.com/w/Message_Stream_Encryption
+
+// Diffie-Hellman is done modulo this 768 bit prime, with generator 2.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+
+// Public keys are sent as big endian numbers of this length.
+const KEY_LEN: usize = 96;
+
+// Random padding of up to this length follows each side's key and crypto messages.
+const MAX_PAD: usize = 512;
+
+// Verification constant, finding it encrypted in the peer's reply tells us where its padding ends.
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+// The start of each RC4 keystream is thrown away, its first bytes are known to be weak.
+const RC4_DISCARD: usize = 1024;
+
+// Does the crypto handshake as the connecting side, before the BitTorrent handshake.
+// Forced encryption only offers RC4, otherwise the peer can choose to carry on in plaintext.
+pub async fn initiate(mut stream: Box<dyn PeerTransport>, info_hash: ID, encryption: Encryption) -> io::Result<PeerStream> {
+
+    let (private_key, public_key) = keypair();
+    let mut buf = public_key.to_vec();
+    buf.extend(random_pad());
+    stream.write_all(&buf).await?;
+
+    let mut peer_key = [0; KEY_LEN];
+    stream.read_exact(&mut peer_key).await?;
+    let secret = shared_secret(&private_key, &peer_key);
+    let mut encryptor = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decryptor = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let provide = match encryption {
+        Encryption::Forced => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+    let pad = random_pad();
+    let mut msg = VC.to_vec();
+    msg.extend_from_slice(&provide.to_be_bytes());
+    msg.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&pad);
+    // Our BitTorrent handshake is sent once this is done, rather than inside it.
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    encryptor.apply(&mut msg);
+
+    // The hashes tell the peer which torrent we want without giving away the info hash.
+    let mut buf = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    buf.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    buf.extend(msg);
+    stream.write_all(&buf).await?;
+
+    // The peer's padding is read through a byte at a time, so nothing after its reply is taken.
+    let mut encrypted_vc = VC;
+    decryptor.apply(&mut encrypted_vc);
+    let mut window = Vec::with_capacity(MAX_PAD + VC.len());
+    loop {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(&encrypted_vc) {
+            break;
+        }
+        if window.len() == MAX_PAD + VC.len() {
+            return Err(error("no verification constant from peer"));
+        }
+    }
+
+    let mut reply = [0; 6];
+    stream.read_exact(&mut reply).await?;
+    decryptor.apply(&mut reply);
+    let select = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+    let pad_len = u16::from_be_bytes([reply[4], reply[5]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(error("peer padding too long"));
+    }
+    let mut pad = vec![0; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decryptor.apply(&mut pad);
+
+    match select {
+        CRYPTO_RC4 => Ok(PeerStream {
+            stream,
+            cipher: Some(Cipher { read: decryptor, write: encryptor, pending: Vec::new(), written: 0 }),
+            crypto: Some(CryptoMethod::Rc4),
+        }),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => Ok(PeerStream {
+            stream,
+            cipher: None,
+            crypto: Some(CryptoMethod::Plaintext),
+        }),
+        _ => Err(error("peer selected a method we didn't offer")),
+    }
+}
+
+// Private key and the public key sent to the peer.
+fn keypair() -> (BigUint, [u8; KEY_LEN]) {
+    let private_key = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+    let public_key = BigUint::from(GENERATOR).modpow(&private_key, &prime());
+    (private_key, to_key_bytes(&public_key))
+}
+
+fn shared_secret(private_key: &BigUint, peer_key: &[u8]) -> [u8; KEY_LEN] {
+    to_key_bytes(&BigUint::from_bytes_be(peer_key).modpow(private_key, &prime()))
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap()
+}
+
+// Left padded with zeros to the full key length.
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn random_pad() -> Vec<u8> {
+    let len = rand::random::<usize>() % (MAX_PAD + 1);
+    (0..len).map(|_| rand::random()).collect()
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::other(format!("encryption: {}", msg))
+}
+
+#[derive(Debug)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::without_discard(key);
+        rc4.apply(&mut [0; RC4_DISCARD]);
+        rc4
+    }
+
+    fn without_discard(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    // Encrypts or decrypts in place.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            *byte ^= self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cipher {
+
+    read: Rc4,
+
+    write: Rc4,
+
+    // Data already run through the write cipher, it has to be sent before anything else is encrypted.
+    pending: Vec<u8>,
+
+    written: usize,
+
+}
+
+// Connection to a peer, everything read and written goes through RC4 if the crypto handshake chose it.
+#[derive(Debug)]
+pub struct PeerStream {
+
+    stream: Box<dyn PeerTransport>,
+
+    cipher: Option<Cipher>,
+
+    // None if there was no crypto handshake.
+    crypto: Option<CryptoMethod>,
+
+}
+
+impl PeerStream {
+
+    // A plaintext connection.
+    pub fn new(stream: Box<dyn PeerTransport>) -> Self {
+        Self { stream, cipher: None, crypto: None }
+    }
+
+    pub fn crypto(&self) -> Option<CryptoMethod> {
+        self.crypto
+    }
+
+    pub fn is_utp(&self) -> bool {
+        self.stream.is_utp()
+    }
+}
+
+// Sends what's left of the pending data.
+fn poll_pending(stream: &mut dyn PeerTransport, cipher: &mut Cipher, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    while cipher.written < cipher.pending.len() {
+        let n = ready!(Pin::new(&mut *stream).poll_write(cx, &cipher.pending[cipher.written..]))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        cipher.written += n;
+    }
+    cipher.pending.clear();
+    cipher.written = 0;
+    Poll::Ready(Ok(()))
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.cipher {
+            cipher.read.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerStream {
+
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(cipher) = &mut this.cipher else {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        };
+        ready!(poll_pending(&mut *this.stream, cipher, cx))?;
+        // Taken whole, it goes out on the next write or flush.
+        cipher.pending.extend_from_slice(buf);
+        cipher.write.apply(&mut cipher.pending);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(cipher) = &mut this.cipher {
+            ready!(poll_pending(&mut *this.stream, cipher, cx))?;
+        }
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(cipher) = &mut this.cipher {
+            ready!(poll_pending(&mut *this.stream, cipher, cx))?;
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_rc4() {
+        let mut data = *b"Plaintext";
+        Rc4::without_discard(b"Key").apply(&mut data);
+        assert_eq!(data, hex_literal::hex!("BBF316E8D940AF0AD3"));
+    }
+
+    // Plays the receiving side against initiate.
+    async fn respond(listener: TcpListener, info_hash: ID, select: u32) -> TcpStream {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut peer_key = [0; KEY_LEN];
+        socket.read_exact(&mut peer_key).await.unwrap();
+        let (private_key, public_key) = keypair();
+        let mut buf = public_key.to_vec();
+        buf.extend(random_pad());
+        socket.write_all(&buf).await.unwrap();
+        let secret = shared_secret(&private_key, &peer_key);
+
+        // Skip the initiator's padding.
+        let req1 = hash(&[b"req1", &secret]);
+        let mut window = Vec::new();
+        while !window.ends_with(&req1) {
+            window.push(socket.read_u8().await.unwrap());
+        }
+        let mut req2 = [0; 20];
+        socket.read_exact(&mut req2).await.unwrap();
+        let req3 = hash(&[b"req3", &secret]);
+        let expected = hash(&[b"req2", &info_hash]);
+        assert!(req2.iter().zip(req3).map(|(a, b)| a ^ b).eq(expected));
+
+        let mut decryptor = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut encryptor = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+        let mut header = [0; 14];
+        socket.read_exact(&mut header).await.unwrap();
+        decryptor.apply(&mut header);
+        assert_eq!(header[..8], VC);
+        let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+        let mut rest = vec![0; pad_len + 2];
+        socket.read_exact(&mut rest).await.unwrap();
+        decryptor.apply(&mut rest);
+        // No initial payload.
+        assert_eq!(rest[pad_len..], [0u8; 2]);
+
+        let mut reply = VC.to_vec();
+        reply.extend_from_slice(&select.to_be_bytes());
+        reply.extend_from_slice(&[0, 0]);
+        encryptor.apply(&mut reply);
+        socket.write_all(&reply).await.unwrap();
+
+        // Carry on with RC4 over the connection.
+        let mut msg = [0; 5];
+        socket.read_exact(&mut msg).await.unwrap();
+        if select == CRYPTO_RC4 {
+            decryptor.apply(&mut msg);
+        }
+        assert_eq!(&msg, b"hello");
+        let mut msg = *b"world";
+        if select == CRYPTO_RC4 {
+            encryptor.apply(&mut msg);
+        }
+        socket.write_all(&msg).await.unwrap();
+        socket
+    }
+
+    async fn exchange(select: u32) -> Option<CryptoMethod> {
+        let info_hash = [7; 20];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let peer = tokio::spawn(respond(listener, info_hash, select));
+
+        let mut stream = initiate(Box::new(stream), info_hash, Encryption::Enabled).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut msg = [0; 5];
+        stream.read_exact(&mut msg).await.unwrap();
+        assert_eq!(&msg, b"world");
+        peer.await.unwrap();
+        stream.crypto()
+    }
+
+    #[tokio::test]
+    async fn test_initiate_rc4() {
+        assert_eq!(exchange(CRYPTO_RC4).await, Some(CryptoMethod::Rc4));
+    }
+
+    #[tokio::test]
+    async fn test_initiate_plaintext() {
+        assert_eq!(exchange(CRYPTO_PLAINTEXT).await, Some(CryptoMethod::Plaintext));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::ID;
+
+// Reference: https://wiki.theory.org/BitTorrentSpecification#peer_id
+
+// Two letter client codes of Azureus-style ids, e.g. -qB4250-.
+const AZUREUS_CLIENTS: [(&[u8; 2], &str); 16] = [
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BT", "BitTorrent"),
+    (b"BX", "bitter"),
+    (b"DE", "Deluge"),
+    (b"FD", "Free Download Manager"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"lt", "libTorrent"),
+    (b"qB", "qBittorrent"),
+    (b"RS", "bitter"),
+    (b"SD", "Thunder"),
+    (b"TR", "Transmission"),
+    (b"UM", "µTorrent Mac"),
+    (b"UT", "µTorrent"),
+    (b"WW", "WebTorrent"),
+];
+
+// Single letter client codes of Shadow-style ids, e.g. S58B-----.
+const SHADOW_CLIENTS: [(u8, &str); 7] = [
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// Azureus-style prefix of our generated peer ids.
+pub const CLIENT_ID_PREFIX: [u8; 8] = *b"-BX0100-";
+
+// Prefix followed by random bytes, so each client instance has its own id.
+pub fn generate_peer_id(prefix: &[u8; 8]) -> ID {
+    let mut id = [0; 20];
+    id[..8].copy_from_slice(prefix);
+    id[8..].copy_from_slice(&rand::random::<[u8; 12]>());
+    id
+}
+
+// Name and version of the client that made a peer id.
+// Ids that aren't in a known style are shown by their first bytes in hex.
+pub fn peer_id_to_client(id: &ID) -> String {
+    azureus_client(id)
+        .or_else(|| shadow_client(id))
+        .unwrap_or_else(|| hex::encode(&id[..8]))
+}
+
+// -XXVVVV-, version digits are read as major.minor, e.g. 2940 is 2.94.
+fn azureus_client(id: &ID) -> Option<String> {
+    if id[0] != b'-' || id[7] != b'-' || !id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = [id[1], id[2]];
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(c, _)| **c == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| String::from_utf8_lossy(&code).into_owned());
+    let minor = std::str::from_utf8(&id[4..7]).ok()?.trim_end_matches('0');
+    let major = id[3] as char;
+    Some(if minor.is_empty() {
+        format!("{} {}", name, major)
+    } else {
+        format!("{} {}.{}", name, major, minor)
+    })
+}
+
+// XVVVVV followed by dashes, each version character is a number in base 64.
+fn shadow_client(id: &ID) -> Option<String> {
+    let (_, name) = SHADOW_CLIENTS.iter().find(|(c, _)| *c == id[0])?;
+    let end = id[1..].windows(2).position(|w| w == b"--")? + 1;
+    if end > 6 {
+        return None;
+    }
+    let version = id[1..end]
+        .iter()
+        .map(|c| match c {
+            b'0'..=b'9' => Some((c - b'0').to_string()),
+            b'A'..=b'Z' => Some((c - b'A' + 10).to_string()),
+            b'a'..=b'z' => Some((c - b'a' + 36).to_string()),
+            b'.' => Some("62".to_string()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if version.is_empty() {
+        return Some(name.to_string());
+    }
+    Some(format!("{} {}", name, version.join(".")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(prefix: &[u8]) -> ID {
+        let mut id = [b'x'; 20];
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    #[test]
+    fn test_peer_id_to_client() {
+        assert_eq!(peer_id_to_client(&id(b"-qB4250-")), "qBittorrent 4.25");
+        assert_eq!(peer_id_to_client(&id(b"-TR2940-")), "Transmission 2.94");
+        assert_eq!(peer_id_to_client(&id(b"-DE2000-")), "Deluge 2");
+        assert_eq!(peer_id_to_client(&id(b"-ZZ1230-")), "ZZ 1.23");
+        assert_eq!(peer_id_to_client(&id(b"S58B-----")), "Shadow 5.8.11");
+        assert_eq!(peer_id_to_client(&id(b"T03I--")), "BitTornado 0.3.18");
+        // Garbage.
+        assert_eq!(peer_id_to_client(&[0xab; 20]), "abababababababab");
+        assert_eq!(peer_id_to_client(&id(b"-qB42\x0050-")), hex::encode(b"-qB42\x0050"));
+    }
+
+    #[test]
+    fn test_generate_peer_id() {
+        let a = generate_peer_id(&CLIENT_ID_PREFIX);
+        let b = generate_peer_id(&CLIENT_ID_PREFIX);
+        assert_eq!(&a[..8], b"-BX0100-");
+        assert_ne!(a, b);
+        assert_eq!(peer_id_to_client(&a), "bitter 0.1");
+    }
+}
@@ -1,5 +1,5 @@
 use std::{collections::{HashMap, HashSet}, net::SocketAddr, sync::Arc, time::Instant};
-use tokio::{sync::mpsc, net::TcpStream, time};
+use tokio::{sync::{mpsc, OwnedSemaphorePermit}, time};
 use tokio_util::codec::Framed;
 use futures::{SinkExt, StreamExt, stream::SplitSink};
 use crate::{
@@ -8,9 +8,24 @@ use crate::{
     torrent::{TorrentCommand, TorrentContext},
     Bitfield,
 };
-use super::{*, message::*, handshake::*, state::*};
+use super::{*, message::*, handshake::*, state::*, extension::*};
 
-type MessageSink = SplitSink<Framed<TcpStream, MessageCodec>, Message>;
+type MessageSink = SplitSink<Framed<PeerStream, MessageCodec>, Message>;
+
+// Peers that send nothing for this long are disconnected, they should send keep-alives at least every 2 minutes.
+const IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(150);
+
+// Send a keep-alive if we haven't sent anything else for this long.
+const KEEP_ALIVE_INTERVAL: time::Duration = time::Duration::from_secs(90);
+
+// Window that unexpected blocks are counted over.
+const UNEXPECTED_BLOCK_WINDOW: time::Duration = time::Duration::from_secs(60);
+
+// Blocks for requests we cancelled or freed can still arrive for a while, they don't count as unexpected.
+const CANCELLED_GRACE: time::Duration = time::Duration::from_secs(30);
+
+// How long a peer that has unchoked us can leave our requests unanswered before we disconnect.
+const SNUB_TIMEOUT: time::Duration = time::Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct PeerSession {
@@ -36,6 +51,26 @@ pub struct PeerSession {
 
     state: SessionState,
 
+    // Id to send peer exchange messages with, if the peer supports it.
+    pex_id: Option<u8>,
+
+    // Peers the peer has been told about through peer exchange.
+    pex_sent: HashSet<SocketAddr>,
+
+    // When we last read a message from the peer, or sent one to it.
+    last_received: Instant,
+
+    last_sent: Instant,
+
+    // Held whilst we have the peer unchoked.
+    upload_slot: Option<OwnedSemaphorePermit>,
+
+    // Requests we've withdrawn, with when, so late blocks for them aren't held against the peer.
+    cancelled: HashMap<BlockRequest, Instant>,
+
+    // Start of the window unexpected blocks are counted in.
+    unexpected_window: Instant,
+
 }
 
 impl PeerSession {
@@ -53,40 +88,62 @@ impl PeerSession {
                 peer_tx: peer_tx.clone(),
                 bitfield,
                 state: SessionState::default(),
+                pex_id: None,
+                pex_sent: HashSet::new(),
                 requests_in: HashSet::new(),
                 requests_out: HashSet::new(),
                 write_requests: HashMap::new(),
+                last_received: Instant::now(),
+                last_sent: Instant::now(),
+                upload_slot: None,
+                cancelled: HashMap::new(),
+                unexpected_window: Instant::now(),
             }, 
             peer_tx,
         )
     }
 
-    pub async fn start_session(&mut self, inbound_stream: Option<TcpStream>) -> Result<()> {
-        let inbound = inbound_stream.is_some();
-        let mut socket = self.connect(inbound_stream).await?;
-        self.exchange_handshake(&mut socket, inbound).await?;
-        let socket = socket.map_codec(|_| MessageCodec);
-        self.run(socket).await?;
+    pub async fn start_session(&mut self, inbound: Option<InboundPeer>) -> Result<()> {
+        self.state.update(|state| state.conn_state = ConnState::Connecting);
+        let (mut socket, inbound_handshake) = match inbound {
+            Some(InboundPeer { socket, handshake, .. }) => (socket, Some(handshake)),
+            None => (self.connect().await?, None),
+        };
+        let handshake = self.exchange_handshake(&mut socket, inbound_handshake).await?;
+        let socket = socket.map_codec(|_| MessageCodec::default());
+        self.run(socket, handshake).await?;
         Ok(())
     }
     
-    async fn connect(&mut self, inbound_stream: Option<TcpStream>) -> Result<Framed<TcpStream, HandshakeCodec>> {
-        self.state.update(|state| state.conn_state = ConnState::Connecting);
-        if let Some(stream) = inbound_stream {
-            Ok(Framed::new(stream, HandshakeCodec))
-        } else {
-            let timeout = time::Duration::from_secs(10);
-            tracing::trace!("attempting outbound connection");
-            let stream = time::timeout(timeout, TcpStream::connect(self.address))
-                .await
-                .map_err(|_| PeerError::Timeout)??;
-            tracing::trace!("outbound connection successful");
-            Ok(Framed::new(stream, HandshakeCodec))
-        }
+    async fn connect(&mut self) -> Result<Framed<PeerStream, HandshakeCodec>> {
+        tracing::trace!("attempting outbound connection");
+        let ctx = &self.torrent_ctx;
+        let result = time::timeout(
+            ctx.connect_timeout,
+            connect_peer(self.address, ctx.info_hash, &ctx.connect_options),
+        )
+            .await
+            .map_err(|_| PeerError::Timeout)
+            .and_then(|result| result.map_err(PeerError::from));
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(e) => {
+                // The torrent decides whether to try again later.
+                let _ = self.torrent_ctx.torrent_tx.send(TorrentCommand::ConnectFailed { address: self.address });
+                return Err(e);
+            },
+        };
+        tracing::trace!("outbound connection successful");
+        self.state.update(|state| {
+            state.crypto = stream.crypto();
+            state.utp = stream.is_utp();
+        });
+        Ok(Framed::new(stream, HandshakeCodec))
     }
 
     pub async fn disconnect(&mut self) {
         tracing::info!("disconnecting peer");
+        self.torrent_ctx.picker.pieces.write().await.bitfield_remove(&self.bitfield);
         self.state.update(|state| *state = SessionState::default());
         let _ = self.torrent_ctx.torrent_tx.send(TorrentCommand::PeerState {
             address: self.address,
@@ -94,19 +151,30 @@ impl PeerSession {
         });
     }
 
-    async fn exchange_handshake(&mut self, socket: &mut Framed<TcpStream, HandshakeCodec>, inbound: bool) -> Result<()> {
+    // Returns the peer's handshake, inbound peers have already sent theirs.
+    async fn exchange_handshake(&mut self, socket: &mut Framed<PeerStream, HandshakeCodec>, inbound_handshake: Option<Handshake>) -> Result<Handshake> {
         
-        let handshake = Handshake::new(self.torrent_ctx.info_hash, self.torrent_ctx.client_id);
-        tracing::debug!("handshake: {:#?}", handshake);
+        let mut our_handshake = Handshake::new(self.torrent_ctx.info_hash, self.torrent_ctx.client_id);
+        if self.torrent_ctx.dht_port.is_some() {
+            our_handshake.set_dht();
+        }
+        tracing::debug!("handshake: {:#?}", our_handshake);
 
+        let inbound = inbound_handshake.is_some();
         if !inbound {
             tracing::trace!("send handshake");
-            socket.send(handshake).await?;
+            socket.send(our_handshake).await?;
         }
 
-        tracing::trace!("waiting for handshake");
         // Receive handshake.
-        if let Some(Ok(handshake)) = socket.next().await {
+        let handshake = match inbound_handshake {
+            Some(handshake) => Some(handshake),
+            None => {
+                tracing::trace!("waiting for handshake");
+                socket.next().await.and_then(|result| result.ok())
+            },
+        };
+        if let Some(handshake) = handshake {
             tracing::trace!("read: handshake");
 
             // Validate handshake.
@@ -120,21 +188,33 @@ impl PeerSession {
             // Respond with handshake if connection is inbound.
             if inbound {
                 tracing::trace!("send handshake");
-                socket.send(handshake).await?;
+                socket.send(our_handshake).await?;
+            }
+
+            // Trackers can give back our own address, the outbound side sees our reply and tells the torrent.
+            if handshake.peer_id == self.torrent_ctx.client_id {
+                if !inbound {
+                    let _ = self.torrent_ctx.torrent_tx.send(TorrentCommand::OwnAddress { address: self.address });
+                }
+                return Err(PeerError::ConnectedToSelf);
             }
 
             tracing::trace!("handshake successful, peer connected");
-            Ok(())
+            Ok(handshake)
 
         } else {
             Err(PeerError::NoHandshake)
         }
     }
 
-    async fn run(&mut self, socket: Framed<TcpStream, MessageCodec>) -> Result<()> {
+    async fn run(&mut self, socket: Framed<PeerStream, MessageCodec>, handshake: Handshake) -> Result<()> {
 
         self.state.connect_time = Some(Instant::now());
-        self.state.update(|state| state.conn_state = ConnState::Introducing);
+        self.last_received = Instant::now();
+        self.state.update(|state| {
+            state.conn_state = ConnState::Introducing;
+            state.peer_id = Some(handshake.peer_id);
+        });
 
         let (mut sink, mut stream) = socket.split();
         let mut ticker = time::interval(time::Duration::from_secs(1));
@@ -145,10 +225,31 @@ impl PeerSession {
             self.send_message(&mut sink, Message::Bitfield(bf)).await?;
         }
 
+        // Bitfield must come first, the port can follow.
+        // Only tell peers running a DHT node about ours.
+        if let Some(port) = self.torrent_ctx.dht_port.filter(|_| handshake.supports_dht()) {
+            self.send_message(&mut sink, Message::Port { port }).await?;
+        }
+
+        // Peer exchange is the only extension used in sessions.
+        if self.torrent_ctx.pex && handshake.supports_extensions() {
+            let ext_handshake = ExtendedHandshake {
+                m: HashMap::from([("ut_pex".to_string(), UT_PEX_ID)]),
+                metadata_size: None,
+            };
+            self.send_message(&mut sink, Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload: bencode::encode_to_raw(&ext_handshake)?,
+            }).await?;
+        }
+
         loop { tokio::select! {
 
             // Message from peer.
-            Some(Ok(msg)) = stream.next() => self.handle_msg(&mut sink, msg).await?,
+            Some(Ok(msg)) = stream.next() => {
+                self.last_received = Instant::now();
+                self.handle_msg(&mut sink, msg).await?
+            },
 
             // Command from elsewhere in application.
             Some(cmd) = self.peer_rx.recv() => {
@@ -160,6 +261,17 @@ impl PeerSession {
                     PeerCommand::PieceWritten(idx) => self.handle_written_piece(&mut sink, idx).await?,
 
                     // From torrent.
+                    PeerCommand::NotInterested => self.lose_interest(&mut sink).await?,
+
+                    PeerCommand::Pex(connected) => self.send_pex(&mut sink, connected).await?,
+
+                    PeerCommand::CancelRequest(request) => {
+                        if self.requests_out.remove(&request) {
+                            self.cancelled.insert(request, Instant::now());
+                            self.send_message(&mut sink, Message::Cancel(request)).await?;
+                        }
+                    },
+
                     PeerCommand::Shutdown => {
                         tracing::trace!("session shutdown");
                         break;
@@ -168,7 +280,15 @@ impl PeerSession {
                 }
             }
 
-            t = ticker.tick() => self.tick(t.into_std()).await?,
+            t = ticker.tick() => {
+                let t = t.into_std();
+                self.tick(t).await?;
+                // Pick up an upload slot freed by another peer.
+                self.try_unchoke(&mut sink).await?;
+                if t.saturating_duration_since(self.last_sent) >= KEEP_ALIVE_INTERVAL {
+                    self.send_message(&mut sink, Message::KeepAlive).await?;
+                }
+            },
 
         }}
 
@@ -180,6 +300,7 @@ impl PeerSession {
     #[inline(always)]
     async fn send_message(&mut self, sink: &mut MessageSink, msg: Message) -> Result<()> {
         tracing::trace!("send: {}", msg);
+        self.last_sent = Instant::now();
         sink.send(msg).await
     }
 
@@ -202,6 +323,9 @@ impl PeerSession {
             Message::Choke => {
                 if !self.state.peer_choking {
                     self.state.peer_choking = true;
+                    if self.state.interested {
+                        self.state.update(|state| state.interested_since = Some(Instant::now()));
+                    }
                     // Free pending requests for other peers.
                     self.free_requests_out().await;
                 }
@@ -219,30 +343,37 @@ impl PeerSession {
             
             Message::Interested => {
                 // TODO: Only send unchoke reciprocally.
-                // TODO: limit upload slots.
                 if !self.state.peer_interested {
                     self.state.peer_interested = true;
-                    self.send_message(sink, Message::Unchoke).await?;
-                    self.state.choked = false;
+                    self.try_unchoke(sink).await?;
                 }
             },
             
-            Message::NotInterested => self.state.peer_interested = false,
+            Message::NotInterested => {
+                self.state.peer_interested = false;
+                self.choke(sink).await?;
+            },
             
             Message::Block(block) => {
-                self.handle_block(block).await;
+                self.handle_block(block).await?;
                 self.make_requests(sink).await?;
             },
             
-            // TODO: do we need to stop whole task if request is invalid?
-            // Will need to match error.
+            // Invalid requests end the session, the peer is either broken or trying to make us read lots of data.
             Message::Request(request) => self.handle_request(request).await?,
             
             Message::Have { idx } => self.handle_have(sink, idx).await?,
             
-            Message::Port { port: _ } => { todo!() },
+            // Peer supports DHT, pass on its node address.
+            Message::Port { port } => {
+                let node = SocketAddr::new(self.address.ip(), port);
+                let _ = self.torrent_ctx.torrent_tx.send(TorrentCommand::DhtNode(node));
+            },
             
             Message::Cancel(block_info) => self.handle_cancel(block_info).await?,
+
+            // May come before the bitfield, so don't leave the introducing state.
+            Message::Extended { id, payload } => return self.handle_extended(id, payload),
         
         }
 
@@ -270,7 +401,7 @@ impl PeerSession {
         bitfield.resize(self.torrent_ctx.info.num_pieces as usize, false);
         // Interested if peer has pieces we don't.
         let interested = self.torrent_ctx.picker.pieces.write().await.bitfield_update(&bitfield);
-        self.state.update(|state| state.num_pieces = bitfield.count_ones() as usize);
+        self.state.update(|state| state.num_pieces = bitfield.count_ones());
         self.bitfield = bitfield;
         self.update_interest(sink, interested).await
     }
@@ -296,35 +427,56 @@ impl PeerSession {
             .await
             .increment_piece(idx as usize);
 
-        self.update_interest(sink, interested).await
+        // A single piece we don't want says nothing about the rest of the peer's pieces.
+        if interested {
+            self.update_interest(sink, true).await?;
+        }
+        Ok(())
     }
 
-    async fn handle_block(&mut self, block: Block) {
+    async fn handle_block(&mut self, block: Block) -> Result<()> {
         
         let request = BlockRequest::from_block(&block);
         if !self.requests_out.remove(&request) {
-            // TODO: penalise peer.
-            // TODO: add defence against random block spamming.
+            // Written at the requested offset, a block of another length would corrupt the piece.
+            if let Some(requested) = self.requests_out
+                .iter()
+                .find(|r| r.piece_idx == request.piece_idx && r.offset == request.offset)
+                .copied()
+            {
+                tracing::warn!("block of length {} for request: {:?}", request.len, requested);
+                self.cancel_request_out(&requested).await;
+                return Err(PeerError::InvalidBlockLength);
+            }
+            if self.cancelled.remove(&request).is_some() {
+                tracing::debug!("block arrived after cancel: {:?}", &request);
+                return Ok(());
+            }
             tracing::warn!("unexpected block: {:?}", &request);
-            return;
+            self.state.update(|state| state.unexpected_blocks += 1);
+            if self.state.unexpected_blocks > self.torrent_ctx.max_unexpected_blocks {
+                return Err(PeerError::UnexpectedBlocks);
+            }
+            return Ok(());
         }
+        self.state.last_block_time = Some(Instant::now());
         
-        let is_duplicate = if let Some(partial_piece) = self
-            .torrent_ctx
-            .picker
-            .partial_pieces
-            .read()
-            .await
-            .get(&request.piece_idx)
-        {
-            partial_piece.write().await.received_block(&request)  
-        } else {
-            // This should'nt be possible.
-            // Maybe it would in end game mode, if piece completed and already written.
-            // Block is being checked for in requests_out, so it should be in partial_pieces.
-            tracing::warn!("received block for non-existent piece: {:?}", &request);
-            return
+        let partial_pieces = self.torrent_ctx.picker.partial_pieces.read().await;
+        let is_duplicate = match partial_pieces.get(&request.piece_idx) {
+            Some(partial_piece) => partial_piece.write().await.received_block(&request),
+            None => {
+                drop(partial_pieces);
+                // Another peer can complete the piece while our request is in flight, mostly in end game.
+                // Pieces are marked as had before their partial piece is removed, so anything else shouldn't happen.
+                if self.torrent_ctx.picker.pieces.read().await.own_bitfield()[request.piece_idx] {
+                    tracing::debug!("block for completed piece: {:?}", &request);
+                } else {
+                    tracing::warn!("received block for non-existent piece: {:?}", &request);
+                }
+                return Ok(());
+            },
         };
+        drop(partial_pieces);
 
         if !is_duplicate {
 
@@ -335,12 +487,20 @@ impl PeerSession {
                     block,
                 });
             self.write_requests.insert(request.piece_idx, request.len);
+
+            // Other peers may have been asked for the same block.
+            if self.torrent_ctx.picker.in_end_game() {
+                let _ = self.torrent_ctx.torrent_tx.send(TorrentCommand::BlockReceived {
+                    address: self.address,
+                    request,
+                });
+            }
                 
         } else {
-            // Again, do we need to check for spamming?
-            // Should allow when in end game mode.
+            // We requested it, so most likely the same block from another peer in end game.
             tracing::warn!("duplicate block: {:?}", &request);
         }
+        Ok(())
     }
     
     async fn handle_request(&mut self, request: BlockRequest) -> Result<()> {
@@ -354,12 +514,12 @@ impl PeerSession {
             tracing::error!("invalid request: {:?}", request);
             return Err(PeerError::InvalidMessage);
         }
-        if self.requests_out.contains(&request) {
+        if self.requests_in.contains(&request) {
             tracing::warn!("duplicate request: {:?}", request);
             return Ok(());
         }
 
-        self.requests_out.insert(request);
+        self.requests_in.insert(request);
         let _ = self.torrent_ctx.disk_tx.send(DiskCommand::ReadBlock {
             id: self.torrent_ctx.info_hash,
             block: request,
@@ -374,7 +534,7 @@ impl PeerSession {
             tracing::warn!("invalid cancel: {:?}", block_info);
             return Err(PeerError::InvalidMessage);
         }
-        self.requests_out.remove(&block_info);
+        self.requests_in.remove(&block_info);
         Ok(())
     }
 
@@ -410,10 +570,15 @@ impl PeerSession {
         
         let requests = self
             .torrent_ctx.picker
-            .pick_blocks(&self.requests_out, 20, &self.bitfield)
+            .pick_blocks(&self.requests_out, self.state.target_queue_len, &self.bitfield)
             .await;
 
+        // Snub timer starts from when we have something to wait for.
+        if self.requests_out.is_empty() && !requests.is_empty() {
+            self.state.last_block_time = Some(Instant::now());
+        }
         for block in requests {
+            self.torrent_ctx.down_limits.acquire(block.len).await;
             tracing::trace!("send request: {:?}", block);
             self.requests_out.insert(block);
             sink.send(Message::Request(block)).await?;
@@ -430,6 +595,7 @@ impl PeerSession {
             tracing::warn!("block read but no request: {:?}", request);
             return Ok(());
         }
+        self.torrent_ctx.up_limits.acquire(request.len).await;
         sink.send(Message::Block(block)).await?;
         self.state.update(|state| state.throughput.up += request.len as u64);
         Ok(())
@@ -439,7 +605,9 @@ impl PeerSession {
     async fn free_requests_out(&mut self) {
         tracing::trace!("freeing requested blocks");
         let partial_pieces = self.torrent_ctx.picker.partial_pieces.read().await;
+        let now = Instant::now();
         for request in self.requests_out.drain() {
+            self.cancelled.insert(request, now);
             if let Some(partial_piece) = partial_pieces.get(&request.piece_idx) {
                 partial_piece.write().await.free_block(&request);
                 tracing::trace!("freed block request: {:?}", request);
@@ -447,17 +615,122 @@ impl PeerSession {
         }
     }
     
+    // Drop one of our requests, freeing the block for other peers.
+    async fn cancel_request_out(&mut self, request: &BlockRequest) -> bool {
+        if !self.requests_out.remove(request) {
+            return false;
+        }
+        if let Some(partial_piece) = self.torrent_ctx.picker.partial_pieces.read().await.get(&request.piece_idx) {
+            partial_piece.write().await.free_block(request);
+        }
+        true
+    }
+
     // If we have BECOME interested, send a message to indicate this.
     async fn update_interest(&mut self, sink: &mut MessageSink, interested: bool) -> Result<()> {
         if !self.state.interested && interested {
-            self.state.interested = true;
+            self.state.update(|state| {
+                state.interested = true;
+                state.interested_since = Some(Instant::now());
+            });
             self.send_message(sink, Message::Interested).await?;
         } else if self.state.interested && !interested {
-            self.state.interested = false;
+            self.state.update(|state| {
+                state.interested = false;
+                state.interested_since = None;
+            });
         }
         Ok(())
     }
 
+    // Unchokes an interested peer if an upload slot is free, otherwise it stays choked until one is.
+    async fn try_unchoke(&mut self, sink: &mut MessageSink) -> Result<()> {
+        if !self.state.peer_interested || !self.state.choked {
+            return Ok(());
+        }
+        let Ok(slot) = self.torrent_ctx.upload_slots.clone().try_acquire_owned() else {
+            return Ok(());
+        };
+        self.upload_slot = Some(slot);
+        self.state.choked = false;
+        self.send_message(sink, Message::Unchoke).await
+    }
+
+    // Chokes the peer, giving up its upload slot and dropping its requests.
+    async fn choke(&mut self, sink: &mut MessageSink) -> Result<()> {
+        if self.state.choked {
+            return Ok(());
+        }
+        self.upload_slot = None;
+        self.state.choked = true;
+        self.requests_in.clear();
+        self.send_message(sink, Message::Choke).await
+    }
+
+    // Tell the peer we are no longer interested, as it has choked us for too long.
+    async fn lose_interest(&mut self, sink: &mut MessageSink) -> Result<()> {
+        if !self.state.interested {
+            return Ok(());
+        }
+        tracing::debug!("choked for too long, no longer interested");
+        self.state.update(|state| {
+            state.interested = false;
+            state.interested_since = None;
+        });
+        self.free_requests_out().await;
+        self.send_message(sink, Message::NotInterested).await
+    }
+
+    fn handle_extended(&mut self, id: u8, payload: Vec<u8>) -> Result<()> {
+        
+        // Peers must not be shared for private torrents.
+        if !self.torrent_ctx.pex {
+            return Ok(());
+        }
+
+        match id {
+            EXTENDED_HANDSHAKE_ID => {
+                let ext_handshake: ExtendedHandshake = bencode::decode_bytes(&payload)?;
+                self.pex_id = ext_handshake.id("ut_pex");
+            },
+            UT_PEX_ID => {
+                let pex: PexMessage = bencode::decode_bytes(&payload)?;
+                let mut added = pex.added();
+                added.truncate(MAX_PEX_PEERS);
+                tracing::debug!("peer exchange added {} peers, dropped {}", added.len(), pex.dropped().len());
+                if !added.is_empty() {
+                    let _ = self.torrent_ctx.torrent_tx.send(TorrentCommand::Peers { peers: added, tracker: None });
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // Tells the peer which peers have connected or dropped since the last exchange.
+    async fn send_pex(&mut self, sink: &mut MessageSink, connected: Vec<SocketAddr>) -> Result<()> {
+
+        let Some(id) = self.pex_id else {
+            return Ok(());
+        };
+        let connected: HashSet<SocketAddr> = connected
+            .into_iter()
+            .filter(|address| *address != self.address)
+            .collect();
+        let added: Vec<SocketAddr> = connected.difference(&self.pex_sent).take(MAX_PEX_PEERS).copied().collect();
+        let dropped: Vec<SocketAddr> = self.pex_sent.difference(&connected).take(MAX_PEX_PEERS).copied().collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(());
+        }
+
+        self.pex_sent.extend(&added);
+        for address in &dropped {
+            self.pex_sent.remove(address);
+        }
+        let payload = bencode::encode_to_raw(&PexMessage::new(&added, &dropped))?;
+        self.send_message(sink, Message::Extended { id, payload }).await
+    }
+
     async fn tick(&mut self, time: Instant) -> Result<()> {
     
         // Disconnect for inactivity.
@@ -469,6 +742,38 @@ impl PeerSession {
             return Err(PeerError::Timeout)
         }
 
+        if time.saturating_duration_since(self.unexpected_window) >= UNEXPECTED_BLOCK_WINDOW {
+            self.unexpected_window = time;
+            if self.state.unexpected_blocks > 0 {
+                self.state.update(|state| state.unexpected_blocks = 0);
+            }
+        }
+        self.cancelled.retain(|_, at| time.saturating_duration_since(*at) < CANCELLED_GRACE);
+
+        if time.saturating_duration_since(self.last_received) >= IDLE_TIMEOUT {
+            tracing::debug!("peer went silent");
+            return Err(PeerError::Timeout)
+        }
+
+        // Disconnect if unchoked with requests pending but nothing has arrived, so another peer can be tried.
+        if self.state.interested
+        && !self.state.peer_choking
+        && !self.requests_out.is_empty()
+        && self.state.last_block_time.is_some_and(|last| time.saturating_duration_since(last) >= SNUB_TIMEOUT)
+        {
+            tracing::debug!("peer snubbed us");
+            return Err(PeerError::Snubbed)
+        }
+
+        // Size the request queue to the peer's rate, once it has given us something to measure.
+        let rate = self.state.throughput.down.avg();
+        if rate > 0 {
+            let target = target_queue_len(rate, self.torrent_ctx.max_request_queue);
+            if target != self.state.target_queue_len {
+                self.state.update(|state| state.target_queue_len = target);
+            }
+        }
+
         // Send stats if there is a state change.
         if self.state.changed {
             let _ = self.torrent_ctx.torrent_tx.send(TorrentCommand::PeerState {
@@ -481,3 +786,252 @@ impl PeerSession {
         Ok(())
     }
 }
+
+// Lets tests inspect the requests of a session directly.
+#[cfg(test)]
+impl PeerSession {
+
+    pub fn requests_out(&self) -> &HashSet<BlockRequest> {
+        &self.requests_out
+    }
+
+    pub fn requests_in(&self) -> &HashSet<BlockRequest> {
+        &self.requests_in
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{info::TorrentInfo, picker::Picker, MetaInfo};
+
+    fn test_ctx() -> Arc<TorrentContext> {
+        let metainfo = MetaInfo::new("tests/test_torrents/test_multi.torrent").unwrap();
+        let info = TorrentInfo::new(&metainfo);
+        Arc::new(TorrentContext {
+            info_hash: metainfo.info_hash(),
+            client_id: [0; 20],
+            picker: Picker::new(info.clone(), Default::default()),
+            torrent_tx: mpsc::unbounded_channel().0,
+            disk_tx: mpsc::unbounded_channel().0,
+            dht_port: None,
+            pex: true,
+            down_limits: Default::default(),
+            up_limits: Default::default(),
+            upload_slots: Arc::new(tokio::sync::Semaphore::new(1)),
+            max_unexpected_blocks: 2,
+            max_request_queue: 500,
+            connect_timeout: time::Duration::from_secs(10),
+            connect_options: ConnectOptions::default(),
+            info,
+        })
+    }
+
+    fn test_session() -> PeerSession {
+        PeerSession::new("127.0.0.1:6881".parse().unwrap(), test_ctx()).0
+    }
+
+    // Sink to a connected socket, with the other end returned to keep it open.
+    async fn test_sink() -> (MessageSink, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (other, _) = listener.accept().await.unwrap();
+        let (sink, _) = Framed::new(PeerStream::new(Box::new(stream)), MessageCodec::default()).split();
+        (sink, other)
+    }
+
+    #[tokio::test]
+    async fn test_requests_in() {
+        let mut session = test_session();
+        let request = BlockRequest { piece_idx: 0, offset: 0, len: crate::BLOCK_SIZE };
+        
+        // Requests whilst choked are rejected.
+        assert!(session.handle_request(request).await.is_err());
+        
+        session.state.choked = false;
+        session.handle_request(request).await.unwrap();
+        assert!(session.requests_in().contains(&request));
+        assert!(session.requests_out().is_empty());
+
+        session.handle_cancel(request).await.unwrap();
+        assert!(session.requests_in().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_request_out() {
+        let mut session = test_session();
+        let bf = Bitfield::repeat(true, session.torrent_ctx.info.num_pieces as usize);
+        session.torrent_ctx.picker.pieces.write().await.bitfield_update(&bf);
+        
+        let requests = session.torrent_ctx.picker.pick_blocks(&session.requests_out, 2, &bf).await;
+        session.requests_out.extend(requests.iter().copied());
+        assert_eq!(session.requests_out().len(), 2);
+
+        // A cancelled block is free to be picked again.
+        assert!(session.cancel_request_out(&requests[0]).await);
+        assert!(!session.cancel_request_out(&requests[0]).await);
+        let repicked = session.torrent_ctx.picker.pick_blocks(session.requests_out(), 2, &bf).await;
+        assert_eq!(repicked, vec![requests[0]]);
+    }
+
+    #[tokio::test]
+    async fn test_choke_frees_blocks() {
+        let ctx = test_ctx();
+        let (mut sink, _other) = test_sink().await;
+        let mut a = PeerSession::new("127.0.0.1:6881".parse().unwrap(), ctx.clone()).0;
+        let b = PeerSession::new("127.0.0.1:6882".parse().unwrap(), ctx.clone()).0;
+        // Both peers only have piece 0.
+        let mut bf = Bitfield::repeat(false, ctx.info.num_pieces as usize);
+        bf.set(0, true);
+        ctx.picker.pieces.write().await.bitfield_update(&bf);
+        ctx.picker.pieces.write().await.bitfield_update(&bf);
+
+        a.state.peer_choking = false;
+        let requests = ctx.picker.pick_blocks(&a.requests_out, 2, &bf).await;
+        assert!(requests.iter().all(|r| r.piece_idx == 0));
+        a.requests_out.extend(requests.iter().copied());
+
+        a.handle_msg(&mut sink, Message::Choke).await.unwrap();
+        assert!(a.requests_out().is_empty());
+
+        // B gets the blocks A was asked for.
+        let repicked = ctx.picker.pick_blocks(b.requests_out(), requests.len(), &bf).await;
+        assert_eq!(repicked, requests);
+    }
+
+    #[tokio::test]
+    async fn test_have_keeps_interest() {
+        let mut session = test_session();
+        let (mut sink, _other) = test_sink().await;
+        let num_pieces = session.torrent_ctx.info.num_pieces as usize;
+        let mut wanted = Bitfield::repeat(false, num_pieces);
+        wanted.set(0, true);
+        session.torrent_ctx.picker.pieces.write().await.set_range(wanted);
+
+        session.handle_have(&mut sink, 0).await.unwrap();
+        assert!(session.state.interested);
+
+        // An unwanted piece doesn't withdraw interest in the wanted one.
+        session.handle_have(&mut sink, 1).await.unwrap();
+        assert!(session.state.interested);
+    }
+
+    #[tokio::test]
+    async fn test_extended_deeply_nested() {
+        let mut session = test_session();
+        // Deep enough to overflow the stack if decoded without a limit.
+        let payload = format!("d1:x{}e", "l".repeat(100_000)).into_bytes();
+        for id in [EXTENDED_HANDSHAKE_ID, UT_PEX_ID] {
+            assert!(matches!(
+                session.handle_extended(id, payload.clone()),
+                Err(PeerError::BencodeError(bencode::Error::TooDeep)),
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snubbed() {
+        let mut session = test_session();
+        let now = Instant::now();
+        session.state.connect_time = Some(now);
+        session.state.interested = true;
+        session.state.peer_choking = false;
+        session.requests_out.insert(BlockRequest { piece_idx: 0, offset: 0, len: crate::BLOCK_SIZE });
+
+        session.state.last_block_time = Some(now);
+        assert!(session.tick(now + SNUB_TIMEOUT / 2).await.is_ok());
+        assert!(matches!(session.tick(now + SNUB_TIMEOUT).await, Err(PeerError::Snubbed)));
+
+        // Not snubbing if we're choked, that's left to the choked timeout.
+        session.state.peer_choking = true;
+        assert!(session.tick(now + SNUB_TIMEOUT).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let mut session = test_session();
+        let now = Instant::now();
+        session.state.connect_time = Some(now);
+        session.state.interested = true;
+
+        session.last_received = now;
+        assert!(session.tick(now + IDLE_TIMEOUT / 2).await.is_ok());
+        assert!(matches!(session.tick(now + IDLE_TIMEOUT).await, Err(PeerError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_upload_slots() {
+        let ctx = test_ctx();
+        let (mut sink, _other) = test_sink().await;
+        let mut a = PeerSession::new("127.0.0.1:6881".parse().unwrap(), ctx.clone()).0;
+        let mut b = PeerSession::new("127.0.0.1:6882".parse().unwrap(), ctx).0;
+
+        a.handle_msg(&mut sink, Message::Interested).await.unwrap();
+        b.handle_msg(&mut sink, Message::Interested).await.unwrap();
+        assert!(!a.state.choked);
+        assert!(b.state.choked);
+
+        // Slot is freed when the unchoked peer loses interest.
+        a.handle_msg(&mut sink, Message::NotInterested).await.unwrap();
+        assert!(a.state.choked);
+        b.try_unchoke(&mut sink).await.unwrap();
+        assert!(!b.state.choked);
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_blocks() {
+        let mut session = test_session();
+        let bf = Bitfield::repeat(true, session.torrent_ctx.info.num_pieces as usize);
+        session.torrent_ctx.picker.pieces.write().await.bitfield_update(&bf);
+        let block = |request: BlockRequest| Block::from_block_request(&request, crate::block::BlockData::Owned(vec![0; request.len]));
+
+        // Blocks for cancelled requests are let through.
+        let requests = session.torrent_ctx.picker.pick_blocks(&session.requests_out, 1, &bf).await;
+        session.requests_out.extend(requests.iter().copied());
+        session.free_requests_out().await;
+        session.handle_block(block(requests[0])).await.unwrap();
+        assert_eq!(session.state.unexpected_blocks, 0);
+
+        let unrequested = BlockRequest { piece_idx: 0, offset: 0, len: crate::BLOCK_SIZE };
+        session.handle_block(block(unrequested)).await.unwrap();
+        session.handle_block(block(unrequested)).await.unwrap();
+        assert!(matches!(session.handle_block(block(unrequested)).await, Err(PeerError::UnexpectedBlocks)));
+    }
+
+    #[tokio::test]
+    async fn test_block_wrong_length() {
+        let mut session = test_session();
+        let bf = Bitfield::repeat(true, session.torrent_ctx.info.num_pieces as usize);
+        session.torrent_ctx.picker.pieces.write().await.bitfield_update(&bf);
+        let requests = session.torrent_ctx.picker.pick_blocks(&session.requests_out, 1, &bf).await;
+        session.requests_out.extend(requests.iter().copied());
+
+        let short = Block::from_block_request(&requests[0], crate::block::BlockData::Owned(vec![0; requests[0].len - 1]));
+        assert!(matches!(session.handle_block(short).await, Err(PeerError::InvalidBlockLength)));
+        assert!(session.write_requests.is_empty());
+
+        // The block is free for another peer.
+        let repicked = session.torrent_ctx.picker.pick_blocks(session.requests_out(), 1, &bf).await;
+        assert_eq!(repicked, requests);
+    }
+
+    #[tokio::test]
+    async fn test_block_for_completed_piece() {
+        let mut session = test_session();
+        let bf = Bitfield::repeat(true, session.torrent_ctx.info.num_pieces as usize);
+        session.torrent_ctx.picker.pieces.write().await.bitfield_update(&bf);
+        let requests = session.torrent_ctx.picker.pick_blocks(&session.requests_out, 1, &bf).await;
+        session.requests_out.extend(requests.iter().copied());
+
+        // Another peer completes the piece first.
+        let idx = requests[0].piece_idx;
+        session.torrent_ctx.picker.pieces.write().await.received_piece(idx);
+        session.torrent_ctx.picker.partial_pieces.write().await.remove(&idx);
+
+        let block = Block::from_block_request(&requests[0], crate::block::BlockData::Owned(vec![0; requests[0].len]));
+        session.handle_block(block).await.unwrap();
+        assert!(session.requests_out.is_empty());
+        assert!(session.write_requests.is_empty());
+        assert_eq!(session.state.unexpected_blocks, 0);
+    }
+}
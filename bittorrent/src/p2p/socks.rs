@@ -0,0 +1,144 @@
+use std::{io, net::SocketAddr};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+use crate::config::ProxyConfig;
+
+// Asks a SOCKS5 proxy to connect to a peer, over a stream already connected to the proxy.
+// Once this returns the stream carries the peer connection.
+// Reference: https://www.rfc-editor.org/rfc/rfc1928, https://www.rfc-editor.org/rfc/rfc1929
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+pub async fn handshake(stream: &mut TcpStream, address: SocketAddr, proxy: &ProxyConfig) -> io::Result<()> {
+
+    let method = if proxy.auth.is_some() { METHOD_PASSWORD } else { METHOD_NO_AUTH };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(error("not a socks5 proxy"));
+    }
+    // Proxies answer 0xFF if they don't accept the method offered.
+    if reply[1] != method {
+        return Err(error("proxy rejected authentication method"));
+    }
+
+    if let Some((username, password)) = &proxy.auth {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(error("proxy username or password too long"));
+        }
+        let mut buf = vec![1, username.len() as u8];
+        buf.extend_from_slice(username.as_bytes());
+        buf.push(password.len() as u8);
+        buf.extend_from_slice(password.as_bytes());
+        stream.write_all(&buf).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(error("proxy authentication failed"));
+        }
+    }
+
+    let mut buf = vec![VERSION, CMD_CONNECT, 0];
+    match address {
+        SocketAddr::V4(addr) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+        },
+        SocketAddr::V6(addr) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+        },
+    }
+    buf.extend_from_slice(&address.port().to_be_bytes());
+    stream.write_all(&buf).await?;
+
+    // Version, reply code, reserved and the type of the bound address that follows.
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(error(&format!("proxy failed to connect to peer, reply code {}", reply[1])));
+    }
+    // Bound address and port aren't needed.
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(error("proxy sent invalid address type")),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::other(format!("socks5: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_handshake_with_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyConfig {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            auth: Some(("user".to_string(), "pass".to_string())),
+        };
+        let peer: SocketAddr = "203.0.113.7:6881".parse().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            socket.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0; 11];
+            socket.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            socket.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 203, 0, 113, 7, 0x1A, 0xE1]);
+            socket.write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x1F, 0x90]).await.unwrap();
+            socket.write_all(b"peer data").await.unwrap();
+        });
+
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await.unwrap();
+        handshake(&mut stream, peer, &proxy).await.unwrap();
+        // Anything after the reply comes from the peer.
+        let mut data = [0; 9];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"peer data");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyConfig { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port(), auth: None };
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 3];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            // Connection refused.
+            socket.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        });
+
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await.unwrap();
+        assert!(handshake(&mut stream, "203.0.113.7:6881".parse().unwrap(), &proxy).await.is_err());
+    }
+}
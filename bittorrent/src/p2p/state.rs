@@ -1,4 +1,19 @@
-use crate::stats::ThroughputStats;
+use crate::{stats::ThroughputStats, BLOCK_SIZE, ID};
+
+// Requests in flight before the peer's download rate is known.
+pub const INITIAL_REQUEST_QUEUE: usize = 20;
+
+pub const MIN_REQUEST_QUEUE: usize = 4;
+
+// Enough requests are kept in flight to cover this many seconds of the peer's download rate.
+// A rough bandwidth-delay product, generous to allow for high latency peers.
+const REQUEST_QUEUE_SECS: u64 = 3;
+
+// Request queue length for a peer downloading at rate bytes per second.
+pub fn target_queue_len(rate: u64, max: usize) -> usize {
+    let blocks = (rate * REQUEST_QUEUE_SECS / BLOCK_SIZE as u64) as usize;
+    blocks.clamp(MIN_REQUEST_QUEUE, max.max(MIN_REQUEST_QUEUE))
+}
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ConnState {
@@ -8,11 +23,28 @@ pub enum ConnState {
     Disconnected,
 }
 
+// What the crypto handshake settled on for the rest of the connection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CryptoMethod {
+    // Only the handshake was hidden.
+    Plaintext,
+    Rc4,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct SessionState {
 
     pub conn_state: ConnState,
 
+    // From the peer's handshake.
+    pub peer_id: Option<ID>,
+
+    // None if the connection didn't start with a crypto handshake.
+    pub crypto: Option<CryptoMethod>,
+
+    // Whether the connection is over uTP rather than TCP.
+    pub utp: bool,
+
     // Whether we are answering the peer's requests.
     pub choked: bool,
 
@@ -25,13 +57,25 @@ pub struct SessionState {
     // Whether the peer is interested in our pieces.
     pub peer_interested: bool,
 
-    // Stats on download/upload throughput.
+    // Stats on download/upload throughput, with totals over the session.
     pub throughput: ThroughputStats,
 
     pub num_pieces: usize,
 
     pub connect_time: Option<std::time::Instant>,
 
+    // When we became interested, or were last choked whilst interested.
+    pub interested_since: Option<std::time::Instant>,
+
+    // When a requested block last arrived, or when we started waiting on requests if none have since.
+    pub last_block_time: Option<std::time::Instant>,
+
+    // Blocks received that we didn't request, in the current window.
+    pub unexpected_blocks: usize,
+
+    // Number of block requests to keep in flight to the peer.
+    pub target_queue_len: usize,
+
     pub changed: bool,
 
 }
@@ -40,6 +84,9 @@ impl Default for SessionState {
     fn default() -> SessionState {
         SessionState {
             conn_state: ConnState::Disconnected,
+            peer_id: None,
+            crypto: None,
+            utp: false,
             choked: true,
             interested: false,
             peer_choking: true,
@@ -47,15 +94,39 @@ impl Default for SessionState {
             throughput: ThroughputStats::default(),
             num_pieces: 0,
             connect_time: None,
+            interested_since: None,
+            last_block_time: None,
+            unexpected_blocks: 0,
+            target_queue_len: INITIAL_REQUEST_QUEUE,
             changed: false,
         }
     }
 }
 
 impl SessionState {
+
+    // Name and version of the peer's client, once its handshake is in.
+    pub fn client(&self) -> Option<String> {
+        self.peer_id.as_ref().map(super::peer_id::peer_id_to_client)
+    }
+
     #[inline(always)]
     pub fn update(&mut self, f: impl FnOnce(&mut SessionState)) {
         f(self);
         self.changed = true;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_queue_len() {
+        assert_eq!(target_queue_len(0, 500), MIN_REQUEST_QUEUE);
+        // 1MB/s over 3 seconds of 16KiB blocks.
+        assert_eq!(target_queue_len(1 << 20, 500), 192);
+        assert_eq!(target_queue_len(100 << 20, 500), 500);
+        assert_eq!(target_queue_len(1 << 20, 1), MIN_REQUEST_QUEUE);
+    }
+}
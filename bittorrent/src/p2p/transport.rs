@@ -0,0 +1,97 @@
+use std::{fmt::Debug, io, net::{IpAddr, SocketAddr}};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpSocket, TcpStream}, time};
+use crate::{config::{Config, Encryption, ProxyConfig, Transport}, ID};
+use super::{mse::{self, PeerStream}, socks, utp};
+
+// How long a peer has to answer a uTP connection before we try TCP instead.
+const UTP_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+
+// Byte stream a peer connection runs over.
+// Sync, so errors holding a connection like ClientError are Sync too.
+pub trait PeerTransport: AsyncRead + AsyncWrite + Send + Sync + Unpin + Debug {
+    fn is_utp(&self) -> bool {
+        false
+    }
+}
+
+impl PeerTransport for TcpStream {}
+
+// How outbound connections to peers are made, taken from the config.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+
+    // Local address to connect from.
+    pub bind_interface: Option<IpAddr>,
+
+    pub proxy: Option<ProxyConfig>,
+
+    pub encryption: Encryption,
+
+    pub transport: Transport,
+
+}
+
+impl ConnectOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            bind_interface: config.bind_interface,
+            proxy: config.proxy.clone(),
+            encryption: config.encryption,
+            transport: config.transport,
+        }
+    }
+}
+
+// Outbound connection to a peer, starting with the crypto handshake unless encryption is disabled.
+// If that fails the peer likely doesn't support it, so unless encryption is forced we reconnect in plaintext.
+pub async fn connect_peer(address: SocketAddr, info_hash: ID, options: &ConnectOptions) -> io::Result<PeerStream> {
+    let transport = open(address, options).await?;
+    if options.encryption == Encryption::Disabled {
+        return Ok(PeerStream::new(transport));
+    }
+    match mse::initiate(transport, info_hash, options.encryption).await {
+        Ok(stream) => Ok(stream),
+        Err(e) if options.encryption == Encryption::Enabled => {
+            tracing::debug!("encrypted handshake failed, reconnecting in plaintext: {}", e);
+            Ok(PeerStream::new(open(address, options).await?))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// uTP is tried first if chosen, peers that don't answer it are connected to over TCP.
+// Proxies only carry TCP, so uTP isn't used with one.
+async fn open(address: SocketAddr, options: &ConnectOptions) -> io::Result<Box<dyn PeerTransport>> {
+    if options.transport == Transport::Utp && options.proxy.is_none() {
+        match time::timeout(UTP_CONNECT_TIMEOUT, utp::connect(address, options.bind_interface)).await {
+            Ok(Ok(stream)) => return Ok(Box::new(stream)),
+            Ok(Err(e)) => tracing::debug!("utp connection failed, trying tcp: {}", e),
+            Err(_) => tracing::debug!("utp connection timed out, trying tcp"),
+        }
+    }
+    Ok(Box::new(connect_tcp(address, options.bind_interface, options.proxy.as_ref()).await?))
+}
+
+// Through the proxy if there is one.
+async fn connect_tcp(address: SocketAddr, local_ip: Option<IpAddr>, proxy: Option<&ProxyConfig>) -> io::Result<TcpStream> {
+    let Some(proxy) = proxy else {
+        return dial(address, local_ip).await;
+    };
+    let proxy_address = tokio::net::lookup_host((proxy.host.as_str(), proxy.port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "proxy host not found"))?;
+    let mut stream = dial(proxy_address, local_ip).await?;
+    socks::handshake(&mut stream, address, proxy).await?;
+    Ok(stream)
+}
+
+// Made from the given local address if there is one.
+async fn dial(address: SocketAddr, local_ip: Option<IpAddr>) -> io::Result<TcpStream> {
+    let Some(local_ip) = local_ip else {
+        return TcpStream::connect(address).await;
+    };
+    let socket = if address.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.bind(SocketAddr::new(local_ip, 0))?;
+    socket.connect(address).await
+}
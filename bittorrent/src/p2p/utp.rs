@@ -0,0 +1,603 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    net::UdpSocket,
+    time::{self, Duration, Instant},
+};
+use super::transport::PeerTransport;
+
+// Micro transport protocol, a reliable stream over UDP that backs off as soon as it sees queueing delay,
+// so it gives way to the user's other traffic. Only outbound connections are made so far.
+// Reference: https://www.bittorrent.org/beps/bep_0029.html
+
+const ST_DATA: u8   = 0;
+const ST_FIN: u8    = 1;
+const ST_STATE: u8  = 2;
+const ST_RESET: u8  = 3;
+const ST_SYN: u8    = 4;
+const VERSION: u8   = 1;
+
+const HEADER_LEN: usize = 20;
+
+// Payload of each data packet, small enough to fit a typical MTU.
+const PAYLOAD_SIZE: usize = 1200;
+
+// Window advertised for data received but not yet taken by the stream.
+const RECV_WINDOW: usize = 1024 * 1024;
+
+// Packets further ahead than this are dropped rather than held for reordering.
+const MAX_REORDER: u16 = 1024;
+
+// Bytes buffered between the stream and the connection task, each way.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// LEDBAT aims to add this much queueing delay, the window shrinks above it and grows below it.
+const TARGET_DELAY: f64 = 100_000.0; // microseconds
+// Most the window grows by in a round trip.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: usize = PAYLOAD_SIZE;
+const INITIAL_WINDOW: usize = PAYLOAD_SIZE * 2;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Times a SYN is resent before the peer is taken not to support uTP.
+const SYN_RESENDS: u32 = 2;
+// Times a packet is resent before the connection is given up on.
+const MAX_RESENDS: u32 = 5;
+// Acks for the same packet before the one after it is taken as lost.
+const DUPLICATE_ACKS: u32 = 3;
+
+// Connects to a peer over uTP, the connection is run by its own task until both sides are done.
+pub async fn connect(address: SocketAddr, local_ip: Option<IpAddr>) -> io::Result<UtpStream> {
+    let local_ip = local_ip.unwrap_or(match address {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+    socket.connect(address).await?;
+
+    let mut conn = Connection::new(socket, rand::random());
+    conn.handshake().await?;
+
+    let (stream, conn_end) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = conn.run(conn_end).await {
+            tracing::debug!("utp connection to {} closed: {}", address, e);
+        }
+    });
+    Ok(UtpStream { stream })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Header {
+    kind: u8,
+    connection_id: u16,
+    // Microseconds on the sender's clock when the packet was sent.
+    timestamp: u32,
+    // Difference between the sender's clock and the timestamp of the last packet it received.
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.push((self.kind << 4) | VERSION);
+        // No extensions.
+        buf.push(0);
+        buf.extend_from_slice(&self.connection_id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        buf.extend_from_slice(&self.wnd_size.to_be_bytes());
+        buf.extend_from_slice(&self.seq_nr.to_be_bytes());
+        buf.extend_from_slice(&self.ack_nr.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    // Returns the header and where the payload starts, after any extensions.
+    fn decode(buf: &[u8]) -> Option<(Header, usize)> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0F != VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let header = Header {
+            kind: buf[0] >> 4,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+        // Extensions are a chain of (next extension, length, data), none are used so they're skipped.
+        let mut extension = buf[1];
+        let mut offset = HEADER_LEN;
+        while extension != 0 {
+            extension = *buf.get(offset)?;
+            offset += 2 + *buf.get(offset + 1)? as usize;
+        }
+        (offset <= buf.len()).then_some((header, offset))
+    }
+}
+
+// Data or FIN packet waiting to be acked.
+struct SentPacket {
+    kind: u8,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    resends: u32,
+}
+
+struct Connection {
+
+    socket: UdpSocket,
+
+    // We send with send_id, the peer with recv_id.
+    send_id: u16,
+
+    recv_id: u16,
+
+    // Next sequence number to send.
+    seq_nr: u16,
+
+    // Last sequence number received in order.
+    ack_nr: u16,
+
+    // Our timestamps count from here.
+    start: Instant,
+
+    // Sent back as timestamp_diff, from the last packet the peer sent.
+    reply_micro: u32,
+
+    in_flight: VecDeque<SentPacket>,
+
+    bytes_in_flight: usize,
+
+    // Congestion window in bytes, set by LEDBAT.
+    max_window: f64,
+
+    peer_window: usize,
+
+    // Lowest delay seen, taken as the delay with nothing queued. Clock drift over long connections is ignored.
+    base_delay: Option<u32>,
+
+    rtt: Option<Duration>,
+
+    rtt_var: Duration,
+
+    timeout: Duration,
+
+    duplicate_acks: u32,
+
+    // Packets that came early, waiting on the ones before them.
+    out_of_order: HashMap<u16, Vec<u8>>,
+
+    // In order data the stream hasn't taken yet.
+    received: BytesMut,
+
+    // Set when the stream stops taking data, anything received after is dropped.
+    discard: bool,
+
+    // Sequence number of the peer's FIN, once it arrives.
+    fin_nr: Option<u16>,
+
+    // Everything up to the peer's FIN has been received.
+    peer_finished: bool,
+
+    fin_sent: bool,
+
+}
+
+impl Connection {
+
+    fn new(socket: UdpSocket, recv_id: u16) -> Self {
+        Self {
+            socket,
+            send_id: recv_id.wrapping_add(1),
+            recv_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            start: Instant::now(),
+            reply_micro: 0,
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            max_window: INITIAL_WINDOW as f64,
+            peer_window: RECV_WINDOW,
+            base_delay: None,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            duplicate_acks: 0,
+            out_of_order: HashMap::new(),
+            received: BytesMut::new(),
+            discard: false,
+            fin_nr: None,
+            peer_finished: false,
+            fin_sent: false,
+        }
+    }
+
+    // Sends a SYN until the peer acks it.
+    async fn handshake(&mut self) -> io::Result<()> {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        let mut buf = [0; 2048];
+        let mut timeout = INITIAL_TIMEOUT;
+        for _ in 0..=SYN_RESENDS {
+            self.send(ST_SYN, seq_nr, &[]).await?;
+            let deadline = Instant::now() + timeout;
+            while let Ok(len) = time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                let Some((header, _)) = Header::decode(&buf[..len?]) else { continue };
+                if header.connection_id != self.recv_id {
+                    continue;
+                }
+                match header.kind {
+                    ST_STATE if header.ack_nr == seq_nr => {
+                        // The STATE doesn't use up a sequence number, the peer's first data packet has the same one.
+                        self.ack_nr = header.seq_nr.wrapping_sub(1);
+                        self.peer_window = header.wnd_size as usize;
+                        self.reply_micro = self.now_micros().wrapping_sub(header.timestamp);
+                        return Ok(());
+                    },
+                    ST_RESET => return Err(io::ErrorKind::ConnectionRefused.into()),
+                    _ => {},
+                }
+            }
+            timeout *= 2;
+        }
+        Err(io::ErrorKind::TimedOut.into())
+    }
+
+    // Moves data between the stream and the peer.
+    // Peer connections are only ever closed whole, so once our FIN is acked we're done.
+    async fn run(mut self, stream: DuplexStream) -> io::Result<()> {
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut send_buf = vec![0; PAYLOAD_SIZE];
+        let mut recv_buf = vec![0; 64 * 1024];
+        let mut writer_closed = false;
+        loop {
+
+            if self.fin_sent && self.in_flight.is_empty() {
+                return Ok(());
+            }
+            // The stream reads to its end once the peer has finished.
+            if self.peer_finished && self.received.is_empty() && !writer_closed {
+                let _ = writer.shutdown().await;
+                writer_closed = true;
+            }
+
+            let can_send = !self.fin_sent && self.bytes_in_flight + PAYLOAD_SIZE <= self.window();
+            let resend_at = self.in_flight.front().map(|packet| packet.sent_at + self.timeout);
+            tokio::select! {
+
+                read = reader.read(&mut send_buf), if can_send => match read? {
+                    0 => {
+                        self.send_packet(ST_FIN, Vec::new()).await?;
+                        self.fin_sent = true;
+                    },
+                    n => self.send_packet(ST_DATA, send_buf[..n].to_vec()).await?,
+                },
+
+                written = writer.write(&self.received), if !self.received.is_empty() && !writer_closed => match written {
+                    Ok(n) => self.received.advance(n),
+                    Err(_) => {
+                        self.received.clear();
+                        self.discard = true;
+                        writer_closed = true;
+                    },
+                },
+
+                len = self.socket.recv(&mut recv_buf) => self.handle_packet(&recv_buf[..len?]).await?,
+
+                _ = time::sleep_until(resend_at.unwrap_or_else(Instant::now)), if resend_at.is_some() => {
+                    self.handle_timeout().await?;
+                },
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Some((header, offset)) = Header::decode(buf) else { return Ok(()) };
+        if header.connection_id != self.recv_id {
+            return Ok(());
+        }
+        if header.kind == ST_RESET {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        self.reply_micro = self.now_micros().wrapping_sub(header.timestamp);
+        self.peer_window = header.wnd_size as usize;
+        self.handle_ack(&header).await?;
+
+        match header.kind {
+            ST_DATA => {
+                self.handle_data(header.seq_nr, buf[offset..].to_vec());
+                self.send_ack().await?;
+            },
+            // A FIN takes a sequence number, the peer is finished once everything before it is in.
+            ST_FIN => {
+                self.fin_nr = Some(header.seq_nr);
+                self.handle_data(header.seq_nr, Vec::new());
+                self.send_ack().await?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // Everything up to the ack number has arrived.
+    async fn handle_ack(&mut self, header: &Header) -> io::Result<()> {
+        let now = Instant::now();
+        let mut acked_bytes = 0;
+        let mut acked_any = false;
+        while let Some(packet) = self.in_flight.front() {
+            // Sent after the acked packet.
+            if header.ack_nr.wrapping_sub(packet.seq_nr) >= 0x8000 {
+                break;
+            }
+            let packet = self.in_flight.pop_front().unwrap();
+            acked_bytes += packet.payload.len();
+            self.bytes_in_flight -= packet.payload.len();
+            // Resent packets can't tell which send the ack was for.
+            if packet.resends == 0 {
+                self.update_rtt(now - packet.sent_at);
+            }
+            acked_any = true;
+        }
+
+        if acked_any {
+            self.duplicate_acks = 0;
+            self.update_window(header.timestamp_diff, acked_bytes);
+        } else if header.kind == ST_STATE && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS {
+                self.resend_first().await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_data(&mut self, seq_nr: u16, payload: Vec<u8>) {
+        let ahead = seq_nr.wrapping_sub(self.ack_nr);
+        // Already received, or too far ahead to hold on to.
+        if ahead == 0 || ahead > MAX_REORDER {
+            return;
+        }
+        if ahead > 1 {
+            self.out_of_order.insert(seq_nr, payload);
+            return;
+        }
+        self.deliver(seq_nr, payload);
+        while let Some(payload) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.deliver(self.ack_nr.wrapping_add(1), payload);
+        }
+    }
+
+    fn deliver(&mut self, seq_nr: u16, payload: Vec<u8>) {
+        self.ack_nr = seq_nr;
+        if self.fin_nr == Some(seq_nr) {
+            self.peer_finished = true;
+        }
+        if !self.discard {
+            self.received.extend_from_slice(&payload);
+        }
+    }
+
+    async fn handle_timeout(&mut self) -> io::Result<()> {
+        if self.in_flight.front().is_some_and(|packet| packet.resends == MAX_RESENDS) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        // Loss is taken as congestion, start again from the smallest window.
+        self.max_window = MIN_WINDOW as f64;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.resend_first().await
+    }
+
+    async fn resend_first(&mut self) -> io::Result<()> {
+        let Some(packet) = self.in_flight.front() else { return Ok(()) };
+        self.send(packet.kind, packet.seq_nr, &packet.payload).await?;
+        if let Some(packet) = self.in_flight.front_mut() {
+            packet.resends += 1;
+            packet.sent_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    // Smoothed round trip time, which the resend timeout is set from.
+    fn update_rtt(&mut self, sample: Duration) {
+        let rtt = match self.rtt {
+            None => {
+                self.rtt_var = sample / 2;
+                sample
+            },
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                (rtt * 7 + sample) / 8
+            },
+        };
+        self.rtt = Some(rtt);
+        self.timeout = (rtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    // LEDBAT, grows the window while the delay the peer sees is under target and shrinks it above.
+    // The peer's timestamp_diff is the one way delay of our packets plus the difference in clocks,
+    // taking away the lowest one seen leaves the delay from queueing.
+    fn update_window(&mut self, delay: u32, acked_bytes: usize) {
+        // The peer hasn't got a measurement yet.
+        if delay == 0 {
+            return;
+        }
+        let base_delay = self.base_delay.map_or(delay, |base| base.min(delay));
+        self.base_delay = Some(base_delay);
+        let queueing = delay.wrapping_sub(base_delay) as f64;
+        let off_target = ((TARGET_DELAY - queueing) / TARGET_DELAY).max(-1.0);
+        let window_factor = acked_bytes as f64 / self.max_window;
+        self.max_window = (self.max_window + MAX_WINDOW_INCREASE * off_target * window_factor).max(MIN_WINDOW as f64);
+    }
+
+    fn window(&self) -> usize {
+        (self.max_window as usize).min(self.peer_window)
+    }
+
+    fn recv_window(&self) -> usize {
+        let buffered = self.received.len() + self.out_of_order.values().map(Vec::len).sum::<usize>();
+        RECV_WINDOW.saturating_sub(buffered)
+    }
+
+    fn now_micros(&self) -> u32 {
+        self.start.elapsed().as_micros() as u32
+    }
+
+    // Data and FIN packets take a sequence number and are kept until acked.
+    async fn send_packet(&mut self, kind: u8, payload: Vec<u8>) -> io::Result<()> {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.send(kind, seq_nr, &payload).await?;
+        self.bytes_in_flight += payload.len();
+        self.in_flight.push_back(SentPacket { kind, seq_nr, payload, sent_at: Instant::now(), resends: 0 });
+        Ok(())
+    }
+
+    async fn send_ack(&self) -> io::Result<()> {
+        self.send(ST_STATE, self.seq_nr, &[]).await
+    }
+
+    async fn send(&self, kind: u8, seq_nr: u16, payload: &[u8]) -> io::Result<()> {
+        let header = Header {
+            kind,
+            // A SYN carries the id the peer will send with.
+            connection_id: if kind == ST_SYN { self.recv_id } else { self.send_id },
+            timestamp: self.now_micros(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: self.recv_window() as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        };
+        self.socket.send(&header.encode(payload)).await?;
+        Ok(())
+    }
+}
+
+// Stream side of a uTP connection.
+#[derive(Debug)]
+pub struct UtpStream {
+    stream: DuplexStream,
+}
+
+impl PeerTransport for UtpStream {
+    fn is_utp(&self) -> bool {
+        true
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let header = Header {
+            kind: ST_DATA,
+            connection_id: 0x1234,
+            timestamp: 1,
+            timestamp_diff: 2,
+            wnd_size: 3,
+            seq_nr: 4,
+            ack_nr: 5,
+        };
+        let buf = header.encode(b"payload");
+        assert_eq!(buf.len(), HEADER_LEN + 7);
+        assert_eq!(buf[0], 0x01);
+        assert_eq!(Header::decode(&buf), Some((header, HEADER_LEN)));
+
+        // Extensions are skipped over.
+        let mut extended = buf[..HEADER_LEN].to_vec();
+        extended[1] = 1;
+        extended.extend_from_slice(&[0, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        extended.extend_from_slice(b"payload");
+        assert_eq!(Header::decode(&extended).map(|(_, offset)| offset), Some(HEADER_LEN + 10));
+        // Truncated extension.
+        assert_eq!(Header::decode(&extended[..HEADER_LEN + 4]), None);
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = peer.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+            let (syn, _) = Header::decode(&buf[..len]).unwrap();
+            assert_eq!(syn.kind, ST_SYN);
+            let packet = |kind, seq_nr, ack_nr, payload: &[u8]| Header {
+                kind,
+                connection_id: syn.connection_id,
+                timestamp: 0,
+                timestamp_diff: 0,
+                wnd_size: RECV_WINDOW as u32,
+                seq_nr,
+                ack_nr,
+            }.encode(payload);
+            peer.send_to(&packet(ST_STATE, 100, syn.seq_nr, &[]), from).await.unwrap();
+
+            let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+            let (data, offset) = Header::decode(&buf[..len]).unwrap();
+            assert_eq!(data.kind, ST_DATA);
+            assert_eq!(data.connection_id, syn.connection_id.wrapping_add(1));
+            assert_eq!(data.seq_nr, syn.seq_nr.wrapping_add(1));
+            assert_eq!(data.ack_nr, 99);
+            assert_eq!(&buf[offset..len], b"hello");
+
+            // Our first data packet has the sequence number of the STATE.
+            peer.send_to(&packet(ST_DATA, 100, data.seq_nr, b"world"), from).await.unwrap();
+            let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+            let (ack, _) = Header::decode(&buf[..len]).unwrap();
+            assert_eq!((ack.kind, ack.ack_nr), (ST_STATE, 100));
+        });
+
+        let mut stream = connect(address, None).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut msg = [0; 5];
+        stream.read_exact(&mut msg).await.unwrap();
+        assert_eq!(&msg, b"world");
+        server.await.unwrap();
+    }
+}
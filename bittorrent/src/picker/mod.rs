@@ -1,11 +1,11 @@
-use std::collections::{HashSet, HashMap};
+use std::{collections::{HashSet, HashMap}, sync::atomic::{AtomicBool, Ordering}};
 use tokio::sync::RwLock;
-use crate::{block::BlockRequest, Bitfield};
+use crate::{block::BlockRequest, info::TorrentInfo, Bitfield};
 
 pub mod piece_picker;
 pub mod partial_piece;
 
-use piece_picker::Pieces;
+use piece_picker::{Pieces, PickerStrategy};
 use partial_piece::PartialPiece;
 
 #[derive(Debug)]
@@ -16,24 +16,45 @@ pub struct Picker {
     // Maps piece idx to partial piece.
     pub partial_pieces: RwLock<HashMap<usize, RwLock<PartialPiece>>>,
     
-    num_pieces:         u32,
-    
-    piece_len:          usize,
-    
-    last_piece_len:     usize,
+    info:               TorrentInfo,
+
+    // Set once every piece has been picked, blocks may then be requested from several peers.
+    end_game:           AtomicBool,
+
+    // Partial pieces are buffered on disk until complete, so new pieces aren't started past this.
+    max_partial_pieces: usize,
 
 }
 
 impl Picker {
 
-    pub fn new(num_pieces: u32, piece_len: usize, last_piece_len: usize) -> Self {
+    pub fn new(info: TorrentInfo, strategy: PickerStrategy) -> Self {
         Self {
-            pieces: RwLock::new(Pieces::new(num_pieces as usize)),
+            pieces: RwLock::new(Pieces::new(info.num_pieces as usize, strategy)),
             partial_pieces: RwLock::new(HashMap::new()),
-            num_pieces,
-            piece_len,
-            last_piece_len,
+            info,
+            end_game: AtomicBool::new(false),
+            max_partial_pieces: usize::MAX,
+        }
+    }
+
+    pub fn set_max_partial_pieces(&mut self, max: usize) {
+        self.max_partial_pieces = max.max(1);
+    }
+
+    // Only partial pieces the peer can help complete count towards the limit, otherwise
+    // pieces it doesn't have, or that no connected peer has, would leave it nothing to download.
+    async fn partial_pieces_full(&self, bf: &Bitfield) -> bool {
+        let partial_pieces = self.partial_pieces.read().await;
+        if partial_pieces.len() < self.max_partial_pieces {
+            return false;
         }
+        let pieces = self.pieces.read().await;
+        partial_pieces.keys().filter(|idx| bf[**idx] && pieces.is_available(**idx)).count() >= self.max_partial_pieces
+    }
+
+    pub fn in_end_game(&self) -> bool {
+        self.end_game.load(Ordering::Relaxed)
     }
 
     pub async fn pick_blocks(
@@ -64,21 +85,27 @@ impl Picker {
             remaining -= partial_piece
                 .write()
                 .await
-                .pick_next_blocks(remaining, &mut requests, &current_requests, false);
+                .pick_next_blocks(remaining, &mut requests, current_requests, false);
         }
         
         // Pick blocks from new pieces.
         while remaining != 0 {
+
+            // Wait for buffered pieces to complete before starting more.
+            if self.partial_pieces_full(bf).await {
+                return requests;
+            }
             
             if let Some(idx) = self.pieces.write().await.pick_new_piece(bf) {
                 tracing::trace!("picked piece {}", idx);
                 // Begin a new partial piece.
-                let mut partial_piece = PartialPiece::new(idx, if idx as u32 == self.num_pieces - 1 { self.last_piece_len } else { self.piece_len });
+                let mut partial_piece = PartialPiece::new(idx, self.info.piece_len(idx));
                 remaining -= partial_piece.pick_next_blocks(remaining, &mut requests, current_requests, false);
                 self.partial_pieces.write().await.insert(idx, partial_piece.into());
             
             } else {
                 // End game if all pieces have been picked.
+                self.end_game.store(true, Ordering::Relaxed);
                 for partial_piece in self.partial_pieces.write().await.values_mut() {
                     
                     if remaining == 0 {
@@ -91,7 +118,7 @@ impl Picker {
                     remaining -= partial_piece
                         .write()
                         .await
-                        .pick_next_blocks(remaining, &mut requests, &current_requests, true);
+                        .pick_next_blocks(remaining, &mut requests, current_requests, true);
                 }
                 return requests;
             }
@@ -103,12 +130,18 @@ impl Picker {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::BLOCK_SIZE;
+    use crate::{info::{FileInfo, TorrentInfo}, BLOCK_SIZE};
+    use piece_picker::{piece_priorities, FilePriority};
     use bitvec::prelude::*;
 
+    // Pieces of two blocks, all of the same length.
+    fn info(num_pieces: u32) -> TorrentInfo {
+        TorrentInfo { total_len: num_pieces as u64 * 32_768, piece_len: 32_768, last_piece_len: 32_768, num_pieces }
+    }
+
     #[tokio::test]
     async fn test_pick_blocks() {
-        let picker = Picker::new(1028, 32_768, 32_768);
+        let picker = Picker::new(info(1028), PickerStrategy::default());
         let bf = BitVec::repeat(true, 1028);
         picker.pieces.write().await.bitfield_update(&bf);
         let requests_1 = picker.pick_blocks(&HashSet::new(), 4, &bf).await;
@@ -120,17 +153,19 @@ mod tests {
     #[tokio::test]
     async fn test_pick_blocks_end_game() {
         
-        let picker = Picker::new(2, 32_768, 32_768);
+        let picker = Picker::new(info(2), PickerStrategy::default());
         let bf = BitVec::repeat(true, 2);
         picker.pieces.write().await.bitfield_update(&bf);
         
         // Pick all the blocks.
         let requests_1 = picker.pick_blocks(&HashSet::new(), 4, &bf).await;
         assert_eq!(requests_1.len(), 4);
+        assert!(!picker.in_end_game());
         
         // Try endgame.
         let requests_2 = picker.pick_blocks(&HashSet::new(), 4, &bf).await;
         assert_eq!(requests_2.len(), 4);
+        assert!(picker.in_end_game());
         
         // Endgame with blocks already in queue.
         let mut previous_requests = HashSet::new();
@@ -139,4 +174,214 @@ mod tests {
         let requests_3 = picker.pick_blocks(&previous_requests, 4, &bf).await;
         assert_eq!(requests_3.len(), 2);
     }
+
+    #[tokio::test]
+    async fn test_pick_freed_blocks_end_game() {
+        let picker = Picker::new(info(1), PickerStrategy::default());
+        let bf = BitVec::repeat(true, 1);
+        picker.pieces.write().await.bitfield_update(&bf);
+
+        let requests = picker.pick_blocks(&HashSet::new(), 2, &bf).await;
+        assert_eq!(requests.len(), 2);
+        assert_eq!(picker.pick_blocks(&HashSet::new(), 2, &bf).await.len(), 2);
+        assert!(picker.in_end_game());
+
+        // A block freed in end game is picked like any other.
+        picker.partial_pieces.read().await[&0].write().await.free_block(&requests[0]);
+        let prev = HashSet::from([requests[1]]);
+        assert_eq!(picker.pick_blocks(&prev, 2, &bf).await, vec![requests[0]]);
+
+        // It can still arrive from a peer asked for it before it was freed.
+        picker.partial_pieces.read().await[&0].write().await.free_block(&requests[0]);
+        assert!(!picker.partial_pieces.read().await[&0].write().await.received_block(&requests[0]));
+    }
+
+    #[tokio::test]
+    async fn test_pick_blocks_wanted() {
+        let picker = Picker::new(info(4), PickerStrategy::default());
+        let bf = BitVec::repeat(true, 4);
+        let mut wanted = BitVec::repeat(false, 4);
+        wanted.set(2, true);
+        picker.pieces.write().await.set_range(wanted);
+        assert!(!picker.pieces.write().await.bitfield_update(&BitVec::repeat(false, 4)));
+        assert!(picker.pieces.write().await.bitfield_update(&bf));
+
+        // Only blocks from the wanted piece are picked.
+        let requests = picker.pick_blocks(&HashSet::new(), 2, &bf).await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.piece_idx == 2));
+
+        assert!(!picker.pieces.read().await.wanted_complete());
+        picker.pieces.write().await.received_piece(2);
+        assert!(picker.pieces.read().await.wanted_complete());
+    }
+
+    #[tokio::test]
+    async fn test_pick_blocks_last_piece() {
+        // The last piece is a full block and 100 bytes.
+        let info = TorrentInfo {
+            total_len: 32_768 + BLOCK_SIZE as u64 + 100,
+            piece_len: 32_768,
+            last_piece_len: BLOCK_SIZE + 100,
+            num_pieces: 2,
+        };
+        let picker = Picker::new(info, PickerStrategy::Sequential);
+        let bf = BitVec::repeat(true, 2);
+        picker.pieces.write().await.bitfield_update(&bf);
+
+        let requests = picker.pick_blocks(&HashSet::new(), 4, &bf).await;
+        let lens: Vec<_> = requests.iter().map(|r| (r.piece_idx, r.offset, r.len)).collect();
+        assert_eq!(lens, [(0, 0, BLOCK_SIZE), (0, BLOCK_SIZE, BLOCK_SIZE), (1, 0, BLOCK_SIZE), (1, BLOCK_SIZE, 100)]);
+
+        // A lone piece of two full blocks and one of 7232 bytes.
+        let info = TorrentInfo { total_len: 40_000, piece_len: 65_536, last_piece_len: 40_000, num_pieces: 1 };
+        let picker = Picker::new(info, PickerStrategy::Sequential);
+        let bf = BitVec::repeat(true, 1);
+        picker.pieces.write().await.bitfield_update(&bf);
+        let requests = picker.pick_blocks(&HashSet::new(), 3, &bf).await;
+        let lens: Vec<_> = requests.iter().map(|r| (r.offset, r.len)).collect();
+        assert_eq!(lens, [(0, BLOCK_SIZE), (BLOCK_SIZE, BLOCK_SIZE), (2 * BLOCK_SIZE, 7232)]);
+    }
+
+    #[tokio::test]
+    async fn test_max_partial_pieces() {
+        let mut picker = Picker::new(info(4), PickerStrategy::Sequential);
+        picker.set_max_partial_pieces(1);
+        let bf = BitVec::repeat(true, 4);
+        picker.pieces.write().await.bitfield_update(&bf);
+
+        // Only the two blocks of the first piece, without going into end game.
+        let requests = picker.pick_blocks(&HashSet::new(), 4, &bf).await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.piece_idx == 0));
+        assert!(!picker.in_end_game());
+
+        // Once no peer has the partial piece, another can be started.
+        picker.pieces.write().await.bitfield_remove(&bitvec![u8, Msb0; 1, 0, 0, 0]);
+        let requests = picker.pick_blocks(&HashSet::new(), 4, &bf).await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.piece_idx == 1));
+
+        // A peer without the partial piece can start one it has.
+        let bf_3 = bitvec![u8, Msb0; 0, 0, 1, 1];
+        let requests = picker.pick_blocks(&HashSet::new(), 2, &bf_3).await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.piece_idx == 2));
+        // Whilst one with it waits for it to complete.
+        assert!(picker.pick_blocks(&HashSet::new(), 4, &bf).await.is_empty());
+    }
+
+    #[test]
+    fn test_piece_priorities() {
+        // Files of 10, 30 and 24 bytes over pieces of 16 bytes, the first two share piece 0 and 1.
+        let info = TorrentInfo { total_len: 64, piece_len: 16, last_piece_len: 16, num_pieces: 4 };
+        let mut offset = 0;
+        let files: Vec<FileInfo> = [10, 30, 24].into_iter().map(|length| {
+            let file = FileInfo { path: "f".into(), length, offset, md5sum: None };
+            offset += length;
+            file
+        }).collect();
+
+        let (wanted, high) = piece_priorities(&info, &files, &[FilePriority::Normal, FilePriority::Skip, FilePriority::Skip]);
+        assert_eq!(wanted, bitvec![1, 0, 0, 0]);
+        assert!(high.not_any());
+
+        // Piece 2 spans the skipped second file and the wanted third, so must still be downloaded.
+        let (wanted, high) = piece_priorities(&info, &files, &[FilePriority::Skip, FilePriority::Skip, FilePriority::High]);
+        assert_eq!(wanted, bitvec![0, 0, 1, 1]);
+        assert_eq!(high, bitvec![0, 0, 1, 1]);
+
+        let mut pieces = Pieces::new(4, PickerStrategy::Sequential);
+        pieces.bitfield_update(&BitVec::repeat(true, 4));
+        let (wanted, high) = piece_priorities(&info, &files, &[FilePriority::Normal, FilePriority::Normal, FilePriority::High]);
+        pieces.set_priorities(wanted, high);
+        // High priority pieces come first, even with the sequential strategy.
+        assert_eq!(pieces.pick_new_piece(&BitVec::repeat(true, 4)), Some(2));
+        assert_eq!(pieces.pick_new_piece(&BitVec::repeat(true, 4)), Some(3));
+        assert_eq!(pieces.pick_new_piece(&BitVec::repeat(true, 4)), Some(0));
+    }
+
+    #[test]
+    fn test_range_with_priorities() {
+        let bf: Bitfield = BitVec::repeat(true, 4);
+        let mut pieces = Pieces::new(4, PickerStrategy::Sequential);
+        pieces.bitfield_update(&bf);
+        pieces.set_priorities(bitvec![u8, Msb0; 0, 1, 1, 1], bitvec![u8, Msb0; 0, 0, 1, 0]);
+        pieces.set_range(bitvec![u8, Msb0; 1, 1, 1, 0]);
+
+        // Only pieces both in range and not skipped are picked, with priorities kept.
+        assert_eq!(pieces.pick_new_piece(&bf), Some(2));
+        assert_eq!(pieces.pick_new_piece(&bf), Some(1));
+        assert_eq!(pieces.pick_new_piece(&bf), None);
+
+        // Nor does changing priorities undo the range.
+        let mut pieces = Pieces::new(4, PickerStrategy::Sequential);
+        pieces.bitfield_update(&bf);
+        pieces.set_range(bitvec![u8, Msb0; 0, 1, 1, 0]);
+        pieces.set_priorities(bitvec![u8, Msb0; 1, 1, 0, 1], BitVec::repeat(false, 4));
+        assert_eq!(pieces.pick_new_piece(&bf), Some(1));
+        assert_eq!(pieces.pick_new_piece(&bf), None);
+    }
+
+    #[test]
+    fn test_unavailable_pieces() {
+        let mut pieces = Pieces::new(4, PickerStrategy::Rarest);
+        let mut bf_1: Bitfield = BitVec::repeat(false, 4);
+        bf_1.set(0, true);
+        bf_1.set(1, true);
+        let mut bf_2: Bitfield = BitVec::repeat(false, 4);
+        bf_2.set(1, true);
+        bf_2.set(2, true);
+
+        pieces.bitfield_update(&bf_1);
+        pieces.bitfield_update(&bf_2);
+        pieces.received_piece(3);
+        assert!(pieces.unavailable_pieces().is_empty());
+
+        pieces.bitfield_remove(&bf_1);
+        assert_eq!(pieces.unavailable_pieces(), vec![0]);
+    }
+
+    #[test]
+    fn test_pick_within_k_rarest() {
+        let num_pieces = 20;
+        let mut pieces = Pieces::new(num_pieces, PickerStrategy::Rarest);
+        pieces.set_rarest_k(3);
+        
+        // Have the first pieces so picking is no longer random.
+        let mut own: Bitfield = BitVec::repeat(false, num_pieces);
+        own[..4].fill(true);
+        pieces.set_own_bitfield(own);
+
+        // Piece i is held by i peers, so pieces 4, 5 and 6 are the rarest we need.
+        for n in 1..num_pieces {
+            let mut bf: Bitfield = BitVec::repeat(false, num_pieces);
+            bf[n..].fill(true);
+            pieces.bitfield_update(&bf);
+        }
+
+        // Each pick is one of the 3 rarest pieces not yet picked.
+        let bf = BitVec::repeat(true, num_pieces);
+        let mut remaining: Vec<usize> = (4..num_pieces).collect();
+        while !remaining.is_empty() {
+            let idx = pieces.pick_new_piece(&bf).unwrap();
+            assert!(remaining[..remaining.len().min(3)].contains(&idx), "picked {} from {:?}", idx, remaining);
+            remaining.retain(|i| *i != idx);
+        }
+        assert_eq!(pieces.pick_new_piece(&bf), None);
+    }
+
+    #[test]
+    fn test_pick_sequential() {
+        let mut pieces = Pieces::new(8, PickerStrategy::Sequential);
+        let mut bf: Bitfield = BitVec::repeat(true, 8);
+        bf.set(1, false);
+        pieces.bitfield_update(&bf);
+        // Rarest pieces are ignored.
+        pieces.bitfield_update(&BitVec::repeat(true, 8));
+        pieces.received_piece(2);
+
+        let picked: Vec<usize> = std::iter::from_fn(|| pieces.pick_new_piece(&bf)).collect();
+        assert_eq!(picked, vec![0, 3, 4, 5, 6, 7]);
+    }
 }
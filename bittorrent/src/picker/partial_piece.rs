@@ -26,7 +26,10 @@ pub struct PartialPiece {
     pub len: usize,
     
     // State of all blocks within this piece.
-    pub blocks_states: Vec<BlockState>
+    pub blocks_states: Vec<BlockState>,
+
+    // When the piece was picked, ie. when its first block was requested.
+    pub start_time: std::time::Instant,
 
 }
 
@@ -37,6 +40,7 @@ impl PartialPiece {
             idx,
             len,
             blocks_states: vec![BlockState::default(); num_blocks(len) as usize],
+            start_time: std::time::Instant::now(),
         }
     }
     
@@ -52,10 +56,10 @@ impl PartialPiece {
     // Returns whether the block is a duplicate (already recieved).
     pub fn received_block(&mut self, block: &BlockRequest) -> bool {
         let block_state = &mut self.blocks_states[block.idx_in_piece()];
-        // If we received a block, it must have been requested.
         match *block_state {
-            BlockState::Free => unreachable!("Can't receive a block that wasn't requested"),
-            BlockState::Requested => {
+            // Requested blocks are freed when a peer chokes us, in end game
+            // another peer may still have been asked for it and sent it.
+            BlockState::Free | BlockState::Requested => {
                 *block_state = BlockState::Received;
                 false
             },
@@ -77,11 +81,11 @@ impl PartialPiece {
                 break;
             }
             
+            // Blocks freed from a choked peer are picked again, even in end game.
             if *block == BlockState::Free {
-                assert!(!end_game);
                 buf.push(BlockRequest {
                     piece_idx: self.idx,
-                    offset: i * BLOCK_SIZE as usize,
+                    offset: i * BLOCK_SIZE,
                     len: block_len(self.len, i)
                 });
                 *block = BlockState::Requested;
@@ -91,7 +95,7 @@ impl PartialPiece {
                 
                 let block_info = BlockRequest {
                     piece_idx: self.idx,
-                    offset: i * BLOCK_SIZE as usize,
+                    offset: i * BLOCK_SIZE,
                     len: block_len(self.len, i),
                 };
                 
@@ -1,4 +1,5 @@
-use crate::Bitfield;
+use rand::seq::SliceRandom;
+use crate::{disk::piece_file_intersections, info::{FileInfo, TorrentInfo}, Bitfield};
 
 /*
 A better strategy is to download pieces in rarest first order. The client can determine this
@@ -9,6 +10,53 @@ least common pieces, as having many clients all attempting to jump on the same "
 piece would be counter productive
 */
 
+// Until we have this many pieces, pick at random so we have something to trade quickly.
+const RANDOM_FIRST_PIECES: usize = 4;
+
+// Default number of rarest pieces to pick randomly between.
+pub const DEFAULT_RAREST_K: usize = 5;
+
+// How new pieces are chosen.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PickerStrategy {
+    // Least available pieces first, to keep the swarm healthy.
+    #[default]
+    Rarest,
+    // In order, for streaming or previewing.
+    Sequential,
+    Random,
+}
+
+// How much a file of a multi-file torrent is wanted.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FilePriority {
+    // Not downloaded, except for pieces it shares with wanted files.
+    Skip,
+    #[default]
+    Normal,
+    // Its pieces are picked before any others.
+    High,
+}
+
+// Maps file priorities onto the pieces they cover, giving the wanted and high priority pieces.
+// Pieces spanning several files take the highest priority of them, so boundary pieces of wanted files are kept.
+pub fn piece_priorities(info: &TorrentInfo, files: &[FileInfo], priorities: &[FilePriority]) -> (Bitfield, Bitfield) {
+    debug_assert_eq!(files.len(), priorities.len());
+    let num_pieces = info.num_pieces as usize;
+    let mut wanted = Bitfield::repeat(false, num_pieces);
+    let mut high = Bitfield::repeat(false, num_pieces);
+    for idx in 0..num_pieces {
+        let priority = priorities[piece_file_intersections(info, files, idx)]
+            .iter()
+            .copied()
+            .max_by_key(|p| *p as u8)
+            .unwrap_or_default();
+        wanted.set(idx, priority != FilePriority::Skip);
+        high.set(idx, priority == FilePriority::High);
+    }
+    (wanted, high)
+}
+
 #[derive(Clone, Copy, Default, Debug)]
 struct PieceInfo {
     // Number of peers that have this piece.
@@ -23,15 +71,32 @@ pub struct Pieces {
     pieces: Vec<PieceInfo>,
     // The pieces that we have.
     have: Bitfield,
+    // The pieces we want to download, those in the download range not only in skipped files.
+    wanted: Bitfield,
+    // Pieces covering the download range, all by default.
+    in_range: Bitfield,
+    // Pieces not only in skipped files, all by default.
+    unskipped: Bitfield,
+    // Wanted pieces to pick before the rest.
+    high: Bitfield,
+    strategy: PickerStrategy,
+    // With the rarest strategy, new pieces are picked at random from the k rarest.
+    rarest_k: usize,
 }
 
 impl Pieces {
     
-    pub fn new(num_pieces: usize) -> Self {
+    pub fn new(num_pieces: usize, strategy: PickerStrategy) -> Self {
         let mut have = Bitfield::new();
         have.resize(num_pieces, false);
         Self {
             pieces: vec![PieceInfo::default(); num_pieces],
+            wanted: Bitfield::repeat(true, num_pieces),
+            in_range: Bitfield::repeat(true, num_pieces),
+            unskipped: Bitfield::repeat(true, num_pieces),
+            high: Bitfield::repeat(false, num_pieces),
+            strategy,
+            rarest_k: DEFAULT_RAREST_K,
             have,
         }
     }
@@ -49,10 +114,43 @@ impl Pieces {
         self.have = bf;
     }
 
+    pub fn set_rarest_k(&mut self, k: usize) {
+        self.rarest_k = k.max(1);
+    }
+
+    // The range and file priorities are kept apart, so setting one doesn't undo the other.
+    pub fn set_range(&mut self, in_range: Bitfield) {
+        debug_assert_eq!(in_range.len(), self.have.len());
+        self.in_range = in_range;
+        self.update_wanted();
+    }
+
+    pub fn set_priorities(&mut self, unskipped: Bitfield, high: Bitfield) {
+        debug_assert_eq!(unskipped.len(), self.have.len());
+        debug_assert_eq!(high.len(), self.have.len());
+        self.unskipped = unskipped;
+        self.high = high;
+        self.update_wanted();
+    }
+
+    fn update_wanted(&mut self) {
+        self.wanted = self.in_range.clone();
+        self.wanted &= &self.unskipped;
+    }
+
+    // True when we have every piece we want, which may not be all pieces.
+    pub fn wanted_complete(&self) -> bool {
+        self.have
+            .iter()
+            .zip(self.wanted.iter())
+            .all(|(have, wanted)| *have || !*wanted)
+    }
+
+    // Returns true if we want the piece and don't have it.
     pub fn increment_piece(&mut self, idx: usize) -> bool {
         assert!(idx < self.pieces.len());
         self.pieces[idx].frequency += 1;
-        self.have[idx]
+        !self.have[idx] && self.wanted[idx]
     }
 
     pub fn received_piece(&mut self, idx: usize) {
@@ -60,7 +158,7 @@ impl Pieces {
         self.have.set(idx, true);
     }
 
-    // Will return true if there is at least one piece that peer has and we don't.
+    // Will return true if there is at least one wanted piece that peer has and we don't.
     pub fn bitfield_update(&mut self, bf: &Bitfield) -> bool {
         debug_assert_eq!(bf.len(), self.have.len());
         let mut interested = false;
@@ -70,21 +168,68 @@ impl Pieces {
             .filter(|(_, b)| **b)
             .for_each(|(i, _)| {
                 self.pieces[i].frequency += 1;
-                if !self.have[i] {
+                if !self.have[i] && self.wanted[i] {
                     interested = true;
                 }
         });
         interested
     }
 
+    // Called when a peer disconnects, its pieces are no longer available.
+    pub fn bitfield_remove(&mut self, bf: &Bitfield) {
+        debug_assert_eq!(bf.len(), self.have.len());
+        for i in bf.iter_ones() {
+            self.pieces[i].frequency = self.pieces[i].frequency.saturating_sub(1);
+        }
+    }
+
+    // Whether any connected peer has the piece.
+    pub fn is_available(&self, idx: usize) -> bool {
+        self.pieces[idx].frequency > 0
+    }
+
+    // Wanted pieces we don't have that no connected peer has either.
+    pub fn unavailable_pieces(&self) -> Vec<usize> {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(i, piece)| !self.have[*i] && self.wanted[*i] && piece.frequency == 0)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // With the rarest strategy, picks randomly among the k rarest pieces the peer has,
+    // so peers joining at the same time don't all go for the same piece.
     pub fn pick_new_piece(&mut self, bf: &Bitfield) -> Option<usize> {
-        for idx in 0..self.have.len() {
-            let piece = &mut self.pieces[idx];
-            if !self.have[idx] && piece.frequency > 0 && !piece.is_partial && bf[idx] {
-                piece.is_partial = true;
-                return Some(idx)
-            }
+        
+        let mut candidates: Vec<usize> = (0..self.have.len())
+            .filter(|idx| {
+                let piece = &self.pieces[*idx];
+                !self.have[*idx] && self.wanted[*idx] && piece.frequency > 0 && !piece.is_partial && bf[*idx]
+            })
+            .collect();
+        if candidates.is_empty() {
+            return None;
         }
-        None
+        if candidates.iter().any(|idx| self.high[*idx]) {
+            candidates.retain(|idx| self.high[*idx]);
+        }
+
+        let mut rng = rand::thread_rng();
+        let idx = match self.strategy {
+            PickerStrategy::Sequential => candidates[0],
+            PickerStrategy::Random => *candidates.choose(&mut rng)?,
+            PickerStrategy::Rarest => {
+                if self.have.count_ones() >= RANDOM_FIRST_PIECES && candidates.len() > self.rarest_k {
+                    // Shuffle first so pieces tied with the kth rarest are cut at random.
+                    candidates.shuffle(&mut rng);
+                    candidates.select_nth_unstable_by_key(self.rarest_k - 1, |idx| self.pieces[*idx].frequency);
+                    candidates.truncate(self.rarest_k);
+                }
+                *candidates.choose(&mut rng)?
+            },
+        };
+        self.pieces[idx].is_partial = true;
+        Some(idx)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,161 @@
+use std::{path::{Path, PathBuf}, time::UNIX_EPOCH};
+use serde_derive::{Deserialize, Serialize};
+use crate::{metainfo::{MetaInfo, MetaInfoError}, Bitfield, ID};
+
+// Resume data is kept in the configured resume directory, with files named by info hash.
+
+fn metainfo_path(dir: &Path, id: &ID) -> PathBuf {
+    dir.join(hex::encode(id)).with_extension("torrent")
+}
+
+// Writes the metainfo so a torrent can be restored without the original .torrent file.
+pub fn save_metainfo(dir: &Path, metainfo: &MetaInfo) -> Result<(), MetaInfoError> {
+    if !dir.is_dir() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(metainfo_path(dir, &metainfo.info_hash()), metainfo.to_bytes()?)?;
+    Ok(())
+}
+
+// Loads previously saved metainfo for an info hash, if it exists and still hashes to the same id.
+// Lets a magnet restart skip metadata exchange.
+pub fn load_metainfo(dir: &Path, id: &ID) -> Option<MetaInfo> {
+    let path = metainfo_path(dir, id);
+    if !path.is_file() {
+        return None;
+    }
+    match MetaInfo::new(&path) {
+        Ok(metainfo) if metainfo.info_hash() == *id => Some(metainfo),
+        Ok(_) => {
+            tracing::warn!("resume metainfo {:?} has mismatched info hash", path);
+            None
+        },
+        Err(e) => {
+            tracing::warn!("failed to load resume metainfo {:?}: {}", path, e);
+            None
+        },
+    }
+}
+
+// Size and modification time of a torrent's file on disk.
+// If either changed since progress was saved, the file was touched outside of us.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct FileStamp {
+
+    pub length: u64,
+
+    // Nanoseconds since the unix epoch.
+    pub mtime: u64,
+
+}
+
+impl FileStamp {
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> std::io::Result<Self> {
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Ok(Self { length: metadata.len(), mtime })
+    }
+}
+
+// Verified pieces, valid only while the files match their stamps.
+#[derive(Debug, Deserialize, Serialize)]
+struct Progress {
+
+    #[serde(with = "serde_bytes")]
+    bitfield: Vec<u8>,
+
+    num_pieces: usize,
+
+    files: Vec<FileStamp>,
+
+}
+
+fn progress_path(dir: &Path, id: &ID) -> PathBuf {
+    dir.join(hex::encode(id)).with_extension("resume")
+}
+
+// Writes the verified pieces, so the next start can skip hash checking the files.
+pub fn save_progress(dir: &Path, id: &ID, bitfield: &Bitfield, files: Vec<FileStamp>) -> Result<(), MetaInfoError> {
+    if !dir.is_dir() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let progress = Progress {
+        bitfield: bitfield.as_raw_slice().to_vec(),
+        num_pieces: bitfield.len(),
+        files,
+    };
+    // Written then renamed, so a crash part way through leaves the previous progress intact.
+    let path = progress_path(dir, id);
+    let tmp = path.with_extension("resume.tmp");
+    std::fs::write(&tmp, bencode::encode_to_raw(&progress)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+// Loads saved progress if the files on disk are still the ones it was saved for.
+pub fn load_progress(dir: &Path, id: &ID, num_pieces: usize, files: &[FileStamp]) -> Option<Bitfield> {
+    let path = progress_path(dir, id);
+    if !path.is_file() {
+        return None;
+    }
+    let read = || -> Result<Progress, MetaInfoError> { Ok(bencode::decode_bytes(&std::fs::read(&path)?)?) };
+    let progress = match read() {
+        Ok(progress) => progress,
+        Err(e) => {
+            tracing::warn!("failed to load resume progress {:?}: {}", path, e);
+            return None;
+        },
+    };
+
+    if progress.num_pieces != num_pieces || progress.bitfield.len() != num_pieces.div_ceil(8) {
+        tracing::warn!("resume progress {:?} has the wrong number of pieces", path);
+        return None;
+    }
+    if progress.files != files {
+        tracing::info!("files changed since progress was saved, rechecking");
+        return None;
+    }
+
+    let mut bitfield = Bitfield::from_vec(progress.bitfield);
+    bitfield.truncate(num_pieces);
+    Some(bitfield)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metainfo_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let metainfo = MetaInfo::new("tests/test_torrents/test_multi.torrent").unwrap();
+        save_metainfo(dir.path(), &metainfo).unwrap();
+        
+        let loaded = load_metainfo(dir.path(), &metainfo.info_hash()).unwrap();
+        assert_eq!(loaded.info_hash(), metainfo.info_hash());
+        assert_eq!(loaded.tracker_urls().len(), metainfo.tracker_urls().len());
+        assert!(load_metainfo(dir.path(), &[0; 20]).is_none());
+    }
+
+    #[test]
+    fn test_progress_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = [1; 20];
+        let files = vec![FileStamp { length: 100, mtime: 5 }, FileStamp { length: 20, mtime: 6 }];
+        let mut bitfield = Bitfield::repeat(false, 11);
+        bitfield.set(0, true);
+        bitfield.set(10, true);
+        save_progress(dir.path(), &id, &bitfield, files.clone()).unwrap();
+        // Only the renamed file is left behind.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        assert_eq!(load_progress(dir.path(), &id, 11, &files), Some(bitfield));
+        // Files touched since saving.
+        let touched = vec![files[0], FileStamp { length: 20, mtime: 7 }];
+        assert!(load_progress(dir.path(), &id, 11, &touched).is_none());
+        assert!(load_progress(dir.path(), &id, 12, &files).is_none());
+        assert!(load_progress(dir.path(), &[0; 20], 11, &files).is_none());
+    }
+}
@@ -16,6 +16,19 @@ pub struct TorrentStats {
 
     pub throughput: ThroughputStats,
 
+    // From the last tracker to respond to an announce.
+    pub tracker: TrackerStats,
+
+    // Only collected when piece timing is enabled in the config.
+    pub piece_timing: Option<PieceTimingStats>,
+
+    // Pieces completed since the last stats were sent, when the piece map is enabled in the config.
+    // The first stats after checking include the pieces already on disk.
+    // Only in stats sent each second, not those asked for.
+    pub new_pieces: Option<Vec<usize>>,
+
+    pub cache: CacheStats,
+
 }
 
 #[derive(Debug)]
@@ -27,6 +40,9 @@ pub struct PieceStats {
 
     pub num_downloaded: usize,
 
+    // Pieces hash checked so far, whilst checking existing files.
+    pub num_checked: usize,
+
 }
 
 impl PieceStats {
@@ -35,6 +51,40 @@ impl PieceStats {
     }
 }
 
+// Swarm size and announce interval as reported by a tracker, each is None if the tracker left it out.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TrackerStats {
+
+    pub seeders: Option<u64>,
+
+    pub leechers: Option<u64>,
+
+    pub interval: Option<Duration>,
+
+}
+
+// Blocks served to peers from the disk read cache, for tuning its size.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+
+    pub hits: u64,
+
+    pub misses: u64,
+
+    pub bytes_from_cache: u64,
+
+    pub bytes_from_disk: u64,
+
+}
+
+impl CacheStats {
+    // None until a block has been read.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PeerStats {
 
@@ -57,6 +107,12 @@ impl ThroughputStats {
         self.up.reset();
         self.down.reset();
     }
+
+    // Rates drop to zero straight away, totals are kept.
+    pub fn idle(&mut self) {
+        self.up.idle();
+        self.down.idle();
+    }
 }
 
 impl std::ops::AddAssign<&ThroughputStats> for ThroughputStats {
@@ -89,6 +145,11 @@ impl Counter {
         }
     }
 
+    pub fn idle(&mut self) {
+        self.avg = 0.0;
+        self.round = 0;
+    }
+
     pub fn avg(&self) -> u64 {
         self.avg as u64
     }
@@ -113,4 +174,111 @@ impl std::ops::AddAssign<u64> for Counter {
     }
 }
 
+// Upper bounds of the piece timing histogram buckets, the last bucket holds everything slower.
+pub const PIECE_TIMING_BUCKETS: [Duration; 5] = [
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(15),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+];
+
+// Summary of how long pieces took from first block requested to verified and written.
+#[derive(Debug, Default, Clone)]
+pub struct PieceTimingStats {
+
+    pub num_verified: usize,
+
+    pub num_failed: usize,
+
+    pub p50: Duration,
 
+    pub p90: Duration,
+
+    pub p99: Duration,
+
+    pub max: Duration,
+
+    // Counts per bucket of PIECE_TIMING_BUCKETS, with one extra for slower pieces.
+    pub histogram: Vec<usize>,
+
+}
+
+// Collects piece completion times for a torrent.
+#[derive(Debug, Default)]
+pub struct PieceTimings {
+
+    // Kept sorted.
+    durations: Vec<Duration>,
+
+    num_failed: usize,
+
+}
+
+impl PieceTimings {
+
+    pub fn record(&mut self, duration: Duration) {
+        let idx = self.durations.partition_point(|d| *d <= duration);
+        self.durations.insert(idx, duration);
+    }
+
+    pub fn record_failed(&mut self) {
+        self.num_failed += 1;
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        if self.durations.is_empty() {
+            return Duration::default();
+        }
+        let idx = ((self.durations.len() - 1) as f64 * p).round() as usize;
+        self.durations[idx]
+    }
+
+    pub fn stats(&self) -> PieceTimingStats {
+        let mut histogram = vec![0; PIECE_TIMING_BUCKETS.len() + 1];
+        for duration in &self.durations {
+            let bucket = PIECE_TIMING_BUCKETS.partition_point(|bound| bound <= duration);
+            histogram[bucket] += 1;
+        }
+        PieceTimingStats {
+            num_verified: self.durations.len(),
+            num_failed: self.num_failed,
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+            max: self.durations.last().copied().unwrap_or_default(),
+            histogram,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_hit_rate() {
+        let mut stats = CacheStats::default();
+        assert_eq!(stats.hit_rate(), None);
+        stats.hits = 3;
+        stats.misses = 1;
+        assert_eq!(stats.hit_rate(), Some(0.75));
+    }
+
+    #[test]
+    fn test_piece_timings() {
+        let mut timings = PieceTimings::default();
+        for secs in [40, 2, 10, 0, 90, 3, 20, 4, 7, 1] {
+            timings.record(Duration::from_secs(secs));
+        }
+        timings.record_failed();
+
+        let stats = timings.stats();
+        assert_eq!(stats.num_verified, 10);
+        assert_eq!(stats.num_failed, 1);
+        assert_eq!(stats.p50, Duration::from_secs(7));
+        assert_eq!(stats.p90, Duration::from_secs(40));
+        assert_eq!(stats.max, Duration::from_secs(90));
+        assert_eq!(stats.histogram, vec![1, 4, 2, 1, 1, 1]);
+    }
+}
@@ -1,18 +1,21 @@
 use std::{
-    collections::HashMap, 
-    net::{Ipv4Addr, SocketAddr}, 
+    collections::{HashMap, HashSet}, 
+    net::SocketAddr, 
     sync::Arc, time::Instant,
 };
-use tokio::{net::TcpListener, sync::{mpsc, oneshot}, time};
+use tokio::{sync::{mpsc, oneshot, Semaphore}, time};
 use tracing::Instrument;
 use url::Url;
 use crate::{
-    config::Config, 
-    disk::{AllocationError, DiskTx}, 
-    info::TorrentInfo, 
-    p2p::{state::{ConnState, SessionState}, PeerCommand, PeerHandle},
-    picker::Picker,
-    stats::{PeerStats, PieceStats, ThroughputStats, TorrentStats},
+    block::BlockRequest,
+    config::{ChokedPeerAction, Config}, 
+    dht::{DhtCommand, DhtTx},
+    disk::{AllocationError, DiskCommand, DiskError, DiskTx}, 
+    info::{FileInfo, TorrentInfo}, 
+    limiter::{ConnectionLimit, RateLimiter, RateLimits},
+    p2p::{state::{ConnState, SessionState}, InboundPeer, PeerCommand, PeerHandle},
+    picker::{piece_picker::{piece_priorities, FilePriority}, Picker},
+    stats::{CacheStats, PeerStats, PieceStats, PieceTimings, ThroughputStats, TorrentStats, TrackerStats},
     tracker::{AnnounceParams, Event, TrackersHandle},
     Bitfield,
     UserCommand,
@@ -20,6 +23,18 @@ use crate::{
     ID,
 };
 
+// How often connected peers are shared through peer exchange.
+const PEX_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// How often progress is saved to the resume directory while pieces are coming in.
+const RESUME_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+// Most DHT nodes learned from peers that are kept.
+const MAX_DHT_NODES: usize = 1000;
+
+// Nodes learned from peers are passed on to the DHT again if seen after this long.
+const DHT_NODE_LIFETIME: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+
 #[derive(Debug, thiserror::Error)]
 pub enum TorrentError {
 
@@ -40,12 +55,70 @@ pub enum TorrentCommand {
     
     // Sent by disk task when piece written.
     PieceWritten { idx: usize, valid: bool },
+
+    // Sent by disk task whilst checking existing files, with the number of pieces checked.
+    CheckProgress { checked: usize },
+
+    // Sent by disk task as blocks are read for peers.
+    CacheStats(CacheStats),
+
+    // Sent by disk task when a piece couldn't be written or read, e.g. when the disk is full.
+    DiskError { piece_idx: usize, error: DiskError },
+
+    // Sent by disk task when a downloaded file doesn't match its md5sum.
+    Md5Mismatch { path: std::path::PathBuf },
     
     // Sent by peers to update state.
     PeerState { address: SocketAddr, state: SessionState },
 
-    // Sent by trackers to update peer list.
-    Peers(Vec<SocketAddr>),
+    // Sent by client when a peer connects to us for this torrent.
+    InboundPeer(Box<InboundPeer>),
+
+    // Sent by peers when an outbound connection couldn't be made.
+    ConnectFailed { address: SocketAddr },
+
+    // Sent by peers when an outbound connection turned out to be to ourselves.
+    OwnAddress { address: SocketAddr },
+
+    // Sent by peers when a block arrives in end game, so other peers can cancel it.
+    BlockReceived { address: SocketAddr, request: BlockRequest },
+
+    // Sent by trackers, DHT and peer exchange to update peer list.
+    // Trackers also give their view of the swarm.
+    Peers {
+        peers: Vec<SocketAddr>,
+        tracker: Option<TrackerStats>,
+    },
+
+    // Sent by trackers when none of them are responding.
+    TrackersUnreachable,
+
+    // Sent by peers advertising a DHT node via the port message.
+    DhtNode(SocketAddr),
+
+    // Sent by client to only download pieces covering a byte range, pausing once done.
+    SetDownloadRange(std::ops::Range<u64>),
+
+    // Sent by client to set the priority of each file, skipped files aren't downloaded.
+    SetFilePriorities(Vec<FilePriority>),
+
+    // Sent by client to announce to trackers straight away.
+    Reannounce,
+
+    // Sent by client to scrape trackers for swarm stats.
+    Scrape,
+
+    // Sent by client for the current stats, rather than waiting for the next push.
+    GetStats(oneshot::Sender<TorrentStats>),
+
+    // Sent by client to stop once done downloading.
+    StopSeeding,
+
+    // Sent by client to disconnect from the swarm without removing the torrent.
+    Pause,
+
+    // Sent by client to reconnect a paused torrent.
+    Resume,
 
     // Sent by itself or client to shutdown.
     Shutdown,
@@ -64,6 +137,7 @@ pub enum TorrentState {
 
 // Type aliases.
 pub type Result<T> = std::result::Result<T, TorrentError>;
+pub type DhtNodes = Arc<std::sync::Mutex<HashMap<SocketAddr, Instant>>>;
 pub type TorrentTx = mpsc::UnboundedSender<TorrentCommand>;
 pub type TorrentRx = mpsc::UnboundedReceiver<TorrentCommand>;
 
@@ -86,10 +160,11 @@ impl TorrentHandle {
         let (mut torrent, torrent_tx) = Torrent::new(params);
 
         let handle = tokio::task::spawn(async move { 
-            if let Err(e) = torrent.start(rx).await {
+            let result = torrent.start(rx).await;
+            if let Err(e) = &result {
                 tracing::error!("torrent error: {}", e);
             }
-            torrent.shutdown().await;
+            torrent.shutdown(result).await;
         }.instrument(tracing::info_span!("torrent", id = %hex::encode(info_hash)[..4])));
         
         TorrentHandle {
@@ -115,12 +190,37 @@ pub struct TorrentContext {
 
     pub info: TorrentInfo,
 
+    // Port of our DHT node, sent to peers when the DHT can be used for this torrent.
+    pub dht_port: Option<u16>,
+
+    // Whether peers can be shared with peer exchange, never for private torrents.
+    pub pex: bool,
+
+    // Limits on blocks requested from and sent to peers, shared by all sessions.
+    pub down_limits: RateLimits,
+
+    pub up_limits: RateLimits,
+
+    // Held by sessions whilst the peer is unchoked.
+    pub upload_slots: Arc<Semaphore>,
+
+    pub max_unexpected_blocks: usize,
+
+    pub max_request_queue: usize,
+
+    pub connect_timeout: std::time::Duration,
+
+    pub connect_options: crate::p2p::ConnectOptions,
+
 }
 
 pub struct TorrentParams {
 
     pub info: TorrentInfo,
 
+    // Files in the order of the metainfo, a single file for single file torrents.
+    pub files: Vec<FileInfo>,
+
     pub info_hash: ID,
 
     pub client_id: ID,
@@ -135,6 +235,21 @@ pub struct TorrentParams {
 
     pub config: Config,
 
+    pub dht_nodes: DhtNodes,
+
+    // None if the DHT is disabled or the torrent is private.
+    pub dht_tx: Option<DhtTx>,
+
+    // Private torrents must only get peers from their trackers.
+    pub private: bool,
+
+    // Client wide limits, shared with other torrents.
+    pub global_down_limit: Option<Arc<RateLimiter>>,
+
+    pub global_up_limit: Option<Arc<RateLimiter>>,
+
+    pub global_connections: Arc<ConnectionLimit>,
+
 }
 
 struct Torrent {
@@ -144,24 +259,72 @@ struct Torrent {
     // Context is a read-only state accessible by peers in threads.
     ctx: Arc<TorrentContext>,
 
+    // Taken for each peer session, shared with other torrents.
+    global_connections: Arc<ConnectionLimit>,
+
     // Peers we have active sessions with.
     peers: HashMap<SocketAddr, PeerHandle>,
     
     // Peers we know about but don't have a session with.
     available: Vec<SocketAddr>,
 
+    // Failed outbound connections to each peer, cleared once one succeeds.
+    connect_failures: HashMap<SocketAddr, usize>,
+
+    // Peers that couldn't be connected to, made available again once their cooldown ends.
+    retrying: Vec<(SocketAddr, Instant)>,
+
+    // Addresses that reached ourselves, never connected to again.
+    own_addresses: HashSet<SocketAddr>,
+
     trackers: TrackersHandle,
 
     user_tx: UserTx,
 
     throughput: ThroughputStats,
 
+    // Swarm as last reported by a tracker.
+    tracker_stats: TrackerStats,
+
+    files: Vec<FileInfo>,
+
+    // Read cache use as last reported by the disk task.
+    cache_stats: CacheStats,
+
+    // Pieces checked so far whilst in the checking state.
+    num_checked: usize,
+
+    // Set when peers connect, disconnect or get pieces, so piece availability is re-checked.
+    availability_changed: bool,
+
+    // Unavailable pieces last reported to the user.
+    unavailable: Vec<usize>,
+
+    // Present if piece timing is enabled.
+    piece_timings: Option<PieceTimings>,
+
+    // Pieces completed since stats were last sent, present if the piece map is enabled.
+    new_pieces: Option<Vec<usize>>,
+
     state: TorrentState,
 
     listen_port: u16,
 
     config: Config,
 
+    // DHT nodes learned from peers, shared across all torrents.
+    dht_nodes: DhtNodes,
+
+    dht_tx: Option<DhtTx>,
+
+    // When connected peers were last shared with peer exchange.
+    last_pex: Instant,
+
+    // When progress was last saved, and whether pieces were written since.
+    last_resume_save: Instant,
+
+    progress_changed: bool,
+
 }
 
 impl Torrent {
@@ -169,6 +332,9 @@ impl Torrent {
     pub fn new(params: TorrentParams) -> (Self, TorrentTx) {        
         
         let (torrent_tx, torrent_rx) = mpsc::unbounded_channel();
+        let mut picker = Picker::new(params.info.clone(), params.config.picker_strategy);
+        picker.pieces.get_mut().set_rarest_k(params.config.rarest_first_k);
+        picker.set_max_partial_pieces(params.config.max_write_buffer_pieces);
 
         (
             Torrent {
@@ -176,25 +342,53 @@ impl Torrent {
                     TorrentContext {
                         info_hash: params.info_hash,
                         client_id: params.client_id,
-                        picker: Picker::new(
-                            params.info.num_pieces,
-                            params.info.piece_len,
-                            params.info.last_piece_len,
-                        ),
+                        picker,
                         torrent_tx: torrent_tx.clone(),
                         info: params.info,
                         disk_tx: params.disk_tx,
+                        dht_port: params.dht_tx.as_ref().map(|_| params.config.dht_port),
+                        pex: params.config.enable_pex && !params.private,
+                        down_limits: RateLimits::new([
+                            params.config.max_down_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+                            params.global_down_limit,
+                        ]),
+                        up_limits: RateLimits::new([
+                            params.config.max_up_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+                            params.global_up_limit,
+                        ]),
+                        upload_slots: Arc::new(Semaphore::new(params.config.upload_slots)),
+                        max_unexpected_blocks: params.config.max_unexpected_blocks,
+                        max_request_queue: params.config.max_request_queue,
+                        connect_timeout: params.config.connect_timeout,
+                        connect_options: crate::p2p::ConnectOptions::from_config(&params.config),
                     }
                 ),
-                trackers: TrackersHandle::new(params.tracker_urls),
+                trackers: TrackersHandle::new(params.tracker_urls, params.config.proxy.clone()),
+                global_connections: params.global_connections,
                 peers: HashMap::new(),
                 available: Vec::new(),
+                connect_failures: HashMap::new(),
+                retrying: Vec::new(),
+                own_addresses: HashSet::new(),
                 user_tx: params.user_tx,
                 torrent_rx,
                 throughput: ThroughputStats::default(),
+                tracker_stats: TrackerStats::default(),
+                files: params.files,
+                cache_stats: CacheStats::default(),
+                num_checked: 0,
+                availability_changed: false,
+                unavailable: Vec::new(),
+                piece_timings: params.config.piece_timing.then(PieceTimings::default),
+                new_pieces: params.config.report_piece_map.then(Vec::new),
                 state: TorrentState::Checking,
                 listen_port: params.listen_port,
                 config: params.config,
+                dht_nodes: params.dht_nodes,
+                dht_tx: params.dht_tx,
+                last_pex: Instant::now(),
+                last_resume_save: Instant::now(),
+                progress_changed: false,
             },
             torrent_tx
         )
@@ -206,27 +400,54 @@ impl Torrent {
         rx: oneshot::Receiver<std::result::Result<Bitfield, AllocationError>>
     ) -> Result<()> {
 
-        // Wait for disk allocation result, set own bitfield to pieces we already have.
-        let bf = rx.await.map_err(|_| TorrentError::DiskFailure)??;
+        let start_time = Instant::now();
+        let mut rx = rx;
+        let mut pending = Vec::new();
+
+        // Wait for disk allocation result, showing progress as existing files are checked.
+        let bf = loop { tokio::select! {
+            bf = &mut rx => break bf.map_err(|_| TorrentError::DiskFailure)??,
+            Some(cmd) = self.torrent_rx.recv() => match cmd {
+                TorrentCommand::CheckProgress { checked } => {
+                    self.num_checked = checked;
+                    self.send_stats(start_time, Instant::now()).await;
+                },
+                // Answered now, so callers aren't left waiting on the check.
+                TorrentCommand::GetStats(tx) => {
+                    let _ = tx.send(self.stats(start_time, Instant::now()).await);
+                },
+                // Trackers haven't been told we started, so there's no need to wait for the check
+                // only to announce started and then stopped.
+                TorrentCommand::Shutdown => return Ok(()),
+                // Handled once running.
+                cmd => pending.push(cmd),
+            },
+        }};
+        // Sent back in the order they arrived, ahead of anything else.
+        while let Ok(cmd) = self.torrent_rx.try_recv() {
+            pending.push(cmd);
+        }
+        for cmd in pending {
+            let _ = self.ctx.torrent_tx.send(cmd);
+        }
+
+        // Set own bitfield to pieces we already have.
         tracing::info!("own bitfield has {}/{} pieces", bf.count_ones(), self.ctx.info.num_pieces);
+        if let Some(new_pieces) = &mut self.new_pieces {
+            new_pieces.extend(bf.iter_ones());
+        }
         if bf.any() {
             self.ctx.picker.pieces.write().await.set_own_bitfield(bf);
         }
 
-        self.run().await?;
+        self.run(start_time).await?;
         Ok(())
     }
 
-    async fn run(&mut self) -> Result<()> {
+    async fn run(&mut self, start_time: Instant) -> Result<()> {
         
-        let start_time = Instant::now();
         let mut ticker = time::interval(time::Duration::from_secs(1));
         
-        // Start listening for incoming connections.
-        let listen_address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.listen_port);
-        let listener = TcpListener::bind(listen_address).await?;
-        tracing::info!("listening on {:?}", listen_address);
-        
         self.trackers.start(self.ctx.torrent_tx.clone()).await;
         self.announce(Some(Event::Started)).await;
 
@@ -242,31 +463,94 @@ impl Torrent {
 
             now = ticker.tick() => self.tick(start_time, now.into_std()).await,
 
-            // Accept incoming peer connections.
-            new_peer_conn = listener.accept() => {
-                match new_peer_conn {
-                    Ok((stream, address)) => {
-                        self.peers.insert(address, PeerHandle::start_session(address, self.ctx.clone(), Some(stream)));
-                    },
-                    Err(e) => tracing::warn!("inbound peer connection error: {}", e),
-                };
-            },
-
             Some(cmd) = self.torrent_rx.recv() => {
                 match cmd {
 
                     // From peers.
                     TorrentCommand::PeerState { address, state } => self.handle_peer_state(address, state).await,
 
+                    TorrentCommand::ConnectFailed { address } => self.handle_connect_failure(address),
+
+                    // Accepted by the client, which read the handshake to find us.
+                    TorrentCommand::InboundPeer(peer) => {
+                        let address = peer.address;
+                        if self.state != TorrentState::Paused && !self.add_peer(address, Some(*peer)) {
+                            tracing::debug!("at connection limit, dropped inbound peer {}", address);
+                        }
+                    },
+
+                    TorrentCommand::OwnAddress { address } => {
+                        tracing::info!("{} is our own address", address);
+                        self.own_addresses.insert(address);
+                    },
+
+                    TorrentCommand::BlockReceived { address, request } => {
+                        for (peer_address, peer) in self.peers.iter() {
+                            if *peer_address != address {
+                                let _ = peer.peer_tx.send(PeerCommand::CancelRequest(request));
+                            }
+                        }
+                    },
+
                     // From disk.
                     TorrentCommand::PieceWritten { idx, valid } => self.handle_piece_write(idx, valid).await,
 
+                    TorrentCommand::DiskError { piece_idx, error } => self.handle_disk_error(piece_idx, error).await,
+
+                    TorrentCommand::Md5Mismatch { path } => {
+                        let _ = self.user_tx.send(UserCommand::TorrentError {
+                            id: self.ctx.info_hash,
+                            msg: format!("md5 mismatch: {}", path.display()),
+                        });
+                    },
+
+                    TorrentCommand::CacheStats(stats) => self.cache_stats = stats,
+
+                    TorrentCommand::CheckProgress { checked } => self.num_checked = checked,
+
                     // From trackers.
-                    TorrentCommand::Peers(peers) => {
+                    TorrentCommand::Peers { peers, tracker } => {
+                        if let Some(tracker) = tracker {
+                            self.tracker_stats = tracker;
+                        }
+                        let peers = self.new_peers(peers);
                         self.available.extend(peers);
                         self.manage_peer_nums().await;
                     },
 
+                    TorrentCommand::TrackersUnreachable => {
+                        tracing::warn!("no trackers reachable");
+                        let _ = self.user_tx.send(UserCommand::TorrentError {
+                            id: self.ctx.info_hash,
+                            msg: "no trackers reachable".to_string(),
+                        });
+                    },
+
+                    TorrentCommand::DhtNode(node) => self.handle_dht_node(node),
+
+                    TorrentCommand::SetDownloadRange(range) => self.set_download_range(range).await,
+
+                    TorrentCommand::SetFilePriorities(priorities) => self.set_file_priorities(priorities).await,
+
+                    TorrentCommand::Reannounce => self.reannounce().await,
+
+                    TorrentCommand::Scrape => self.trackers.scrape(self.ctx.info_hash, self.user_tx.clone()),
+
+                    TorrentCommand::GetStats(tx) => {
+                        let _ = tx.send(self.stats(start_time, Instant::now()).await);
+                    },
+
+                    TorrentCommand::Pause => self.pause().await,
+
+                    TorrentCommand::Resume => self.resume().await,
+
+                    TorrentCommand::StopSeeding => if self.state == TorrentState::Seeding {
+                        tracing::info!("stopped seeding");
+                        break;
+                    } else {
+                        tracing::warn!("can't stop seeding, torrent is {:?}", self.state);
+                    },
+
                     TorrentCommand::Shutdown => break,
                 }
             }
@@ -275,48 +559,75 @@ impl Torrent {
         Ok(())
     }
 
-    async fn shutdown(&mut self) {
+    async fn shutdown(&mut self, result: Result<()>) {
         
         for peer in self.peers.values() {
             peer.peer_tx.send(PeerCommand::Shutdown).ok();
         }
         
         for (addr, peer) in self.peers.drain() {
+            self.global_connections.release();
             if let Err(e) = peer.session_handle.await {
                 tracing::error!("peer task {} panicked: {}", addr, e);
             }
         }
         
-        // Announce completed event to trackers.
-        self.trackers.shutdown().await;
-        let _ = self.user_tx.send(crate::UserCommand::TorrentFinished { id: self.ctx.info_hash });
+        // Leave resume data in sync with what's on disk.
+        // Whilst checking our bitfield isn't known yet, and saving it would lose the existing data.
+        if self.state != TorrentState::Checking {
+            if let Some(rx) = self.save_progress().await {
+                let _ = rx.await;
+            }
+        }
+
+        // Announce stopped event to trackers.
+        let params = self.announce_params(Some(Event::Stopped)).await;
+        self.trackers.shutdown(params).await;
+        let _ = self.user_tx.send(crate::UserCommand::TorrentStopped { id: self.ctx.info_hash, result });
     }
 
     async fn manage_peer_nums(&mut self) {
 
-        let count_to_max = self.config.max_peers - self.peers.len();
+        if self.state == TorrentState::Paused {
+            return;
+        }
+
+        let count_to_max = self.config.max_peers.saturating_sub(self.peers.len());
         let connect_count = count_to_max.min(self.available.len());
         tracing::info!("num peers {}, attempting {} new", self.peers.len(), connect_count); 
         // If there is enough in available, connect to max, otherwise connect to as many possible and announce the number remaining.
-        for address in self.available.drain(..connect_count) {
+        let mut connecting = self.available.drain(..connect_count).collect::<Vec<_>>().into_iter();
+        while let Some(address) = connecting.next() {
             if self.peers.contains_key(&address) {
                 tracing::warn!("peer already connected: {}", address);
                 continue;
             }
-            self.peers.insert(address, PeerHandle::start_session(address, self.ctx.clone(), None));
+            if !self.add_peer(address, None) {
+                tracing::debug!("global connection limit reached");
+                // Keep the unused addresses for when a connection frees up.
+                self.available.splice(0..0, std::iter::once(address).chain(connecting));
+                break;
+            }
         }
-        if self.peers.len() == self.config.max_peers as usize {
+        if self.peers.len() == self.config.max_peers {
             tracing::info!("max peers reached");
-            let _ = self.trackers.tracker_tx.send(None);
+            self.trackers.update(None);
         } else {
             self.announce(None).await;
         }
 
     }
 
-    async fn announce(&mut self, event: Option<Event>) {
+    // Starts a session if neither this torrent's nor the global connection limit is reached.
+    fn add_peer(&mut self, address: SocketAddr, inbound: Option<InboundPeer>) -> bool {
+        if self.peers.len() >= self.config.max_peers || !self.global_connections.try_acquire() {
+            return false;
+        }
+        self.peers.insert(address, PeerHandle::start_session(address, self.ctx.clone(), inbound));
+        true
+    }
 
-        tracing::info!("announcing to trackers");
+    async fn announce_params(&self, event: Option<Event>) -> AnnounceParams {
         let left = self.ctx.info.total_len - 
         (
             self.ctx.picker.pieces
@@ -328,7 +639,7 @@ impl Torrent {
                 as u64
         );
         
-        let params = AnnounceParams {
+        AnnounceParams {
             info_hash: self.ctx.info_hash,
             client_id: self.ctx.client_id,
             port: self.listen_port,
@@ -336,29 +647,71 @@ impl Torrent {
             downloaded: self.throughput.down.total(),
             left,
             event,
-            num_want: None, // Default 50.
-        };
+            num_want: Some(self.peers_wanted()),
+            force: false,
+            ip: self.config.announce_ip,
+        }
+    }
+
+    // How many more peers it takes to reach max peers, counting those waiting to be connected to.
+    // Whilst above zero trackers are announced to as often as their min interval allows.
+    fn peers_wanted(&self) -> usize {
+        self.config.max_peers.saturating_sub(self.peers.len() + self.available.len())
+    }
+
+    // Announce ignoring the tracker's interval, as long as its min interval has passed.
+    async fn reannounce(&mut self) {
+        if self.state == TorrentState::Paused {
+            return;
+        }
+        tracing::info!("forcing announce to trackers");
+        let params = AnnounceParams { force: true, ..self.announce_params(None).await };
+        self.trackers.update(Some(params));
+    }
+
+    async fn announce(&mut self, event: Option<Event>) {
+
+        tracing::info!("announcing to trackers");
+        let params = self.announce_params(event).await;
         tracing::debug!("announce: {:#?}", params);
 
-        // If we have no peers and no trackers, shutdown.
-        if let Err(_) = self.trackers.tracker_tx.send(Some(params)) {
-            if self.peers.is_empty() {
-                tracing::warn!("no peers and no trackers, shutting down");
-                let _ = self.ctx.torrent_tx.send(TorrentCommand::Shutdown);
-            }
-        };
+        if let Some(dht_tx) = &self.dht_tx {
+            let _ = dht_tx.send(DhtCommand::GetPeers {
+                info_hash: self.ctx.info_hash,
+                port: self.listen_port,
+                torrent_tx: self.ctx.torrent_tx.clone(),
+            });
+        }
+
+        // If we have no peers, no trackers and no dht, shutdown.
+        if !self.trackers.update(Some(params)) && self.peers.is_empty() && self.dht_tx.is_none() {
+            tracing::warn!("no peers and no trackers, shutting down");
+            let _ = self.ctx.torrent_tx.send(TorrentCommand::Shutdown);
+        }
     }
 
     async fn handle_piece_write(&mut self, idx: usize, valid: bool) {
         if valid {
-            self.ctx.picker.partial_pieces.write().await.remove(&idx);
+            // Marked as had first, so sessions with blocks still in flight for it can tell it's complete.
             self.ctx.picker.pieces.write().await.received_piece(idx);
+            let partial_piece = self.ctx.picker.partial_pieces.write().await.remove(&idx);
+            self.availability_changed = true;
+            self.progress_changed = true;
+            if let Some(new_pieces) = &mut self.new_pieces {
+                new_pieces.push(idx);
+            }
+
+            if let (Some(timings), Some(piece)) = (&mut self.piece_timings, partial_piece) {
+                let elapsed = piece.read().await.start_time.elapsed();
+                tracing::debug!("piece {} verified and written in {:?}", idx, elapsed);
+                timings.record(elapsed);
+            }
             
             let num_pieces_missing = self.ctx.picker.pieces.read().await.own_bitfield().count_zeros();
             tracing::info!("piece {} downloaded, {} pieces remain", idx, num_pieces_missing);
 
             for (addr, peer) in &self.peers {
-                if let Err(_) = peer.peer_tx.send(PeerCommand::PieceWritten(idx)) {
+                if peer.peer_tx.send(PeerCommand::PieceWritten(idx)).is_err() {
                     tracing::error!("peer {} unexpectedly dropped", addr);
                 };
             }
@@ -366,25 +719,164 @@ impl Torrent {
             // Check if torrent is fully downloaded.
             if num_pieces_missing == 0 {
                 tracing::info!("torrent download complete");
-                let _ = self.ctx.torrent_tx.send(TorrentCommand::Shutdown);
+                self.state = TorrentState::Seeding;
+                self.announce(Some(Event::Completed)).await;
+                let _ = self.user_tx.send(UserCommand::TorrentFinished { id: self.ctx.info_hash });
+                if self.config.verify_md5 {
+                    let _ = self.ctx.disk_tx.send(DiskCommand::VerifyMd5 { id: self.ctx.info_hash });
+                }
+                if !self.config.seed_after_complete {
+                    let _ = self.ctx.torrent_tx.send(TorrentCommand::Shutdown);
+                }
+            } else if self.ctx.picker.pieces.read().await.wanted_complete() {
+                tracing::info!("wanted pieces downloaded");
+                self.pause().await;
             }
         
         } else {
             // Free all blocks in piece.
             // TODO: Punish peer in some way.
+            if let Some(timings) = &mut self.piece_timings {
+                timings.record_failed();
+            }
             if let Some(piece) = self.ctx.picker.partial_pieces.read().await.get(&idx) {
                 piece.write().await.free_all_blocks();
             }
         }
     }
 
+    // Pauses rather than carrying on downloading pieces that can't be saved.
+    async fn handle_disk_error(&mut self, idx: usize, error: DiskError) {
+        tracing::error!("disk error on piece {}: {}", idx, error);
+        // The piece can be downloaded again once resumed.
+        if let Some(piece) = self.ctx.picker.partial_pieces.read().await.get(&idx) {
+            piece.write().await.free_all_blocks();
+        }
+        let _ = self.user_tx.send(UserCommand::TorrentError {
+            id: self.ctx.info_hash,
+            msg: format!("disk error: {}", error),
+        });
+        self.pause().await;
+    }
+
+    // Limits downloading to the pieces covering the byte range.
+    async fn set_download_range(&mut self, range: std::ops::Range<u64>) {
+        
+        let end = range.end.min(self.ctx.info.total_len);
+        if range.start >= end {
+            tracing::warn!("invalid download range: {:?}", range);
+            return;
+        }
+        let piece_len = self.ctx.info.piece_len as u64;
+        let first = (range.start / piece_len) as usize;
+        let last = ((end - 1) / piece_len) as usize;
+        tracing::info!("download limited to pieces {}..={}", first, last);
+
+        let mut in_range = Bitfield::repeat(false, self.ctx.info.num_pieces as usize);
+        in_range[first..=last].fill(true);
+        let mut pieces = self.ctx.picker.pieces.write().await;
+        pieces.set_range(in_range);
+        if pieces.wanted_complete() {
+            drop(pieces);
+            self.pause().await;
+        }
+    }
+
+    // Pieces only in skipped files aren't picked, those shared with a wanted file still are.
+    async fn set_file_priorities(&mut self, priorities: Vec<FilePriority>) {
+
+        if priorities.len() != self.files.len() {
+            tracing::warn!("got {} file priorities for {} files", priorities.len(), self.files.len());
+            return;
+        }
+        let (wanted, high) = piece_priorities(&self.ctx.info, &self.files, &priorities);
+        tracing::info!("{}/{} pieces wanted", wanted.count_ones(), wanted.len());
+        let _ = self.ctx.disk_tx.send(DiskCommand::SkipFiles {
+            id: self.ctx.info_hash,
+            skip: priorities.iter().map(|p| *p == FilePriority::Skip).collect(),
+        });
+
+        let mut pieces = self.ctx.picker.pieces.write().await;
+        pieces.set_priorities(wanted, high);
+        if pieces.wanted_complete() {
+            drop(pieces);
+            self.pause().await;
+        }
+    }
+
+    // Stops downloading, disconnecting peers and telling trackers we've stopped.
+    // Pieces we have are kept, so resuming carries on where we left off.
+    async fn pause(&mut self) {
+        if self.state == TorrentState::Paused {
+            return;
+        }
+        tracing::info!("pausing torrent");
+        self.state = TorrentState::Paused;
+        for peer in self.peers.values() {
+            let _ = peer.peer_tx.send(PeerCommand::Shutdown);
+        }
+        self.available.clear();
+        self.retrying.clear();
+        let params = self.announce_params(Some(Event::Stopped)).await;
+        self.trackers.shutdown(params).await;
+    }
+
+    async fn resume(&mut self) {
+        if self.state != TorrentState::Paused {
+            tracing::warn!("can't resume, torrent is {:?}", self.state);
+            return;
+        }
+        tracing::info!("resuming torrent");
+        self.state = if self.ctx.picker.pieces.read().await.all() {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
+        self.trackers.start(self.ctx.torrent_tx.clone()).await;
+        self.announce(Some(Event::Started)).await;
+    }
+
+    fn handle_dht_node(&mut self, node: SocketAddr) {
+        let is_new = match self.dht_nodes.lock() {
+            Ok(mut nodes) => add_dht_node(&mut nodes, node, Instant::now()),
+            Err(e) => {
+                tracing::error!("dht nodes poisoned: {}", e);
+                return;
+            },
+        };
+        if is_new {
+            tracing::debug!("discovered dht node: {}", node);
+            if let Some(dht_tx) = &self.dht_tx {
+                let _ = dht_tx.send(DhtCommand::AddNode(node));
+            }
+            let _ = self.user_tx.send(UserCommand::DhtNodeDiscovered { addr: node });
+        }
+    }
+
     // Also handles disconnections.
     async fn handle_peer_state(&mut self, address: SocketAddr, state: SessionState) {
         if let Some(peer) = self.peers.get_mut(&address) {
+            if peer.state.num_pieces != state.num_pieces || state.conn_state != peer.state.conn_state {
+                self.availability_changed = true;
+            }
+            let was_connected = peer.state.conn_state == ConnState::Connected;
+            match state.conn_state {
+                ConnState::Connected if !was_connected => {
+                    self.connect_failures.remove(&address);
+                    if let Some(peer_id) = state.peer_id {
+                        let _ = self.user_tx.send(UserCommand::PeerConnected { id: self.ctx.info_hash, addr: address, peer_id });
+                    }
+                },
+                ConnState::Disconnected if was_connected => {
+                    let _ = self.user_tx.send(UserCommand::PeerDisconnected { id: self.ctx.info_hash, addr: address });
+                },
+                _ => {},
+            }
             peer.state = state;
             self.throughput += &state.throughput;
             if peer.state.conn_state == ConnState::Disconnected {
                 self.peers.remove(&address);
+                self.global_connections.release();
                 self.manage_peer_nums().await;
             }
 
@@ -393,8 +885,82 @@ impl Torrent {
         }
     }
 
+    // Leaves out peers already known, ourselves, and those that have failed to connect too many times.
+    fn new_peers(&self, peers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let known = self.peers
+            .keys()
+            .chain(self.available.iter())
+            .chain(self.retrying.iter().map(|(address, _)| address))
+            .copied()
+            .collect();
+        let retries = self.config.connect_retries;
+        filter_peers(peers, known, &self.own_addresses, self.listen_port)
+            .into_iter()
+            .filter(|address| self.connect_failures.get(address).is_none_or(|&n| n <= retries))
+            .collect()
+    }
+
+    // Peers that couldn't be reached are tried again after a cooldown, until they've failed too many times.
+    fn handle_connect_failure(&mut self, address: SocketAddr) {
+        let failures = self.connect_failures.entry(address).or_insert(0);
+        *failures += 1;
+        if *failures <= self.config.connect_retries {
+            tracing::debug!("couldn't connect to {}, retrying in {:?}", address, self.config.connect_retry_cooldown);
+            self.retrying.push((address, Instant::now() + self.config.connect_retry_cooldown));
+        } else {
+            tracing::debug!("couldn't connect to {}, giving up", address);
+        }
+    }
+
+    async fn retry_peers(&mut self, now: Instant) {
+        if self.state == TorrentState::Paused {
+            return;
+        }
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retrying)
+            .into_iter()
+            .partition(|(_, retry_at)| *retry_at <= now);
+        self.retrying = waiting;
+        if !ready.is_empty() {
+            self.available.extend(ready.into_iter().map(|(address, _)| address));
+            self.manage_peer_nums().await;
+        }
+    }
+
     async fn tick(&mut self, start_time: Instant, now: Instant) {
 
+        // Disconnected peers may still report their last transfers, which shouldn't show as a rate.
+        if self.state == TorrentState::Paused {
+            self.throughput.idle();
+        }
+        self.send_stats(start_time, now).await;
+        self.throughput.reset();
+        self.handle_choking_peers(now);
+        self.retry_peers(now).await;
+        if self.ctx.pex && now.duration_since(self.last_pex) >= PEX_INTERVAL {
+            self.last_pex = now;
+            self.share_peers();
+        }
+        if self.availability_changed {
+            self.availability_changed = false;
+            self.check_availability().await;
+        }
+        if self.progress_changed && now.duration_since(self.last_resume_save) >= RESUME_INTERVAL {
+            self.last_resume_save = now;
+            self.save_progress().await;
+        }
+    }
+
+    async fn send_stats(&mut self, start_time: Instant, now: Instant) {
+        let mut stats = self.stats(start_time, now).await;
+        stats.new_pieces = self.new_pieces.as_mut().map(std::mem::take);
+        let _ = self.user_tx.send(UserCommand::TorrentStats {
+            id: self.ctx.info_hash,
+            stats,
+        });
+    }
+
+    async fn stats(&self, start_time: Instant, now: Instant) -> TorrentStats {
+
         let time_elapsed = now.duration_since(start_time);
         let num_pieces = self.ctx.info.num_pieces as usize;
         let num_downloaded = self.ctx.picker.pieces.read().await.own_bitfield().count_ones();
@@ -409,23 +975,179 @@ impl Torrent {
             })
             .collect();
 
-        let stats = TorrentStats {
+        TorrentStats {
             start_time,
             time_elapsed,
             piece_stats: PieceStats {
                 num_pieces,
                 num_pending,
                 num_downloaded,
+                num_checked: self.num_checked,
             },
             state: self.state,
             throughput: self.throughput,
+            tracker: self.tracker_stats,
             peer_stats,
-        };
+            piece_timing: self.piece_timings.as_ref().map(PieceTimings::stats),
+            new_pieces: None,
+            cache: self.cache_stats,
+        }
+    }
 
-        let _ = self.user_tx.send(UserCommand::TorrentStats {
+    // Has the disk save our verified pieces, so restarting doesn't need to check every piece.
+    // Gives a receiver that resolves once saved, None if there's no resume directory.
+    async fn save_progress(&mut self) -> Option<oneshot::Receiver<()>> {
+        let dir = self.config.resume_dir.clone()?;
+        let (tx, rx) = oneshot::channel();
+        self.progress_changed = false;
+        let bitfield = self.ctx.picker.pieces.read().await.own_bitfield().clone();
+        self.ctx.disk_tx.send(DiskCommand::SaveProgress {
             id: self.ctx.info_hash,
-            stats,
-        });
-        self.throughput.reset();
+            dir,
+            bitfield,
+            tx,
+        }).ok()?;
+        Some(rx)
+    }
+
+    // Sends connected peers to every session, each tells its peer what changed since last time.
+    fn share_peers(&self) {
+        let connected: Vec<SocketAddr> = self.peers
+            .iter()
+            .filter(|(_, peer)| peer.state.conn_state == ConnState::Connected)
+            .map(|(address, _)| *address)
+            .collect();
+        for peer in self.peers.values() {
+            let _ = peer.peer_tx.send(PeerCommand::Pex(connected.clone()));
+        }
+    }
+
+    // Warn the user if the torrent can't complete with the peers we have.
+    async fn check_availability(&mut self) {
+        
+        if self.state != TorrentState::Downloading {
+            return;
+        }
+        // Only meaningful once peers have told us what they have.
+        if !self.peers.values().any(|peer| peer.state.conn_state == ConnState::Connected) {
+            return;
+        }
+
+        let unavailable = self.ctx.picker.pieces.read().await.unavailable_pieces();
+        if unavailable != self.unavailable {
+            if !unavailable.is_empty() {
+                tracing::warn!("{} pieces not available from any peer", unavailable.len());
+                let _ = self.user_tx.send(UserCommand::TorrentIncomplete {
+                    id: self.ctx.info_hash,
+                    missing_pieces: unavailable.clone(),
+                });
+            }
+            self.unavailable = unavailable;
+        }
+    }
+
+    // Deal with peers that have kept us choked whilst we are interested.
+    fn handle_choking_peers(&mut self, now: Instant) {
+        let disconnect = match self.config.choked_peer_action {
+            ChokedPeerAction::NotInterested => false,
+            ChokedPeerAction::Disconnect => true,
+            ChokedPeerAction::DisconnectIfAvailable => !self.available.is_empty(),
+        };
+        for (address, peer) in self.peers.iter_mut() {
+            let Some(since) = peer.state.interested_since else { continue };
+            if !peer.state.interested 
+            || !peer.state.peer_choking 
+            || now.saturating_duration_since(since) < self.config.choked_timeout 
+            {
+                continue;
+            }
+            tracing::debug!("peer {} choked us for too long", address);
+            let cmd = if disconnect { PeerCommand::Shutdown } else { PeerCommand::NotInterested };
+            let _ = peer.peer_tx.send(cmd);
+            // Don't act again before the session reports back.
+            peer.state.interested_since = None;
+        }
+    }
+}
+
+// Trackers, DHT and peer exchange give overlapping peers, so only those not already known are kept.
+// Our own address isn't known until connecting to it, other than through loopback.
+fn filter_peers(
+    peers: Vec<SocketAddr>,
+    mut known: HashSet<SocketAddr>,
+    own_addresses: &HashSet<SocketAddr>,
+    listen_port: u16,
+) -> Vec<SocketAddr> {
+    peers
+        .into_iter()
+        .filter(|address| {
+            let ip = address.ip();
+            let is_own = own_addresses.contains(address)
+                || (address.port() == listen_port && (ip.is_loopback() || ip.is_unspecified()));
+            !is_own && known.insert(*address)
+        })
+        .collect()
+}
+
+// Returns true if the node is new or was last added over its lifetime ago.
+// Expired nodes are only cleared out once the set is full, after which new nodes are dropped.
+fn add_dht_node(nodes: &mut HashMap<SocketAddr, Instant>, node: SocketAddr, now: Instant) -> bool {
+    if nodes.get(&node).is_some_and(|added| now.duration_since(*added) < DHT_NODE_LIFETIME) {
+        return false;
+    }
+    if nodes.len() >= MAX_DHT_NODES && !nodes.contains_key(&node) {
+        nodes.retain(|_, added| now.duration_since(*added) < DHT_NODE_LIFETIME);
+        if nodes.len() >= MAX_DHT_NODES {
+            return false;
+        }
+    }
+    nodes.insert(node, now);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_dht_node() {
+        let node = |i: usize| SocketAddr::new([10, 0, (i >> 8) as u8, i as u8].into(), 6881);
+        let now = Instant::now();
+        let mut nodes = HashMap::new();
+
+        for i in 0..MAX_DHT_NODES {
+            assert!(add_dht_node(&mut nodes, node(i), now));
+        }
+        assert!(!add_dht_node(&mut nodes, node(0), now));
+        assert!(!add_dht_node(&mut nodes, node(MAX_DHT_NODES), now));
+        assert_eq!(nodes.len(), MAX_DHT_NODES);
+
+        // Once expired, nodes make room for new ones and are passed on again if seen.
+        let later = now + DHT_NODE_LIFETIME;
+        assert!(add_dht_node(&mut nodes, node(MAX_DHT_NODES), later));
+        assert_eq!(nodes.len(), 1);
+        assert!(add_dht_node(&mut nodes, node(0), later));
+        assert!(!add_dht_node(&mut nodes, node(0), later));
+    }
+
+    #[test]
+    fn test_filter_peers() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let known = HashSet::from([addr("1.1.1.1:6881")]);
+        let own = HashSet::from([addr("2.2.2.2:50000")]);
+
+        let peers = vec![
+            addr("1.1.1.1:6881"),   // Already known.
+            addr("3.3.3.3:6881"),
+            addr("3.3.3.3:6881"),   // Repeated.
+            addr("2.2.2.2:50000"),  // Ourselves.
+            addr("127.0.0.1:50000"),
+            addr("127.0.0.1:6881"), // Another client on this machine.
+            addr("[::1]:50000"),
+        ];
+        assert_eq!(
+            filter_peers(peers, known, &own, 50000),
+            vec![addr("3.3.3.3:6881"), addr("127.0.0.1:6881")],
+        );
     }
 }
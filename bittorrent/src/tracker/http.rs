@@ -1,9 +1,10 @@
-use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, time::{Duration, Instant}};
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, time::{Duration, Instant}};
 use bytes::Buf;
 use url::Url;
 use serde::de;
 use serde_derive::Deserialize;
-use super::{AnnounceParams, Result, Tracker, TrackerError, DEFAULT_MIN_ANNOUNCE_INTERVAL};
+use crate::{config::ProxyConfig, stats::TrackerStats, ID};
+use super::{AnnounceParams, AnnounceResponse, Result, ScrapeData, Tracker, TrackerError, DEFAULT_MIN_ANNOUNCE_INTERVAL};
 
 pub struct HttpTracker {
 
@@ -22,22 +23,37 @@ pub struct HttpTracker {
 }
 
 impl HttpTracker {
-    pub fn new(url: Url) -> Self {
-        Self {
-            client: reqwest::Client::new(),
+    pub fn new(url: Url, proxy: Option<&ProxyConfig>) -> Result<Self> {
+        let mut client = reqwest::Client::builder();
+        if let Some(proxy) = proxy {
+            client = client.proxy(reqwest::Proxy::all(proxy.url())?);
+        }
+        Ok(Self {
+            client: client.build()?,
             url,
             id: None,
             last_announce: None,
             interval: None,
             min_interval: None,
-        }
+        })
+    }
+
+    // By convention the scrape url replaces "announce" at the start of the last path segment with "scrape".
+    // Trackers without "announce" there don't support scrape.
+    fn scrape_url(&self) -> Option<Url> {
+        let path = self.url.path();
+        let segment_start = path.rfind('/')? + 1;
+        let rest = path[segment_start..].strip_prefix("announce")?;
+        let mut url = self.url.clone();
+        url.set_path(&format!("{}scrape{}", &path[..segment_start], rest));
+        Some(url)
     }
 }
 
 #[async_trait::async_trait]
 impl Tracker for HttpTracker {
     
-    async fn announce(&mut self, params: AnnounceParams) -> Result<Vec<SocketAddr>> {
+    async fn announce(&mut self, params: AnnounceParams) -> Result<AnnounceResponse> {
 
         let mut url = format!(
             "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
@@ -55,6 +71,9 @@ impl Tracker for HttpTracker {
         if let Some(num_peers) = params.num_want {
             url.push_str(&format!("&numwant={}", num_peers));
         }
+        if let Some(ip) = params.ip {
+            url.push_str(&format!("&ip={}", ip));
+        }
         if let Some(tracker_id) = &self.id {
             url.push_str(&format!("&tracker_id={}", tracker_id));
         }
@@ -88,9 +107,50 @@ impl Tracker for HttpTracker {
         }
 
         self.last_announce = Some(Instant::now());
-        Ok(resp.peers)
+        let mut peers = resp.peers;
+        peers.extend(resp.peers6);
+        Ok(AnnounceResponse {
+            peers,
+            stats: TrackerStats {
+                seeders: resp.complete,
+                leechers: resp.incomplete,
+                interval: self.interval,
+            },
+        })
+    }
+
+    async fn scrape(&mut self, info_hashes: &[ID]) -> Result<HashMap<ID, ScrapeData>> {
+
+        let mut url = self.scrape_url().ok_or(TrackerError::ScrapeUnsupported)?;
+        // Keep any query the tracker url already has, e.g. a passkey.
+        let query = url.query()
+            .into_iter()
+            .map(String::from)
+            .chain(info_hashes.iter().map(|info_hash| format!("info_hash={}", urlencoding::encode_binary(info_hash))))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(Some(&query));
+        tracing::debug!("scrape url: {}", url);
+
+        let raw_resp = self.client
+            .get(url)
+            .send()
+            .await?
+            .bytes()
+            .await?;
+        let resp: ScrapeResponse = bencode::decode_bytes(&raw_resp)?;
+        if let Some(failure) = resp.failure_reason {
+            return Err(TrackerError::ResponseError(failure));
+        }
+
+        Ok(resp.files
+            .into_iter()
+            .filter_map(|(info_hash, data)| Some((info_hash.into_vec().try_into().ok()?, data)))
+            .collect())
     }
 
+    fn url(&self) -> &Url { &self.url }
+
     fn can_announce(&self, time: Instant) -> bool {
 
         if let Some(last_announce) = self.last_announce {
@@ -105,8 +165,9 @@ impl Tracker for HttpTracker {
     fn should_announce(&self, time: Instant) -> bool {
             
         if let Some(last_announce) = self.last_announce {
+            // An interval shorter than the min interval is held to the min interval.
             time.duration_since(last_announce) 
-            >= self.interval.unwrap_or(Duration::from_secs(DEFAULT_MIN_ANNOUNCE_INTERVAL))
+            >= self.interval.max(self.min_interval).unwrap_or(Duration::from_secs(DEFAULT_MIN_ANNOUNCE_INTERVAL))
 
         } else {
             true
@@ -148,6 +209,23 @@ pub struct HttpResponse {
     #[serde(default)]
     #[serde(deserialize_with = "peer_derserialize")]
     pub peers: Vec<SocketAddr>,
+
+    // IPv6 peers, always in compact form.
+    #[serde(default)]
+    #[serde(deserialize_with = "peer6_deserialize")]
+    pub peers6: Vec<SocketAddr>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ScrapeResponse {
+
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+
+    // Stats keyed by info hash.
+    #[serde(default)]
+    pub files: HashMap<serde_bytes::ByteBuf, ScrapeData>,
+
 }
 
 // The tracker can either return a dictionary model or a compacted string.
@@ -175,7 +253,7 @@ where
             E: de::Error, 
         {   
             
-            if v.len() % 6 != 0 {
+            if !v.len().is_multiple_of(6) {
                 return Err(E::custom("peer string not multiple of 6"));
             }
 
@@ -195,6 +273,7 @@ where
 
         // Dictionary model.
         // The dictionary model is a list of dictionaries, each with the keys "ip" and "port".
+        // The ip is a dotted quad, hex IPv6 or a hostname, hostnames aren't resolved so those peers are skipped.
         fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
         where
             A: de::SeqAccess<'de>, 
@@ -207,11 +286,10 @@ where
 
             let mut peers = Vec::new();
             while let Some(peer) = seq.next_element::<PeerItem>()? {
-                let ip = match peer.ip.parse::<u32>() {
-                    Ok(ip) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), peer.port),
-                    Err(_) => continue,
-                };
-                peers.push(ip);
+                match peer.ip.parse::<IpAddr>() {
+                    Ok(ip) => peers.push(SocketAddr::new(ip, peer.port)),
+                    Err(_) => tracing::debug!("skipping peer with invalid ip: {}", peer.ip),
+                }
             }
 
             Ok(peers)
@@ -221,6 +299,23 @@ where
     deserializer.deserialize_any(PeerVisitor)
 }
 
+// IPv6 peers are given under their own key.
+// The first 16 bytes are the IP address and the last 2 bytes are the port number.
+// Reference: https://www.bittorrent.org/beps/bep_0007.html
+fn peer6_deserialize<'de, D>(deserializer: D) -> std::result::Result<Vec<SocketAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let buf: serde_bytes::ByteBuf = serde_bytes::deserialize(deserializer)?;
+    if !buf.len().is_multiple_of(18) {
+        return Err(de::Error::custom("peer6 string not multiple of 18"));
+    }
+
+    Ok(buf.chunks_exact(18).map(|mut chunk| {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::from(chunk.get_u128())), chunk.get_u16())
+    }).collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,4 +331,70 @@ mod tests {
         assert!(response.peers.contains(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(97, 117, 154, 184)), 5000)));
         assert!(response.peers.contains(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(5, 135, 159, 46)), 51413)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_response_dictionary() {
+        let mut raw = b"d8:intervali1800e5:peersl".to_vec();
+        for (ip, port) in [("192.168.1.1", 6881), ("2001:db8::1", 51413), ("tracker.example.com", 6881)] {
+            raw.extend_from_slice(format!("d2:ip{}:{}7:peer id20:", ip.len(), ip).as_bytes());
+            raw.extend_from_slice(&[0xff; 20]);
+            raw.extend_from_slice(format!("4:porti{}ee", port).as_bytes());
+        }
+        raw.extend_from_slice(b"ee");
+        let response: HttpResponse = bencode::decode_bytes(&raw).unwrap();
+        // Hostnames are skipped.
+        assert_eq!(response.peers, [
+            SocketAddr::new(Ipv4Addr::new(192, 168, 1, 1).into(), 6881),
+            SocketAddr::new("2001:db8::1".parse::<Ipv6Addr>().unwrap().into(), 51413),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_response_peers6() {
+        let mut raw = b"d8:intervali1800e5:peers6:".to_vec();
+        raw.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        raw.extend_from_slice(b"6:peers618:");
+        raw.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        raw.extend_from_slice(&[0xc8, 0xd5]);
+        raw.push(b'e');
+        let response: HttpResponse = bencode::decode_bytes(&raw).unwrap();
+        assert_eq!(response.peers, [SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 6881)]);
+        assert_eq!(response.peers6, [SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 51413)]);
+
+        let raw = b"d6:peers617:aaaaaaaaaaaaaaaaae";
+        assert!(bencode::decode_bytes::<HttpResponse>(raw).is_err());
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape_url = |url: &str| HttpTracker::new(Url::parse(url).unwrap(), None).unwrap().scrape_url().map(String::from);
+        assert_eq!(scrape_url("http://example.com/announce").as_deref(), Some("http://example.com/scrape"));
+        assert_eq!(scrape_url("http://example.com/x/announce.php?key=1").as_deref(), Some("http://example.com/x/scrape.php?key=1"));
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_min_interval() {
+        let mut tracker = HttpTracker::new(Url::parse("http://example.com/announce").unwrap(), None).unwrap();
+        let now = Instant::now();
+        tracker.last_announce = Some(now);
+        tracker.interval = Some(Duration::from_secs(30));
+        tracker.min_interval = Some(Duration::from_secs(120));
+
+        assert!(!tracker.can_announce(now + Duration::from_secs(60)));
+        assert!(!tracker.should_announce(now + Duration::from_secs(60)));
+        assert!(tracker.can_announce(now + Duration::from_secs(120)));
+        assert!(tracker.should_announce(now + Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let mut raw = b"d5:filesd20:".to_vec();
+        raw.extend_from_slice(&[0xaa; 20]);
+        raw.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let response: ScrapeResponse = bencode::decode_bytes(&raw).unwrap();
+        let data = response.files.get(&serde_bytes::ByteBuf::from(vec![0xaa; 20])).unwrap();
+        assert_eq!(*data, ScrapeData { complete: 5, downloaded: 50, incomplete: 10 });
+    }
+}
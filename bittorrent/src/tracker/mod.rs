@@ -1,8 +1,13 @@
-use std::{net::SocketAddr, time::Instant};
-use tokio::task::JoinHandle;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::Instrument;
 use url::Url;
-use crate::{torrent::{TorrentCommand, TorrentTx}, ID};
+use crate::{config::ProxyConfig, stats::TrackerStats, torrent::{TorrentCommand, TorrentTx}, UserCommand, UserTx, ID};
 
 mod http;
 mod udp;
@@ -10,12 +15,23 @@ pub use udp::UdpTracker;
 pub use http::HttpTracker;
 
 type Result<T> = std::result::Result<T, TrackerError>;
-pub type TrackerTx = tokio::sync::watch::Sender<Option<AnnounceParams>>;
-pub type TrackerRx = tokio::sync::watch::Receiver<Option<AnnounceParams>>;
+type TrackerTx = mpsc::UnboundedSender<Option<AnnounceParams>>;
+type TrackerRx = mpsc::UnboundedReceiver<Option<AnnounceParams>>;
+
+// How long to wait for stopped announces on shutdown.
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 // In cases where the tracker doesn't give us a min interval.
 const DEFAULT_MIN_ANNOUNCE_INTERVAL: u64 = 60; // seconds
 
+// Wait before retrying a tier where every tracker failed, doubling with each failure up to the max.
+const RETRY_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+// Limit on a single announce, so a dead tracker doesn't hold up the rest of its tier.
+// UDP trackers retransmit for far longer, this leaves time for a couple of attempts.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(thiserror::Error, Debug)]
 pub enum TrackerError {
 
@@ -37,109 +53,302 @@ pub enum TrackerError {
     #[error("response error: {0}")]
     ResponseError(String),
 
+    #[error("tracker doesn't support scrape")]
+    ScrapeUnsupported,
+
+    #[error("unsupported tracker scheme: {0}")]
+    UnsupportedScheme(String),
+
+    #[error("udp trackers can't be used through a proxy")]
+    ProxyUnsupported,
+
 }
 
 pub struct TrackersHandle {
 
-    // Bit wasteful to keep here i guess.
-    urls: Vec<Url>,
+    // Trackers grouped by tier, in order of preference.
+    tiers: Vec<Vec<Url>>,
 
     handles: Vec<JoinHandle<()>>,
 
-    tracker_rx: TrackerRx,
+    // Number of tiers where no tracker is responding.
+    failed_tiers: Arc<AtomicUsize>,
 
-    pub tracker_tx: TrackerTx,
+    // One for each running tier, so every tier sees every event.
+    tracker_txs: Vec<TrackerTx>,
+
+    // HTTP trackers are reached through this, UDP trackers are skipped.
+    proxy: Option<ProxyConfig>,
     
 }
 
 impl TrackersHandle {
 
-    pub fn new(urls: Vec<Vec<Url>>) -> Self {
+    pub fn new(urls: Vec<Vec<Url>>, proxy: Option<ProxyConfig>) -> Self {
         
-        let (tracker_tx, tracker_rx) = tokio::sync::watch::channel(None);
-        let urls = urls.into_iter().flatten().collect();
+        let tiers = urls.into_iter().filter(|tier| !tier.is_empty()).collect();
 
         Self {
-            urls,
-            tracker_rx,
-            tracker_tx,
+            tiers,
+            failed_tiers: Arc::new(AtomicUsize::new(0)),
+            tracker_txs: Vec::new(),
             handles: Vec::new(),
+            proxy,
         }
     }
 
+    // Every tier is announced to, each by its own task.
+    // Can be started again after shutdown, e.g. when a paused torrent resumes.
     pub async fn start(&mut self, torrent_tx: TorrentTx) {
-        
-        let mut handles = vec![];
-        for url in self.urls.iter() {
-
-            // Create tracker based on scheme.
-            let mut tracker: Box<dyn Tracker> = match url.scheme() {
-                "http" => Box::new(HttpTracker::new(url.clone())),
-                "udp"  => Box::new(UdpTracker::new(url.clone()).await),
-                _ => {
-                    tracing::warn!("unsupported tracker scheme: {}", url.scheme());
-                    continue;
-                },
-            };
 
-            let tx = torrent_tx.clone();
-            let rx = self.tracker_rx.clone();
-            let handle = tokio::spawn(async move {
-                if let Err(e) = tracker.run(tx, rx).await {
-                    tracing::error!("tracker error: {}", e);
+        self.failed_tiers.store(0, Ordering::SeqCst);
+
+        let mut tiers = vec![];
+        for (i, urls) in self.tiers.iter().enumerate() {
+            let mut tier = Vec::with_capacity(urls.len());
+            for url in urls {
+                match new_tracker(url, self.proxy.as_ref()).await {
+                    Ok(tracker) => tier.push(tracker),
+                    Err(e) => tracing::warn!("skipping tracker {}: {}", url, e),
                 }
-            }.instrument(tracing::info_span!("tracker", url = %url)));
+            }
+            if !tier.is_empty() {
+                tiers.push((i, tier));
+            }
+        }
+
+        // Tiers without a usable tracker aren't started, so they don't count towards all failing.
+        let mut handles = vec![];
+        let mut tracker_txs = vec![];
+        let num_tiers = tiers.len();
+        for (i, tier) in tiers {
+            let status = TierStatus { failed_tiers: self.failed_tiers.clone(), num_tiers };
+            let tx = torrent_tx.clone();
+            let (tracker_tx, tracker_rx) = mpsc::unbounded_channel();
+            let handle = tokio::spawn(
+                run_tier(tier, status, tx, tracker_rx).instrument(tracing::info_span!("tracker tier", tier = i))
+            );
             handles.push(handle);
+            tracker_txs.push(tracker_tx);
         }
 
         self.handles = handles;
+        self.tracker_txs = tracker_txs;
+    }
+
+    // Gives the tiers the latest params, they announce once their interval allows unless there's an event or it's forced.
+    // None holds off announcing. Returns false if no tier is running.
+    pub fn update(&self, params: Option<AnnounceParams>) -> bool {
+        let mut sent = false;
+        for tracker_tx in &self.tracker_txs {
+            sent |= tracker_tx.send(params).is_ok();
+        }
+        sent
     }
 
-    pub async fn shutdown(&mut self) {
+    // Asks every tracker for swarm stats, results are sent to the user as they arrive.
+    // Uses its own tracker instances, so announcing isn't held up.
+    pub fn scrape(&self, info_hash: ID, user_tx: UserTx) {
+        for url in self.tiers.iter().flatten().cloned() {
+            let user_tx = user_tx.clone();
+            let proxy = self.proxy.clone();
+            tokio::spawn(async move {
+                let Ok(mut tracker) = new_tracker(&url, proxy.as_ref()).await else { return };
+                match tracker.scrape(&[info_hash]).await {
+                    Ok(mut files) => if let Some(data) = files.remove(&info_hash) {
+                        let _ = user_tx.send(UserCommand::ScrapeResult { id: info_hash, tracker: url, data });
+                    },
+                    Err(TrackerError::ScrapeUnsupported) => tracing::debug!("{} doesn't support scrape", url),
+                    Err(e) => tracing::warn!("scrape of {} failed: {}", url, e),
+                }
+            }.instrument(tracing::info_span!("scrape")));
+        }
+    }
+
+    // Sends a stopped announce, then waits for trackers to finish, giving up after a timeout.
+    pub async fn shutdown(&mut self, params: AnnounceParams) {
+        self.update(Some(AnnounceParams { event: Some(Event::Stopped), ..params }));
+        self.tracker_txs.clear();
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_TIMEOUT;
         for handle in self.handles.drain(..) {
-            if let Err(e) = handle.await {
-                tracing::error!("tracker join error: {}", e);
-            };
+            let abort_handle = handle.abort_handle();
+            match tokio::time::timeout_at(deadline, handle).await {
+                Ok(Err(e)) => tracing::error!("tracker join error: {}", e),
+                Err(_) => {
+                    tracing::warn!("tracker did not stop in time");
+                    abort_handle.abort();
+                },
+                Ok(Ok(())) => {},
+            }
         }
     }
 }
 
-#[async_trait::async_trait]
-pub trait Tracker: Send + Sync {
+// Creates a tracker based on the url's scheme.
+async fn new_tracker(url: &Url, proxy: Option<&ProxyConfig>) -> Result<Box<dyn Tracker>> {
+    match url.scheme() {
+        "http" => Ok(Box::new(HttpTracker::new(url.clone(), proxy)?)),
+        // Would need the proxy to relay UDP, which few do.
+        "udp" if proxy.is_some() => Err(TrackerError::ProxyUnsupported),
+        "udp"  => Ok(Box::new(UdpTracker::new(url.clone()).await)),
+        scheme => Err(TrackerError::UnsupportedScheme(scheme.to_string())),
+    }
+}
 
-    async fn announce(&mut self, params: AnnounceParams) -> Result<Vec<SocketAddr>>;
+// Shared by the tasks of a torrent's tiers, to tell when all of them are failing.
+struct TierStatus {
 
-    fn can_announce(&self, time: Instant) -> bool;
+    failed_tiers: Arc<AtomicUsize>,
 
-    fn should_announce(&self, time: Instant) -> bool;
+    num_tiers: usize,
 
-    async fn run(
-        &mut self,
-        torrent_tx: TorrentTx,
-        mut tracker_rx: TrackerRx,
-    ) -> Result<()> {
-        loop {
+}
 
-            tracker_rx.changed().await.ok();
-            let params = *tracker_rx.borrow();
-            let time = Instant::now();
+// Announces for a tier of trackers, trying them in order until one responds.
+// The one that responds moves to the front, so it's tried first from then on.
+// If none respond, the tier backs off before trying again.
+// Reference: https://www.bittorrent.org/beps/bep_0012.html
+async fn run_tier(mut tier: Vec<Box<dyn Tracker>>, status: TierStatus, torrent_tx: TorrentTx, mut tracker_rx: TrackerRx) {
+
+    let mut failures = 0;
+    let mut retry_at: Option<tokio::time::Instant> = None;
+    let mut latest: Option<AnnounceParams> = None;
+    // Kept until a tracker accepts it, newer params without an event don't replace it.
+    let mut pending_event: Option<Event> = None;
+    loop {
+
+        let retrying = tokio::select! {
+            params = tracker_rx.recv() => {
+                // Torrent has been dropped.
+                let Some(mut params) = params else { return };
+                // Params queue up whilst announcing, only the latest are used but events and forcing carry over.
+                let mut force = false;
+                loop {
+                    if let Some(params) = params {
+                        pending_event = params.event.or(pending_event);
+                        force |= params.force;
+                    }
+                    latest = params;
+                    match tracker_rx.try_recv() {
+                        Ok(next) => params = next,
+                        Err(_) => break,
+                    }
+                }
+                if let Some(latest) = &mut latest {
+                    latest.force |= force;
+                }
+                false
+            },
+            _ = tokio::time::sleep_until(retry_at.unwrap_or_else(tokio::time::Instant::now)), if retry_at.is_some() => {
+                retry_at = None;
+                true
+            },
+        };
+        let Some(params) = latest else { continue };
+        let params = AnnounceParams { event: pending_event, ..params };
+        let time = Instant::now();
+
+        // Whilst backing off only stopping is announced, as the torrent won't be around to retry.
+        if retry_at.is_some() && params.event != Some(Event::Stopped) {
+            continue;
+        }
 
-            if let Some(params) = params {
-                if params.event.is_some()
-                || (params.num_want > Some(0) && self.can_announce(time))
-                || self.should_announce(time) {
+        // The front tracker is the one in use, so its intervals apply.
+        let current = &tier[0];
+        if retrying
+        || params.event.is_some()
+        || ((params.force || params.num_want > Some(0)) && current.can_announce(time))
+        || current.should_announce(time) {
 
-                    let peers = self.announce(params).await?;
+            let resp = announce_tier(&mut tier, params).await;
+            if params.event == Some(Event::Stopped) {
+                return;
+            }
+            match resp {
+                Some(AnnounceResponse { peers, stats }) => {
+                    pending_event = None;
+                    if failures > 0 {
+                        failures = 0;
+                        retry_at = None;
+                        status.failed_tiers.fetch_sub(1, Ordering::SeqCst);
+                    }
                     tracing::info!("provided {} peers", peers.len());
-                    if torrent_tx.send(TorrentCommand::Peers(peers)).is_err() {
-                        return Ok(());
+                    if torrent_tx.send(TorrentCommand::Peers { peers, tracker: Some(stats) }).is_err() {
+                        return;
                     }
-                
-                }
+                },
+                None => {
+                    if failures == 0 && status.failed_tiers.fetch_add(1, Ordering::SeqCst) + 1 == status.num_tiers {
+                        let _ = torrent_tx.send(TorrentCommand::TrackersUnreachable);
+                    }
+                    let backoff = RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(failures)).min(MAX_RETRY_BACKOFF);
+                    failures += 1;
+                    tracing::warn!("no tracker in tier responded, retrying in {:?}", backoff);
+                    retry_at = Some(tokio::time::Instant::now() + backoff);
+                },
             }
+        }
+    }
+}
 
+async fn announce_tier(tier: &mut Vec<Box<dyn Tracker>>, params: AnnounceParams) -> Option<AnnounceResponse> {
+    for i in 0..tier.len() {
+        let resp = tokio::time::timeout(ANNOUNCE_TIMEOUT, tier[i].announce(params))
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        match resp {
+            Ok(resp) => {
+                let tracker = tier.remove(i);
+                tier.insert(0, tracker);
+                return Some(resp);
+            },
+            Err(e) => tracing::warn!("announce to {} failed: {}", tier[i].url(), e),
         }
     }
+    None
+}
+
+// Swarm stats for a torrent.
+// Reference: https://www.bittorrent.org/beps/bep_0048.html
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde_derive::Deserialize)]
+pub struct ScrapeData {
+
+    // Number of seeders.
+    pub complete: u64,
+
+    // Number of times the torrent has been downloaded.
+    #[serde(default)]
+    pub downloaded: u64,
+
+    // Number of leechers.
+    pub incomplete: u64,
+
+}
+
+#[async_trait::async_trait]
+pub trait Tracker: Send + Sync {
+
+    async fn announce(&mut self, params: AnnounceParams) -> Result<AnnounceResponse>;
+
+    fn url(&self) -> &Url;
+
+    // Stats for each of the info hashes the tracker knows about.
+    async fn scrape(&mut self, _info_hashes: &[ID]) -> Result<HashMap<ID, ScrapeData>> {
+        Err(TrackerError::ScrapeUnsupported)
+    }
+
+    fn can_announce(&self, time: Instant) -> bool;
+
+    fn should_announce(&self, time: Instant) -> bool;
+
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceResponse {
+
+    pub peers: Vec<SocketAddr>,
+
+    pub stats: TrackerStats,
 
 }
 
@@ -172,6 +381,12 @@ pub struct AnnounceParams {
     // Number of peers that the client would like to receive from the tracker.
     pub num_want: Option<usize>,
 
+    // Announce now regardless of the interval, the min interval is still respected.
+    pub force: bool,
+
+    // Address peers should reach us on, if not the one the announce comes from.
+    pub ip: Option<std::net::IpAddr>,
+
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Default)]
@@ -194,4 +409,237 @@ impl std::fmt::Display for Event {
             Event::Stopped => write!(f, "stopped"),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Within its interval, and past the min interval unless set otherwise.
+    struct MockTracker {
+        url: Url,
+        announces: Arc<AtomicUsize>,
+        fails: bool,
+        past_min_interval: bool,
+        // Never answers.
+        hangs: bool,
+    }
+
+    impl MockTracker {
+        fn boxed(name: &str, fails: bool, announces: Arc<AtomicUsize>) -> Box<dyn Tracker> {
+            let url = Url::parse(&format!("http://{}/announce", name)).unwrap();
+            Box::new(Self { url, announces, fails, past_min_interval: true, hangs: false })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Tracker for MockTracker {
+
+        async fn announce(&mut self, _: AnnounceParams) -> Result<AnnounceResponse> {
+            self.announces.fetch_add(1, Ordering::SeqCst);
+            if self.hangs {
+                std::future::pending::<()>().await;
+            }
+            if self.fails {
+                return Err(TrackerError::ResponseError("down".to_string()));
+            }
+            Ok(AnnounceResponse::default())
+        }
+
+        fn url(&self) -> &Url { &self.url }
+
+        fn can_announce(&self, _: Instant) -> bool { self.past_min_interval }
+
+        fn should_announce(&self, _: Instant) -> bool { false }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_force_announce() {
+        let announces = Arc::new(AtomicUsize::new(0));
+        let tier = vec![MockTracker::boxed("a", false, announces.clone())];
+        let status = TierStatus { failed_tiers: Arc::new(AtomicUsize::new(0)), num_tiers: 1 };
+        let (torrent_tx, _torrent_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tracker_tx, tracker_rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(run_tier(tier, status, torrent_tx, tracker_rx));
+        
+        let settle = || tokio::time::advance(Duration::from_millis(50));
+        tracker_tx.send(Some(AnnounceParams::default())).unwrap();
+        settle().await;
+        assert_eq!(announces.load(Ordering::SeqCst), 0);
+        
+        tracker_tx.send(Some(AnnounceParams { force: true, ..Default::default() })).unwrap();
+        settle().await;
+        assert_eq!(announces.load(Ordering::SeqCst), 1);
+
+        drop(tracker_tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_num_want_respects_min_interval() {
+        let announces = Arc::new(AtomicUsize::new(0));
+        let url = Url::parse("http://a/announce").unwrap();
+        let tier: Vec<Box<dyn Tracker>> = vec![
+            Box::new(MockTracker { url, announces: announces.clone(), fails: false, past_min_interval: false, hangs: false }),
+        ];
+        let status = TierStatus { failed_tiers: Arc::new(AtomicUsize::new(0)), num_tiers: 1 };
+        let (torrent_tx, _torrent_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tracker_tx, tracker_rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(run_tier(tier, status, torrent_tx, tracker_rx));
+
+        // Peer starved, but within the min interval.
+        for _ in 0..3 {
+            tracker_tx.send(Some(AnnounceParams { num_want: Some(20), ..Default::default() })).unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(announces.load(Ordering::SeqCst), 0);
+
+        drop(tracker_tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tier_failover() {
+        let announces = Arc::new(AtomicUsize::new(0));
+        let mut tier = vec![
+            MockTracker::boxed("a", true, announces.clone()),
+            MockTracker::boxed("b", false, announces.clone()),
+            MockTracker::boxed("c", false, announces.clone()),
+        ];
+        let hosts = |tier: &Vec<Box<dyn Tracker>>| tier.iter().map(|t| t.url().host_str().unwrap().to_string()).collect::<Vec<_>>();
+
+        // Falls through the failed tracker, stopping at the first that responds.
+        assert!(announce_tier(&mut tier, AnnounceParams::default()).await.is_some());
+        assert_eq!(announces.load(Ordering::SeqCst), 2);
+        assert_eq!(hosts(&tier), ["b", "a", "c"]);
+
+        // Which is tried first next time.
+        assert!(announce_tier(&mut tier, AnnounceParams::default()).await.is_some());
+        assert_eq!(announces.load(Ordering::SeqCst), 3);
+        assert_eq!(hosts(&tier), ["b", "a", "c"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tier_failover_timeout() {
+        let announces = Arc::new(AtomicUsize::new(0));
+        let url = Url::parse("http://a/announce").unwrap();
+        let mut tier = vec![
+            Box::new(MockTracker { url, announces: announces.clone(), fails: false, past_min_interval: true, hangs: true }),
+            MockTracker::boxed("b", false, announces.clone()),
+        ];
+
+        // Moves on from the tracker that doesn't answer once the announce times out.
+        let start = tokio::time::Instant::now();
+        assert!(announce_tier(&mut tier, AnnounceParams::default()).await.is_some());
+        assert_eq!(start.elapsed(), ANNOUNCE_TIMEOUT);
+        assert_eq!(announces.load(Ordering::SeqCst), 2);
+        assert_eq!(tier[0].url().host_str(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_with_skipped_tier() {
+        // Nothing listens here, so the proxy refuses connections.
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let proxy = ProxyConfig { host: "127.0.0.1".to_string(), port, auth: None };
+        let urls = vec![
+            vec![Url::parse("udp://127.0.0.1:6969/announce").unwrap()],
+            vec![Url::parse("http://127.0.0.1:6969/announce").unwrap()],
+        ];
+        let mut trackers = TrackersHandle::new(urls, Some(proxy));
+        let (torrent_tx, mut torrent_rx) = tokio::sync::mpsc::unbounded_channel();
+        trackers.start(torrent_tx).await;
+
+        // The udp tier is skipped with a proxy, so the http tier failing is all of them.
+        assert!(trackers.update(Some(AnnounceParams { force: true, ..Default::default() })));
+        let unreachable = tokio::time::timeout(Duration::from_secs(5), torrent_rx.recv()).await.unwrap();
+        assert!(matches!(unreachable, Some(TorrentCommand::TrackersUnreachable)));
+    }
+
+    #[tokio::test]
+    async fn test_failed_tiers_back_off() {
+        let announces = Arc::new(AtomicUsize::new(0));
+        let failed_tiers = Arc::new(AtomicUsize::new(0));
+        let (torrent_tx, mut torrent_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut tracker_txs = vec![];
+        for name in ["a", "b"] {
+            let tier = vec![MockTracker::boxed(name, true, announces.clone())];
+            let status = TierStatus { failed_tiers: failed_tiers.clone(), num_tiers: 2 };
+            let (tracker_tx, tracker_rx) = mpsc::unbounded_channel();
+            tokio::spawn(run_tier(tier, status, torrent_tx.clone(), tracker_rx));
+            tracker_txs.push(tracker_tx);
+        }
+        let send = |params: AnnounceParams| tracker_txs.iter().for_each(|tx| tx.send(Some(params)).unwrap());
+
+        send(AnnounceParams { force: true, ..Default::default() });
+        assert!(matches!(torrent_rx.recv().await, Some(TorrentCommand::TrackersUnreachable)));
+        assert_eq!(announces.load(Ordering::SeqCst), 2);
+
+        // Announces are held off whilst backing off, stopping still goes through.
+        send(AnnounceParams { force: true, ..Default::default() });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(announces.load(Ordering::SeqCst), 2);
+        send(AnnounceParams { event: Some(Event::Stopped), ..Default::default() });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(announces.load(Ordering::SeqCst), 4);
+        // Only reported once.
+        assert!(torrent_rx.try_recv().is_err());
+    }
+
+    // Records the event of each announce, failing the first few.
+    struct EventTracker {
+        url: Url,
+        events: Arc<std::sync::Mutex<Vec<Option<Event>>>>,
+        failures: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl Tracker for EventTracker {
+
+        async fn announce(&mut self, params: AnnounceParams) -> Result<AnnounceResponse> {
+            self.events.lock().unwrap().push(params.event);
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(TrackerError::ResponseError("down".to_string()));
+            }
+            Ok(AnnounceResponse::default())
+        }
+
+        fn url(&self) -> &Url { &self.url }
+
+        fn can_announce(&self, _: Instant) -> bool { true }
+
+        fn should_announce(&self, _: Instant) -> bool { false }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_event_kept_until_announced() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let url = Url::parse("http://a/announce").unwrap();
+        let tier: Vec<Box<dyn Tracker>> = vec![Box::new(EventTracker { url, events: events.clone(), failures: 1 })];
+        let status = TierStatus { failed_tiers: Arc::new(AtomicUsize::new(0)), num_tiers: 1 };
+        let (torrent_tx, _torrent_rx) = mpsc::unbounded_channel();
+        let (tracker_tx, tracker_rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(run_tier(tier, status, torrent_tx, tracker_rx));
+        let settle = || tokio::time::advance(Duration::from_millis(50));
+
+        // Not replaced by params that come after it.
+        tracker_tx.send(Some(AnnounceParams { event: Some(Event::Completed), ..Default::default() })).unwrap();
+        tracker_tx.send(Some(AnnounceParams::default())).unwrap();
+        settle().await;
+        assert_eq!(*events.lock().unwrap(), [Some(Event::Completed)]);
+
+        // Nor whilst backing off after the tracker failed.
+        tracker_tx.send(Some(AnnounceParams::default())).unwrap();
+        tokio::time::advance(RETRY_BACKOFF).await;
+        settle().await;
+        assert_eq!(*events.lock().unwrap(), [Some(Event::Completed), Some(Event::Completed)]);
+
+        // Once accepted it isn't sent again.
+        tracker_tx.send(Some(AnnounceParams { force: true, ..Default::default() })).unwrap();
+        settle().await;
+        assert_eq!(*events.lock().unwrap(), [Some(Event::Completed), Some(Event::Completed), None]);
+
+        drop(tracker_tx);
+        handle.await.unwrap();
+    }
+}
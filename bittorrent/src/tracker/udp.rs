@@ -1,8 +1,9 @@
-use std::{net::{Ipv4Addr, SocketAddr, ToSocketAddrs}, time::{Duration, Instant}};
+use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr, ToSocketAddrs}, time::{Duration, Instant}};
 use bytes::{Buf, BufMut, BytesMut};
 use tokio::{net::UdpSocket, time};
 use url::Url;
-use super::{AnnounceParams, Event, Result, Tracker, TrackerError, DEFAULT_MIN_ANNOUNCE_INTERVAL};
+use crate::{stats::TrackerStats, ID};
+use super::{AnnounceParams, AnnounceResponse, Event, Result, ScrapeData, Tracker, TrackerError, DEFAULT_MIN_ANNOUNCE_INTERVAL};
 
 // Reference: https://www.bittorrent.org/beps/bep_0015.html
 // TODO: implement different chains of connect/announce based on circumstances.
@@ -10,6 +11,15 @@ use super::{AnnounceParams, Event, Result, Tracker, TrackerError, DEFAULT_MIN_AN
 const PROTOCOL_ID: i64      = 0x41727101980;
 const ACTION_CONNECT: i32   = 0;
 const ACTION_ANNOUNCE: i32  = 1;
+const ACTION_SCRAPE: i32    = 2;
+const ACTION_ERROR: i32     = 3;
+
+// Connection ids can be used for a minute after being received.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+// Requests are retransmitted after 15 * 2^n seconds without a response, n going up to 8.
+const RETRANSMIT_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRANSMITS: u32 = 8;
 
 pub struct UdpTracker {
 
@@ -17,12 +27,16 @@ pub struct UdpTracker {
 
     url: Url,
 
-    conn_id: Option<i64>,
+    // Connection id and when it was received.
+    conn_id: Option<(i64, Instant)>,
 
     last_announce: Option<Instant>,
 
     interval: Option<Duration>,
 
+    // Time to wait for the first response, doubled on each retransmit.
+    retransmit_base: Duration,
+
 }
 
 impl UdpTracker {
@@ -36,60 +50,116 @@ impl UdpTracker {
             conn_id: None,
             last_announce: None,
             interval: None,
+            retransmit_base: RETRANSMIT_BASE,
         }
     }
 
-    async fn connect(&mut self) -> Result<()> {
-
+    // Connects the socket to the tracker's address, only needed once.
+    async fn resolve(&mut self) -> Result<()> {
+        if self.socket.peer_addr().is_ok() {
+            return Ok(());
+        }
         let host = self.url.host_str().ok_or(TrackerError::InvalidUrl)?;
         let port = self.url.port().ok_or(TrackerError::InvalidUrl)?;
         let addr = (host, port).to_socket_addrs()?.next().ok_or(TrackerError::InvalidUrl)?;
         let timeout_duration = Duration::from_secs(10);
         time::timeout(timeout_duration, self.socket.connect(addr)).await??;
-        
-        // Send connect request.
-        let trans_id = rand::random();
-        
-        let mut buf = BytesMut::with_capacity(16);
-        buf.put_i64(PROTOCOL_ID);
-        buf.put_i32(ACTION_CONNECT);
-        buf.put_i32(trans_id);
-        
-        self.socket.send(&buf).await?;
-        
-        // Receive connect response.
-        let mut resp_buf = [0u8; 16];
-        let n = self.socket.recv(&mut resp_buf).await?;
-        if n < 16 {
-            return Err(TrackerError::ResponseError("invalid response length".to_string()));
+        Ok(())
+    }
+
+    // Gives the cached connection id, or connects for a new one if it has expired.
+    async fn connection_id(&mut self) -> Result<i64> {
+
+        if let Some((conn_id, received)) = self.conn_id {
+            if received.elapsed() < CONNECTION_ID_TTL {
+                return Ok(conn_id);
+            }
         }
-        let mut resp = &resp_buf[..];
-        if resp.get_i32() != ACTION_CONNECT {
-            return Err(TrackerError::ResponseError("expected action 0".to_string()));
+
+        let mut resp_buf = [0u8; 16];
+        for n in 0..=MAX_RETRANSMITS {
+            let wait = self.retransmit_wait(n);
+            match self.transact(PROTOCOL_ID, ACTION_CONNECT, &[], &mut resp_buf, wait).await {
+                Ok(len) => {
+                    if len < 16 {
+                        return Err(TrackerError::ResponseError("invalid response length".to_string()));
+                    }
+                    let conn_id = (&resp_buf[8..]).get_i64();
+                    self.conn_id = Some((conn_id, Instant::now()));
+                    tracing::trace!("connected to tracker");
+                    return Ok(conn_id);
+                },
+                Err(TrackerError::Timeout(_)) => tracing::debug!("no connect response after {:?}, retransmitting", wait),
+                Err(e) => return Err(e),
+            }
         }
-        if resp.get_i32() != trans_id {
-            return Err(TrackerError::ResponseError("invalid transaction id".to_string()));
+        Err(TrackerError::ResponseError("tracker not responding".to_string()))
+    }
+
+    // Sends a request, retransmitting until there is a response.
+    // Gives the length of the response, which starts with the action and transaction id.
+    async fn request(&mut self, action: i32, body: &[u8], resp_buf: &mut [u8]) -> Result<usize> {
+        for n in 0..=MAX_RETRANSMITS {
+            // Waiting may outlast the connection id, so it's checked on every attempt.
+            let conn_id = self.connection_id().await?;
+            let wait = self.retransmit_wait(n);
+            match self.transact(conn_id, action, body, resp_buf, wait).await {
+                Err(TrackerError::Timeout(_)) => tracing::debug!("no response after {:?}, retransmitting", wait),
+                result => return result,
+            }
         }
-        self.conn_id = Some(resp.get_i64());
-        
-        tracing::trace!("connected to tracker");
-        Ok(())
+        Err(TrackerError::ResponseError("tracker not responding".to_string()))
+    }
+
+    fn retransmit_wait(&self, n: u32) -> Duration {
+        self.retransmit_base * 2u32.pow(n)
+    }
+
+    // One attempt at a request, waiting for a response with a matching transaction id.
+    async fn transact(&mut self, conn_id: i64, action: i32, body: &[u8], resp_buf: &mut [u8], wait: Duration) -> Result<usize> {
+
+        self.resolve().await?;
+        let trans_id: i32 = rand::random();
+
+        let mut buf = BytesMut::with_capacity(16 + body.len());
+        buf.put_i64(conn_id);
+        buf.put_i32(action);
+        buf.put_i32(trans_id);
+        buf.put(body);
+        self.socket.send(&buf).await?;
+
+        time::timeout(wait, async {
+            loop {
+                let n = self.socket.recv(resp_buf).await?;
+                if n < 8 {
+                    return Err(TrackerError::ResponseError("invalid response length".to_string()));
+                }
+                let mut resp = &resp_buf[..n];
+                let resp_action = resp.get_i32();
+                // Late responses to earlier attempts are ignored.
+                if resp.get_i32() != trans_id {
+                    continue;
+                }
+                if resp_action == ACTION_ERROR {
+                    // The error may be down to our connection id, get a new one next time.
+                    self.conn_id = None;
+                    return Err(TrackerError::ResponseError(String::from_utf8_lossy(resp).into_owned()));
+                }
+                if resp_action != action {
+                    return Err(TrackerError::ResponseError(format!("expected action {}", action)));
+                }
+                return Ok(n);
+            }
+        }).await?
     }
 }
 
 #[async_trait::async_trait]
 impl Tracker for UdpTracker {
 
-    async fn announce(&mut self, params: AnnounceParams) -> Result<Vec<SocketAddr>> {
-        
-        self.connect().await?;
-
-        let trans_id = rand::random();
+    async fn announce(&mut self, params: AnnounceParams) -> Result<AnnounceResponse> {
 
-        let mut buf = BytesMut::with_capacity(98);
-        buf.put_i64(self.conn_id.unwrap());
-        buf.put_i32(ACTION_ANNOUNCE);
-        buf.put_i32(trans_id);
+        let mut buf = BytesMut::with_capacity(82);
         buf.put(&params.info_hash[..]);
         buf.put(&params.client_id[..]);
         buf.put_u64(params.downloaded);
@@ -103,7 +173,11 @@ impl Tracker for UdpTracker {
                 None => 0,
             }
         );
-        buf.put_i32(0); // IP address, default = 0.
+        // IP address, 0 for the address the packet comes from, which is also used for IPv6.
+        buf.put_u32(match params.ip {
+            Some(std::net::IpAddr::V4(ip)) => ip.into(),
+            _ => 0,
+        });
         buf.put_i32(rand::random()); // Key, random.
         buf.put_i32(
             match params.num_want {
@@ -113,23 +187,15 @@ impl Tracker for UdpTracker {
         );
         buf.put_u16(params.port);
 
-        self.socket.send(&buf).await?;
-
         let mut resp_buf = [0u8; 1024];
-        let n = self.socket.recv(&mut resp_buf).await?;
-        let mut resp = &resp_buf[..];
+        let n = self.request(ACTION_ANNOUNCE, &buf, &mut resp_buf).await?;
         if n < 20 {
             return Err(TrackerError::ResponseError("invalid response length".to_string()));
         }
-        if resp.get_i32() != ACTION_ANNOUNCE {
-            return Err(TrackerError::ResponseError("expected action 1".to_string()));
-        }
-        if resp.get_i32() != trans_id {
-            return Err(TrackerError::ResponseError("invalid transaction id".to_string()));
-        }
-        let _interval = resp.get_i32();
-        let _leechers = resp.get_i32();
-        let _seeders = resp.get_i32();
+        let mut resp = &resp_buf[8..n];
+        let interval = resp.get_i32();
+        let leechers = resp.get_i32();
+        let seeders = resp.get_i32();
         let num_peers = (n - 20) / 6;
 
         let mut peers = Vec::with_capacity(num_peers);
@@ -140,12 +206,59 @@ impl Tracker for UdpTracker {
         }
 
         tracing::info!("provided {} peers", peers.len());
+        if interval > 0 {
+            self.interval = Some(Duration::from_secs(interval as u64));
+        }
         self.last_announce = Some(Instant::now());
-        Ok(peers)
+        Ok(AnnounceResponse {
+            peers,
+            stats: TrackerStats {
+                seeders: u64::try_from(seeders).ok(),
+                leechers: u64::try_from(leechers).ok(),
+                interval: self.interval,
+            },
+        })
     }
 
+    async fn scrape(&mut self, info_hashes: &[ID]) -> Result<HashMap<ID, ScrapeData>> {
+
+        // Responses are limited to 74 torrents.
+        let info_hashes = &info_hashes[..info_hashes.len().min(74)];
+        let body = info_hashes.concat();
+        let mut resp_buf = [0u8; 8 + 74 * 12];
+        let n = self.request(ACTION_SCRAPE, &body, &mut resp_buf).await?;
+
+        // Stats are in the order of the request, a short response leaves the rest unknown.
+        let mut resp = &resp_buf[8..n];
+        let mut files = HashMap::new();
+        for info_hash in info_hashes {
+            if resp.remaining() < 12 {
+                break;
+            }
+            let complete = resp.get_i32() as u64;
+            let downloaded = resp.get_i32() as u64;
+            let incomplete = resp.get_i32() as u64;
+            files.insert(*info_hash, ScrapeData { complete, downloaded, incomplete });
+        }
+        Ok(files)
+    }
+
+    fn url(&self) -> &Url { &self.url }
+
+    // UDP trackers don't give a min interval, so use the default.
     fn can_announce(&self, time: Instant) -> bool {
         
+        if let Some(last_announce) = self.last_announce {
+            time.duration_since(last_announce) 
+            >= Duration::from_secs(DEFAULT_MIN_ANNOUNCE_INTERVAL)
+        
+        } else {
+            true
+        }
+    }
+
+    fn should_announce(&self, time: Instant) -> bool {
+        
         if let Some(last_announce) = self.last_announce {
             time.duration_since(last_announce) 
             >= self.interval.unwrap_or(Duration::from_secs(DEFAULT_MIN_ANNOUNCE_INTERVAL))
@@ -154,6 +267,77 @@ impl Tracker for UdpTracker {
             true
         }
     }
+}
 
-    fn should_announce(&self, time: Instant) -> bool { self.can_announce(time) }
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+    use super::*;
+
+    // Answers connects with an incrementing connection id and ignores the first announce, to force a retransmit.
+    async fn mock_tracker(connects: Arc<AtomicUsize>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut announces = 0;
+            let mut buf = [0u8; 1024];
+            loop {
+                let Ok((n, from)) = socket.recv_from(&mut buf).await else { return };
+                let mut req = &buf[..n];
+                let conn_id = req.get_i64();
+                let action = req.get_i32();
+                let trans_id = req.get_i32();
+                let mut resp = BytesMut::new();
+                if action == ACTION_CONNECT {
+                    assert_eq!(conn_id, PROTOCOL_ID);
+                    let count = connects.fetch_add(1, Ordering::SeqCst) + 1;
+                    resp.put_i32(ACTION_CONNECT);
+                    resp.put_i32(trans_id);
+                    resp.put_i64(count as i64);
+                } else {
+                    assert_eq!(conn_id, connects.load(Ordering::SeqCst) as i64, "stale connection id");
+                    announces += 1;
+                    if announces == 1 {
+                        continue;
+                    }
+                    resp.put_i32(ACTION_ANNOUNCE);
+                    resp.put_i32(trans_id);
+                    resp.put_i32(1800);
+                    resp.put_i32(1);
+                    resp.put_i32(2);
+                    resp.put_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+                }
+                socket.send_to(&resp, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_connection_id_expiry() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let addr = mock_tracker(connects.clone()).await;
+        let mut tracker = UdpTracker::new(Url::parse(&format!("udp://{}", addr)).unwrap()).await;
+        tracker.retransmit_base = Duration::from_millis(50);
+
+        // First announce goes unanswered and is retransmitted.
+        let resp = tracker.announce(AnnounceParams::default()).await.unwrap();
+        assert_eq!(resp.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(resp.stats, TrackerStats {
+            seeders: Some(2),
+            leechers: Some(1),
+            interval: Some(Duration::from_secs(1800)),
+        });
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        // Connection id is reused whilst fresh.
+        tracker.announce(AnnounceParams::default()).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        // And replaced once expired.
+        let (conn_id, _) = tracker.conn_id.unwrap();
+        tracker.conn_id = Some((conn_id, Instant::now() - CONNECTION_ID_TTL));
+        tracker.announce(AnnounceParams::default()).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
 }
@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{task::JoinHandle, time};
+use tracing::Instrument;
+use crate::{client::{ClientCommand, ClientTx}, metainfo::MetaInfo};
+
+// Watches a directory for .torrent files and adds them to the client.
+// A torrent downloads to the path in a sidecar "<name>.dir" file if present,
+// otherwise to a directory named after the .torrent file within the download dir.
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn start_watcher(watch_dir: PathBuf, download_dir: PathBuf, client_tx: ClientTx) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut watcher = Watcher::new(watch_dir, download_dir);
+        let mut interval = time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if client_tx.is_closed() {
+                break;
+            }
+            for (metainfo, dir) in watcher.scan() {
+                if client_tx.send(ClientCommand::NewTorrent { metainfo, dir: Some(dir) }).is_err() {
+                    return;
+                }
+            }
+        }
+    }.instrument(tracing::info_span!("watcher")))
+}
+
+struct Watcher {
+
+    watch_dir: PathBuf,
+
+    download_dir: PathBuf,
+
+    // Files already seen, with their modified time so changed files are retried.
+    seen: HashMap<PathBuf, SystemTime>,
+
+}
+
+impl Watcher {
+
+    fn new(watch_dir: PathBuf, download_dir: PathBuf) -> Self {
+        Self {
+            watch_dir,
+            download_dir,
+            seen: HashMap::new(),
+        }
+    }
+
+    // Returns torrents in the watch directory not seen before, with their download directory.
+    fn scan(&mut self) -> Vec<(MetaInfo, PathBuf)> {
+
+        let entries = match std::fs::read_dir(&self.watch_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("failed to read watch dir {:?}: {}", self.watch_dir, e);
+                return Vec::new();
+            },
+        };
+
+        let mut torrents = Vec::new();
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+
+            if path.extension().unwrap_or_default() != "torrent" || !path.is_file() {
+                continue;
+            }
+            let Ok(modified) = path.metadata().and_then(|m| m.modified()) else {
+                continue;
+            };
+            if self.seen.get(&path) == Some(&modified) {
+                continue;
+            }
+            self.seen.insert(path.clone(), modified);
+
+            match MetaInfo::new(&path) {
+                Ok(metainfo) => {
+                    tracing::info!("adding torrent from watch dir: {:?}", path);
+                    let dir = self.download_dir_for(&path);
+                    torrents.push((metainfo, dir));
+                },
+                // May be partially written, will be retried once modified.
+                Err(e) => tracing::warn!("failed to read torrent {:?}: {}", path, e),
+            }
+        }
+
+        torrents
+    }
+
+    fn download_dir_for(&self, path: &Path) -> PathBuf {
+        let sidecar = path.with_extension("dir");
+        if let Ok(dir) = std::fs::read_to_string(&sidecar) {
+            let dir = dir.trim();
+            if !dir.is_empty() {
+                return PathBuf::from(dir);
+            }
+        }
+        self.download_dir.join(path.file_stem().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let watch_dir = tempfile::tempdir().unwrap();
+        let mut watcher = Watcher::new(watch_dir.path().to_path_buf(), PathBuf::from("downloads"));
+        assert!(watcher.scan().is_empty());
+
+        std::fs::copy("tests/test_torrents/test_single.torrent", watch_dir.path().join("single.torrent")).unwrap();
+        std::fs::copy("tests/test_torrents/test_multi.torrent", watch_dir.path().join("multi.torrent")).unwrap();
+        std::fs::write(watch_dir.path().join("multi.dir"), "/data/multi\n").unwrap();
+        std::fs::write(watch_dir.path().join("notes.txt"), "not a torrent").unwrap();
+
+        let mut torrents = watcher.scan();
+        torrents.sort_by(|a, b| a.1.cmp(&b.1));
+        let dirs: Vec<PathBuf> = torrents.into_iter().map(|(_, dir)| dir).collect();
+        assert_eq!(dirs, vec![PathBuf::from("/data/multi"), PathBuf::from("downloads/single")]);
+
+        // Files already added are not returned again.
+        assert!(watcher.scan().is_empty());
+    }
+}
@@ -1,10 +1,10 @@
 use std::{collections::HashMap, io::{stdout, Stdout}};
-use bittorrent::{Handle, UserCommand, MetaInfo, ID, UserRx};
+use bittorrent::{Config, Handle, Magnet, UserCommand, MetaInfo, TorrentState, ID, UserRx};
 use crossterm::event::{self, Event};
 use futures::StreamExt;
-use ratatui::{backend::CrosstermBackend, layout::Layout, widgets, Frame};
+use ratatui::{backend::CrosstermBackend, layout::{Constraint, Layout, Rect}, widgets, Frame};
 use color_eyre::Result;
-use crate::{data::TorrentData, ui};
+use crate::{data::{Filter, SortMode, TorrentData}, ui};
 
 pub type Terminal = ratatui::Terminal<CrosstermBackend<Stdout>>;
 
@@ -16,14 +16,27 @@ pub struct App {
     // Channel to recieve messages from the bittorrent client.
     user_rx:  UserRx,
 
-    // List of active torrents, in vec for order.
+    // List of active torrents, in the order they were added.
     torrents: Vec<TorrentData>,
 
     // Maps torrent id to index in the torrents vector.
     torrent_lookup: HashMap<ID, usize>,
 
+    // Indices into torrents of the rows of the torrent table, sorted and filtered.
+    // Kept separate so sorting never moves torrents and their lookup.
+    order: Vec<usize>,
+
+    sort: SortMode,
+
+    filter: Filter,
+
     enter_file_explorer: bool,
 
+    // Open the prompt for a magnet link or .torrent url, with any text pasted into the main view.
+    enter_link_prompt: bool,
+
+    pasted: Option<String>,
+
     file_explorer: ratatui_explorer::FileExplorer,
 
     table_state: widgets::TableState,
@@ -33,6 +46,11 @@ pub struct App {
     // Index of the currently selected torrent.
     selected_idx: usize,
 
+    // Whether the selected torrent's files are shown in place of its peers.
+    show_files: bool,
+
+    file_table_state: widgets::TableState,
+
     // Flag to quit the app on next loop.
     quit: bool,
 
@@ -42,11 +60,13 @@ impl App {
 
     pub fn new() -> Result<Self> {
         // TODO: config
-        let (handle, user_rx) = bittorrent::start_client(None);
+        // Completed pieces are needed for the progress of each file.
+        let config = Config { report_piece_map: true, ..Default::default() };
+        let (handle, user_rx) = bittorrent::start_client(Some(config));
         let file_explorer = ratatui_explorer::FileExplorer::with_theme(
             ratatui_explorer::Theme::default()
                 .add_default_title()
-                .with_title_bottom(|_| " 'q': quit | 'enter': select | 'esc': back ".into())
+                .with_title_bottom(|_| " 'q': quit | 'enter': select | 'm': magnet/url | 'esc': back ".into())
         )?;
         
         Ok(Self {
@@ -54,11 +74,18 @@ impl App {
             user_rx,
             torrents: Vec::new(),
             torrent_lookup: HashMap::new(),
+            order: Vec::new(),
+            sort: SortMode::default(),
+            filter: Filter::default(),
             enter_file_explorer: false,
+            enter_link_prompt: false,
+            pasted: None,
             file_explorer,
             table_state: widgets::TableState::default().with_selected(0),
             scroll_state: widgets::ScrollbarState::default(),
             selected_idx: 0,
+            show_files: false,
+            file_table_state: widgets::TableState::default().with_selected(0),
             quit: false,
         })
     }
@@ -70,7 +97,8 @@ impl App {
 
         // Initially enter the file explorer, to let user pick file.
         // Also can't render the UI without a file to download.
-        self.enter_file_explorer(&mut terminal)?;
+        self.enter_file_explorer = true;
+        self.enter_menus(&mut terminal).await?;
 
         loop {
             
@@ -85,13 +113,22 @@ impl App {
                 Some(client_event) = self.user_rx.recv() => {
                     match client_event {
                         
-                        UserCommand::TorrentFinished { id } => {
+                        // Still shown whilst seeding.
+                        UserCommand::TorrentFinished { .. } => {},
+
+                        // Magnets are shown by name until their metadata arrives.
+                        UserCommand::MetadataFetched { id, metainfo } => {
                             if let Some(idx) = self.torrent_lookup.get(&id) {
-                                self.torrents.remove(*idx);
-                                // Expensive but rare.
-                                self.torrent_lookup.clear();
-                                for (i, torrent) in self.torrents.iter().enumerate() {
-                                    self.torrent_lookup.insert(torrent.id, i);
+                                self.torrents[*idx] = TorrentData::new(metainfo);
+                            }
+                        },
+
+                        // Failed torrents are kept so the error can be seen, until removed.
+                        UserCommand::TorrentStopped { id, result } => {
+                            if let Some(&idx) = self.torrent_lookup.get(&id) {
+                                match result {
+                                    Ok(()) => self.remove_torrent(idx),
+                                    Err(e) => self.torrents[idx].error = Some(format!("failed: {}", e)),
                                 }
                             }
                         },
@@ -101,22 +138,49 @@ impl App {
                                 self.torrents[*idx].update_torrent_stats(stats);
                             }
                         },
+
+                        // Peer table is kept current between stats.
+                        UserCommand::PeerConnected { id, addr, .. } => {
+                            if let Some(idx) = self.torrent_lookup.get(&id) {
+                                self.torrents[*idx].peer_connected(addr);
+                            }
+                        },
+
+                        UserCommand::PeerDisconnected { id, addr } => {
+                            if let Some(idx) = self.torrent_lookup.get(&id) {
+                                self.torrents[*idx].peer_disconnected(addr);
+                            }
+                        },
+
+                        UserCommand::DhtNodeDiscovered { .. } => {},
+
+                        // Torrents can't make progress without the disk.
+                        UserCommand::DiskFailure => self.quit = true,
+
+                        UserCommand::TorrentIncomplete { .. } => {},
+
+                        UserCommand::ScrapeResult { .. } => {},
+
+                        UserCommand::TorrentError { id, msg } => {
+                            if let Some(idx) = self.torrent_lookup.get(&id) {
+                                self.torrents[*idx].error = Some(msg);
+                            }
+                        },
                     }
                 },
             }
             
-            if self.enter_file_explorer {
-                self.enter_file_explorer(&mut terminal)?;
-                self.enter_file_explorer = false;
-            }
+            self.enter_menus(&mut terminal).await?;
 
             if self.quit {
                 break;
             }
 
+            // Progress and speed change with each stats, so the order is kept current.
+            self.update_order();
             terminal.draw(|f| self.view(f))?;
 
-            debug_assert!(self.selected_idx < self.torrents.len());
+            debug_assert!(self.torrents.is_empty() || self.selected_idx < self.torrents.len());
         }
 
         Ok(())
@@ -130,6 +194,11 @@ impl App {
     // Main rendering function.
     fn view(&mut self, f: &mut Frame) {
 
+        // Panels need a selected torrent, the file explorer is opened before the next draw.
+        if self.torrents.is_empty() {
+            return;
+        }
+
         let rows = Layout::default()
             .direction(ratatui::layout::Direction::Vertical)
             .constraints(
@@ -152,14 +221,21 @@ impl App {
             )
             .split(rows[1]);
 
-        ui::render_torrent_table(f, rows[0], &mut self.table_state, &self.torrents);
+        ui::render_torrent_table(f, rows[0], &mut self.table_state, &self.torrents, &self.order, self.sort, self.filter);
         ui::render_torrent_panel(f, bottom_row[0], &self.torrents[self.selected_idx]);
-        ui::render_peer_table(f, bottom_row[1], &self.torrents[self.selected_idx]);
+        if self.show_files {
+            ui::render_file_table(f, bottom_row[1], &mut self.file_table_state, &self.torrents[self.selected_idx]);
+        } else {
+            ui::render_peer_table(f, bottom_row[1], &self.torrents[self.selected_idx]);
+        }
     }
 
-    // TODO: handle pasting a magnet link
     async fn handle_event(&mut self, event: Event) -> Result<()> {
         match event {
+            Event::Paste(text) => {
+                self.pasted = Some(text);
+                self.enter_link_prompt = true;
+            },
             Event::Key(key) => {
                 if key.kind != event::KeyEventKind::Press {
                     return Ok(());
@@ -168,10 +244,36 @@ impl App {
                 match key.code {
                     event::KeyCode::Char('q') => self.quit = true,
                     event::KeyCode::Char('n') => self.enter_file_explorer = true,
+                    event::KeyCode::Char('m') => self.enter_link_prompt = true,
                     event::KeyCode::Char('r') => {
                         self.client.remove_torrent(self.torrents[self.selected_idx].id).await?;
                         self.remove_torrent(self.selected_idx);
                     },
+                    event::KeyCode::Char('p') => {
+                        let torrent = &mut self.torrents[self.selected_idx];
+                        if torrent.data.state == TorrentState::Paused {
+                            self.client.resume(torrent.id)?;
+                            // Errors that paused the torrent no longer apply.
+                            torrent.error = None;
+                        } else {
+                            self.client.pause(torrent.id)?;
+                        }
+                    },
+                    event::KeyCode::Char('s') => self.sort = self.sort.next(),
+                    event::KeyCode::Char('v') => self.filter = self.filter.next(),
+                    event::KeyCode::Char('f') => {
+                        self.show_files = !self.show_files;
+                        self.file_table_state.select(Some(0));
+                    },
+                    event::KeyCode::Char(' ') if self.show_files => {
+                        let Some(file_idx) = self.file_table_state.selected() else { return Ok(()) };
+                        let torrent = &mut self.torrents[self.selected_idx];
+                        let priorities = torrent.cycle_priority(file_idx);
+                        self.client.set_file_priorities(torrent.id, priorities)?;
+                    },
+                    // Whilst the file view is open, the arrows move between files.
+                    event::KeyCode::Up if self.show_files => self.prev_file(),
+                    event::KeyCode::Down if self.show_files => self.next_file(),
                     event::KeyCode::Up => self.prev(),
                     event::KeyCode::Down => self.next(),
 
@@ -186,37 +288,77 @@ impl App {
     }
 
     fn next(&mut self) {
-        let i = match self.table_state.selected() {
-            Some(i) => {
-                if i >= self.torrents.len() - 1 {
-                    0
-                } else {
-                    i + 1
-                }
-            },
-            None => 0,
+        if self.order.is_empty() {
+            return;
+        }
+        let i = match self.order.iter().position(|&i| i == self.selected_idx) {
+            Some(pos) => self.order[(pos + 1) % self.order.len()],
+            None => self.order[0],
         };
         self.select(i);
     }
 
     fn prev(&mut self) {
-        let i = match self.table_state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    self.torrents.len() - 1
-                } else {
-                    i - 1
-                }
-            },
-            None => 0,
+        if self.order.is_empty() {
+            return;
+        }
+        let i = match self.order.iter().position(|&i| i == self.selected_idx) {
+            Some(pos) => self.order[(pos + self.order.len() - 1) % self.order.len()],
+            None => self.order[0],
         };
         self.select(i);
     }
 
+    // Sorts and filters the torrent table, the selected torrent stays selected wherever it moves.
+    // If it's filtered out, the first shown torrent is selected instead.
+    fn update_order(&mut self) {
+        self.order = (0..self.torrents.len())
+            .filter(|&i| self.filter.matches(&self.torrents[i]))
+            .collect();
+        self.sort.sort(&mut self.order, &self.torrents);
+
+        match self.order.iter().position(|&i| i == self.selected_idx) {
+            Some(pos) => {
+                self.table_state.select(Some(pos));
+                self.scroll_state = self.scroll_state.position(pos);
+            },
+            None => match self.order.first() {
+                Some(&i) => {
+                    self.selected_idx = i;
+                    self.file_table_state.select(Some(0));
+                    self.table_state.select(Some(0));
+                    self.scroll_state = self.scroll_state.position(0);
+                },
+                // Nothing shown, the panels keep showing the last selection.
+                None => self.table_state.select(None),
+            },
+        }
+    }
+
+    fn next_file(&mut self) {
+        let num_files = self.torrents[self.selected_idx].files.len();
+        // Magnets have no files until their metadata arrives.
+        if num_files == 0 {
+            return;
+        }
+        let i = self.file_table_state.selected().map_or(0, |i| (i + 1) % num_files);
+        self.file_table_state.select(Some(i));
+    }
+
+    fn prev_file(&mut self) {
+        let num_files = self.torrents[self.selected_idx].files.len();
+        if num_files == 0 {
+            return;
+        }
+        let i = self.file_table_state.selected().map_or(0, |i| (i + num_files - 1) % num_files);
+        self.file_table_state.select(Some(i));
+    }
+
+    // Takes an index into torrents, its row in the table is found by update_order.
     fn select(&mut self, idx: usize) {
         self.selected_idx = idx;
-        self.table_state.select(Some(idx));
-        self.scroll_state = self.scroll_state.position(idx);
+        self.file_table_state.select(Some(0));
+        self.update_order();
     }
 
     fn remove_torrent(&mut self, idx: usize) {
@@ -226,11 +368,82 @@ impl App {
         for (i, torrent) in self.torrents.iter().enumerate() {
             self.torrent_lookup.insert(torrent.id, i);
         }
-        if self.torrents.len() > 0 {
-            self.select(0);
+        if self.torrents.is_empty() {
+            self.selected_idx = 0;
+            self.order.clear();
+            self.table_state.select(None);
+            self.enter_file_explorer = true;
+        } else if idx == self.selected_idx {
+            // Next torrent takes the removed one's place, or the previous if it was last.
+            self.select(idx.min(self.torrents.len() - 1));
         } else {
+            // Torrents after the removed one have shifted down.
+            if idx < self.selected_idx {
+                self.selected_idx -= 1;
+            }
+            self.update_order();
+        }
+    }
+
+    // The explorer and link prompt can open each other, so keep going until neither is wanted.
+    async fn enter_menus(&mut self, terminal: &mut Terminal) -> Result<()> {
+        while !self.quit && (self.enter_file_explorer || self.enter_link_prompt) {
+            if self.enter_file_explorer {
+                self.enter_file_explorer = false;
+                self.enter_file_explorer(terminal)?;
+            }
+            if self.enter_link_prompt && !self.quit {
+                self.enter_link_prompt = false;
+                self.enter_link_prompt(terminal).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Asks for a magnet link or .torrent url until one is added, esc goes back.
+    async fn enter_link_prompt(&mut self, terminal: &mut Terminal) -> Result<()> {
+        let mut input = self.pasted.take().unwrap_or_default();
+        while let Some(link) = prompt(terminal, " Magnet link or .torrent url ", input.clone(), None)? {
+            match self.add_link(&link).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    popup(terminal, &e.to_string(), " Failed to add torrent ")?;
+                    // Keep the link so it can be corrected.
+                    input = link;
+                },
+            }
+        }
+        // Can't render the UI without a torrent.
+        if self.torrents.is_empty() {
             self.enter_file_explorer = true;
         }
+        Ok(())
+    }
+
+    // Errors aren't eyre reports, as creating one runs the hook that gives up the terminal.
+    async fn add_link(&mut self, link: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let data = if link.starts_with("magnet:") {
+            let magnet = Magnet::parse(link)?;
+            if self.torrent_lookup.contains_key(&magnet.info_hash) {
+                return Err("torrent already added".into());
+            }
+            self.client.new_magnet(link)?;
+            TorrentData::from_magnet(&magnet)
+        } else if link.starts_with("http://") || link.starts_with("https://") {
+            let bytes = reqwest::get(link).await?.error_for_status()?.bytes().await?;
+            let metainfo = MetaInfo::from_bytes(&bytes)?;
+            if self.torrent_lookup.contains_key(&metainfo.info_hash()) {
+                return Err("torrent already added".into());
+            }
+            self.client.new_torrent(metainfo.clone())?;
+            TorrentData::new(metainfo)
+        } else {
+            return Err("expected a magnet link or http(s) url".into());
+        };
+        self.torrent_lookup.insert(data.id, self.torrents.len());
+        self.torrents.push(data);
+        self.select(self.torrents.len() - 1);
+        Ok(())
     }
 
     fn enter_file_explorer(&mut self, terminal: &mut Terminal) -> Result<()> {
@@ -269,17 +482,35 @@ impl App {
                                 } else {
                                     let metainfo = MetaInfo::new(file.path())?;
                                     let id = metainfo.info_hash();
-                                    // Add the torrent to internal list.
-                                    self.torrent_lookup.insert(id, self.torrents.len());
-                                    self.torrents.push(TorrentData::new(metainfo.clone()));
-                                    // Sends the metainfo to the bittorrent client.
-                                    self.client.new_torrent(metainfo)?;
-                                    // Select this torrent (the latest).
-                                    self.select(self.torrents.len() - 1);
-                                    return Ok(());
+                                    // Ask where to save it until the client accepts the directory, esc goes back to the explorer.
+                                    let mut error = None;
+                                    while let Some(dir) = prompt(terminal, " Download directory (empty for default) ", String::new(), error.take())? {
+                                        // Sends the metainfo to the bittorrent client.
+                                        let result = if dir.is_empty() {
+                                            self.client.new_torrent(metainfo.clone())
+                                        } else {
+                                            self.client.new_torrent_to(metainfo.clone(), dir)
+                                        };
+                                        if let Err(e) = result {
+                                            error = Some(e.to_string());
+                                            continue;
+                                        }
+                                        // Add the torrent to internal list.
+                                        self.torrent_lookup.insert(id, self.torrents.len());
+                                        self.torrents.push(TorrentData::new(metainfo));
+                                        // Select this torrent (the latest).
+                                        self.select(self.torrents.len() - 1);
+                                        return Ok(());
+                                    }
                                 }
                             }
 
+                            // The link prompt needs to await the download of a .torrent, so is opened from the main loop.
+                            event::KeyCode::Char('m') => {
+                                self.enter_link_prompt = true;
+                                return Ok(());
+                            },
+
                             _ => { self.file_explorer.handle(&event)?; }
                         
                         }
@@ -290,55 +521,79 @@ impl App {
             }
         }
     }
+}
 
-//     fn popup(&mut self, terminal: &mut Terminal, msg: &str, title: &str) {
-//         loop {
-
-//             terminal.draw(|f| {
-//                 let block = widgets::Block::default()
-//                     .title(title)
-//                     .title_bottom(" 'Enter' to continue ")
-//                     .borders(widgets::Borders::ALL);
-//                 let popup = widgets::Paragraph::new(msg)
-//                     .centered()
-//                     .block(block);
-//                 let area = centered_rect(60, 20, f.size());
-//                 f.render_widget(widgets::Clear, area);
-//                 f.render_widget(popup, area);
-//             }).unwrap();
-
-//             if let Some(event) = event::read().ok() {
-//                 match event {
-//                     Event::Key(key) => {
-//                         if key.kind != event::KeyEventKind::Press {
-//                             continue;
-//                         }
-//                         match key.code {
-//                             event::KeyCode::Enter => {
-//                                 return;
-//                             },
-//                             _ => {}
-//                         }
-//                     },
-//                     _ => {}
-//                 }
-//             }
-//         }
-//     }
+// Text input starting from the given text, None if cancelled.
+// Pasted text is added to the input as if typed.
+fn prompt(terminal: &mut Terminal, title: &str, mut input: String, error: Option<String>) -> Result<Option<String>> {
+
+    loop {
+        terminal.draw(|f| {
+            let block = widgets::Block::default()
+                .title(title)
+                .title_bottom(error.as_deref().map_or(" 'enter': confirm | 'esc': back ".to_string(), |e| format!(" {} ", e)))
+                .borders(widgets::Borders::ALL);
+            let prompt = widgets::Paragraph::new(input.as_str()).block(block);
+            let area = centered_rect(60, 20, f.size());
+            f.render_widget(widgets::Clear, area);
+            f.render_widget(prompt, area);
+        })?;
+
+        match event::read()? {
+            Event::Key(key) => {
+                if key.kind != event::KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    event::KeyCode::Enter => return Ok(Some(input.trim().to_string())),
+                    event::KeyCode::Esc => return Ok(None),
+                    event::KeyCode::Backspace => { input.pop(); },
+                    event::KeyCode::Char(c) => input.push(c),
+                    _ => {}
+                }
+            },
+            Event::Paste(text) => input.push_str(&text),
+            _ => {}
+        }
+    }
 }
 
-// fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
-//     let popup_layout = Layout::vertical([
-//         Constraint::Percentage((100 - percent_y) / 2),
-//         Constraint::Percentage(percent_y),
-//         Constraint::Percentage((100 - percent_y) / 2),
-//     ])
-//     .split(r);
-
-//     Layout::horizontal([
-//         Constraint::Percentage((100 - percent_x) / 2),
-//         Constraint::Percentage(percent_x),
-//         Constraint::Percentage((100 - percent_x) / 2),
-//     ])
-//     .split(popup_layout[1])[1]
-// }
\ No newline at end of file
+// Shows a message until enter is pressed.
+fn popup(terminal: &mut Terminal, msg: &str, title: &str) -> Result<()> {
+    loop {
+        terminal.draw(|f| {
+            let block = widgets::Block::default()
+                .title(title)
+                .title_bottom(" 'enter': continue ")
+                .borders(widgets::Borders::ALL);
+            let popup = widgets::Paragraph::new(msg)
+                .centered()
+                .block(block);
+            let area = centered_rect(60, 20, f.size());
+            f.render_widget(widgets::Clear, area);
+            f.render_widget(popup, area);
+        })?;
+
+        if let Event::Key(key) = event::read()? {
+            if key.kind == event::KeyEventKind::Press && key.code == event::KeyCode::Enter {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
+    let popup_layout = Layout::vertical([
+        Constraint::Percentage((100 - percent_y) / 2),
+        Constraint::Percentage(percent_y),
+        Constraint::Percentage((100 - percent_y) / 2),
+    ])
+    .split(r);
+
+    Layout::horizontal([
+        Constraint::Percentage((100 - percent_x) / 2),
+        Constraint::Percentage(percent_x),
+        Constraint::Percentage((100 - percent_x) / 2),
+    ])
+    .split(popup_layout[1])[1]
+}
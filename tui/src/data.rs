@@ -1,5 +1,5 @@
-use std::time::{Duration, Instant};
-use bittorrent::{stats::{PeerStats, PieceStats, TorrentStats}, ConnState, MetaInfo, TorrentState, ID};
+use std::{net::SocketAddr, time::{Duration, Instant}};
+use bittorrent::{stats::{PeerStats, PieceStats, TorrentStats}, ConnState, CryptoMethod, FilePriority, Magnet, MetaInfo, SessionState, TorrentState, ID};
 
 // Information the user may want to know about a torrent.
 #[derive(Debug)]
@@ -8,14 +8,123 @@ pub struct TorrentData {
     pub id: ID,
     pub name: String,
     pub size: String,
+    pub total_len: u64,
     pub num_pieces: usize,
+    pub piece_len: usize,
+
+    // Pieces known to be downloaded, from the pieces reported in stats.
+    pub pieces: Vec<bool>,
+    pub files: Vec<FileData>,
     
     pub data: TorrentStats,
     pub history_up: Vec<u64>,
     pub history_down: Vec<u64>,
+
+    // Last problem reported by the client, shown in place of the state.
+    pub error: Option<String>,
     
 }
 
+// A file of the torrent, as shown in the file view.
+#[derive(Debug)]
+pub struct FileData {
+
+    pub path: String,
+    pub length: usize,
+
+    // Offset in bytes from the start of the torrent.
+    pub offset: usize,
+
+    pub priority: FilePriority,
+
+}
+
+// Order of the torrent table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortMode {
+    #[default]
+    Added,
+    Name,
+    Size,
+    Progress,
+    Speed,
+}
+
+impl SortMode {
+
+    pub fn next(self) -> Self {
+        match self {
+            SortMode::Added => SortMode::Name,
+            SortMode::Name => SortMode::Size,
+            SortMode::Size => SortMode::Progress,
+            SortMode::Progress => SortMode::Speed,
+            SortMode::Speed => SortMode::Added,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SortMode::Added => "added",
+            SortMode::Name => "name",
+            SortMode::Size => "size",
+            SortMode::Progress => "progress",
+            SortMode::Speed => "speed",
+        }
+    }
+
+    // Sorts indices into torrents, which start in the order they were added.
+    // Largest, most complete and fastest come first.
+    pub fn sort(self, order: &mut [usize], torrents: &[TorrentData]) {
+        match self {
+            SortMode::Added => order.sort(),
+            SortMode::Name => order.sort_by_cached_key(|&i| torrents[i].name.to_lowercase()),
+            SortMode::Size => order.sort_by_key(|&i| std::cmp::Reverse(torrents[i].total_len)),
+            SortMode::Progress => order.sort_by_key(|&i| std::cmp::Reverse(torrents[i].percent_complete())),
+            SortMode::Speed => order.sort_by_key(|&i| std::cmp::Reverse(torrents[i].data.throughput.down.avg())),
+        }
+    }
+}
+
+// Which torrents are shown in the torrent table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filter {
+    #[default]
+    All,
+    Downloading,
+    Seeding,
+    Paused,
+}
+
+impl Filter {
+
+    pub fn next(self) -> Self {
+        match self {
+            Filter::All => Filter::Downloading,
+            Filter::Downloading => Filter::Seeding,
+            Filter::Seeding => Filter::Paused,
+            Filter::Paused => Filter::All,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Filter::All => "all",
+            Filter::Downloading => "downloading",
+            Filter::Seeding => "seeding",
+            Filter::Paused => "paused",
+        }
+    }
+
+    pub fn matches(self, torrent: &TorrentData) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Downloading => torrent.data.state == TorrentState::Downloading,
+            Filter::Seeding => torrent.data.state == TorrentState::Seeding,
+            Filter::Paused => torrent.data.state == TorrentState::Paused,
+        }
+    }
+}
+
 impl TorrentData {
     
     pub fn new(metainfo: MetaInfo) -> Self {
@@ -23,25 +132,51 @@ impl TorrentData {
             id: metainfo.info_hash(),
             name: metainfo.name().to_string(),
             size: metainfo.size_fmt(),
+            total_len: metainfo.total_len(),
             num_pieces: metainfo.num_pieces() as usize,
+            piece_len: metainfo.piece_len(),
+            pieces: vec![false; metainfo.num_pieces() as usize],
+            files: metainfo.files().into_iter().map(|file| FileData {
+                path: file.path.display().to_string(),
+                length: file.length,
+                offset: file.offset,
+                priority: FilePriority::default(),
+            }).collect(),
             history_up: vec![0; 200],
             history_down: vec![0; 200],
-            data: TorrentStats {
-                start_time: Instant::now(),
-                time_elapsed: Duration::default(),
-                state: TorrentState::default(),
-                piece_stats: PieceStats {
-                    num_pieces: metainfo.num_pieces() as usize,
-                    num_pending: 0,
-                    num_downloaded: 0,
-                },
-                peer_stats: Vec::new(),
-                throughput: Default::default(),
-            }
+            error: None,
+            data: empty_stats(metainfo.num_pieces() as usize),
         }
     }
 
+    // Stands in for the torrent until its metadata is fetched, it has no pieces or files until then.
+    pub fn from_magnet(magnet: &Magnet) -> Self {
+        Self {
+            id: magnet.info_hash,
+            name: magnet.name.clone().unwrap_or_else(|| hex::encode(magnet.info_hash)),
+            size: "?".to_string(),
+            total_len: 0,
+            num_pieces: 0,
+            piece_len: 0,
+            pieces: Vec::new(),
+            files: Vec::new(),
+            history_up: vec![0; 200],
+            history_down: vec![0; 200],
+            error: None,
+            data: empty_stats(0),
+        }
+    }
+
+    pub fn has_metadata(&self) -> bool {
+        self.num_pieces > 0
+    }
+
     pub fn update_torrent_stats(&mut self, stats: TorrentStats) {
+        for idx in stats.new_pieces.iter().flatten() {
+            if let Some(piece) = self.pieces.get_mut(*idx) {
+                *piece = true;
+            }
+        }
         self.history_up.pop();
         self.history_up.insert(0, stats.throughput.up.avg());
         self.history_down.pop();
@@ -49,23 +184,41 @@ impl TorrentData {
         self.data = stats;
     }
 
+    pub fn peer_connected(&mut self, address: SocketAddr) {
+        if !self.data.peer_stats.iter().any(|peer| peer.address == address) {
+            let state = SessionState { conn_state: ConnState::Connected, ..Default::default() };
+            self.data.peer_stats.push(PeerStats { address, state });
+        }
+    }
+
+    pub fn peer_disconnected(&mut self, address: SocketAddr) {
+        self.data.peer_stats.retain(|peer| peer.address != address);
+    }
+
     pub fn torrent_table_row_data(&self) -> [String; 5] {
         [
             self.name.clone(),
             self.size.clone(), 
-            match self.data.state {
+            if let Some(error) = &self.error {
+                error.clone()
+            } else if !self.has_metadata() {
+                "fetching metadata".to_string()
+            } else { match self.data.state {
                 TorrentState::Downloading => "downloading".to_string(),
                 TorrentState::Seeding => "seeding".to_string(),
                 TorrentState::Paused => "paused".to_string(),
-                TorrentState::Checking => "checking".to_string(),
+                TorrentState::Checking => format!(
+                    "checking {:.0}%",
+                    self.data.piece_stats.num_checked as f64 / self.num_pieces as f64 * 100.0,
+                ),
                 TorrentState::Stopped => "stopped".to_string(),
-            },
+            }},
             format!("{:.1}%", self.percent_complete()),
             self.time_elapsed(),
         ]
     }
 
-    pub fn peer_table_row_data(&self) -> Vec<[String; 5]> {
+    pub fn peer_table_row_data(&self) -> Vec<[String; 6]> {
 
         let mut peer_stats = self.data.peer_stats.clone();
         peer_stats.sort_by(|a, b| {
@@ -79,14 +232,67 @@ impl TorrentData {
                 
                 [
                     peer.address.to_string(),
+                    peer.state.client().unwrap_or_default(),
                     peer_flags(&peer),
-                    format!("{:.0}%", peer.state.num_pieces as f64 / self.num_pieces as f64 * 100.0),
+                    // Unknown until the metadata is fetched.
+                    if self.has_metadata() {
+                        format!("{:.0}%", peer.state.num_pieces as f64 / self.num_pieces as f64 * 100.0)
+                    } else {
+                        "?".to_string()
+                    },
                     format!("{:.2}", peer.state.throughput.down.avg() as f64 / 1024.0),
                     format!("{:.2}", peer.state.throughput.up.avg() as f64 / 1024.0),
                 ]
         }).collect()
     }
 
+    pub fn file_table_row_data(&self) -> Vec<[String; 4]> {
+        self.files
+            .iter()
+            .map(|file| [
+                file.path.clone(),
+                format_size(file.length),
+                format!("{:.1}%", self.file_progress(file) * 100.0),
+                match file.priority {
+                    FilePriority::Skip => "skip".to_string(),
+                    FilePriority::Normal => "normal".to_string(),
+                    FilePriority::High => "high".to_string(),
+                },
+            ])
+            .collect()
+    }
+
+    // Moves the file on to the next priority, giving the priorities of every file.
+    pub fn cycle_priority(&mut self, idx: usize) -> Vec<FilePriority> {
+        if let Some(file) = self.files.get_mut(idx) {
+            file.priority = match file.priority {
+                FilePriority::Normal => FilePriority::High,
+                FilePriority::High => FilePriority::Skip,
+                FilePriority::Skip => FilePriority::Normal,
+            };
+        }
+        self.files.iter().map(|file| file.priority).collect()
+    }
+
+    // Share of the file's bytes in downloaded pieces.
+    fn file_progress(&self, file: &FileData) -> f64 {
+        if file.length == 0 {
+            return 1.0;
+        }
+        let end = file.offset + file.length;
+        let first = file.offset / self.piece_len;
+        let last = (end - 1) / self.piece_len;
+        let done: usize = (first..=last)
+            .filter(|idx| self.pieces.get(*idx).copied().unwrap_or(false))
+            .map(|idx| {
+                let piece_start = idx * self.piece_len;
+                let piece_end = piece_start + self.piece_len;
+                piece_end.min(end) - piece_start.max(file.offset)
+            })
+            .sum();
+        done as f64 / file.length as f64
+    }
+
     pub fn percent_complete(&self) -> u16 {
         (
             (
@@ -116,6 +322,21 @@ impl TorrentData {
         }
     }
 
+    // Swarm size from the trackers, "?" for counts not yet known.
+    pub fn swarm(&self) -> String {
+        let count = |n: Option<u64>| n.map_or("?".to_string(), |n| n.to_string());
+        format!(
+            "Seeds: {} / Peers: {}",
+            count(self.data.tracker.seeders),
+            count(self.data.tracker.leechers),
+        )
+    }
+
+    // Share of blocks uploaded from the read cache.
+    pub fn cache_hit_rate(&self) -> String {
+        self.data.cache.hit_rate().map_or("-".to_string(), |rate| format!("{:.0}%", rate * 100.0))
+    }
+
     fn time_elapsed(&self) -> String {
         let total_secs = self.data.time_elapsed.as_secs();
         let hours = total_secs / 3600;
@@ -125,6 +346,40 @@ impl TorrentData {
     }
 }
 
+// Until the first stats arrive.
+fn empty_stats(num_pieces: usize) -> TorrentStats {
+    TorrentStats {
+        start_time: Instant::now(),
+        time_elapsed: Duration::default(),
+        state: TorrentState::default(),
+        piece_stats: PieceStats {
+            num_pieces,
+            num_pending: 0,
+            num_downloaded: 0,
+            num_checked: 0,
+        },
+        peer_stats: Vec::new(),
+        throughput: Default::default(),
+        tracker: Default::default(),
+        piece_timing: None,
+        new_pieces: None,
+        cache: Default::default(),
+    }
+}
+
+fn format_size(bytes: usize) -> String {
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in ["KiB", "MiB", "GiB", "TiB"] {
+        if size <= 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{:.2} {}", size, unit)
+}
+
 fn peer_flags(peer: &PeerStats) -> String {
     
     let mut flags = String::new();
@@ -143,6 +398,17 @@ fn peer_flags(peer: &PeerStats) -> String {
             flags.push('D');
         }
     }
+    match s.crypto {
+        // E: peer is using Protocol Encryption (all traffic)
+        Some(CryptoMethod::Rc4) => flags.push('E'),
+        // e: peer is using Protocol Encryption (handshake)
+        Some(CryptoMethod::Plaintext) => flags.push('e'),
+        None => {},
+    }
+    // P: peer is communicating and transporting data over uTP
+    if s.utp {
+        flags.push('P');
+    }
     // K: peer unchoked your client, but your client is not interested
     if !s.peer_choking && !s.interested {
         flags.push('K');
@@ -1,5 +1,5 @@
 use std::io::stdout;
-use crossterm::{execute, terminal::*, ExecutableCommand};
+use crossterm::{event::{DisableBracketedPaste, EnableBracketedPaste}, execute, terminal::*, ExecutableCommand};
 use tui::app::App;
 
 #[tokio::main]
@@ -10,13 +10,14 @@ async fn main() -> color_eyre::Result<()> {
     let mut app = App::new()?;
     
     // Take control of the terminal.
-    execute!(stdout(), EnterAlternateScreen)?;
+    // Bracketed paste gives a pasted magnet link as one event.
+    execute!(stdout(), EnterAlternateScreen, EnableBracketedPaste)?;
     enable_raw_mode()?;
 
     let res = app.run().await;
     
     // Return control of the terminal.
-    execute!(stdout(), LeaveAlternateScreen)?;
+    execute!(stdout(), DisableBracketedPaste, LeaveAlternateScreen)?;
     disable_raw_mode()?;
 
     app.shutdown().await?;
@@ -31,7 +32,7 @@ fn init_panic_hooks() -> color_eyre::Result<()> {
 
     let panic_hook = panic_hook.into_panic_hook();
     std::panic::set_hook(Box::new(move |panic_info| {
-        stdout().execute(LeaveAlternateScreen).unwrap();
+        stdout().execute(DisableBracketedPaste).unwrap().execute(LeaveAlternateScreen).unwrap();
         disable_raw_mode().unwrap();
         println!("{:?}", panic_info);
         panic_hook(panic_info);
@@ -39,7 +40,7 @@ fn init_panic_hooks() -> color_eyre::Result<()> {
 
     let eyre_hook = eyre_hook.into_eyre_hook();
     color_eyre::eyre::set_hook(Box::new(move |error| {
-        stdout().execute(LeaveAlternateScreen).unwrap();
+        stdout().execute(DisableBracketedPaste).unwrap().execute(LeaveAlternateScreen).unwrap();
         disable_raw_mode().unwrap();
         println!("{:?}", error);
         eyre_hook(error)
@@ -1,5 +1,5 @@
 use ratatui::{widgets, prelude::*};
-use crate::data::TorrentData;
+use crate::data::{Filter, SortMode, TorrentData};
 
 
 // Renders the top left panel.
@@ -7,11 +7,16 @@ pub fn render_torrent_table(
     f: &mut ratatui::Frame, 
     area: Rect, 
     state: &mut widgets::TableState,
-    torrents: &Vec<TorrentData>
+    torrents: &Vec<TorrentData>,
+    // Indices of the torrents to show, in order.
+    order: &[usize],
+    sort: SortMode,
+    filter: Filter,
 ) {
 
     let block = widgets::Block::default()
-        .title(" Torrents ")
+        .title(format!(" Torrents ({}/{}) ", order.len(), torrents.len()))
+        .title_bottom(format!(" 's': sort by {} | 'v': show {} ", sort.label(), filter.label()))
         .borders(widgets::Borders::ALL);
 
     let header = ["Name", "Size", "Status", "Progress", "Time"]
@@ -22,10 +27,10 @@ pub fn render_torrent_table(
         .style(Style::new().underlined())
         .height(1);
 
-    let rows = torrents
+    let rows = order
         .iter()
-        .map(|torrent| {
-            torrent
+        .map(|&i| {
+            torrents[i]
                 .torrent_table_row_data()
                 .iter()
                 .cloned()
@@ -60,10 +65,11 @@ pub fn render_torrent_panel(f: &mut ratatui::Frame, area: Rect, data: &TorrentDa
         .split(area);
 
     let progress_title = format!(
-        " Progress: {{ pieces: {}/{} | eta: {} }} ",
+        " Progress: {{ pieces: {}/{} | eta: {} | {} }} ",
         data.data.piece_stats.num_downloaded,
         data.num_pieces,
         data.eta(),
+        data.swarm(),
     );
 
     let gauge_block = widgets::Block::default()
@@ -91,9 +97,10 @@ pub fn render_torrent_panel(f: &mut ratatui::Frame, area: Rect, data: &TorrentDa
         .style(Style::default().fg(Color::Blue));
 
     let upload_title = format!(
-        " Upload: {{ {:.2} KB/s | peak: {:.2} KB/s }} ",
+        " Upload: {{ {:.2} KB/s | peak: {:.2} KB/s | cache hits: {} }} ",
         data.data.throughput.up.avg() as f64 / 1024.0,
         data.data.throughput.up.peak() as f64 / 1024.0,
+        data.cache_hit_rate(),
     );
 
     let upload_block = widgets::Block::default()
@@ -110,13 +117,54 @@ pub fn render_torrent_panel(f: &mut ratatui::Frame, area: Rect, data: &TorrentDa
     f.render_widget(upload, rows[2]);
 }
 
+// Replaces the peer table whilst the file view is open.
+pub fn render_file_table(
+    f: &mut ratatui::Frame,
+    area: Rect,
+    state: &mut widgets::TableState,
+    data: &TorrentData,
+) {
+
+    let block = widgets::Block::default()
+        .title(" Files ")
+        .title_bottom(" 'space': priority | 'f': peers ")
+        .borders(widgets::Borders::ALL);
+
+    let header = ["Path", "Size", "Progress", "Priority"]
+        .iter()
+        .cloned()
+        .map(widgets::Cell::from)
+        .collect::<widgets::Row>()
+        .style(Style::new().underlined())
+        .height(1);
+
+    let rows = data
+        .file_table_row_data()
+        .into_iter()
+        .map(|file| {
+            file
+                .into_iter()
+                .map(|x| widgets::Cell::from(Text::from(x)))
+                .collect::<widgets::Row>()
+                .height(1)
+        });
+
+    let table = widgets::Table::new(rows, Constraint::from_percentages([55, 15, 15, 15]))
+        .block(block)
+        .header(header)
+        .highlight_style(Style::default().add_modifier(ratatui::style::Modifier::REVERSED))
+        .highlight_spacing(widgets::HighlightSpacing::Always);
+
+    f.render_stateful_widget(table, area, state);
+}
+
 pub fn render_peer_table(f: &mut ratatui::Frame, area: Rect, data: &TorrentData) {
 
     let block = widgets::Block::default()
         .title(" Peers ")
         .borders(widgets::Borders::ALL);
 
-    let header = ["Address", "State", "Coverage", "D KB/s", "U KB/s"]
+    let header = ["Address", "Client", "State", "Coverage", "D KB/s", "U KB/s"]
         .iter()
         .cloned()
         .map(widgets::Cell::from)
@@ -136,7 +184,7 @@ pub fn render_peer_table(f: &mut ratatui::Frame, area: Rect, data: &TorrentData)
                 .height(1)
         }); 
 
-    let table = widgets::Table::new(rows, Constraint::from_percentages([20; 5]))
+    let table = widgets::Table::new(rows, Constraint::from_percentages([20, 20, 15, 15, 15, 15]))
         .block(block)
         .header(header)
         .highlight_style(Style::default().add_modifier(ratatui::style::Modifier::REVERSED))